@@ -0,0 +1,191 @@
+use crate::config::{self, Config};
+use crate::diff;
+use crate::wildmatch;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// The state of an attribute set for a path. Attributes no line mentions
+/// are unspecified, and absent from the attributes of a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum State {
+    Set,
+    Unset,
+    Value(String),
+}
+
+/// A line of a `.gitattributes` file: the attributes given to the paths
+/// matching a pattern, relative to the directory of the file.
+#[derive(Debug)]
+struct Rule {
+    base: String,
+    pattern: String,
+    attributes: Vec<(String, State)>,
+}
+
+/// Returns the attributes of `path`, a path from the top of the working
+/// tree. The `.gitattributes` files of deeper directories take precedence
+/// over those above them, and `.git/info/attributes` over all of them.
+pub fn lookup(git_dir: &Path, path: &Path) -> eyre::Result<HashMap<String, State>> {
+    let path = path.to_string_lossy();
+
+    // Files are read from the lowest precedence to the highest
+    let mut files = vec![(String::new(), ".gitattributes".to_string())];
+    let dirs = path.split('/').collect::<Vec<_>>();
+    let mut base = String::new();
+    for dir in &dirs[..dirs.len() - 1] {
+        base.push_str(dir);
+        base.push('/');
+        files.push((base.clone(), format!("{base}.gitattributes")));
+    }
+    let info = git_dir.join("info/attributes");
+    files.push((String::new(), info.to_string_lossy().into_owned()));
+
+    let mut macros = HashMap::from([(
+        "binary".to_string(),
+        vec![
+            ("diff".to_string(), State::Unset),
+            ("merge".to_string(), State::Unset),
+            ("text".to_string(), State::Unset),
+        ],
+    )]);
+    let mut attributes = HashMap::new();
+    for (base, file) in files {
+        let Ok(content) = fs::read_to_string(&file) else {
+            continue;
+        };
+        for rule in parse(&base, &content) {
+            // Macros can only be defined at the top
+            if let Some(name) = rule.pattern.strip_prefix("[attr]") {
+                if base.is_empty() {
+                    macros.insert(name.to_string(), rule.attributes);
+                }
+                continue;
+            }
+            let Some(relative) = path.strip_prefix(&rule.base) else {
+                continue;
+            };
+            if !wildmatch::matches_path(&rule.pattern, relative) {
+                continue;
+            }
+            for (name, state) in rule.attributes {
+                if let (Some(expansion), State::Set) = (macros.get(&name), &state) {
+                    attributes.extend(expansion.iter().cloned());
+                }
+                attributes.insert(name, state);
+            }
+        }
+    }
+    Ok(attributes)
+}
+
+/// Parses the lines of a `.gitattributes` file found in the directory
+/// `base`.
+fn parse(base: &str, content: &str) -> Vec<Rule> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let pattern = words.next().filter(|p| !p.starts_with('#'))?;
+            let attributes = words
+                .map(|word| match word.split_once('=') {
+                    Some((name, value)) => (name.to_string(), State::Value(value.to_string())),
+                    None => match word.strip_prefix(['-', '!']) {
+                        Some(name) => (name.to_string(), State::Unset),
+                        None => (word.to_string(), State::Set),
+                    },
+                })
+                .collect();
+            Some(Rule {
+                base: base.to_string(),
+                pattern: pattern.to_string(),
+                attributes,
+            })
+        })
+        .collect()
+}
+
+/// Whether a file's line endings are normalized when stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Text {
+    Yes,
+    No,
+    /// Only if the content looks like text.
+    Auto,
+}
+
+/// Converts the content of the file at `path` the way it's stored in the
+/// repository, following its attributes: `$Id$` keywords are collapsed for
+/// `ident`, and text files get LF line endings, `text`, `eol` and
+/// `core.autocrlf` deciding which files are text.
+pub fn to_git(
+    git_dir: &Path,
+    config: &Config,
+    path: &Path,
+    content: Vec<u8>,
+) -> eyre::Result<Vec<u8>> {
+    let attributes = lookup(git_dir, path)?;
+    let mut content = content;
+    if attributes.get("ident") == Some(&State::Set) {
+        content = collapse_ident(&content);
+    }
+
+    let autocrlf = match config.get("core.autocrlf") {
+        Some(value) if value.eq_ignore_ascii_case("input") => true,
+        Some(value) => config::parse_bool(value)?,
+        None => false,
+    };
+    let text = match attributes.get("text") {
+        Some(State::Set) => Text::Yes,
+        Some(State::Unset) => Text::No,
+        Some(State::Value(value)) if value == "auto" => Text::Auto,
+        // An end of line only makes sense for text
+        _ if attributes.contains_key("eol") => Text::Yes,
+        _ if autocrlf => Text::Auto,
+        _ => Text::No,
+    };
+
+    // Files with lone carriage returns aren't guessed to be text
+    let lone_cr = content
+        .iter()
+        .enumerate()
+        .any(|(i, c)| *c == b'\r' && content.get(i + 1) != Some(&b'\n'));
+    let convert = match text {
+        Text::Yes => true,
+        Text::No => false,
+        Text::Auto => !diff::is_binary(&content) && !lone_cr,
+    };
+    if !convert || !content.contains(&b'\r') {
+        return Ok(content);
+    }
+
+    let mut converted = Vec::with_capacity(content.len());
+    for (i, c) in content.iter().enumerate() {
+        if *c != b'\r' || content.get(i + 1) != Some(&b'\n') {
+            converted.push(*c);
+        }
+    }
+    Ok(converted)
+}
+
+/// Collapses the expanded `$Id: <sha> $` keywords of `content` to `$Id$`.
+fn collapse_ident(content: &[u8]) -> Vec<u8> {
+    let mut collapsed = Vec::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.windows(4).position(|w| w == b"$Id:") {
+        let after = &rest[start + 4..];
+        // The keyword ends on its line
+        let Some(end) = after.iter().position(|c| *c == b'$' || *c == b'\n') else {
+            break;
+        };
+        collapsed.extend_from_slice(&rest[..start]);
+        if after[end] == b'$' {
+            collapsed.extend_from_slice(b"$Id$");
+        } else {
+            collapsed.extend_from_slice(&rest[start..start + 4 + end]);
+        }
+        rest = &after[end + (after[end] == b'$') as usize..];
+    }
+    collapsed.extend_from_slice(rest);
+    collapsed
+}
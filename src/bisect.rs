@@ -0,0 +1,650 @@
+use crate::branch::{self, SwitchOptions};
+use crate::commit::Commit;
+use crate::config::Config;
+use crate::log::format_header;
+use crate::mailmap::Mailmap;
+use crate::refs;
+use crate::revision;
+use crate::revwalk::RevWalk;
+use eyre::eyre;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Where `HEAD` was when the bisection started: a branch name, or a sha.
+const START: &str = "BISECT_START";
+/// The terms of the bisection, the new one on the first line.
+const TERMS: &str = "BISECT_TERMS";
+/// The commands of the bisection so far, for `bisect log` and `replay`.
+const LOG: &str = "BISECT_LOG";
+
+/// The subcommands of `bisect`, which can't be terms.
+const COMMANDS: [&str; 11] = [
+    "help",
+    "start",
+    "skip",
+    "next",
+    "reset",
+    "visualize",
+    "view",
+    "replay",
+    "log",
+    "run",
+    "terms",
+];
+
+/// The names of the two states of the commits a bisection tells apart: the
+/// commits with the change looked for are `new`, the older ones without it
+/// `old`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Terms {
+    pub new: String,
+    pub old: String,
+}
+
+impl Default for Terms {
+    fn default() -> Self {
+        Self {
+            new: "bad".to_string(),
+            old: "good".to_string(),
+        }
+    }
+}
+
+impl Terms {
+    /// Reads the terms of the bisection in progress, if they were chosen.
+    pub fn read(git_dir: &Path) -> eyre::Result<Option<Self>> {
+        let Ok(content) = fs::read_to_string(git_dir.join(TERMS)) else {
+            return Ok(None);
+        };
+        let mut lines = content.lines();
+        match (lines.next(), lines.next()) {
+            (Some(new), Some(old)) => Ok(Some(Self {
+                new: new.to_string(),
+                old: old.to_string(),
+            })),
+            _ => Err(eyre!(tr!("invalid file '%s'", TERMS))),
+        }
+    }
+
+    fn write(&self, git_dir: &Path) -> eyre::Result<()> {
+        fs::write(git_dir.join(TERMS), format!("{}\n{}\n", self.new, self.old))?;
+        Ok(())
+    }
+
+    /// Checks that `new` and `old` can be the terms of a bisection: names of
+    /// refs that aren't subcommands, keeping `bad` and `new` for the
+    /// commits with the change, `good` and `old` for the ones without.
+    fn check(new: &str, old: &str) -> eyre::Result<Self> {
+        for (term, orig) in [(new, "bad"), (old, "good")] {
+            if !refs::is_valid_name(&format!("refs/bisect/{term}")) {
+                return Err(eyre!(tr!("'%s' is not a valid term", term)));
+            }
+            if COMMANDS.contains(&term) {
+                return Err(eyre!(tr!(
+                    "can't use the builtin command '%s' as a term",
+                    term
+                )));
+            }
+            let reserved = match orig {
+                "bad" => ["good", "old"],
+                _ => ["bad", "new"],
+            };
+            if reserved.contains(&term) {
+                return Err(eyre!(tr!(
+                    "can't change the meaning of the term '%s'",
+                    term
+                )));
+            }
+        }
+        if new == old {
+            return Err(eyre!(tr!("please use two different terms")));
+        }
+        Ok(Self {
+            new: new.to_string(),
+            old: old.to_string(),
+        })
+    }
+}
+
+/// How a commit was marked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mark {
+    New,
+    Old,
+    Skip,
+}
+
+/// Returns true if a bisection is in progress.
+pub fn is_bisecting(git_dir: &Path) -> bool {
+    git_dir.join(START).is_file()
+}
+
+/// Starts a bisection, with the custom terms `new` and `old` if given. The
+/// first of `revisions` is the first commit with the change, the others
+/// commits without it. A bisection already in progress starts over, from
+/// the same branch.
+pub fn start(
+    git_dir: &Path,
+    config: &Config,
+    new: Option<&str>,
+    old: Option<&str>,
+    revisions: &[String],
+) -> eyre::Result<()> {
+    begin(git_dir, new, old, revisions)?;
+    next(git_dir, config)
+}
+
+/// Marks `revisions`, `HEAD` by default, with `term`: one of the terms of
+/// the bisection, `bad` or `good` by default, or `new` and `old` before the
+/// terms are set. Only one commit can be marked with the new term.
+pub fn mark(git_dir: &Path, config: &Config, term: &str, revisions: &[String]) -> eyre::Result<()> {
+    let terms = terms_for(git_dir, term)?;
+    let mark = match term == terms.new {
+        true => Mark::New,
+        false => Mark::Old,
+    };
+    if mark == Mark::New && revisions.len() > 1 {
+        return Err(eyre!(tr!(
+            "'git bisect %s' can take only one argument.",
+            term
+        )));
+    }
+    for sha in resolve(git_dir, revisions)? {
+        write_mark(git_dir, &terms, mark, &sha, true)?;
+    }
+    next(git_dir, config)
+}
+
+/// Marks `revisions`, `HEAD` by default, as commits that can't be tested,
+/// so the bisection picks others.
+pub fn skip(git_dir: &Path, config: &Config, revisions: &[String]) -> eyre::Result<()> {
+    if !is_bisecting(git_dir) {
+        return Err(eyre!(tr!("You need to start by \"git bisect start\"")));
+    }
+    let terms = Terms::read(git_dir)?.unwrap_or_default();
+    for sha in resolve(git_dir, revisions)? {
+        write_mark(git_dir, &terms, Mark::Skip, &sha, true)?;
+    }
+    next(git_dir, config)
+}
+
+/// Ends the bisection, switching back to where it started or to `commit`.
+pub fn reset(git_dir: &Path, config: &Config, commit: Option<&str>) -> eyre::Result<()> {
+    if !is_bisecting(git_dir) {
+        println!("{}", tr!("We are not bisecting."));
+        return Ok(());
+    }
+    let start = fs::read_to_string(git_dir.join(START))?;
+    let target = commit.unwrap_or(start.trim_end());
+    let options = SwitchOptions {
+        detach_commits: true,
+        ..SwitchOptions::default()
+    };
+    branch::switch(git_dir, config, Some(target), &options, None).map_err(|e| {
+        e.wrap_err(tr!(
+            "could not check out original HEAD '%s'. Try 'git bisect reset <commit>'.",
+            target
+        ))
+    })?;
+    clean_state(git_dir)
+}
+
+/// Returns the log of the bisection, which `replay` can run again.
+pub fn log(git_dir: &Path) -> eyre::Result<String> {
+    fs::read_to_string(git_dir.join(LOG)).map_err(|_| eyre!(tr!("We are not bisecting.")))
+}
+
+/// Runs the commands of the bisection log `file` again, from a new
+/// bisection, and checks out the next commit to test. Like the commands
+/// themselves, starting prints the status of the bisection.
+pub fn replay(git_dir: &Path, config: &Config, file: &Path) -> eyre::Result<()> {
+    let content = fs::read_to_string(file)
+        .map_err(|_| eyre!(tr!("cannot read file '%s' for replaying", file.display())))?;
+    reset(git_dir, config, None)?;
+
+    for line in content.lines() {
+        let line = line.trim();
+        let Some(command) = line
+            .strip_prefix("git bisect ")
+            .or(line.strip_prefix("git-bisect "))
+        else {
+            continue;
+        };
+        let words = split_words(command);
+        let Some((command, args)) = words.split_first() else {
+            continue;
+        };
+        match command.as_str() {
+            "start" => {
+                let mut new = None;
+                let mut old = None;
+                let mut revisions = Vec::new();
+                for arg in args {
+                    if let Some(term) = arg
+                        .strip_prefix("--term-new=")
+                        .or(arg.strip_prefix("--term-bad="))
+                    {
+                        new = Some(term.to_string());
+                    } else if let Some(term) = arg
+                        .strip_prefix("--term-old=")
+                        .or(arg.strip_prefix("--term-good="))
+                    {
+                        old = Some(term.to_string());
+                    } else if arg != "--" {
+                        revisions.push(arg.clone());
+                    }
+                }
+                start(git_dir, config, new.as_deref(), old.as_deref(), &revisions)?;
+            }
+            "skip" => {
+                let terms = Terms::read(git_dir)?.unwrap_or_default();
+                for sha in resolve(git_dir, args)? {
+                    write_mark(git_dir, &terms, Mark::Skip, &sha, true)?;
+                }
+            }
+            "terms" => {}
+            term => {
+                let terms = terms_for(git_dir, term)?;
+                let mark = match term == terms.new {
+                    true => Mark::New,
+                    false => Mark::Old,
+                };
+                for sha in resolve(git_dir, args)? {
+                    write_mark(git_dir, &terms, mark, &sha, true)?;
+                }
+            }
+        }
+    }
+    next(git_dir, config)
+}
+
+/// Prints the terms of the bisection, or only the new or the old one.
+pub fn terms(git_dir: &Path, new: bool, old: bool) -> eyre::Result<()> {
+    if !is_bisecting(git_dir) {
+        return Err(eyre!(tr!("no terms defined")));
+    }
+    let terms = Terms::read(git_dir)?.unwrap_or_default();
+    match (new, old) {
+        (true, _) => println!("{}", terms.new),
+        (_, true) => println!("{}", terms.old),
+        _ => println!(
+            "{}",
+            tr!(
+                "Your current terms are %s for the old state\nand %s for the new state.",
+                &terms.old,
+                &terms.new
+            )
+        ),
+    }
+    Ok(())
+}
+
+/// Sets up a bisection like [`start`], without checking out anything.
+fn begin(
+    git_dir: &Path,
+    new: Option<&str>,
+    old: Option<&str>,
+    revisions: &[String],
+) -> eyre::Result<()> {
+    let terms = match (new, old) {
+        (None, None) => None,
+        (new, old) => Some(Terms::check(new.unwrap_or("bad"), old.unwrap_or("good"))?),
+    };
+    let commits = match revisions.is_empty() {
+        true => Vec::new(),
+        false => resolve(git_dir, revisions)?,
+    };
+
+    let head = match is_bisecting(git_dir) {
+        true => fs::read_to_string(git_dir.join(START))?,
+        false => match refs::symbolic_target(git_dir, "HEAD")? {
+            Some(branch) => branch.trim_start_matches("refs/heads/").to_string(),
+            None => {
+                refs::read_ref(git_dir, "HEAD")?.ok_or(eyre!(tr!("bad HEAD - I need a HEAD")))?
+            }
+        },
+    };
+    clean_state(git_dir)?;
+    fs::write(git_dir.join(START), head.trim_end())?;
+
+    // Marking commits sets the default terms
+    let terms = match (terms, commits.is_empty()) {
+        (Some(terms), _) => Some(terms),
+        (None, true) => None,
+        (None, false) => Some(Terms::default()),
+    };
+    if let Some(terms) = terms {
+        terms.write(git_dir)?;
+        if let Some((bad, good)) = commits.split_first() {
+            write_mark(git_dir, &terms, Mark::New, bad, false)?;
+            for sha in good {
+                write_mark(git_dir, &terms, Mark::Old, sha, false)?;
+            }
+        }
+    }
+
+    // The log has the arguments quoted for the shell
+    let mut line = "git bisect start".to_string();
+    for arg in new
+        .map(|new| format!("--term-new={new}"))
+        .into_iter()
+        .chain(old.map(|old| format!("--term-old={old}")))
+        .chain(revisions.iter().cloned())
+    {
+        line.push_str(&format!(" '{}'", arg.replace('\'', "'\\''")));
+    }
+    append_log(git_dir, &format!("{line}\n"))
+}
+
+/// Resolves `revisions` to the commits they name, `HEAD` without any.
+fn resolve(git_dir: &Path, revisions: &[String]) -> eyre::Result<Vec<String>> {
+    let head = ["HEAD".to_string()];
+    let revisions = match revisions.is_empty() {
+        true => &head[..],
+        false => revisions,
+    };
+    revisions
+        .iter()
+        .map(|revision| {
+            revision::resolve(git_dir, revision)
+                .and_then(|sha| revision::peel(git_dir, &sha))
+                .map_err(|_| eyre!(tr!("'%s' does not appear to be a valid revision", revision)))
+        })
+        .collect()
+}
+
+/// Returns the terms of the bisection in progress, which `term` must be one
+/// of. The first marked commits set them, to `bad` and `good` or to `new`
+/// and `old`.
+fn terms_for(git_dir: &Path, term: &str) -> eyre::Result<Terms> {
+    if !is_bisecting(git_dir) {
+        return Err(eyre!(tr!("You need to start by \"git bisect start\"")));
+    }
+    let terms = match Terms::read(git_dir)? {
+        Some(terms) => terms,
+        None => {
+            let terms = match term {
+                "bad" | "good" => Terms::default(),
+                "new" | "old" => Terms {
+                    new: "new".to_string(),
+                    old: "old".to_string(),
+                },
+                _ => return Err(eyre!(tr!("unknown command: '%s'", term))),
+            };
+            terms.write(git_dir)?;
+            terms
+        }
+    };
+    if term != terms.new && term != terms.old {
+        return Err(eyre!(tr!(
+            "Invalid command: you're currently in a %s/%s bisect",
+            &terms.new,
+            &terms.old
+        )));
+    }
+    Ok(terms)
+}
+
+/// Marks the commit `sha` with a ref under `refs/bisect/`, logging it with
+/// the command marking it unless it came with `start`.
+fn write_mark(
+    git_dir: &Path,
+    terms: &Terms,
+    mark: Mark,
+    sha: &str,
+    command: bool,
+) -> eyre::Result<()> {
+    let (term, name) = match mark {
+        Mark::New => (&terms.new, terms.new.clone()),
+        Mark::Old => (&terms.old, format!("{}-{sha}", terms.old)),
+        Mark::Skip => (&"skip".to_string(), format!("skip-{sha}")),
+    };
+    refs::update_ref(git_dir, &format!("refs/bisect/{name}"), sha)?;
+    let summary = Commit::read(git_dir, sha)?.summary().to_string();
+    append_log(git_dir, &format!("# {term}: [{sha}] {summary}\n"))?;
+    match command {
+        true => append_log(git_dir, &format!("git bisect {term} {sha}\n")),
+        false => Ok(()),
+    }
+}
+
+fn append_log(git_dir: &Path, text: &str) -> eyre::Result<()> {
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(git_dir.join(LOG))?;
+    log.write_all(text.as_bytes())?;
+    Ok(())
+}
+
+/// Removes the refs and files of the bisection in progress.
+fn clean_state(git_dir: &Path) -> eyre::Result<()> {
+    for (name, _) in refs::list_refs(git_dir, "refs/bisect/")? {
+        refs::delete_ref(git_dir, &name)?;
+    }
+    for file in [START, TERMS, LOG] {
+        let path = git_dir.join(file);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks out the commit that best splits the commits left to test, the
+/// ones reachable from the new commit but not from the old ones: the one
+/// reaching closest to half of them. Once the new commit is the only one
+/// left, it's the first new commit.
+fn next(git_dir: &Path, config: &Config) -> eyre::Result<()> {
+    let terms = Terms::read(git_dir)?.unwrap_or_default();
+    let bisect_refs = refs::list_refs(git_dir, "refs/bisect/")?;
+    let find = |prefix: String| {
+        bisect_refs
+            .iter()
+            .filter(|(name, _)| name.starts_with(&prefix))
+            .map(|(_, sha)| sha.clone())
+            .collect::<Vec<_>>()
+    };
+    let bad = refs::read_ref(git_dir, &format!("refs/bisect/{}", terms.new))?;
+    let good = find(format!("refs/bisect/{}-", terms.old));
+    let skipped = find("refs/bisect/skip-".to_string());
+
+    let status = match (&bad, good.len()) {
+        (Some(bad), 1..) => {
+            let mut walk = RevWalk::new();
+            return bisect(git_dir, config, &mut walk, &terms, bad, &good, &skipped);
+        }
+        (None, 0) => tr!("status: waiting for both good and bad commits"),
+        (None, 1) => tr!("status: waiting for bad commit, 1 good commit known"),
+        (None, count) => tr!(
+            "status: waiting for bad commit, %d good commits known",
+            count
+        ),
+        (Some(_), _) => tr!("status: waiting for good commit(s), bad commit known"),
+    };
+    println!("{status}");
+    append_log(git_dir, &format!("# {status}\n"))
+}
+
+/// Checks out the next commit to test between the new commit `bad` and the
+/// old commits `good`, leaving out the `skipped` ones, or prints the first
+/// new commit once found.
+fn bisect(
+    git_dir: &Path,
+    config: &Config,
+    walk: &mut RevWalk,
+    terms: &Terms,
+    bad: &str,
+    good: &[String],
+    skipped: &[String],
+) -> eyre::Result<()> {
+    let candidates = walk.list(git_dir, &[bad.to_string()], good)?;
+    let all = candidates.len();
+    if all == 1 {
+        return first_commit(git_dir, config, walk, terms, bad);
+    }
+
+    // The best commit splits the candidates in halves: the ones it reaches,
+    // itself included, and the others
+    let mut distances = Vec::new();
+    for candidate in &candidates {
+        let reaches = walk
+            .list(git_dir, std::slice::from_ref(candidate), good)?
+            .len();
+        distances.push((reaches.min(all - reaches), reaches, candidate));
+    }
+    let (reaches, best, tried) = match skipped.is_empty() {
+        true => {
+            let mut best = distances[0];
+            for &(distance, reaches, candidate) in &distances[1..] {
+                if distance > best.0 {
+                    best = (distance, reaches, candidate);
+                }
+            }
+            (best.1, best.2, Vec::new())
+        }
+        // With skipped commits, the best of the others is the closest to
+        // the middle, or a pseudo-random one away from the skipped ones if
+        // the middle was skipped
+        false => {
+            distances.sort_by(|a, b| b.0.cmp(&a.0).then(a.2.cmp(b.2)));
+            let reaches = distances[0].1;
+            let (tried, left): (Vec<_>, Vec<_>) = distances
+                .iter()
+                .map(|&(_, _, candidate)| candidate)
+                .partition(|candidate| skipped.contains(candidate));
+            let best = match skipped.contains(distances[0].2) {
+                true => skip_away(&left, bad),
+                false => left[0],
+            };
+            (reaches, best, tried)
+        }
+    };
+    if best == bad {
+        if !tried.is_empty() {
+            println!(
+                "{}",
+                tr!(
+                    "There are only 'skip'ped commits left to test.\nThe first %s commit could be any of:",
+                    &terms.new
+                )
+            );
+            for candidate in tried {
+                println!("{candidate}");
+            }
+            println!("{bad}");
+            println!("{}", tr!("We cannot bisect more!"));
+            std::process::exit(2);
+        }
+        return first_commit(git_dir, config, walk, terms, bad);
+    }
+
+    let left = all - reaches - 1;
+    let steps = estimate_steps(all);
+    let revisions = match left {
+        1 => tr!("Bisecting: %d revision left to test after this", left),
+        _ => tr!("Bisecting: %d revisions left to test after this", left),
+    };
+    let steps = match steps {
+        1 => tr!("(roughly %d step)", steps),
+        _ => tr!("(roughly %d steps)", steps),
+    };
+    println!("{revisions} {steps}");
+    let options = SwitchOptions {
+        detach: true,
+        quiet: true,
+        ..SwitchOptions::default()
+    };
+    branch::switch(git_dir, config, Some(best), &options, None)?;
+    println!("[{best}] {}", walk.commit(git_dir, best)?.summary());
+    Ok(())
+}
+
+/// Prints the first new commit `bad`, which the bisection found.
+fn first_commit(
+    git_dir: &Path,
+    config: &Config,
+    walk: &mut RevWalk,
+    terms: &Terms,
+    bad: &str,
+) -> eyre::Result<()> {
+    let commit = walk.commit(git_dir, bad)?.clone();
+    println!("{}", tr!("%s is the first %s commit", bad, &terms.new));
+    print!("{}", format_header(&Mailmap::load(config), bad, &commit));
+    append_log(
+        git_dir,
+        &format!(
+            "# first {} commit: [{bad}] {}\n",
+            terms.new,
+            commit.summary()
+        ),
+    )
+}
+
+/// Picks one of the `candidates` left to test, best first, away from the
+/// best one as if at random, the same way git does for the same commits.
+/// The new commit `bad` is only picked when the only one left.
+fn skip_away<'a>(candidates: &[&'a String], bad: &str) -> &'a String {
+    const MODULO: u32 = 32768;
+    let count = candidates.len() as u32;
+    let prn = (count.wrapping_mul(1103515245).wrapping_add(12345) / 65536) % MODULO;
+    let index = (count * prn / MODULO) * sqrt(prn) / sqrt(MODULO);
+    match candidates.get(index as usize) {
+        Some(candidate) if *candidate != bad => candidate,
+        Some(_) if index > 0 => candidates[index as usize - 1],
+        _ => candidates[0],
+    }
+}
+
+/// Returns the integer square root of `value`, approximated like git does.
+fn sqrt(value: u32) -> u32 {
+    if value == 0 {
+        return 0;
+    }
+    let mut x = value as f32;
+    loop {
+        let y = (x + value as f32 / x) / 2.0;
+        let d = (y - x).abs();
+        x = y;
+        if d < 0.5 {
+            return x as u32;
+        }
+    }
+}
+
+/// Returns about how many more steps a bisection of `all` commits takes.
+fn estimate_steps(all: usize) -> usize {
+    if all < 3 {
+        return 0;
+    }
+    let n = all.ilog2() as usize;
+    let e = 1 << n;
+    // Past three quarters of the next power of two, one more step is likely
+    match e < 3 * (all - e) {
+        true => n,
+        false => n - 1,
+    }
+}
+
+/// Splits a command of the log into its words, unquoting the ones in
+/// single quotes.
+fn split_words(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = None::<String>;
+    let mut quoted = false;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            '\\' if !quoted => word.get_or_insert_with(String::new).extend(chars.next()),
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
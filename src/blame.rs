@@ -0,0 +1,462 @@
+use crate::commit::{self, Commit, Signature};
+use crate::diff;
+use crate::git;
+use crate::mailmap::Mailmap;
+use crate::refs;
+use crate::revision;
+use crate::revwalk::RevWalk;
+use crate::tree::{self, MODE_GITLINK, MODE_TREE};
+use eyre::eyre;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+
+/// The sha the lines changed in the working tree but not committed yet are
+/// blamed on.
+const NOT_COMMITTED: &str = "0000000000000000000000000000000000000000";
+
+/// How `blame` shows the commits lines come from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Each line after the commit, author and date that last changed it.
+    #[default]
+    Human,
+    /// `--porcelain`: each group of lines last changed by the same commit
+    /// after a header, the commit being described the first time it shows
+    /// up.
+    Porcelain,
+    /// `--incremental`: the headers of `--porcelain` without the lines,
+    /// printed as soon as their commit is found.
+    Incremental,
+}
+
+/// What `blame` shows.
+#[derive(Debug, Default)]
+pub struct BlameOptions {
+    /// The `-L` ranges of lines to blame, like `10,20` or `10,+5`, the
+    /// whole file without any.
+    pub ranges: Vec<String>,
+    pub format: Format,
+}
+
+/// Lines of the blamed file last changed by the same commit, consecutive in
+/// both the file and the version of the commit.
+#[derive(Debug)]
+struct Group {
+    sha: String,
+    /// The first line in the version of the commit, from 0.
+    orig: usize,
+    /// The first line in the blamed file, from 0.
+    start: usize,
+    count: usize,
+}
+
+/// Shows the commit that last changed each line of the file at `path`, from
+/// the top of the working tree, as of `revision`. Without one, the file of
+/// the working tree is blamed, its lines not committed yet being blamed on
+/// the null sha.
+pub fn blame(
+    git_dir: &Path,
+    mailmap: &Mailmap,
+    revision: Option<&str>,
+    path: &str,
+    options: &BlameOptions,
+) -> eyre::Result<()> {
+    let mut blame = Blame::new(path);
+    let tip = match revision {
+        Some(revision) => revision::peel(git_dir, &revision::resolve(git_dir, revision)?)?,
+        None => blame.worktree(git_dir)?,
+    };
+    let content = blame.content(git_dir, &tip)?.ok_or(eyre!(tr!(
+        "no such path %s in %s",
+        path,
+        revision.unwrap_or("HEAD")
+    )))?;
+    let lines = diff::lines(&content.1);
+    let selected = select(&options.ranges, lines.len(), path)?;
+    blame.pass(
+        git_dir,
+        &tip,
+        selected.into_iter().map(|line| (line, line)).collect(),
+    )?;
+
+    let mut out = std::io::stdout().lock();
+    let mut described = HashSet::new();
+    let mut groups = Vec::new();
+    while let Some(found) = blame.next_groups(git_dir)? {
+        if options.format == Format::Incremental {
+            for group in &found {
+                writeln!(
+                    out,
+                    "{} {} {} {}",
+                    group.sha,
+                    group.orig + 1,
+                    group.start + 1,
+                    group.count
+                )?;
+                if described.insert(group.sha.clone()) {
+                    blame.write_details(git_dir, &mut out, mailmap, &group.sha)?;
+                }
+                blame.write_filename(git_dir, &mut out, &group.sha)?;
+            }
+            out.flush()?;
+        }
+        groups.extend(found);
+    }
+    groups.sort_by_key(|group| group.start);
+
+    match options.format {
+        Format::Human => blame.write_human(git_dir, &mut out, mailmap, &groups, &lines),
+        Format::Porcelain => {
+            for group in &groups {
+                let sha = &group.sha;
+                for i in 0..group.count {
+                    let (orig, start) = (group.orig + i + 1, group.start + i + 1);
+                    match i {
+                        0 => writeln!(out, "{sha} {orig} {start} {}", group.count)?,
+                        _ => writeln!(out, "{sha} {orig} {start}")?,
+                    }
+                    if i == 0 && described.insert(sha.clone()) {
+                        blame.write_details(git_dir, &mut out, mailmap, sha)?;
+                        blame.write_filename(git_dir, &mut out, sha)?;
+                    }
+                    out.write_all(b"\t")?;
+                    write_line(&mut out, lines[group.start + i])?;
+                }
+            }
+            Ok(())
+        }
+        Format::Incremental => Ok(()),
+    }
+}
+
+/// Returns the lines, from 0, in the `-L` ranges of a file of `count`
+/// lines, all of them without any.
+fn select(ranges: &[String], count: usize, path: &str) -> eyre::Result<BTreeSet<usize>> {
+    if ranges.is_empty() {
+        return Ok((0..count).collect());
+    }
+    let mut lines = BTreeSet::new();
+    for range in ranges {
+        let (start, end) = parse_range(range, count, path)?;
+        lines.extend(start..end);
+    }
+    Ok(lines)
+}
+
+/// Parses a `-L` range of the file at `path`, of `count` lines, into the
+/// lines from `start` to `end` excluded, from 0. Ranges are
+/// `<start>,<end>`, with `<end>` possibly `+<n>` or `-<n>` lines from
+/// `<start>`, either being the first or last line when left out.
+pub fn parse_range(range: &str, count: usize, path: &str) -> eyre::Result<(usize, usize)> {
+    let invalid = || eyre!(tr!("invalid -L argument '%s'", range));
+    let number = |n: &str| {
+        n.parse::<usize>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(invalid)
+    };
+    let (start, end) = range.split_once(',').unwrap_or((range, ""));
+    let start = match start {
+        "" => 1,
+        start => number(start)?,
+    };
+    if start > count.max(1) {
+        return Err(eyre!(tr!("file %s has only %d lines", path, count)));
+    }
+    let (start, end) = match (end.strip_prefix('+'), end.strip_prefix('-')) {
+        _ if end.is_empty() => (start, count),
+        (Some(n), _) => (start, start + number(n)? - 1),
+        (_, Some(n)) => ((start + 1).saturating_sub(number(n)?).max(1), start),
+        _ => {
+            let end = number(end)?;
+            (start.min(end), start.max(end))
+        }
+    };
+    Ok((start - 1, end.min(count)))
+}
+
+/// Writes a line of the blamed file, ending it with a newline if it's the
+/// last one and has none.
+fn write_line(out: &mut impl Write, line: &[u8]) -> eyre::Result<()> {
+    out.write_all(line)?;
+    if !line.ends_with(b"\n") {
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// The blob of the blamed file in a commit, and its content.
+type File = Rc<(String, Vec<u8>)>;
+
+/// A blame in progress, passing the blame for lines from commits to their
+/// parents as long as the parents have the same lines.
+struct Blame {
+    walk: RevWalk,
+    path: String,
+    /// The commit standing for the working tree, blamed for the lines not
+    /// committed yet, with the content of the file there.
+    worktree: Option<(Commit, File)>,
+    /// The blob and content of the file in the commits to look at, `None`
+    /// where it doesn't exist.
+    contents: HashMap<String, Option<File>>,
+    /// The commits to look at, newest first.
+    queue: BinaryHeap<(i64, String)>,
+    /// The lines blamed on the commits to look at so far, as the line in the
+    /// blamed file and the line in the version of the commit.
+    pending: HashMap<String, Vec<(usize, usize)>>,
+}
+
+impl Blame {
+    fn new(path: &str) -> Self {
+        Self {
+            walk: RevWalk::new(),
+            path: path.to_string(),
+            worktree: None,
+            contents: HashMap::new(),
+            queue: BinaryHeap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Returns the commit the blame of the file in the working tree starts
+    /// from: `HEAD` when the file is as committed, else a commit on top of
+    /// it with the file of the working tree.
+    fn worktree(&mut self, git_dir: &Path) -> eyre::Result<String> {
+        let content = fs::read(&self.path)
+            .map_err(|e| eyre!(tr!("cannot stat path '%s': %s", &self.path, e)))?;
+        let blob = git::hash_object("blob", &content).0.to_string();
+        let head = refs::read_ref(git_dir, "HEAD")?;
+        if let Some(head) = &head {
+            if self
+                .content(git_dir, head)?
+                .is_some_and(|content| content.0 == blob)
+            {
+                return Ok(head.clone());
+            }
+        }
+
+        let signature = Signature {
+            name: "Not Committed Yet".to_string(),
+            email: "not.committed.yet".to_string(),
+            time: commit::now()?,
+            offset: 0,
+        };
+        let commit = Commit {
+            tree: String::new(),
+            parents: head.into_iter().collect(),
+            author: signature.clone(),
+            committer: signature,
+            encoding: None,
+            extra_headers: Vec::new(),
+            message: format!("Version of {} from {}\n", self.path, self.path),
+        };
+        self.worktree = Some((commit, Rc::new((blob, content))));
+        Ok(NOT_COMMITTED.to_string())
+    }
+
+    fn commit(&mut self, git_dir: &Path, sha: &str) -> eyre::Result<&Commit> {
+        match (&self.worktree, sha == NOT_COMMITTED) {
+            (Some((commit, _)), true) => Ok(commit),
+            _ => self.walk.commit(git_dir, sha),
+        }
+    }
+
+    /// Returns the blob and content of the file in the commit `sha`, if it
+    /// has the file.
+    fn content(&mut self, git_dir: &Path, sha: &str) -> eyre::Result<Option<File>> {
+        if let (Some((_, content)), true) = (&self.worktree, sha == NOT_COMMITTED) {
+            return Ok(Some(content.clone()));
+        }
+        if let Some(content) = self.contents.get(sha) {
+            return Ok(content.clone());
+        }
+        let tree = self.walk.commit(git_dir, sha)?.tree.clone();
+        let content = match tree::entry_at(git_dir, &tree, Path::new(&self.path))? {
+            Some((mode, blob)) if mode != MODE_TREE && mode != MODE_GITLINK => {
+                let (_, content) = git::read_object(git_dir, &blob)?;
+                Some(Rc::new((blob, content)))
+            }
+            _ => None,
+        };
+        self.contents.insert(sha.to_string(), content.clone());
+        Ok(content)
+    }
+
+    /// Blames `lines`, the lines of the blamed file and of the version of
+    /// the commit `sha`, on that commit until it's looked at.
+    fn pass(&mut self, git_dir: &Path, sha: &str, lines: Vec<(usize, usize)>) -> eyre::Result<()> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let time = self.commit(git_dir, sha)?.committer.time;
+        self.pending
+            .entry(sha.to_string())
+            .or_default()
+            .extend(lines);
+        self.queue.push((time, sha.to_string()));
+        Ok(())
+    }
+
+    /// Looks at the next commits, newest first, passing the blame for the
+    /// lines their parents have to the parents, in order. Returns the lines
+    /// of the first commit left with some as groups, or `None` once every
+    /// line is blamed.
+    fn next_groups(&mut self, git_dir: &Path) -> eyre::Result<Option<Vec<Group>>> {
+        while let Some((_, sha)) = self.queue.pop() {
+            // A commit may be queued again by children found after it
+            let Some(mut lines) = self.pending.remove(&sha) else {
+                continue;
+            };
+            let ours = self
+                .content(git_dir, &sha)?
+                .ok_or(eyre!("missing blamed file in {sha}"))?;
+            self.contents.remove(&sha);
+            let parents = self.commit(git_dir, &sha)?.parents.clone();
+            for parent in parents {
+                if lines.is_empty() {
+                    break;
+                }
+                let Some(theirs) = self.content(git_dir, &parent)? else {
+                    continue;
+                };
+                if theirs.0 == ours.0 {
+                    self.pass(git_dir, &parent, std::mem::take(&mut lines))?;
+                    break;
+                }
+                let old = diff::lines(&theirs.1);
+                let new = diff::lines(&ours.1);
+                let matching = diff::matching(&old, &new)
+                    .into_iter()
+                    .map(|(i, j)| (j, i))
+                    .collect::<HashMap<_, _>>();
+                let (passed, kept) = lines
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|(_, line)| matching.contains_key(line));
+                let passed = passed
+                    .into_iter()
+                    .map(|(start, line)| (start, matching[&line]))
+                    .collect();
+                self.pass(git_dir, &parent, passed)?;
+                lines = kept;
+            }
+            if lines.is_empty() {
+                continue;
+            }
+
+            lines.sort();
+            let mut groups = Vec::<Group>::new();
+            for (start, orig) in lines {
+                match groups.last_mut() {
+                    Some(last)
+                        if last.start + last.count == start && last.orig + last.count == orig =>
+                    {
+                        last.count += 1;
+                    }
+                    _ => groups.push(Group {
+                        sha: sha.clone(),
+                        orig,
+                        start,
+                        count: 1,
+                    }),
+                }
+            }
+            return Ok(Some(groups));
+        }
+        Ok(None)
+    }
+
+    /// Writes the lines of `--porcelain` and `--incremental` describing the
+    /// commit `sha`: its author, committer and summary, and whether it's a
+    /// root commit.
+    fn write_details(
+        &mut self,
+        git_dir: &Path,
+        out: &mut impl Write,
+        mailmap: &Mailmap,
+        sha: &str,
+    ) -> eyre::Result<()> {
+        let commit = self.commit(git_dir, sha)?;
+        for (role, signature) in [("author", &commit.author), ("committer", &commit.committer)] {
+            let (name, email) = mailmap.map(&signature.name, &signature.email);
+            writeln!(out, "{role} {name}")?;
+            writeln!(out, "{role}-mail <{email}>")?;
+            writeln!(out, "{role}-time {}", signature.time)?;
+            writeln!(out, "{role}-tz {}", commit::format_offset(signature.offset))?;
+        }
+        writeln!(out, "summary {}", commit.summary())?;
+        if commit.parents.is_empty() && sha != NOT_COMMITTED {
+            writeln!(out, "boundary")?;
+        }
+        Ok(())
+    }
+
+    /// Writes the lines of `--porcelain` and `--incremental` naming the file
+    /// in the commit `sha`, after the first parent having it.
+    fn write_filename(
+        &mut self,
+        git_dir: &Path,
+        out: &mut impl Write,
+        sha: &str,
+    ) -> eyre::Result<()> {
+        let parents = self.commit(git_dir, sha)?.parents.clone();
+        for parent in parents {
+            if self.content(git_dir, &parent)?.is_some() {
+                writeln!(out, "previous {parent} {}", self.path)?;
+                break;
+            }
+        }
+        writeln!(out, "filename {}", self.path)?;
+        Ok(())
+    }
+
+    /// Writes each line of `groups` after the abbreviated sha of its commit,
+    /// `^` marking root commits, the name of the author, the date and the
+    /// line number.
+    fn write_human(
+        &mut self,
+        git_dir: &Path,
+        out: &mut impl Write,
+        mailmap: &Mailmap,
+        groups: &[Group],
+        lines: &[&[u8]],
+    ) -> eyre::Result<()> {
+        let mut authors = HashMap::new();
+        for group in groups {
+            if !authors.contains_key(&group.sha) {
+                let commit = self.commit(git_dir, &group.sha)?;
+                let (name, _) = mailmap.map(&commit.author.name, &commit.author.email);
+                let date = commit::format_iso_date(commit.author.time, commit.author.offset);
+                let boundary = commit.parents.is_empty() && group.sha != NOT_COMMITTED;
+                authors.insert(group.sha.clone(), (name, date, boundary));
+            }
+        }
+        let name_width = authors
+            .values()
+            .map(|(name, _, _)| name.chars().count())
+            .max()
+            .unwrap_or(0);
+        let number_width = groups
+            .last()
+            .map_or(1, |group| (group.start + group.count).to_string().len());
+
+        for group in groups {
+            let (name, date, boundary) = &authors[&group.sha];
+            let abbrev = match boundary {
+                true => format!("^{}", &group.sha[..7]),
+                false => group.sha[..8].to_string(),
+            };
+            let end = group.start + group.count;
+            for (number, line) in (group.start + 1..).zip(&lines[group.start..end]) {
+                write!(
+                    out,
+                    "{abbrev} ({name:<name_width$} {date} {number:>number_width$}) "
+                )?;
+                write_line(out, line)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,500 @@
+use crate::column;
+use crate::commit::{Commit, Signature};
+use crate::config::Config;
+use crate::index::{Entry, Index};
+use crate::progress::Progress;
+use crate::ref_filter::{self, Format, RefItem};
+use crate::refs;
+use crate::revision;
+use crate::revwalk::RevWalk;
+use crate::tree::{self, Files};
+use crate::wildmatch;
+use crate::worktree::{self, Operation};
+use eyre::eyre;
+use std::fs;
+use std::path::Path;
+
+/// Points the new branch `name` at the commit `start` points to, `HEAD` by
+/// default. Existing branches are only reset with `force`, and never the
+/// current one.
+pub fn create(
+    git_dir: &Path,
+    config: &Config,
+    name: &str,
+    start: Option<&str>,
+    force: bool,
+) -> eyre::Result<()> {
+    let reference = branch_ref(name)?;
+    let old = refs::read_ref(git_dir, &reference)?;
+    if old.is_some() {
+        if !force {
+            return Err(eyre!(tr!("a branch named '%s' already exists", name)));
+        }
+        if is_current(git_dir, &reference)? {
+            return Err(eyre!(tr!(
+                "cannot force update the current branch '%s'",
+                name
+            )));
+        }
+    }
+
+    let start = start.unwrap_or("HEAD");
+    let sha = revision::resolve(git_dir, start)
+        .map_err(|_| eyre!(tr!("not a valid object name: '%s'", start)))?;
+    set_branch(
+        git_dir,
+        config,
+        &reference,
+        &revision::peel(git_dir, &sha)?,
+        old.as_deref(),
+        start,
+    )
+}
+
+/// Points the branch `reference` at `sha`, from `old`, logging that it was
+/// created or reset from `start`.
+fn set_branch(
+    git_dir: &Path,
+    config: &Config,
+    reference: &str,
+    sha: &str,
+    old: Option<&str>,
+    start: &str,
+) -> eyre::Result<()> {
+    refs::update_ref_from(git_dir, reference, sha, old)?;
+    let who = Signature::now(config, "COMMITTER")?;
+    let message = match old {
+        Some(_) => format!("branch: Reset to {start}"),
+        None => format!("branch: Created from {start}"),
+    };
+    refs::append_reflog(git_dir, reference, old, sha, &who, &message)
+}
+
+/// Deletes the branch `name` and its reflog. Unless forced, the branch must
+/// be merged into `HEAD`, so that no commit is lost. The current branch is
+/// never deleted.
+pub fn delete(git_dir: &Path, name: &str, force: bool) -> eyre::Result<()> {
+    let reference = format!("refs/heads/{name}");
+    let sha =
+        refs::read_ref(git_dir, &reference)?.ok_or(eyre!(tr!("branch '%s' not found.", name)))?;
+    if is_current(git_dir, &reference)? {
+        let dir = std::env::current_dir()?;
+        return Err(eyre!(tr!(
+            "cannot delete branch '%s' used by worktree at '%s'",
+            name,
+            dir.display()
+        )));
+    }
+    if !force {
+        let merged = match refs::read_ref(git_dir, "HEAD")? {
+            Some(head) => RevWalk::new().is_ancestor(git_dir, &sha, &head)?,
+            None => false,
+        };
+        if !merged {
+            return Err(eyre!(tr!(
+                "the branch '%s' is not fully merged.\nIf you are sure you want to delete it, run 'git branch -D %s'",
+                name,
+                name
+            )));
+        }
+    }
+
+    refs::delete_ref(git_dir, &reference)?;
+    let log = git_dir.join("logs").join(&reference);
+    if log.is_file() {
+        fs::remove_file(log)?;
+    }
+    println!("{}", tr!("Deleted branch %s (was %s).", name, &sha[..7]));
+    Ok(())
+}
+
+/// Renames the branch `old`, the current one by default, to `new`, moving
+/// its reflog along. An existing `new` branch is only replaced with `force`.
+/// If `HEAD` is on the branch, it follows it.
+pub fn rename(
+    git_dir: &Path,
+    config: &Config,
+    old: Option<&str>,
+    new: &str,
+    force: bool,
+) -> eyre::Result<()> {
+    let old_ref = match old {
+        Some(old) => format!("refs/heads/{old}"),
+        None => refs::symbolic_target(git_dir, "HEAD")?
+            .filter(|target| target.starts_with("refs/heads/"))
+            .ok_or(eyre!(tr!(
+                "cannot rename the current branch while not on any"
+            )))?,
+    };
+    let old = &old_ref["refs/heads/".len()..];
+    let new_ref = branch_ref(new)?;
+    let current = is_current(git_dir, &old_ref)?;
+    let sha = refs::read_ref(git_dir, &old_ref)?;
+    if old_ref == new_ref && (sha.is_some() || current) {
+        return Ok(());
+    }
+    let Some(sha) = sha else {
+        // An unborn current branch has no ref yet, only HEAD moves
+        if current {
+            return refs::set_symbolic_ref(git_dir, "HEAD", &new_ref);
+        }
+        return Err(eyre!(tr!("no branch named '%s'", old)));
+    };
+    if refs::read_ref(git_dir, &new_ref)?.is_some() {
+        if !force {
+            return Err(eyre!(tr!("a branch named '%s' already exists", new)));
+        }
+        if is_current(git_dir, &new_ref)? {
+            return Err(eyre!(tr!(
+                "cannot force update the current branch '%s'",
+                new
+            )));
+        }
+    }
+
+    refs::delete_ref(git_dir, &new_ref)?;
+    refs::update_ref_from(git_dir, &new_ref, &sha, None)?;
+    let old_log = git_dir.join("logs").join(&old_ref);
+    let new_log = git_dir.join("logs").join(&new_ref);
+    if old_log.is_file() {
+        if let Some(parent) = new_log.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&old_log, &new_log)?;
+    }
+    refs::delete_ref(git_dir, &old_ref)?;
+    if current {
+        refs::set_symbolic_ref(git_dir, "HEAD", &new_ref)?;
+    }
+
+    let who = Signature::now(config, "COMMITTER")?;
+    let message = format!("Branch: renamed {old_ref} to {new_ref}");
+    refs::append_reflog(git_dir, &new_ref, Some(&sha), &sha, &who, &message)
+}
+
+/// How `list` shows branches.
+#[derive(Debug, Default)]
+pub struct ListOptions {
+    /// Only lists the branches matching one of these patterns.
+    pub patterns: Vec<String>,
+    /// The keys the branches are sorted by, those of `branch.sort` if empty.
+    pub sort: Vec<String>,
+    /// Shows each branch with this `for-each-ref` format.
+    pub format: Option<String>,
+    /// Shows the commit of each branch and how it compares with its
+    /// upstream from 1, naming the upstream from 2.
+    pub verbose: u8,
+    /// Lays the branches out in columns, with these `column.ui` options.
+    pub column: Option<String>,
+    /// Never lays the branches out in columns.
+    pub no_column: bool,
+}
+
+/// Prints the branches, marking the current one, with a detached `HEAD`
+/// first.
+pub fn list(git_dir: &Path, config: &Config, options: &ListOptions) -> eyre::Result<()> {
+    let mut columns = column::Options::load(config, "branch")?;
+    match (&options.column, options.no_column) {
+        // Asking for columns enables them, unless the options say when
+        (Some(column), _) => columns.parse(&format!("always,{column}"))?,
+        (None, true) => columns.disable(),
+        (None, false) if options.verbose > 0 => columns.disable(),
+        (None, false) => {}
+    }
+    let sort = match options.sort.is_empty() {
+        true => config
+            .get_all("branch.sort")
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+        false => options.sort.clone(),
+    };
+
+    let mut branches = Vec::new();
+    for (name, sha) in refs::list_refs(git_dir, "refs/heads/")? {
+        let branch = &name["refs/heads/".len()..];
+        if options.patterns.is_empty()
+            || options
+                .patterns
+                .iter()
+                .any(|p| wildmatch::wildmatch(p.as_bytes(), branch.as_bytes(), true))
+        {
+            branches.push(RefItem::new(git_dir, name, sha)?);
+        }
+    }
+    ref_filter::sort(git_dir, config, &mut branches, &sort)?;
+    // A detached HEAD comes first
+    if refs::symbolic_target(git_dir, "HEAD")?.is_none() && options.patterns.is_empty() {
+        if let Some(sha) = refs::read_ref(git_dir, "HEAD")? {
+            let name = tr!("(HEAD detached at %s)", &sha[..7]);
+            branches.insert(
+                0,
+                RefItem {
+                    name,
+                    sha,
+                    head: true,
+                },
+            );
+        }
+    }
+
+    let format = options.format.as_deref().map(Format::parse).transpose()?;
+    let short = |branch: &RefItem| {
+        let name = &branch.name;
+        name.strip_prefix("refs/heads/").unwrap_or(name).to_string()
+    };
+    let width = branches
+        .iter()
+        .map(|branch| short(branch).chars().count())
+        .max()
+        .unwrap_or(0);
+    let mut cells = Vec::new();
+    for branch in &branches {
+        let mark = if branch.head { '*' } else { ' ' };
+        cells.push(match &format {
+            Some(format) => {
+                String::from_utf8_lossy(&format.format(git_dir, config, branch)?).into_owned()
+            }
+            None if options.verbose > 0 => {
+                // The upstream is described before the subject
+                let tracking = match short(branch) {
+                    _ if !branch.name.starts_with("refs/heads/") => None,
+                    name => ref_filter::tracking(git_dir, config, &name, &branch.sha)?,
+                };
+                let tracking = match (tracking, options.verbose) {
+                    (None, _) => String::new(),
+                    (Some((_, counts)), 1) => match ref_filter::describe_tracking(counts) {
+                        description if description.is_empty() => description,
+                        description => format!("[{description}] "),
+                    },
+                    (Some((upstream, counts)), _) => {
+                        let upstream =
+                            ref_filter::upstream_name(git_dir, &upstream, counts.is_some())?;
+                        match ref_filter::describe_tracking(counts) {
+                            description if description.is_empty() => {
+                                format!("[{upstream}] ")
+                            }
+                            description => format!("[{upstream}: {description}] "),
+                        }
+                    }
+                };
+                let subject = Commit::read(git_dir, &branch.sha)?.summary().to_string();
+                format!(
+                    "{mark} {:width$} {} {tracking}{subject}",
+                    short(branch),
+                    &branch.sha[..7]
+                )
+            }
+            None => format!("{mark} {}", short(branch)),
+        });
+    }
+    columns.print(&cells);
+    Ok(())
+}
+
+/// How `switch` and `checkout` move `HEAD`.
+#[derive(Debug, Default)]
+pub struct SwitchOptions {
+    /// Creates this branch at the target and switches to it.
+    pub create: Option<String>,
+    /// Resets the branch to create if it already exists.
+    pub force_create: bool,
+    /// Detaches `HEAD` at the target, even if it's a branch.
+    pub detach: bool,
+    /// Detaches `HEAD` when the target isn't a branch, like `checkout`
+    /// does, where `switch` wants `detach`.
+    pub detach_commits: bool,
+    /// Throws away the local changes, instead of refusing to switch when
+    /// they're in the way.
+    pub force: bool,
+    /// Doesn't print where `HEAD` was and now is.
+    pub quiet: bool,
+}
+
+/// Switches to the branch or commit `target`, `HEAD` by default: the files
+/// that differ between the current commit and the target are updated in the
+/// working tree and the index, local changes to the others are carried
+/// over, and `HEAD` is moved to the target. The checkout is reported to
+/// `progress`.
+pub fn switch(
+    git_dir: &Path,
+    config: &Config,
+    target: Option<&str>,
+    options: &SwitchOptions,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<()> {
+    let start = target.unwrap_or("HEAD");
+    let mut reset = None;
+    let branch = match (&options.create, target) {
+        (Some(name), _) => Some(branch_ref(name)?),
+        (None, Some(target)) if !options.detach => {
+            let reference = format!("refs/heads/{target}");
+            refs::read_ref(git_dir, &reference)?.map(|_| reference)
+        }
+        _ => None,
+    };
+    if branch.is_none() && !options.detach && !options.detach_commits {
+        return Err(match target {
+            Some(target) => eyre!(tr!("a branch is expected, got '%s'", target)),
+            None => eyre!(tr!("missing branch or commit argument")),
+        });
+    }
+    let sha = revision::resolve(git_dir, start)
+        .map_err(|_| eyre!(tr!("invalid reference: %s", start)))?;
+    let sha = revision::peel(git_dir, &sha)?;
+
+    if let (Some(name), Some(reference)) = (&options.create, &branch) {
+        reset = refs::read_ref(git_dir, reference)?;
+        if reset.is_some() && !options.force_create {
+            return Err(eyre!(tr!("a branch named '%s' already exists", name)));
+        }
+    }
+
+    let current = refs::symbolic_target(git_dir, "HEAD")?;
+    if options.create.is_none() && branch.is_some() && branch == current {
+        let name = &branch.unwrap_or_default()["refs/heads/".len()..];
+        println!("{}", tr!("Already on '%s'", name));
+        return Ok(());
+    }
+
+    let head = refs::read_ref(git_dir, "HEAD")?;
+    let old = match &head {
+        Some(head) => tree::flatten_tree(git_dir, &Commit::read(git_dir, head)?.tree)?,
+        None => Files::new(),
+    };
+    let new = tree::flatten_tree(git_dir, &Commit::read(git_dir, &sha)?.tree)?;
+    // Forcing rewrites the files changed locally as well
+    let current_files =
+        worktree::prepare_update(git_dir, &old, &new, Operation::Checkout, options.force)?;
+    if let (Some(_), Some(reference)) = (&options.create, &branch) {
+        set_branch(git_dir, config, reference, &sha, reset.as_deref(), start)?;
+    }
+    worktree::checkout_files(git_dir, &current_files, &new, progress)?;
+    update_index(git_dir, &old, &new, options.force)?;
+
+    let from = match (&current, &head) {
+        (Some(current), _) => current.trim_start_matches("refs/heads/").to_string(),
+        (None, Some(head)) => head.clone(),
+        (None, None) => String::new(),
+    };
+    let to = match &branch {
+        Some(branch) => {
+            refs::set_symbolic_ref(git_dir, "HEAD", branch)?;
+            branch["refs/heads/".len()..].to_string()
+        }
+        None => {
+            refs::detach_head(git_dir, &sha)?;
+            sha.clone()
+        }
+    };
+    let who = Signature::now(config, "COMMITTER")?;
+    let message = format!("checkout: moving from {from} to {to}");
+    refs::append_reflog(git_dir, "HEAD", head.as_deref(), &sha, &who, &message)?;
+
+    if options.quiet {
+        return Ok(());
+    }
+    // Leaving a detached HEAD shows where it was, to find its commits back
+    if let (None, Some(head)) = (&current, &head) {
+        if *head != sha {
+            let summary = Commit::read(git_dir, head)?.summary().to_string();
+            println!(
+                "{}",
+                tr!("Previous HEAD position was %s %s", &head[..7], &summary)
+            );
+        }
+    }
+    match &branch {
+        Some(_) if reset.is_some() => println!("{}", tr!("Switched to and reset branch '%s'", &to)),
+        Some(_) if options.create.is_some() => {
+            println!("{}", tr!("Switched to a new branch '%s'", &to))
+        }
+        Some(_) => println!("{}", tr!("Switched to branch '%s'", &to)),
+        None => {
+            let summary = Commit::read(git_dir, &sha)?.summary().to_string();
+            println!("{}", tr!("HEAD is now at %s %s", &sha[..7], &summary));
+        }
+    }
+    Ok(())
+}
+
+/// Switches to the new branch `name` without any commit, removing the
+/// tracked files from the working tree. Local changes to them are only
+/// thrown away with `force`.
+pub fn switch_orphan(git_dir: &Path, name: &str, force: bool) -> eyre::Result<()> {
+    let branch = branch_ref(name)?;
+    if refs::read_ref(git_dir, &branch)?.is_some() {
+        return Err(eyre!(tr!("a branch named '%s' already exists", name)));
+    }
+
+    if let Some(head) = refs::read_ref(git_dir, "HEAD")? {
+        let tracked = tree::flatten_tree(git_dir, &Commit::read(git_dir, &head)?.tree)?;
+        if !force {
+            worktree::check_update(git_dir, &tracked, &Files::new(), Operation::Checkout)?;
+        }
+        worktree::checkout_files(git_dir, &tracked, &Files::new(), None)?;
+    }
+
+    refs::set_symbolic_ref(git_dir, "HEAD", &branch)?;
+    println!("{}", tr!("Switched to a new branch '%s'", name));
+    Ok(())
+}
+
+/// Stages the files of `new` that the switch from `old` wrote or removed,
+/// keeping the staged changes to the other files unless `force`.
+pub fn update_index(git_dir: &Path, old: &Files, new: &Files, force: bool) -> eyre::Result<()> {
+    let mut index = Index::read(git_dir)?;
+    for path in old.keys().filter(|path| !new.contains_key(*path)) {
+        index.remove(&path.to_string_lossy().replace('\\', "/"));
+    }
+    for (path, (mode, sha)) in new {
+        if !force && old.get(path) == Some(&(*mode, sha.clone())) {
+            continue;
+        }
+        let name = path.to_string_lossy().replace('\\', "/");
+        let entry = match fs::symlink_metadata(path) {
+            Ok(metadata) => Entry::new(name, sha.clone(), *mode, &metadata),
+            Err(_) => Entry {
+                mode: *mode,
+                sha: sha.clone(),
+                path: name,
+                ..Entry::default()
+            },
+        };
+        index.add(entry);
+    }
+    index.write(git_dir)
+}
+
+/// Returns the ref of the branch `name`, failing if the name is invalid.
+fn branch_ref(name: &str) -> eyre::Result<String> {
+    let reference = format!("refs/heads/{name}");
+    if name == "HEAD" || name.starts_with('-') || !refs::is_valid_name(&reference) {
+        return Err(eyre!(tr!("'%s' is not a valid branch name", name)));
+    }
+    Ok(reference)
+}
+
+/// Whether `HEAD` is on the branch `reference`.
+fn is_current(git_dir: &Path, reference: &str) -> eyre::Result<bool> {
+    Ok(refs::symbolic_target(git_dir, "HEAD")?.as_deref() == Some(reference))
+}
+
+/// Returns the remote and the remote ref the current branch tracks, from
+/// the `branch.<name>.remote` and `branch.<name>.merge` config.
+pub fn upstream(git_dir: &Path, config: &Config) -> eyre::Result<Option<(String, String)>> {
+    let Some(head) = refs::symbolic_target(git_dir, "HEAD")? else {
+        return Ok(None);
+    };
+    let branch = head.strip_prefix("refs/heads/").unwrap_or(&head);
+    let remote = config.get(&format!("branch.{branch}.remote"));
+    let merge = config.get(&format!("branch.{branch}.merge"));
+    Ok(remote
+        .zip(merge)
+        .map(|(remote, merge)| (remote.to_string(), merge.to_string())))
+}
+
+/// Returns the remote the current branch tracks, `origin` if it tracks none.
+pub fn upstream_remote(git_dir: &Path, config: &Config) -> eyre::Result<String> {
+    Ok(upstream(git_dir, config)?.map_or("origin".to_string(), |(remote, _)| remote))
+}
@@ -0,0 +1,119 @@
+use crate::git::{self, GitFile};
+use crate::odb;
+use crate::pack;
+use crate::revision;
+use crate::revwalk::RevWalk;
+use crate::signature;
+use eyre::eyre;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+/// What `cat_file` prints of each object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Its type.
+    Type,
+    /// The size of its content.
+    Size,
+    /// Its content without the signature, as it was signed.
+    SignaturePayload,
+    /// Its content, trees being listed one entry per line.
+    Pretty,
+}
+
+/// What `batch` reads and prints.
+#[derive(Debug, Default)]
+pub struct BatchOptions {
+    /// Prints the content of the objects after their info.
+    pub contents: bool,
+    /// Prints every object of the repository instead of the named ones.
+    pub all: bool,
+    /// With `all`, prints the objects in the order they're found rather
+    /// than by sha.
+    pub unordered: bool,
+}
+
+/// Prints the objects named by the lines of `input`, or every object with
+/// `all`, like `git cat-file --batch`: their sha, type and size, then their
+/// content with `contents`. Names that don't lead to an object are printed
+/// as `<name> missing`.
+pub fn batch(
+    git_dir: &Path,
+    input: impl BufRead,
+    options: &BatchOptions,
+    mut out: impl Write,
+) -> eyre::Result<()> {
+    let packs = pack::packs(git_dir)?;
+    let names = match options.all {
+        true => odb::objects(git_dir, &packs, !options.unordered)?,
+        false => input.lines().collect::<Result<_, _>>()?,
+    };
+
+    for name in names {
+        let object = revision::resolve(git_dir, &name).and_then(|sha| {
+            let info = odb::object_info(git_dir, &packs, &sha)?;
+            Ok((sha, info))
+        });
+        // Enumerated objects exist, failing to read them is an error
+        let (sha, info) = match object {
+            Ok(object) => object,
+            Err(e) if options.all => return Err(e),
+            Err(_) => {
+                writeln!(out, "{name} missing")?;
+                continue;
+            }
+        };
+        writeln!(out, "{sha} {} {}", info.kind, info.size)?;
+        if options.contents {
+            let (_, content) = git::read_object(git_dir, &sha)?;
+            out.write_all(&content)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if `object` names an object the repository has.
+pub fn exists(git_dir: &Path, object: &str) -> eyre::Result<bool> {
+    let sha = revision::resolve(git_dir, object)
+        .map_err(|_| eyre!(tr!("Not a valid object name %s", object)))?;
+    Ok(git::read_object(git_dir, &sha).is_ok())
+}
+
+/// Prints what `mode` shows of the objects `objects` name.
+pub fn cat_file(
+    git_dir: &Path,
+    objects: &[String],
+    mode: Mode,
+    mut out: impl Write,
+) -> eyre::Result<()> {
+    for sha in revision::resolve_objects(git_dir, &mut RevWalk::new(), objects)? {
+        match mode {
+            Mode::Pretty => out.write_all(&GitFile::read(git_dir, &sha)?.pretty())?,
+            _ => {
+                let (kind, content) = git::read_object(git_dir, &sha)?;
+                match mode {
+                    Mode::Type => writeln!(out, "{kind}")?,
+                    Mode::Size => writeln!(out, "{}", content.len())?,
+                    _ => out.write_all(&signature::payload(&kind, &content)?)?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints the content of the object `object` as an object of type `kind`,
+/// peeling it down to that type if needed.
+pub fn cat_file_as(
+    git_dir: &Path,
+    kind: &str,
+    object: &str,
+    mut out: impl Write,
+) -> eyre::Result<()> {
+    let sha = revision::resolve(git_dir, object)?;
+    let content = git::read_object_as(git_dir, &sha, kind)?
+        .ok_or(eyre!(tr!("git cat-file %s: bad file", object)))?;
+    out.write_all(&content)?;
+    Ok(())
+}
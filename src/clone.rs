@@ -0,0 +1,137 @@
+use crate::branch;
+use crate::commit::Commit;
+use crate::config::{self, Config};
+use crate::fetch::{self, FetchOptions, Refspec, Remote};
+use crate::init::{self, InitOptions};
+use crate::partial;
+use crate::progress::Progress;
+use crate::refs;
+use crate::transport;
+use crate::tree::{self, Files};
+use crate::worktree;
+use eyre::eyre;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Clones the repository at `url` into `directory`, by default named after
+/// the repository: creates an empty repository there with the remote
+/// `origin` for `url`, fetches its branches and tags, and checks out the
+/// branch its `HEAD` points to, fetching the history `options` keeps. The
+/// fetch and the checkout are reported to `progress`. The directory is
+/// removed if the clone fails.
+pub fn clone(
+    url: &str,
+    directory: Option<&Path>,
+    options: &FetchOptions,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<()> {
+    // The url is recorded in the new repository, relative paths would no
+    // longer lead to the cloned one from there
+    let url = match transport::is_remote(url) || url.contains("://") {
+        true => url.to_string(),
+        false => fs::canonicalize(url)
+            .map_err(|_| eyre!(tr!("repository '%s' does not exist", url)))?
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let directory = match directory {
+        Some(directory) => directory.to_path_buf(),
+        None => PathBuf::from(directory_name(&url)),
+    };
+    let empty = fs::read_dir(&directory).map_or(true, |mut entries| entries.next().is_none());
+    if !empty {
+        return Err(eyre!(tr!(
+            "destination path '%s' already exists and is not an empty directory.",
+            directory.display()
+        )));
+    }
+
+    eprintln!("{}", tr!("Cloning into '%s'...", directory.display()));
+    let created = !directory.exists();
+    fs::create_dir_all(&directory)?;
+    let cwd = std::env::current_dir()?;
+    std::env::set_current_dir(&directory)?;
+    let result = clone_into(&url, options, progress);
+    if result.is_err() {
+        std::env::set_current_dir(&cwd)?;
+        match created {
+            true => fs::remove_dir_all(&directory)?,
+            false => fs::remove_dir_all(directory.join(".git"))?,
+        }
+    }
+    result
+}
+
+/// Clones the repository at `url` into the current directory.
+fn clone_into(
+    url: &str,
+    options: &FetchOptions,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<()> {
+    let (git_dir, _) = init::init(
+        Path::new(""),
+        &Config::load(Path::new(".git"))?,
+        &InitOptions::default(),
+    )?;
+    partial::enable_lazy_fetch(&git_dir);
+    config::set_value(&git_dir, "remote.origin.url", url)?;
+    config::set_value(
+        &git_dir,
+        "remote.origin.fetch",
+        "+refs/heads/*:refs/remotes/origin/*",
+    )?;
+
+    let config = Config::load(&git_dir)?;
+    let mut remote = Remote::load(&config, "origin")?;
+    let advertisement = fetch::ls_remote(&config, &remote)?;
+    // Tags are copied as they are
+    remote
+        .refspecs
+        .push(Refspec::parse("+refs/tags/*:refs/tags/*"));
+    fetch::fetch(&git_dir, &config, &remote, &[], options, progress)?;
+
+    let Some(head) = advertisement.head.first() else {
+        match advertisement.refs.is_empty() {
+            true => eprintln!(
+                "{}",
+                tr!("warning: You appear to have cloned an empty repository.")
+            ),
+            false => eprintln!(
+                "{}",
+                tr!("warning: remote HEAD refers to nonexistent ref, unable to checkout")
+            ),
+        }
+        return Ok(());
+    };
+    let (_, sha) = advertisement
+        .refs
+        .iter()
+        .find(|(name, _)| name == head)
+        .ok_or(eyre!("remote HEAD {head} not advertised"))?;
+
+    // The local branch of the remote `HEAD` tracks it
+    let branch = head.strip_prefix("refs/heads/").unwrap_or(head);
+    refs::update_ref(&git_dir, head, sha)?;
+    refs::set_symbolic_ref(&git_dir, "HEAD", head)?;
+    refs::set_symbolic_ref(
+        &git_dir,
+        "refs/remotes/origin/HEAD",
+        &format!("refs/remotes/origin/{branch}"),
+    )?;
+    config::set_value(&git_dir, &format!("branch.{branch}.remote"), "origin")?;
+    config::set_value(&git_dir, &format!("branch.{branch}.merge"), head)?;
+
+    let files = tree::flatten_tree(&git_dir, &Commit::read(&git_dir, sha)?.tree)?;
+    worktree::checkout_files(&git_dir, &Files::new(), &files, progress)?;
+    branch::update_index(&git_dir, &Files::new(), &files, true)
+}
+
+/// Returns the directory a clone of `url` goes to by default: the last
+/// component of its path, without `.git`, like `repo` for
+/// `https://host/user/repo.git` or `/path/to/repo/.git`.
+fn directory_name(url: &str) -> String {
+    let path = url.trim_end_matches('/');
+    let path = path.strip_suffix("/.git").unwrap_or(path);
+    let name = path.rsplit(['/', ':']).next().unwrap_or(path);
+    name.strip_suffix(".git").unwrap_or(name).to_string()
+}
@@ -0,0 +1,147 @@
+use crate::config::Config;
+use std::io::IsTerminal;
+
+/// How lists are laid out in columns, from `--column` or `column.*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    enabled: bool,
+    layout: Layout,
+    /// Whether columns are as narrow as their cells, rather than all as wide
+    /// as the widest cell.
+    dense: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    /// Cells fill the columns first.
+    Column,
+    /// Cells fill the rows first.
+    Row,
+    /// One cell per line.
+    Plain,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            layout: Layout::Column,
+            dense: false,
+        }
+    }
+}
+
+impl Options {
+    /// Reads the options of `command` from `column.ui` and `column.<command>`.
+    pub fn load(config: &Config, command: &str) -> eyre::Result<Self> {
+        let mut options = Self::default();
+        for key in ["column.ui".to_string(), format!("column.{command}")] {
+            if let Some(value) = config.get(&key) {
+                options.parse(value)?;
+            }
+        }
+        Ok(options)
+    }
+
+    /// Applies comma-separated options, like `always,row,dense`. Asking for
+    /// a layout enables columns.
+    pub fn parse(&mut self, value: &str) -> eyre::Result<()> {
+        for option in value.split(',').filter(|o| !o.is_empty()) {
+            match option {
+                "always" => self.enabled = true,
+                "never" => self.enabled = false,
+                "auto" => self.enabled = std::io::stdout().is_terminal(),
+                "column" | "row" | "plain" => {
+                    self.layout = match option {
+                        "column" => Layout::Column,
+                        "row" => Layout::Row,
+                        _ => Layout::Plain,
+                    };
+                    self.enabled = true;
+                }
+                "dense" => self.dense = true,
+                "nodense" => self.dense = false,
+                _ => return Err(eyre::eyre!(tr!("unsupported option '%s'", option))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Disables columns.
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    /// Lays out `cells` in columns as wide as the terminal, or the `COLUMNS`
+    /// environment variable says, one per line if columns are disabled.
+    /// Cells are separated by at least one space.
+    pub fn print(&self, cells: &[String]) {
+        if !self.enabled || self.layout == Layout::Plain || cells.is_empty() {
+            cells.iter().for_each(|cell| println!("{cell}"));
+            return;
+        }
+
+        const PADDING: usize = 1;
+        let width = std::env::var("COLUMNS")
+            .ok()
+            .and_then(|columns| columns.parse::<usize>().ok())
+            .filter(|columns| *columns > 0)
+            .unwrap_or(80);
+        let lengths = cells.iter().map(|c| c.chars().count()).collect::<Vec<_>>();
+        let widest = lengths.iter().max().copied().unwrap_or(0) + PADDING;
+
+        let mut rows = cells.len().div_ceil((width / widest).max(1));
+        // Dense columns fit more of them, so fewer rows are needed
+        while self.dense && rows > 1 {
+            let (_, widths) = self.widths(&lengths, rows - 1, PADDING);
+            if widths.iter().sum::<usize>() > width {
+                break;
+            }
+            rows -= 1;
+        }
+
+        let (columns, widths) = self.widths(&lengths, rows, PADDING);
+        for y in 0..rows {
+            let mut line = String::new();
+            for (x, column_width) in widths.iter().enumerate() {
+                let i = self.index(rows, columns, x, y);
+                let Some(cell) = cells.get(i) else {
+                    continue;
+                };
+                line.push_str(cell);
+                let last = match self.layout {
+                    Layout::Row => x == columns - 1 || i == cells.len() - 1,
+                    _ => i + rows >= cells.len(),
+                };
+                if !last {
+                    let cell_width = if self.dense { *column_width } else { widest };
+                    line.push_str(&" ".repeat(cell_width - lengths[i]));
+                }
+            }
+            println!("{line}");
+        }
+    }
+
+    /// Returns the number of columns needed for `rows` rows, and the width
+    /// of each column with its padding.
+    fn widths(&self, lengths: &[usize], rows: usize, padding: usize) -> (usize, Vec<usize>) {
+        let columns = lengths.len().div_ceil(rows);
+        let widths = (0..columns)
+            .map(|x| {
+                (0..rows)
+                    .filter_map(|y| lengths.get(self.index(rows, columns, x, y)))
+                    .max()
+                    .map_or(0, |len| len + padding)
+            })
+            .collect();
+        (columns, widths)
+    }
+
+    /// Returns the index of the cell at column `x` and row `y`.
+    fn index(&self, rows: usize, columns: usize, x: usize, y: usize) -> usize {
+        match self.layout {
+            Layout::Row => y * columns + x,
+            _ => x * rows + y,
+        }
+    }
+}
@@ -0,0 +1,783 @@
+use crate::config::Config;
+use crate::diff;
+use crate::git::{GitFile, GitFileContent};
+use crate::hooks;
+use crate::index::{self, Entry, Index};
+use crate::mailmap;
+use crate::odb::Odb;
+use crate::refs;
+use crate::revision;
+use crate::signature;
+use crate::status::Status;
+use crate::tree::{self, Change, Files, MODE_GITLINK};
+use eyre::eyre;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The identity and timestamp found in the `author` and `committer` lines
+/// of a commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    /// Seconds since the unix epoch.
+    pub time: i64,
+    /// Timezone offset in minutes.
+    pub offset: i32,
+}
+
+impl Signature {
+    /// Parses a signature of the form `Name <email> 1700000000 +0100`.
+    pub fn parse(s: &str) -> eyre::Result<Self> {
+        let open = s.find('<').ok_or(eyre!("missing '<' in signature"))?;
+        let close = s.rfind('>').ok_or(eyre!("missing '>' in signature"))?;
+        let name = s[..open].trim_end().to_string();
+        let email = s[open + 1..close].to_string();
+
+        let mut rest = s[close + 1..].split_whitespace();
+        let time = rest.next().unwrap_or("0").parse::<i64>()?;
+        let offset = parse_offset(rest.next().unwrap_or("+0000"))?;
+
+        Ok(Self {
+            name,
+            email,
+            time,
+            offset,
+        })
+    }
+}
+
+impl Signature {
+    /// Returns the signature of the current user in the given role
+    /// (`AUTHOR` or `COMMITTER`), read from the `GIT_<ROLE>_NAME` and
+    /// `GIT_<ROLE>_EMAIL` environment variables or the `user.name` and
+    /// `user.email` config, and timestamped now unless `GIT_<ROLE>_DATE` is
+    /// set.
+    pub fn now(config: &Config, role: &str) -> eyre::Result<Self> {
+        let name = std::env::var(format!("GIT_{role}_NAME"))
+            .ok()
+            .or_else(|| config.get("user.name").map(|s| s.to_string()))
+            .ok_or(eyre!(
+                "{} identity unknown, please set user.name",
+                role.to_lowercase()
+            ))?;
+        let email = std::env::var(format!("GIT_{role}_EMAIL"))
+            .ok()
+            .or_else(|| config.get("user.email").map(|s| s.to_string()))
+            .ok_or(eyre!(
+                "{} identity unknown, please set user.email",
+                role.to_lowercase()
+            ))?;
+        let (time, offset) = date_of(role)?;
+        Ok(Self {
+            name,
+            email,
+            time,
+            offset,
+        })
+    }
+}
+
+impl std::fmt::Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} <{}> {} {}",
+            self.name,
+            self.email,
+            self.time,
+            format_offset(self.offset)
+        )
+    }
+}
+
+/// Parses a timezone offset of the form `+HHMM` or `-HHMM` into minutes.
+fn parse_offset(s: &str) -> eyre::Result<i32> {
+    let (sign, digits) = match s.as_bytes().first() {
+        Some(b'-') => (-1, &s[1..]),
+        Some(b'+') => (1, &s[1..]),
+        _ => (1, s),
+    };
+    if digits.len() != 4 {
+        return Err(eyre!("invalid timezone offset {s}"));
+    }
+    let hours = digits[..2].parse::<i32>()?;
+    let minutes = digits[2..].parse::<i32>()?;
+    Ok(sign * (hours * 60 + minutes))
+}
+
+/// Returns the date of a new signature in the given role: the date set by
+/// `GIT_<ROLE>_DATE`, or now.
+fn date_of(role: &str) -> eyre::Result<(i64, i32)> {
+    match std::env::var(format!("GIT_{role}_DATE")) {
+        Ok(date) => parse_date(&date),
+        Err(_) => Ok((now()?, 0)),
+    }
+}
+
+/// Returns the current time in seconds since the unix epoch.
+pub fn now() -> eyre::Result<i64> {
+    Ok(std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64)
+}
+
+/// Parses a date in one of the formats git accepts for `--date` and
+/// `GIT_<ROLE>_DATE`, returning the timestamp and the timezone offset in
+/// minutes:
+/// - git's internal format, `1700000000 +0100`, optionally prefixed by `@`
+/// - ISO 8601, like `2026-10-16T12:00:00+01:00` or `2026-10-16 12:00 +0100`
+/// - RFC 2822, like `Thu, 16 Oct 2026 12:00:00 +0100`
+/// - git's default format, like `Thu Oct 16 12:00:00 2026 +0100`
+///
+/// Dates without a timezone are in UTC.
+pub fn parse_date(date: &str) -> eyre::Result<(i64, i32)> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let invalid = || eyre!(tr!("invalid date format: %s", date));
+    if date == "now" {
+        return Ok((now()?, 0));
+    }
+
+    let mut tokens = date
+        .split([' ', ','])
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>();
+
+    // A timestamp, with an optional timezone
+    if let Some(first) = tokens.first() {
+        let timestamp = first.strip_prefix('@').unwrap_or(first);
+        if timestamp.len() >= 9 && timestamp.bytes().all(|b| b.is_ascii_digit()) {
+            let offset = match tokens.get(1) {
+                Some(zone) => parse_offset(zone).map_err(|_| invalid())?,
+                None => 0,
+            };
+            return Ok((timestamp.parse()?, offset));
+        }
+    }
+
+    // ISO 8601 dates may join the time with `T`, and the zone without space
+    if let Some(i) = tokens.iter().position(|t| t.contains('-') && t.len() > 10) {
+        let token = tokens.remove(i);
+        let (day, rest) = token.split_at(10);
+        let rest = rest.strip_prefix('T').unwrap_or(rest);
+        let zone = rest.find(['Z', '+', '-']).unwrap_or(rest.len());
+        tokens.insert(i, day);
+        tokens.insert(i + 1, &rest[..zone]);
+        if zone < rest.len() {
+            tokens.insert(i + 2, &rest[zone..]);
+        }
+    }
+
+    let (mut year, mut month, mut day) = (None, None, None);
+    let (mut seconds, mut offset) = (0, 0);
+    for token in tokens {
+        let lower = token.to_lowercase();
+        let numbers = token
+            .split([':', '-', '.'])
+            .map(|n| n.parse::<i64>())
+            .collect::<Result<Vec<_>, _>>();
+        if let Some(m) = MONTHS.iter().position(|m| lower.starts_with(m)) {
+            month = Some(m as i64 + 1);
+        } else if lower == "z" || lower == "utc" || lower == "gmt" {
+            offset = 0;
+        } else if token.starts_with(['+', '-']) {
+            offset = parse_offset(&token.replace(':', "")).map_err(|_| invalid())?;
+        } else if token.contains('-') {
+            let Ok([y, m, d]) = numbers.as_deref() else {
+                return Err(invalid());
+            };
+            (year, month, day) = (Some(*y), Some(*m), Some(*d));
+        } else if token.contains(':') {
+            let Ok([h, m, rest @ ..]) = numbers.as_deref() else {
+                return Err(invalid());
+            };
+            seconds = h * 3600 + m * 60 + rest.first().unwrap_or(&0);
+        } else if let Ok([n]) = numbers.as_deref() {
+            match token.len() {
+                4 => year = Some(*n),
+                _ => day = Some(*n),
+            }
+        } else if !lower.chars().all(|c| c.is_ascii_alphabetic()) {
+            // Day names are ignored
+            return Err(invalid());
+        }
+    }
+
+    let (Some(year), Some(month), Some(day)) = (year, month, day) else {
+        return Err(invalid());
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+
+    // Convert the civil date to days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Ok((days * 86400 + seconds - offset as i64 * 60, offset))
+}
+
+/// Formats a timestamp like git's default date format, e.g.
+/// `Thu Oct 16 12:00:00 2026 +0200`, in the timezone of `offset`.
+pub fn format_date(time: i64, offset: i32) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let local = time + offset as i64 * 60;
+    let days = local.div_euclid(86400);
+    let seconds = local.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{} {} {} {:02}:{:02}:{:02} {} {}",
+        DAYS[days.rem_euclid(7) as usize],
+        MONTHS[month as usize - 1],
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        year,
+        format_offset(offset)
+    )
+}
+
+/// Formats a timestamp like git's `iso` date format, e.g. `2026-10-16
+/// 12:00:00 +0200`, in the timezone of `offset`.
+pub fn format_iso_date(time: i64, offset: i32) -> String {
+    let local = time + offset as i64 * 60;
+    let seconds = local.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(local.div_euclid(86400));
+    format!(
+        "{year}-{month:02}-{day:02} {:02}:{:02}:{:02} {}",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        format_offset(offset)
+    )
+}
+
+/// Converts days since the epoch to a civil date, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Formats a timezone offset in minutes as `+HHMM` or `-HHMM`.
+pub fn format_offset(offset: i32) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs();
+    format!("{}{:02}{:02}", sign, offset / 60, offset % 60)
+}
+
+/// A parsed commit object.
+#[derive(Debug, Clone)]
+pub struct Commit {
+    pub tree: String,
+    pub parents: Vec<String>,
+    pub author: Signature,
+    pub committer: Signature,
+    /// The encoding of the commit object, from its `encoding` header. The
+    /// other fields are always decoded to UTF-8.
+    pub encoding: Option<String>,
+    /// The other headers, like `gpgsig` or `mergetag`, in order. Values
+    /// spanning several lines are joined with newlines.
+    pub extra_headers: Vec<(String, String)>,
+    pub message: String,
+}
+
+impl Commit {
+    /// Parses the content of a commit object (without the object header).
+    pub fn parse(content: &[u8]) -> eyre::Result<Self> {
+        // The whole object is in the encoding named by its header, names
+        // included. Without it, git assumes UTF-8
+        let headers_end = find(content, b"\n\n").unwrap_or(content.len());
+        let encoding = content[..headers_end]
+            .split(|b| *b == b'\n')
+            .find_map(|line| line.strip_prefix(b"encoding "))
+            .map(|label| String::from_utf8_lossy(label).to_string());
+        let content = match &encoding {
+            Some(label) => decode(content, label)
+                .unwrap_or_else(|_| String::from_utf8_lossy(content).to_string()),
+            None => String::from_utf8_lossy(content).to_string(),
+        };
+        let (headers, message) = content.split_once("\n\n").unwrap_or((&content, ""));
+
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        let mut extra_headers: Vec<(String, String)> = Vec::new();
+
+        let mut continues_extra = false;
+        for line in headers.lines() {
+            // Lines starting with a space continue the value of the header
+            // before them
+            if let Some(continuation) = line.strip_prefix(' ') {
+                if let (true, Some((_, value))) = (continues_extra, extra_headers.last_mut()) {
+                    value.push('\n');
+                    value.push_str(continuation);
+                }
+                continue;
+            }
+            let Some((key, value)) = line.split_once(' ') else {
+                continue;
+            };
+            continues_extra = false;
+            match key {
+                "tree" => tree = Some(value.to_string()),
+                "parent" => parents.push(value.to_string()),
+                "author" => author = Some(Signature::parse(value)?),
+                "committer" => committer = Some(Signature::parse(value)?),
+                "encoding" => {}
+                _ => {
+                    extra_headers.push((key.to_string(), value.to_string()));
+                    continues_extra = true;
+                }
+            }
+        }
+
+        let author = author.ok_or(eyre!("missing author in commit"))?;
+        Ok(Self {
+            tree: tree.ok_or(eyre!("missing tree in commit"))?,
+            parents,
+            committer: committer.unwrap_or_else(|| author.clone()),
+            author,
+            encoding,
+            extra_headers,
+            message: message.to_string(),
+        })
+    }
+
+    /// Returns the content of the commit object (without the object header).
+    pub fn serialize(&self) -> Vec<u8> {
+        let content = self.to_string();
+
+        // The encoding was validated when the commit was parsed or created
+        match &self.encoding {
+            Some(label) => encode(&content, label).unwrap_or(content.into_bytes()),
+            None => content.into_bytes(),
+        }
+    }
+
+    /// Returns the encoding to record in new commits, from
+    /// `i18n.commitEncoding`. UTF-8, the default, isn't recorded.
+    pub fn encoding_for(config: &Config) -> eyre::Result<Option<String>> {
+        match config.get("i18n.commitEncoding") {
+            Some(label) if !is_utf8(label) => {
+                encoding(label)?;
+                Ok(Some(label.to_string()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Signs the commit with the key `key`, the default one if empty, adding
+    /// the signature in a `gpgsig` header.
+    pub fn sign(&mut self, config: &Config, key: &str) -> eyre::Result<()> {
+        self.extra_headers.retain(|(name, _)| name != "gpgsig");
+        let signature = signature::sign(config, &self.serialize(), key)?;
+        let signature = signature.trim_end_matches('\n').to_string();
+        self.extra_headers.push(("gpgsig".to_string(), signature));
+        Ok(())
+    }
+
+    /// Writes the commit to the object database and returns its sha.
+    pub fn write(&self, git_dir: &Path) -> eyre::Result<String> {
+        Odb::new(git_dir)
+            .write("commit", &self.serialize())
+            .map(String::from)
+    }
+
+    /// Returns the first line of the commit message.
+    pub fn summary(&self) -> &str {
+        self.message.lines().next().unwrap_or_default()
+    }
+
+    /// Reads and parses the commit object with the given sha.
+    pub fn read(git_dir: &Path, sha: &str) -> eyre::Result<Self> {
+        match GitFile::read(git_dir, sha)?.file_content {
+            GitFileContent::Commit(commit) => Ok(*commit),
+            _ => Err(eyre!("object {sha} is not a commit")),
+        }
+    }
+}
+
+impl std::fmt::Display for Commit {
+    /// Formats the content of the commit object as git prints it, decoded to
+    /// UTF-8.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "tree {}", self.tree)?;
+        for parent in &self.parents {
+            writeln!(f, "parent {parent}")?;
+        }
+        writeln!(f, "author {}", self.author)?;
+        writeln!(f, "committer {}", self.committer)?;
+        if let Some(encoding) = &self.encoding {
+            writeln!(f, "encoding {encoding}")?;
+        }
+        for (key, value) in &self.extra_headers {
+            writeln!(f, "{key} {}", value.replace('\n', "\n "))?;
+        }
+        writeln!(f)?;
+        f.write_str(&self.message)
+    }
+}
+
+/// Returns the encoding in which to print commit messages, from
+/// `i18n.logOutputEncoding`, defaulting to `i18n.commitEncoding` then
+/// UTF-8.
+pub fn log_output_encoding(config: &Config) -> &str {
+    config
+        .get("i18n.logOutputEncoding")
+        .or(config.get("i18n.commitEncoding"))
+        .unwrap_or("UTF-8")
+}
+
+/// Returns true if the encoding label names UTF-8.
+fn is_utf8(label: &str) -> bool {
+    matches!(label.to_lowercase().as_str(), "utf-8" | "utf8")
+}
+
+fn encoding(label: &str) -> eyre::Result<&'static encoding_rs::Encoding> {
+    encoding_rs::Encoding::for_label(label.as_bytes()).ok_or(eyre!("unknown encoding '{label}'"))
+}
+
+/// Converts text in the encoding named by `label` to UTF-8. Invalid
+/// sequences are replaced, like git does when reencoding fails.
+pub fn decode(bytes: &[u8], label: &str) -> eyre::Result<String> {
+    let (text, _, _) = encoding(label)?.decode(bytes);
+    Ok(text.into_owned())
+}
+
+/// Converts UTF-8 text to the encoding named by `label`.
+pub fn encode(text: &str, label: &str) -> eyre::Result<Vec<u8>> {
+    let (bytes, _, _) = encoding(label)?.encode(text);
+    Ok(bytes.into_owned())
+}
+
+/// Returns the position of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Which files `commit` records, on top of the content of the index, and
+/// who it credits.
+#[derive(Debug, Default)]
+pub struct CommitOptions {
+    /// Stages the changes of every tracked file before recording the index.
+    pub all: bool,
+    /// Records the changes of the files under these paths, along with the
+    /// content of `HEAD` for the other files, unless `include` is set.
+    pub pathspec: Vec<PathBuf>,
+    /// Records the changes of the files of `pathspec` along with the
+    /// content of the index.
+    pub include: bool,
+    /// The author, as `Name <email>`, instead of the current user.
+    pub author: Option<String>,
+    /// The author date, in a format `parse_date` accepts, instead of now.
+    pub date: Option<String>,
+    /// Signs the commit with this key, the default one if empty.
+    pub gpg_sign: Option<String>,
+}
+
+/// Creates a commit on the current branch with the tree of the index, after
+/// staging the changes of the working tree chosen by `options`, and moves
+/// the branch to it, creating it if it's unborn. A merge in progress is
+/// concluded, with `MERGE_HEAD` as second parent.
+pub fn commit(
+    git_dir: &Path,
+    config: &Config,
+    message: Option<&str>,
+    options: &CommitOptions,
+) -> eyre::Result<()> {
+    let head = refs::read_ref(git_dir, "HEAD")?;
+    let merge_head = refs::read_ref(git_dir, "MERGE_HEAD")?;
+    if merge_head.is_some() && !options.pathspec.is_empty() {
+        return Err(eyre!(tr!("cannot do a partial commit during a merge.")));
+    }
+    let mut index = Index::read(git_dir)?;
+    if index.entries.iter().any(|e| e.stage() != 0) {
+        return Err(eyre!(tr!(
+            "Committing is not possible because you have unmerged files."
+        )));
+    }
+    let mut message = match message {
+        Some(message) => cleanup_message(message),
+        None => cleanup_message(&fs::read_to_string(git_dir.join("MERGE_MSG")).unwrap_or_default()),
+    };
+    if message.is_empty() {
+        return Err(eyre!(tr!("Aborting commit due to empty commit message.")));
+    }
+
+    let committed = match &head {
+        Some(head) => tree::flatten_tree(git_dir, &Commit::read(git_dir, head)?.tree)?,
+        None => Files::new(),
+    };
+    let staged = index.files();
+    tree::check_pathspec(&options.pathspec, staged.keys().chain(committed.keys()))?;
+    // `--all` leaves the files removed from the index untracked, paths name
+    // them as well
+    let chosen = match (options.all, options.pathspec.is_empty()) {
+        (true, _) => staged.keys().cloned().collect::<BTreeSet<_>>(),
+        (false, true) => BTreeSet::new(),
+        (false, false) => staged
+            .keys()
+            .chain(committed.keys())
+            .filter(|path| tree::pathspec_matches(&options.pathspec, path))
+            .cloned()
+            .collect(),
+    };
+    stage_worktree(git_dir, config, &mut index, &chosen)?;
+
+    // Paths commit the files they name as in the working tree, and the
+    // others as in HEAD
+    let staged = index.files();
+    let files = match options.pathspec.is_empty() || options.include {
+        true => staged,
+        false => committed
+            .iter()
+            .filter(|(path, _)| !chosen.contains(*path))
+            .chain(staged.iter().filter(|(path, _)| chosen.contains(*path)))
+            .map(|(path, file)| (path.clone(), file.clone()))
+            .collect(),
+    };
+
+    // Merges are recorded even when they don't change the tree of HEAD
+    if files == committed && merge_head.is_none() {
+        let status = Status::read(git_dir, config)?;
+        return Err(eyre!(
+            match (status.unstaged.is_empty(), status.untracked.is_empty()) {
+                (true, true) => tr!("nothing to commit, working tree clean"),
+                (true, false) => {
+                    tr!("nothing added to commit but untracked files present (use \"git add\" to track)")
+                }
+                (false, _) => {
+                    tr!("no changes added to commit (use \"git add\" and/or \"git commit -a\")")
+                }
+            }
+        ));
+    }
+
+    let mut author = match &options.author {
+        Some(author) => {
+            let Some((Some(name), email, "")) = mailmap::parse_contact(author) else {
+                return Err(eyre!(tr!(
+                    "--author '%s' is not 'Name <email>' and matches no existing author",
+                    author
+                )));
+            };
+            let (time, offset) = date_of("AUTHOR")?;
+            Signature {
+                name,
+                email: email.to_string(),
+                time,
+                offset,
+            }
+        }
+        None => Signature::now(config, "AUTHOR")?,
+    };
+    if let Some(date) = &options.date {
+        (author.time, author.offset) = parse_date(date)?;
+    }
+
+    hooks::run(|hook| hook.pre_commit(git_dir, merge_head.is_some()))?;
+    hooks::run(|hook| hook.commit_msg(git_dir, &mut message))?;
+    let mut commit = Commit {
+        tree: tree::write_tree(git_dir, &files)?,
+        parents: head.iter().chain(&merge_head).cloned().collect(),
+        author,
+        committer: Signature::now(config, "COMMITTER")?,
+        encoding: Commit::encoding_for(config)?,
+        extra_headers: Vec::new(),
+        message,
+    };
+    if let Some(key) = &options.gpg_sign {
+        commit.sign(config, key)?;
+    }
+    let sha = commit.write(git_dir)?;
+    // The branch must not have moved since HEAD was read
+    refs::update_ref_from(git_dir, "HEAD", &sha, head.as_deref())?;
+    index.write(git_dir)?;
+    refs::delete_ref(git_dir, "MERGE_HEAD")?;
+    let _ = fs::remove_file(git_dir.join("MERGE_MSG"));
+
+    let kind = match (&head, &merge_head) {
+        (None, _) => "commit (initial)",
+        (_, Some(_)) => "commit (merge)",
+        _ => "commit",
+    };
+    let reflog_message = format!("{kind}: {}", commit.summary());
+    let branch = refs::symbolic_target(git_dir, "HEAD")?;
+    for name in branch.iter().map(String::as_str).chain(["HEAD"]) {
+        refs::append_reflog(
+            git_dir,
+            name,
+            head.as_deref(),
+            &sha,
+            &commit.committer,
+            &reflog_message,
+        )?;
+    }
+
+    let branch = branch
+        .as_deref()
+        .map(|b| b.trim_start_matches("refs/heads/"))
+        .unwrap_or("detached HEAD");
+    let root = match head {
+        Some(_) => "",
+        None => " (root-commit)",
+    };
+    println!("[{branch}{root} {}] {}", &sha[..7], commit.summary());
+    print_summary(git_dir, &tree::diff_files(&committed, &files))
+}
+
+/// Writes a commit of the tree `tree` with the commits `parents`, both given
+/// as revisions, authored and committed now, and returns its sha. Unlike
+/// `commit`, no ref is moved.
+pub fn commit_tree(
+    git_dir: &Path,
+    config: &Config,
+    tree: &str,
+    parents: &[String],
+    message: String,
+    gpg_sign: Option<&str>,
+) -> eyre::Result<String> {
+    let mut commit = Commit {
+        tree: revision::resolve(git_dir, tree)?,
+        parents: parents
+            .iter()
+            .map(|parent| revision::resolve(git_dir, parent))
+            .collect::<eyre::Result<_>>()?,
+        author: Signature::now(config, "AUTHOR")?,
+        committer: Signature::now(config, "COMMITTER")?,
+        encoding: Commit::encoding_for(config)?,
+        extra_headers: Vec::new(),
+        message,
+    };
+    if let Some(key) = gpg_sign {
+        commit.sign(config, key)?;
+    }
+    commit.write(git_dir)
+}
+
+/// Stages the content of the working tree files at `paths` in `index`, and
+/// unstages the ones removed from the working tree.
+fn stage_worktree(
+    git_dir: &Path,
+    config: &Config,
+    index: &mut Index,
+    paths: &BTreeSet<PathBuf>,
+) -> eyre::Result<()> {
+    for path in paths {
+        let name = path.to_string_lossy().replace('\\', "/");
+        // Submodules are staged by commit, not by content, and files assumed
+        // unchanged or skipped stay as they are staged
+        if index
+            .get(&name)
+            .is_some_and(|e| e.mode == MODE_GITLINK || e.ignores_worktree())
+        {
+            continue;
+        }
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) if !metadata.is_dir() => metadata,
+            _ => {
+                index.remove(&name);
+                continue;
+            }
+        };
+        if index
+            .get(&name)
+            .is_some_and(|e| index.is_up_to_date(e, &metadata))
+        {
+            continue;
+        }
+        let (mode, content) = index::worktree_file(git_dir, config, index, &name, &metadata)?;
+        let sha = Odb::new(git_dir).write("blob", &content)?;
+        index.add(Entry::new(name, sha.into(), mode, &metadata));
+    }
+    Ok(())
+}
+
+/// Strips the trailing whitespace of the lines of a message, the blank
+/// lines around it and the repeated blank lines inside it.
+pub fn cleanup_message(message: &str) -> String {
+    let mut lines = Vec::new();
+    for line in message.lines().map(str::trim_end) {
+        let repeated_blank = line.is_empty() && lines.last().map_or(true, |l: &&str| l.is_empty());
+        if !repeated_blank {
+            lines.push(line);
+        }
+    }
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+    match lines.is_empty() {
+        true => String::new(),
+        false => format!("{}\n", lines.join("\n")),
+    }
+}
+
+/// Prints the number of files, insertions and deletions of `changes`, and
+/// the files they create and delete, like git does after a commit.
+fn print_summary(git_dir: &Path, changes: &[Change]) -> eyre::Result<()> {
+    let (mut insertions, mut deletions) = (0, 0);
+    for change in changes {
+        let (added, removed) = diff::line_counts(git_dir, change)?;
+        insertions += added;
+        deletions += removed;
+    }
+
+    let mut summary = match changes.len() {
+        1 => tr!(" %d file changed", 1),
+        count => tr!(" %d files changed", count),
+    };
+    if insertions > 0 || deletions == 0 {
+        summary.push_str(&match insertions {
+            1 => tr!(", %d insertion(+)", 1),
+            count => tr!(", %d insertions(+)", count),
+        });
+    }
+    if deletions > 0 || insertions == 0 {
+        summary.push_str(&match deletions {
+            1 => tr!(", %d deletion(-)", 1),
+            count => tr!(", %d deletions(-)", count),
+        });
+    }
+    println!("{summary}");
+
+    for change in changes {
+        match (&change.old, &change.new) {
+            (None, Some((mode, _))) => {
+                println!(" create mode {mode:06} {}", change.path.display())
+            }
+            (Some((mode, _)), None) => {
+                println!(" delete mode {mode:06} {}", change.path.display())
+            }
+            (Some((old, _)), Some((new, _))) if old != new => {
+                println!(
+                    " mode change {old:06} => {new:06} {}",
+                    change.path.display()
+                )
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,335 @@
+use crate::commit::Commit;
+use eyre::eyre;
+use sha1::Digest;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Parent value of a commit without that parent.
+const NO_PARENT: u32 = 0x7000_0000;
+/// Bit set on the second parent value of an octopus merge, whose parents
+/// from the second on are listed in the extra edges chunk.
+const EXTRA_EDGES: u32 = 0x8000_0000;
+/// Bit set on the last parent of a list of extra edges.
+const LAST_EDGE: u32 = 0x8000_0000;
+/// The largest topological level a commit-graph can record.
+const GENERATION_MAX: u32 = 0x3fff_ffff;
+
+/// A commit as recorded in a commit-graph.
+#[derive(Debug)]
+pub struct GraphCommit {
+    pub tree: String,
+    /// The positions of the parents in the graph.
+    pub parents: Vec<usize>,
+    /// The topological level of the commit: 1 for root commits, 1 more than
+    /// their highest parent for the others.
+    pub generation: u32,
+    pub time: i64,
+}
+
+/// A commit-graph file, holding the parsed commits of part of the history
+/// of a repository.
+#[derive(Debug)]
+struct Layer {
+    path: PathBuf,
+    data: Vec<u8>,
+    count: usize,
+    oid_fanout: usize,
+    oid_lookup: usize,
+    commit_data: usize,
+    extra_edges: Option<usize>,
+}
+
+impl Layer {
+    fn open(path: &Path) -> eyre::Result<Self> {
+        let data = fs::read(path)?;
+        let invalid = || eyre!("invalid commit-graph file {}", path.display());
+        if !data.starts_with(b"CGPH") || data.len() < 8 {
+            return Err(invalid());
+        }
+
+        // The chunk table lists the id and offset of each chunk, followed by
+        // an entry marking the end of the last chunk
+        let chunk_count = data[6] as usize;
+        let mut chunks = Vec::new();
+        for i in 0..chunk_count {
+            let entry = data.get(8 + i * 12..8 + i * 12 + 12).ok_or_else(invalid)?;
+            let offset = u64::from_be_bytes(entry[4..].try_into()?) as usize;
+            chunks.push((&entry[..4], offset));
+        }
+        let chunk = |id: &[u8]| chunks.iter().find(|(c, _)| *c == id).map(|(_, o)| *o);
+
+        let oid_fanout = chunk(b"OIDF").ok_or_else(invalid)?;
+        let count = data
+            .get(oid_fanout + 255 * 4..oid_fanout + 256 * 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .ok_or_else(invalid)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            count,
+            oid_fanout,
+            oid_lookup: chunk(b"OIDL").ok_or_else(invalid)?,
+            commit_data: chunk(b"CDAT").ok_or_else(invalid)?,
+            extra_edges: chunk(b"EDGE"),
+            data,
+        })
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> eyre::Result<&[u8]> {
+        self.data
+            .get(offset..offset + len)
+            .ok_or(eyre!("truncated commit-graph file {}", self.path.display()))
+    }
+
+    fn word(&self, offset: usize) -> eyre::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(offset, 4)?.try_into()?))
+    }
+
+    fn fanout(&self, byte: usize) -> eyre::Result<u32> {
+        self.word(self.oid_fanout + byte * 4)
+    }
+
+    fn oid(&self, i: usize) -> eyre::Result<String> {
+        Ok(hex::encode(self.bytes(self.oid_lookup + i * 20, 20)?))
+    }
+}
+
+/// The commit-graph of a repository: a single file, or a chain of files
+/// each adding the commits missing from the files before it.
+#[derive(Debug)]
+pub struct CommitGraph {
+    layers: Vec<Layer>,
+}
+
+impl CommitGraph {
+    /// Loads the commit-graph of the repository at `git_dir`, if it has one.
+    pub fn load(git_dir: &Path) -> eyre::Result<Option<Self>> {
+        let info = git_dir.join("objects/info");
+        let single = info.join("commit-graph");
+        if single.is_file() {
+            return Ok(Some(Self {
+                layers: vec![Layer::open(&single)?],
+            }));
+        }
+
+        let Ok(chain) = fs::read_to_string(info.join("commit-graphs/commit-graph-chain")) else {
+            return Ok(None);
+        };
+        let layers = chain
+            .lines()
+            .map(|hash| Layer::open(&info.join(format!("commit-graphs/graph-{hash}.graph"))))
+            .collect::<eyre::Result<_>>()?;
+        Ok(Some(Self { layers }))
+    }
+
+    /// Returns the number of commits in the graph.
+    pub fn len(&self) -> usize {
+        self.layers.iter().map(|l| l.count).sum()
+    }
+
+    /// Returns true if the graph has no commits.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the layer holding the commit at `position`, and the position
+    /// of the commit in that layer.
+    fn layer(&self, mut position: usize) -> eyre::Result<(&Layer, usize)> {
+        for layer in &self.layers {
+            if position < layer.count {
+                return Ok((layer, position));
+            }
+            position -= layer.count;
+        }
+        Err(eyre!("commit-graph position {position} out of range"))
+    }
+
+    /// Returns the sha of the commit at `position`.
+    pub fn oid(&self, position: usize) -> eyre::Result<String> {
+        let (layer, i) = self.layer(position)?;
+        layer.oid(i)
+    }
+
+    /// Returns the position of the commit `sha` in the graph, if it's there.
+    pub fn position(&self, sha: &str) -> eyre::Result<Option<usize>> {
+        let byte = usize::from_str_radix(sha.get(..2).unwrap_or_default(), 16)?;
+        let mut offset = 0;
+        for layer in &self.layers {
+            // The fanout bounds the ids starting with the same byte
+            let mut low = match byte {
+                0 => 0,
+                _ => layer.fanout(byte - 1)? as usize,
+            };
+            let mut high = layer.fanout(byte)? as usize;
+            while low < high {
+                let middle = (low + high) / 2;
+                match layer.oid(middle)?.as_str().cmp(sha) {
+                    std::cmp::Ordering::Less => low = middle + 1,
+                    std::cmp::Ordering::Greater => high = middle,
+                    std::cmp::Ordering::Equal => return Ok(Some(offset + middle)),
+                }
+            }
+            offset += layer.count;
+        }
+        Ok(None)
+    }
+
+    /// Returns the commit at `position`.
+    pub fn commit(&self, position: usize) -> eyre::Result<GraphCommit> {
+        let (layer, i) = self.layer(position)?;
+        let entry = layer.commit_data + i * 36;
+        let tree = hex::encode(layer.bytes(entry, 20)?);
+
+        let mut parents = Vec::new();
+        let first = layer.word(entry + 20)?;
+        let second = layer.word(entry + 24)?;
+        if first != NO_PARENT {
+            parents.push(first as usize);
+        }
+        if second & EXTRA_EDGES != 0 {
+            let edges = layer
+                .extra_edges
+                .ok_or(eyre!("commit-graph has no extra edges chunk"))?;
+            let mut edge = edges + (second & !EXTRA_EDGES) as usize * 4;
+            loop {
+                let parent = layer.word(edge)?;
+                parents.push((parent & !LAST_EDGE) as usize);
+                if parent & LAST_EDGE != 0 {
+                    break;
+                }
+                edge += 4;
+            }
+        } else if second != NO_PARENT {
+            parents.push(second as usize);
+        }
+
+        // The topological level takes the upper 30 bits, the commit time the
+        // lower 34 bits
+        let high = layer.word(entry + 28)?;
+        let low = layer.word(entry + 32)?;
+        Ok(GraphCommit {
+            tree,
+            parents,
+            generation: high >> 2,
+            time: (((high & 3) as i64) << 32) | low as i64,
+        })
+    }
+
+    /// Checks the graph against the object database, returning a
+    /// description of every mismatch found.
+    pub fn verify(&self, git_dir: &Path) -> eyre::Result<Vec<String>> {
+        let mut errors = Vec::new();
+
+        for layer in &self.layers {
+            let (content, checksum) = layer.data.split_at(layer.data.len().saturating_sub(20));
+            if sha1::Sha1::digest(content).as_slice() != checksum {
+                errors.push(tr!(
+                    "the commit-graph file %s has incorrect checksum and is likely corrupt",
+                    layer.path.display()
+                ));
+            }
+
+            // Object ids are sorted, the fanout counting those up to each
+            // first byte
+            let mut expected = 0;
+            for byte in 0..256 {
+                while expected < layer.count
+                    && layer.bytes(layer.oid_lookup + expected * 20, 1)?[0] as usize <= byte
+                {
+                    expected += 1;
+                }
+                let fanout = layer.fanout(byte)?;
+                if fanout as usize != expected {
+                    errors.push(tr!(
+                        "commit-graph has incorrect fanout value: fanout[%d] = %d != %d",
+                        byte,
+                        fanout,
+                        expected
+                    ));
+                }
+            }
+            for i in 1..layer.count {
+                let (previous, oid) = (layer.oid(i - 1)?, layer.oid(i)?);
+                if previous >= oid {
+                    errors.push(tr!(
+                        "commit-graph has incorrect OID order: %s then %s",
+                        previous,
+                        oid
+                    ));
+                }
+            }
+        }
+
+        for position in 0..self.len() {
+            let sha = self.oid(position)?;
+            let graph_commit = self.commit(position)?;
+            let Ok(commit) = Commit::read(git_dir, &sha) else {
+                errors.push(tr!("failed to parse commit %s from commit-graph", sha));
+                continue;
+            };
+
+            if graph_commit.tree != commit.tree {
+                errors.push(tr!(
+                    "root tree OID for commit %s in commit-graph is %s != %s",
+                    sha,
+                    graph_commit.tree,
+                    commit.tree
+                ));
+            }
+
+            let graph_parents = graph_commit
+                .parents
+                .iter()
+                .map(|p| self.oid(*p))
+                .collect::<eyre::Result<Vec<_>>>()?;
+            for (i, parent) in commit.parents.iter().enumerate() {
+                match graph_parents.get(i) {
+                    Some(graph_parent) if graph_parent != parent => errors.push(tr!(
+                        "commit-graph parent for %s is %s != %s",
+                        sha,
+                        graph_parent,
+                        parent
+                    )),
+                    None => {
+                        errors.push(tr!(
+                            "commit-graph parent list for commit %s terminates early",
+                            sha
+                        ));
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            if graph_parents.len() > commit.parents.len() {
+                errors.push(tr!(
+                    "commit-graph parent list for commit %s is too long",
+                    sha
+                ));
+            }
+
+            // Levels saturate at the largest value the graph can record
+            let mut max_parent = 0;
+            for parent in &graph_commit.parents {
+                max_parent = max_parent.max(self.commit(*parent)?.generation);
+            }
+            let expected = (max_parent + 1).min(GENERATION_MAX);
+            if graph_commit.generation < expected {
+                errors.push(tr!(
+                    "commit-graph generation for commit %s is %d < %d",
+                    sha,
+                    graph_commit.generation,
+                    expected
+                ));
+            }
+
+            if graph_commit.time != commit.committer.time {
+                errors.push(tr!(
+                    "commit date for commit %s in commit-graph is %d != %d",
+                    sha,
+                    graph_commit.time,
+                    commit.committer.time
+                ));
+            }
+        }
+        Ok(errors)
+    }
+}
@@ -0,0 +1,387 @@
+use crate::measure;
+use crate::refs;
+use crate::tempfile::Temporary;
+use crate::wildmatch::wildmatch;
+use eyre::eyre;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How deep included files can nest, which also stops include cycles.
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// The configuration of the repository, read from the global `~/.gitconfig`
+/// and the repository's `.git/config`. Keys are stored as
+/// `section.subsection.name`, with the section and name lowercased.
+#[derive(Debug, Default)]
+pub struct Config {
+    entries: Vec<(String, String)>,
+}
+
+impl Config {
+    /// Loads the global configuration and the one of the repository at
+    /// `git_dir`, later files taking precedence over earlier ones.
+    pub fn load(git_dir: &Path) -> eyre::Result<Self> {
+        measure::phase("config", || {
+            let mut config = Self::default();
+            if let Some(home) = std::env::var_os("HOME") {
+                config.read_file(git_dir, &Path::new(&home).join(".gitconfig"), 0)?;
+            }
+            config.read_file(git_dir, &git_dir.join("config"), 0)?;
+            Ok(config)
+        })
+    }
+
+    /// Returns the last value set for the key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the last value set for the key, interpreted as a boolean.
+    pub fn get_bool(&self, key: &str) -> eyre::Result<Option<bool>> {
+        self.get(key).map(parse_bool).transpose()
+    }
+
+    /// Returns every value set for the key, in the order they were read.
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .filter(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    /// Returns the subsections of `section` setting the variable `name`,
+    /// with the value they set, in the order they were read. For example
+    /// `subsections("gc", "reflogExpire")` lists the `gc.<pattern>.reflogExpire`
+    /// values.
+    pub fn subsections(&self, section: &str, name: &str) -> Vec<(&str, &str)> {
+        let prefix = format!("{}.", section.to_lowercase());
+        let suffix = format!(".{}", name.to_lowercase());
+        self.entries
+            .iter()
+            .filter_map(|(k, v)| {
+                let sub = k.strip_prefix(&prefix)?.strip_suffix(&suffix)?;
+                Some((sub, v.as_str()))
+            })
+            .collect()
+    }
+
+    /// Reads the config file at `path`, if it exists, and the files it
+    /// includes, `depth` being the number of includes that led to it.
+    fn read_file(&mut self, git_dir: &Path, path: &Path, depth: usize) -> eyre::Result<()> {
+        let Ok(content) = fs::read_to_string(path) else {
+            return Ok(());
+        };
+        let entries =
+            parse(&content).map_err(|e| eyre!("bad config file {}: {e}", path.display()))?;
+
+        // Included files are read in place of the variable including them
+        for (key, value) in entries {
+            let include = include_path(git_dir, &key, &value, path)?;
+            self.entries.push((key, value));
+            let Some(include) = include else {
+                continue;
+            };
+            if depth >= MAX_INCLUDE_DEPTH {
+                return Err(eyre!(tr!(
+                    "exceeded maximum include depth (%d) while including\n\t%s\nfrom\n\t%s\nThis might be due to circular includes.",
+                    MAX_INCLUDE_DEPTH,
+                    include.display(),
+                    path.display()
+                )));
+            }
+            self.read_file(git_dir, &include, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sets `key` to `value` in the config file of the repository at `git_dir`,
+/// like `git config <key> <value>`: the last line setting the key is
+/// replaced, else the value is added at the end of the last section of the
+/// key, else in a new section at the end of the file. The file is written
+/// through a lock file.
+pub fn set_value(git_dir: &Path, key: &str, value: &str) -> eyre::Result<()> {
+    let (section, name) = key
+        .rsplit_once('.')
+        .ok_or(eyre!(tr!("key does not contain a section: %s", key)))?;
+    // Subsections are case sensitive, like in `parse_section`
+    let section = match section.split_once('.') {
+        Some((name, sub)) => format!("{}.{sub}", name.to_lowercase()),
+        None => section.to_lowercase(),
+    };
+    let path = git_dir.join("config");
+    let content = fs::read_to_string(&path).unwrap_or_default();
+    let mut lines = content.lines().map(str::to_string).collect::<Vec<_>>();
+
+    let mut current = String::new();
+    let mut section_end = None;
+    let mut last = None;
+    for (i, line) in lines.iter().enumerate() {
+        let line = line.trim_start();
+        if let Some(header) = line.strip_prefix('[') {
+            let end = header
+                .find(']')
+                .ok_or(eyre!("unterminated section {line}"))?;
+            current = parse_section(&header[..end])?;
+        } else if current == section {
+            let key = line.split('=').next().unwrap_or_default().trim();
+            if key.eq_ignore_ascii_case(name) {
+                last = Some(i);
+            }
+        }
+        if current == section {
+            section_end = Some(i);
+        }
+    }
+
+    let line = format!("\t{name} = {}", quote_value(value));
+    match (last, section_end) {
+        (Some(i), _) => lines[i] = line,
+        (None, Some(i)) => lines.insert(i + 1, line),
+        (None, None) => {
+            let header = match section.split_once('.') {
+                Some((name, sub)) => {
+                    let sub = sub.replace('\\', "\\\\").replace('"', "\\\"");
+                    format!("[{name} \"{sub}\"]")
+                }
+                None => format!("[{section}]"),
+            };
+            lines.push(header);
+            lines.push(line);
+        }
+    }
+
+    let lock = path.with_extension("lock");
+    let _temporary = Temporary::new(&lock);
+    fs::write(
+        &lock,
+        lines.iter().map(|l| format!("{l}\n")).collect::<String>(),
+    )?;
+    fs::rename(lock, path)?;
+    Ok(())
+}
+
+/// Quotes a value for a config file when it has characters that would
+/// otherwise be lost: comment characters, or whitespace at its ends.
+fn quote_value(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    let quoted = value.contains(['#', ';']) || value.trim() != value;
+    match quoted {
+        true => format!("\"{escaped}\""),
+        false => escaped,
+    }
+}
+
+/// Parses the content of a config file into its entries.
+fn parse(content: &str) -> eyre::Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    let mut section = String::new();
+    let mut lines = content.lines();
+
+    while let Some(line) = lines.next() {
+        let mut line = line.trim_start().to_string();
+
+        // A trailing backslash continues the value on the next line
+        while line.ends_with('\\') && !line.ends_with("\\\\") {
+            line.pop();
+            line.push_str(lines.next().unwrap_or_default());
+        }
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            let end = line.find(']').ok_or(eyre!("unterminated section {line}"))?;
+            section = parse_section(&line[1..end])?;
+
+            // A key can follow the section header on the same line
+            line = line[end + 1..].trim_start().to_string();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+        }
+
+        if section.is_empty() {
+            return Err(eyre!("key outside of a section: {line}"));
+        }
+
+        // A key without a value is a boolean set to true
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), parse_value(value)?),
+            None => (line.trim(), "true".to_string()),
+        };
+        entries.push((format!("{section}.{}", name.to_lowercase()), value));
+    }
+    Ok(entries)
+}
+
+/// Returns the file the entry `key` of the config file at `path` includes:
+/// `include.path` always includes its file, `includeIf.<condition>.path`
+/// only if the condition holds.
+fn include_path(
+    git_dir: &Path,
+    key: &str,
+    value: &str,
+    path: &Path,
+) -> eyre::Result<Option<PathBuf>> {
+    let included = match key.strip_prefix("includeif.") {
+        Some(rest) => match rest.strip_suffix(".path") {
+            Some(condition) => include_condition(git_dir, condition, path)?,
+            None => false,
+        },
+        None => key == "include.path",
+    };
+    if !included || value.is_empty() {
+        return Ok(None);
+    }
+
+    // Relative paths are relative to the including file
+    let value = expand_home(value);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    Ok(Some(dir.join(value)))
+}
+
+/// Returns true if the condition of an `includeIf` section holds:
+/// `gitdir:<pattern>` (`gitdir/i:` ignoring case) matches the git
+/// directory, `onbranch:<pattern>` the current branch.
+fn include_condition(git_dir: &Path, condition: &str, path: &Path) -> eyre::Result<bool> {
+    let Some((kind, pattern)) = condition.split_once(':') else {
+        return Ok(false);
+    };
+    match kind {
+        "gitdir" | "gitdir/i" => {
+            let Ok(git_dir) = git_dir.canonicalize() else {
+                return Ok(false);
+            };
+            let mut pattern = expand_home(pattern).to_string_lossy().into_owned();
+            if let Some(rest) = pattern.strip_prefix("./") {
+                let dir = path.parent().unwrap_or(Path::new("."));
+                let dir = dir.canonicalize().unwrap_or(dir.to_path_buf());
+                pattern = format!("{}/{rest}", dir.display());
+            } else if !Path::new(&pattern).is_absolute() {
+                pattern = format!("**/{pattern}");
+            }
+            // A directory matches everything inside it
+            if pattern.ends_with('/') {
+                pattern.push_str("**");
+            }
+            let mut git_dir = git_dir.to_string_lossy().into_owned();
+            if kind == "gitdir/i" {
+                pattern = pattern.to_lowercase();
+                git_dir = git_dir.to_lowercase();
+            }
+            Ok(wildmatch(pattern.as_bytes(), git_dir.as_bytes(), true))
+        }
+        "onbranch" => {
+            let Some(head) = refs::symbolic_target(git_dir, "HEAD")? else {
+                return Ok(false);
+            };
+            let branch = head.strip_prefix("refs/heads/").unwrap_or(&head);
+            let mut pattern = pattern.to_string();
+            if pattern.ends_with('/') {
+                pattern.push_str("**");
+            }
+            Ok(wildmatch(pattern.as_bytes(), branch.as_bytes(), true))
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Expands a leading `~/` to the home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Parses a section header (without brackets) into `section` or
+/// `section.subsection`.
+fn parse_section(header: &str) -> eyre::Result<String> {
+    let header = header.trim();
+    match header.split_once(char::is_whitespace) {
+        // `[section "subsection"]`, where the subsection is case sensitive
+        Some((name, sub)) => {
+            let sub = sub
+                .trim()
+                .strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+                .ok_or(eyre!("invalid section header [{header}]"))?;
+            let sub = sub.replace("\\\"", "\"").replace("\\\\", "\\");
+            Ok(format!("{}.{sub}", name.to_lowercase()))
+        }
+        // `[section]` or the deprecated `[section.subsection]`
+        None => Ok(match header.split_once('.') {
+            Some((name, sub)) => format!("{}.{sub}", name.to_lowercase()),
+            None => header.to_lowercase(),
+        }),
+    }
+}
+
+/// Parses a value, removing comments and quotes and expanding escapes.
+fn parse_value(value: &str) -> eyre::Result<String> {
+    let mut out = String::new();
+    let mut in_quotes = false;
+    // Length of the value that must be kept when trimming trailing
+    // whitespace, which is significant inside quotes
+    let mut keep = 0;
+    let mut chars = value.trim().chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '#' | ';' if !in_quotes => break,
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('b') => {
+                    out.pop();
+                }
+                Some(c @ ('"' | '\\')) => out.push(c),
+                Some(c) => return Err(eyre!("invalid escape \\{c}")),
+                None => {}
+            },
+            c => out.push(c),
+        }
+        if in_quotes {
+            keep = out.len();
+        }
+    }
+
+    if in_quotes {
+        return Err(eyre!("unterminated quote in {value}"));
+    }
+    let trimmed = out.trim_end().len().max(keep);
+    out.truncate(trimmed);
+    Ok(out)
+}
+
+/// Parses a config boolean.
+pub fn parse_bool(value: &str) -> eyre::Result<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" | "" => Ok(false),
+        _ => Err(eyre!("invalid boolean value {value}")),
+    }
+}
+
+/// Lowercases the section and name of a key, keeping the subsection as is.
+fn normalize_key(key: &str) -> String {
+    let (section, rest) = key.split_once('.').unwrap_or((key, ""));
+    match rest.rsplit_once('.') {
+        Some((sub, name)) => format!("{}.{sub}.{}", section.to_lowercase(), name.to_lowercase()),
+        None => format!("{}.{}", section.to_lowercase(), rest.to_lowercase()),
+    }
+}
@@ -0,0 +1,88 @@
+use crate::config::Config;
+use crate::pktline;
+use crate::transport::{self, Refs, Transport};
+use eyre::eyre;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+/// The port `git daemon` listens on by default.
+const DEFAULT_PORT: u16 = 9418;
+
+/// Returns true if `url` names a repository served by `git daemon`.
+pub fn is_git(url: &str) -> bool {
+    url.starts_with("git://")
+}
+
+/// A repository served by `git daemon` at a `git://` url, like
+/// `git://host[:port]/path`. A TCP connection runs the service named in its
+/// first packet, and the requests and their responses then follow one
+/// another on it.
+pub struct Connection {
+    /// The host, with the port if the url has one.
+    host: String,
+    path: String,
+    /// The version of the protocol asked for with `protocol.version`.
+    version: u32,
+    stream: Option<TcpStream>,
+}
+
+impl Connection {
+    pub fn new(config: &Config, url: &str) -> eyre::Result<Self> {
+        let rest = url.strip_prefix("git://").unwrap_or(url);
+        let slash = rest.find('/').ok_or(eyre!(tr!(
+            "no path specified; see 'git help pull' for valid url syntax"
+        )))?;
+        let (host, path) = rest.split_at(slash);
+        Ok(Self {
+            host: host.to_string(),
+            path: path.to_string(),
+            version: transport::version(config)?,
+            stream: None,
+        })
+    }
+}
+
+impl Transport for Connection {
+    fn connect(&mut self, service: &str) -> eyre::Result<Refs> {
+        // Hosts without a port, IPv6 addresses in brackets included, are
+        // reached on the default one
+        let address = match self.host.rsplit_once(':') {
+            Some((_, port)) if !port.ends_with(']') => self.host.clone(),
+            _ => format!("{}:{DEFAULT_PORT}", self.host),
+        };
+        let stream = TcpStream::connect(&address).map_err(|e| {
+            eyre!(tr!(
+                "unable to connect to %s: %s",
+                &self.host,
+                &e.to_string()
+            ))
+        })?;
+
+        // The request names the service and the repository, followed by
+        // extra parameters after a second NUL
+        let mut request = format!("{service} {}\0host={}\0", self.path, self.host);
+        if self.version == 2 && service == "git-upload-pack" {
+            request.push_str("\0version=2\0");
+        }
+        pktline::Writer::new(&stream).write(request.as_bytes())?;
+
+        let mut reader = pktline::Reader::new(&stream);
+        let line = reader.read_line()?;
+        let refs = transport::read_refs(&mut reader, line)?;
+        self.stream = Some(stream);
+        Ok(refs)
+    }
+
+    fn request(&mut self, service: &str, request: &[u8]) -> eyre::Result<Box<dyn Read + '_>> {
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or(eyre!("{service} was not started"))?;
+        stream.write_all(request)?;
+        Ok(Box::new(stream))
+    }
+
+    fn is_stateless(&self) -> bool {
+        false
+    }
+}
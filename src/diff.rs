@@ -0,0 +1,391 @@
+use crate::git;
+use crate::tree::{Change, MODE_GITLINK};
+use std::path::Path;
+
+/// Splits content into lines, keeping the line terminators.
+pub fn lines(content: &[u8]) -> Vec<&[u8]> {
+    content.split_inclusive(|b| *b == b'\n').collect()
+}
+
+/// Returns the pairs of indices `(i, j)` such that `a[i] == b[j]` forming a
+/// longest common subsequence of `a` and `b`, in increasing order.
+pub fn matching<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    // Common prefixes and suffixes are matched without searching
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let mut pairs = (0..prefix).map(|i| (i, i)).collect::<Vec<_>>();
+    let middle = myers(&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    pairs.extend(middle.into_iter().map(|(i, j)| (i + prefix, j + prefix)));
+    pairs.extend((0..suffix).map(|i| (a.len() - suffix + i, b.len() - suffix + i)));
+    pairs
+}
+
+/// The Myers O(ND) difference algorithm, returning the matched indices.
+fn myers<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+
+    // `v[k]` is the furthest x reached on diagonal k = x - y. The diagonals
+    // -d..=d of `v` are kept for every edit distance d to backtrack the path
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let at = |k: isize| (k + offset) as usize;
+
+    'search: for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                v[at(k + 1)]
+            } else {
+                v[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                trace.push(v[at(-d)..=at(d)].to_vec());
+                break 'search;
+            }
+        }
+        trace.push(v[at(-d)..=at(d)].to_vec());
+    }
+
+    // Walk the path backwards from the end, collecting the diagonal moves
+    let mut pairs = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..trace.len() as isize).rev() {
+        let k = x - y;
+        let (prev_x, prev_y) = if d == 0 {
+            (0, 0)
+        } else {
+            // Diagonals of the previous round are stored from -(d - 1)
+            let prev = &trace[d as usize - 1];
+            let get = |k: isize| prev[(k + d - 1) as usize];
+            let prev_k = if k == -d || (k != d && get(k - 1) < get(k + 1)) {
+                k + 1
+            } else {
+                k - 1
+            };
+            (get(prev_k), get(prev_k) - prev_k)
+        };
+
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            pairs.push((x as usize, y as usize));
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+
+    pairs.reverse();
+    pairs
+}
+
+/// The outcome of a three-way merge of file contents.
+pub struct Merge3 {
+    pub content: Vec<u8>,
+    pub conflicts: usize,
+}
+
+/// Merges the changes made from `base` to `ours` and from `base` to
+/// `theirs`. Regions changed differently on both sides are written with
+/// conflict markers labeled with `labels`.
+pub fn merge3(base: &[u8], ours: &[u8], theirs: &[u8], labels: (&str, &str)) -> Merge3 {
+    let (o, a, b) = (lines(base), lines(ours), lines(theirs));
+
+    // Map the base lines to their position in each side
+    let mut in_a = vec![None; o.len()];
+    let mut in_b = vec![None; o.len()];
+    for (i, j) in matching(&o, &a) {
+        in_a[i] = Some(j);
+    }
+    for (i, j) in matching(&o, &b) {
+        in_b[i] = Some(j);
+    }
+
+    let mut content = Vec::new();
+    let mut conflicts = 0;
+    let (mut io, mut ia, mut ib) = (0, 0, 0);
+
+    loop {
+        // Find the next base line kept unchanged by both sides
+        let stable = (io..o.len()).find_map(|i| Some((i, in_a[i]?, in_b[i]?)));
+
+        let (eo, ea, eb) = stable.unwrap_or((o.len(), a.len(), b.len()));
+        if (eo, ea, eb) != (io, ia, ib) {
+            let (co, ca, cb) = (&o[io..eo], &a[ia..ea], &b[ib..eb]);
+            if ca == co || ca == cb {
+                content.extend(cb.concat());
+            } else if cb == co {
+                content.extend(ca.concat());
+            } else {
+                conflicts += 1;
+                content.extend_from_slice(format!("<<<<<<< {}\n", labels.0).as_bytes());
+                push_conflict_side(&mut content, ca);
+                content.extend_from_slice(b"=======\n");
+                push_conflict_side(&mut content, cb);
+                content.extend_from_slice(format!(">>>>>>> {}\n", labels.1).as_bytes());
+            }
+        }
+
+        let Some((eo, ea, eb)) = stable else {
+            break;
+        };
+        content.extend_from_slice(o[eo]);
+        (io, ia, ib) = (eo + 1, ea + 1, eb + 1);
+    }
+
+    Merge3 { content, conflicts }
+}
+
+/// Appends the lines of one side of a conflict to `content`, terminating the
+/// last one if needed so conflict markers start on their own line.
+fn push_conflict_side(content: &mut Vec<u8>, lines: &[&[u8]]) {
+    for line in lines {
+        content.extend_from_slice(line);
+    }
+    if !lines.is_empty() && content.last() != Some(&b'\n') {
+        content.push(b'\n');
+    }
+}
+
+/// An operation of an edit script turning one sequence of lines into
+/// another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// A hunk of a unified diff: a run of changes with the unchanged lines
+/// around them.
+#[derive(Debug)]
+pub struct Hunk<'a> {
+    /// The index of the first line of the hunk in the old and new content.
+    pub old_start: usize,
+    pub new_start: usize,
+    pub lines: Vec<(Op, &'a [u8])>,
+}
+
+impl Hunk<'_> {
+    /// Returns the number of lines the hunk covers in the old content.
+    pub fn old_count(&self) -> usize {
+        self.lines
+            .iter()
+            .filter(|(op, _)| *op != Op::Insert)
+            .count()
+    }
+
+    /// Returns the number of lines the hunk covers in the new content.
+    pub fn new_count(&self) -> usize {
+        self.lines
+            .iter()
+            .filter(|(op, _)| *op != Op::Delete)
+            .count()
+    }
+}
+
+impl std::fmt::Display for Hunk<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "@@ -{} +{} @@",
+            range(self.old_start, self.old_count()),
+            range(self.new_start, self.new_count())
+        )?;
+        for (op, line) in &self.lines {
+            let prefix = match op {
+                Op::Equal => ' ',
+                Op::Delete => '-',
+                Op::Insert => '+',
+            };
+            write!(f, "{prefix}{}", String::from_utf8_lossy(line))?;
+            if !line.ends_with(b"\n") {
+                write!(f, "\n\\ No newline at end of file\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the hunks of a unified diff from the lines `a` to the lines `b`,
+/// with `context` unchanged lines around each change.
+pub fn hunks<'a>(a: &[&'a [u8]], b: &[&'a [u8]], context: usize) -> Vec<Hunk<'a>> {
+    // Expand the matching lines into an edit script, recording the position
+    // in both files before each operation
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for (mi, mj) in matching(a, b).into_iter().chain([(a.len(), b.len())]) {
+        while i < mi {
+            ops.push((Op::Delete, i, j));
+            i += 1;
+        }
+        while j < mj {
+            ops.push((Op::Insert, i, j));
+            j += 1;
+        }
+        if mi < a.len() {
+            ops.push((Op::Equal, i, j));
+            i += 1;
+            j += 1;
+        }
+    }
+
+    // Group changes closer than twice the context into the same hunk
+    let changes = (0..ops.len())
+        .filter(|k| ops[*k].0 != Op::Equal)
+        .collect::<Vec<_>>();
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for k in changes {
+        match groups.last_mut() {
+            Some((_, end)) if k - *end <= 2 * context + 1 => *end = k,
+            _ => groups.push((k, k)),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(first, last)| {
+            let start = first.saturating_sub(context);
+            let end = (last + context + 1).min(ops.len());
+            let ops = &ops[start..end];
+            Hunk {
+                old_start: ops[0].1,
+                new_start: ops[0].2,
+                lines: ops
+                    .iter()
+                    .map(|(op, i, j)| match op {
+                        Op::Insert => (*op, b[*j]),
+                        _ => (*op, a[*i]),
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+/// Returns the hunks of a unified diff from `old` to `new`, with `context`
+/// unchanged lines around each change.
+pub fn unified(old: &[u8], new: &[u8], context: usize) -> String {
+    let (a, b) = (lines(old), lines(new));
+    hunks(&a, &b, context)
+        .iter()
+        .map(|hunk| hunk.to_string())
+        .collect()
+}
+
+/// Applies the hunks of a diff from the lines `old` for which `selected` is
+/// true, leaving the lines of the others unchanged.
+pub fn apply_hunks(old: &[&[u8]], hunks: &[Hunk], selected: &[bool]) -> Vec<u8> {
+    let mut content = Vec::new();
+    let mut position = 0;
+    for (hunk, selected) in hunks.iter().zip(selected) {
+        content.extend(old[position..hunk.old_start].concat());
+        let kept = match selected {
+            true => Op::Delete,
+            false => Op::Insert,
+        };
+        for (op, line) in &hunk.lines {
+            if *op != kept {
+                content.extend_from_slice(line);
+            }
+        }
+        position = hunk.old_start + hunk.old_count();
+    }
+    content.extend(old[position..].concat());
+    content
+}
+
+/// Formats the range of a hunk header. An empty range starts at the line
+/// preceding it.
+fn range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{count}", start + 1),
+    }
+}
+
+/// Returns true if the content looks binary, like git does: it contains a
+/// null byte in its first 8000 bytes.
+pub fn is_binary(content: &[u8]) -> bool {
+    content.iter().take(8000).any(|b| *b == 0)
+}
+
+/// Returns the number of lines a change inserts and deletes, binary files
+/// counting for none.
+pub fn line_counts(git_dir: &Path, change: &Change) -> eyre::Result<(usize, usize)> {
+    let read = |side: &Option<(u32, String)>| match side {
+        Some((mode, sha)) if *mode != MODE_GITLINK => git::read_blob(git_dir, sha),
+        _ => Ok(Vec::new()),
+    };
+    let (old, new) = (read(&change.old)?, read(&change.new)?);
+    if is_binary(&old) || is_binary(&new) {
+        return Ok((0, 0));
+    }
+    let (a, b) = (lines(&old), lines(&new));
+    let common = matching(&a, &b).len();
+    Ok((b.len() - common, a.len() - common))
+}
+
+/// Returns the patch for a changed file in git's extended diff format.
+pub fn patch(git_dir: &Path, change: &Change) -> eyre::Result<String> {
+    let path = change.path.display();
+    let mut out = format!("diff --git a/{path} b/{path}\n");
+
+    let (old_mode, old_sha) = change.old.clone().unwrap_or((0, git::NULL_SHA.to_string()));
+    let (new_mode, new_sha) = change.new.clone().unwrap_or((0, git::NULL_SHA.to_string()));
+    match (&change.old, &change.new) {
+        (None, _) => out.push_str(&format!("new file mode {new_mode:06}\n")),
+        (_, None) => out.push_str(&format!("deleted file mode {old_mode:06}\n")),
+        _ if old_mode != new_mode => {
+            out.push_str(&format!("old mode {old_mode:06}\nnew mode {new_mode:06}\n"));
+        }
+        _ => {}
+    }
+    if old_sha == new_sha {
+        return Ok(out);
+    }
+
+    let mode = match old_mode == new_mode {
+        true => format!(" {new_mode:06}"),
+        false => String::new(),
+    };
+    out.push_str(&format!(
+        "index {}..{}{mode}\n",
+        &old_sha[..7],
+        &new_sha[..7]
+    ));
+
+    let read = |side: &Option<(u32, String)>| match side {
+        Some((_, sha)) => git::read_blob(git_dir, sha),
+        None => Ok(Vec::new()),
+    };
+    let (old, new) = (read(&change.old)?, read(&change.new)?);
+    let old_name = match change.old {
+        Some(_) => format!("a/{path}"),
+        None => "/dev/null".to_string(),
+    };
+    let new_name = match change.new {
+        Some(_) => format!("b/{path}"),
+        None => "/dev/null".to_string(),
+    };
+
+    if is_binary(&old) || is_binary(&new) {
+        out.push_str(&format!("Binary files {old_name} and {new_name} differ\n"));
+    } else {
+        out.push_str(&format!("--- {old_name}\n+++ {new_name}\n"));
+        out.push_str(&unified(&old, &new, 3));
+    }
+    Ok(out)
+}
@@ -0,0 +1,132 @@
+use crate::config::{self, Config};
+use crate::wildmatch;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A line of an exclude file: the paths matching a pattern, relative to the
+/// directory of the file, are ignored, or not ignored anymore if negated.
+#[derive(Debug)]
+struct Pattern {
+    base: String,
+    pattern: String,
+    negated: bool,
+    /// Only matches directories, written with a trailing `/`.
+    directory: bool,
+}
+
+/// The rules telling which untracked files are ignored: the patterns of
+/// `core.excludesFile`, of `.git/info/exclude` and of the `.gitignore` files
+/// of the working tree, by increasing precedence. The `.gitignore` files are
+/// read as their directories are looked at.
+#[derive(Debug)]
+pub struct Excludes {
+    patterns: Vec<Pattern>,
+    /// The patterns of the `.gitignore` file of each directory, by the
+    /// directory from the top of the working tree.
+    gitignores: HashMap<String, Vec<Pattern>>,
+}
+
+impl Excludes {
+    /// Reads the exclude files of the repository at `git_dir`.
+    pub fn load(git_dir: &Path, config: &Config) -> eyre::Result<Self> {
+        // Without `core.excludesFile`, git reads `$XDG_CONFIG_HOME/git/ignore`
+        let global = match config.get("core.excludesFile") {
+            Some(path) => Some(config::expand_home(path)),
+            None => match std::env::var_os("XDG_CONFIG_HOME") {
+                Some(dir) => Some(PathBuf::from(dir).join("git/ignore")),
+                None => {
+                    std::env::var_os("HOME").map(|home| Path::new(&home).join(".config/git/ignore"))
+                }
+            },
+        };
+        let mut patterns = Vec::new();
+        for file in global.iter().chain([&git_dir.join("info/exclude")]) {
+            if let Ok(content) = fs::read_to_string(file) {
+                patterns.extend(parse("", &content));
+            }
+        }
+        Ok(Self {
+            patterns,
+            gitignores: HashMap::new(),
+        })
+    }
+
+    /// Returns true if the file or directory `path`, from the top of the
+    /// working tree, is ignored, itself or through one of its directories:
+    /// the last pattern matching it, in order of precedence, isn't negated.
+    pub fn is_excluded(&mut self, path: &str, is_dir: bool) -> bool {
+        let components = path
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .collect::<Vec<_>>();
+        (0..components.len()).any(|i| {
+            // Only the last component can be a file
+            let last = i + 1 == components.len();
+            let parent = components[..i].join("/");
+            self.matches(&parent, &components[..=i].join("/"), is_dir || !last)
+        })
+    }
+
+    /// Returns true if the last pattern matching `path`, in the directory
+    /// `parent`, isn't negated.
+    fn matches(&mut self, parent: &str, path: &str, is_dir: bool) -> bool {
+        // The `.gitignore` files from the top down to the parent apply
+        let mut dirs = vec![String::new()];
+        let mut base = String::new();
+        for dir in parent.split('/').filter(|dir| !dir.is_empty()) {
+            base.push_str(dir);
+            base.push('/');
+            dirs.push(base.clone());
+        }
+        for dir in &dirs {
+            if !self.gitignores.contains_key(dir) {
+                let content = fs::read_to_string(format!("{dir}.gitignore")).unwrap_or_default();
+                self.gitignores.insert(dir.clone(), parse(dir, &content));
+            }
+        }
+
+        let patterns = self
+            .patterns
+            .iter()
+            .chain(dirs.iter().flat_map(|dir| &self.gitignores[dir]));
+        let mut excluded = false;
+        for pattern in patterns {
+            let Some(relative) = path.strip_prefix(&pattern.base) else {
+                continue;
+            };
+            if (is_dir || !pattern.directory) && wildmatch::matches_path(&pattern.pattern, relative)
+            {
+                excluded = !pattern.negated;
+            }
+        }
+        excluded
+    }
+}
+
+/// Parses the lines of an exclude file found in the directory `base`.
+fn parse(base: &str, content: &str) -> Vec<Pattern> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let (directory, pattern) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            Some(Pattern {
+                base: base.to_string(),
+                pattern: pattern.to_string(),
+                negated,
+                directory,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,1338 @@
+use crate::commit::Commit;
+use crate::config::Config;
+use crate::git::{self, GitFile, GitFileContent};
+use crate::negotiator::{Algorithm, Negotiator};
+use crate::pack;
+use crate::partial::{self, Filter};
+use crate::pktline::{self, Packet};
+use crate::progress::{self, Progress};
+use crate::refs;
+use crate::repository;
+use crate::revision;
+use crate::revwalk::RevWalk;
+use crate::shallow;
+use crate::tempfile::Temporary;
+use crate::transport::{self, Transport};
+use crate::tree::MODE_GITLINK;
+use eyre::eyre;
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// A mapping from remote refs to local refs, like
+/// `+refs/heads/*:refs/remotes/origin/*`.
+#[derive(Debug, Clone)]
+pub struct Refspec {
+    /// Whether non fast-forward updates are allowed.
+    pub force: bool,
+    pub src: String,
+    pub dst: String,
+}
+
+impl Refspec {
+    pub fn parse(spec: &str) -> Self {
+        let (force, spec) = match spec.strip_prefix('+') {
+            Some(spec) => (true, spec),
+            None => (false, spec),
+        };
+        let (src, dst) = spec.split_once(':').unwrap_or((spec, ""));
+        Self {
+            force,
+            src: src.to_string(),
+            dst: dst.to_string(),
+        }
+    }
+
+    /// Returns the local ref the remote ref `name` maps to, if any. A `*` in
+    /// the source matches any sequence of characters, which replace the `*`
+    /// of the destination.
+    pub fn map(&self, name: &str) -> Option<String> {
+        match self.src.split_once('*') {
+            Some((prefix, suffix)) => {
+                let matched = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+                Some(self.dst.replacen('*', matched, 1))
+            }
+            None => (name == self.src).then(|| self.dst.clone()),
+        }
+    }
+
+    /// Returns the start of the names of the remote refs the refspec
+    /// selects, the whole source unless it has a `*`.
+    pub fn prefix(&self) -> &str {
+        self.src.split('*').next().unwrap_or_default()
+    }
+
+    /// Returns the remote ref the local ref `name` is mapped from, if any.
+    pub fn map_back(&self, name: &str) -> Option<String> {
+        Self {
+            force: self.force,
+            src: self.dst.clone(),
+            dst: self.src.clone(),
+        }
+        .map(name)
+    }
+}
+
+/// A remote repository configured in `.git/config`.
+#[derive(Debug)]
+pub struct Remote {
+    pub name: String,
+    pub url: String,
+    /// Where to push, `remote.<name>.pushurl` or else the url.
+    pub push_url: String,
+    pub refspecs: Vec<Refspec>,
+}
+
+impl Remote {
+    /// Loads the `remote.<name>.*` configuration, rewriting the urls with
+    /// the `url.<base>.insteadOf` rules. Without a `pushurl`, the url is
+    /// rewritten for pushing with the `url.<base>.pushInsteadOf` rules first.
+    pub fn load(config: &Config, name: &str) -> eyre::Result<Self> {
+        let url = config.get(&format!("remote.{name}.url")).ok_or(eyre!(tr!(
+            "'%s' does not appear to be a git repository",
+            name
+        )))?;
+        let push_url = match config.get(&format!("remote.{name}.pushurl")) {
+            Some(push_url) => rewrite_url(config, push_url, "insteadOf"),
+            None => match rewrite_url(config, url, "pushInsteadOf") {
+                rewritten if rewritten != url => rewritten,
+                _ => rewrite_url(config, url, "insteadOf"),
+            },
+        };
+        let refspecs = config
+            .get_all(&format!("remote.{name}.fetch"))
+            .into_iter()
+            .map(Refspec::parse)
+            .collect();
+
+        Ok(Self {
+            name: name.to_string(),
+            url: rewrite_url(config, url, "insteadOf"),
+            push_url,
+            refspecs,
+        })
+    }
+}
+
+/// Rewrites `url` with the `url.<base>.<key>` rules: the longest prefix of
+/// `url` given by a rule is replaced by the base of the rule.
+fn rewrite_url(config: &Config, url: &str, key: &str) -> String {
+    config
+        .subsections("url", key)
+        .into_iter()
+        .filter(|(_, prefix)| url.starts_with(prefix))
+        .max_by_key(|(_, prefix)| prefix.len())
+        .map(|(base, prefix)| format!("{base}{}", &url[prefix.len()..]))
+        .unwrap_or(url.to_string())
+}
+
+/// A line of `.git/FETCH_HEAD`, recording a ref fetched by the last fetch.
+#[derive(Debug)]
+pub struct FetchHead {
+    pub sha: String,
+    /// Whether `pull` should merge this ref.
+    pub for_merge: bool,
+    /// Describes the fetched ref, like `branch 'main' of ../upstream`.
+    pub description: String,
+}
+
+impl FetchHead {
+    /// Reads the entries of `.git/FETCH_HEAD`.
+    pub fn read(git_dir: &Path) -> eyre::Result<Vec<Self>> {
+        let content = fs::read_to_string(git_dir.join("FETCH_HEAD"))
+            .map_err(|_| eyre!(tr!("no FETCH_HEAD, nothing was fetched")))?;
+
+        // Lines are `SHA\t[not-for-merge]\tDESCRIPTION`
+        content
+            .lines()
+            .map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let sha = fields.next().unwrap_or_default().to_string();
+                let for_merge = fields.next() != Some("not-for-merge");
+                let description = fields.next().unwrap_or_default().to_string();
+                match refs::is_sha(&sha) {
+                    true => Ok(Self {
+                        sha,
+                        for_merge,
+                        description,
+                    }),
+                    false => Err(eyre!("invalid FETCH_HEAD line {line}")),
+                }
+            })
+            .collect()
+    }
+
+    /// Writes the entries to `.git/FETCH_HEAD`, entries to merge first.
+    fn write(git_dir: &Path, entries: &mut [Self]) -> eyre::Result<()> {
+        entries.sort_by_key(|e| !e.for_merge);
+        let content = entries
+            .iter()
+            .map(|e| {
+                let marker = if e.for_merge { "" } else { "not-for-merge" };
+                format!("{}\t{marker}\t{}\n", e.sha, e.description)
+            })
+            .collect::<String>();
+        fs::write(git_dir.join("FETCH_HEAD"), content)?;
+        Ok(())
+    }
+}
+
+/// How much of the history of the fetched refs `fetch` downloads.
+#[derive(Debug, Default, Clone)]
+pub struct FetchOptions {
+    /// Keeps this many commits of the history of the fetched refs.
+    pub depth: Option<u32>,
+    /// Fetches this many more commits behind the shallow commits of the
+    /// local repository.
+    pub deepen: Option<u32>,
+    /// Leaves out the commits older than this timestamp.
+    pub shallow_since: Option<i64>,
+    /// Leaves out the commits reachable from these refs of the remote.
+    pub shallow_exclude: Vec<String>,
+    /// Leaves out trees and blobs, making the repository a partial clone.
+    pub filter: Option<Filter>,
+    /// Only fetches the wanted objects and what the filter keeps of the
+    /// objects they reference, without negotiating the commits the local
+    /// repository has, like partial clones fetching their missing objects.
+    pub no_dependents: bool,
+}
+
+impl FetchOptions {
+    /// Returns true if the options cut the history fetched, or deepen it.
+    fn is_deepening(&self) -> bool {
+        self.depth.is_some()
+            || self.deepen.is_some()
+            || self.shallow_since.is_some()
+            || !self.shallow_exclude.is_empty()
+    }
+
+    /// Returns the lines asking a server for the history the options keep.
+    fn deepen_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(depth) = self.depth.or(self.deepen) {
+            lines.push(format!("deepen {depth}"));
+        }
+        if let Some(since) = self.shallow_since {
+            lines.push(format!("deepen-since {since}"));
+        }
+        for name in &self.shallow_exclude {
+            lines.push(format!("deepen-not {name}"));
+        }
+        lines
+    }
+
+    /// Checks that the server advertising `advertised` supports the options,
+    /// returning the version 0 capabilities they need. Filters are ignored
+    /// by servers without the `filter` capability.
+    fn capabilities(
+        &self,
+        git_dir: &Path,
+        advertised: &transport::Refs,
+    ) -> eyre::Result<Vec<&'static str>> {
+        // Version 2 servers supporting shallow fetches support all of them
+        let has = |capability: &str| match (advertised.version, capability) {
+            (2, "filter") => advertised.has_feature("fetch", "filter"),
+            (2, _) => advertised.has_feature("fetch", "shallow"),
+            _ => advertised.has(capability),
+        };
+        let mut capabilities = Vec::new();
+        if self.is_deepening() || !shallow::read(git_dir)?.is_empty() {
+            if !has("shallow") {
+                return Err(eyre!(tr!("Server does not support shallow clients")));
+            }
+            capabilities.push("shallow");
+        }
+        let features = [
+            (self.deepen.is_some(), "deepen-relative", "--deepen"),
+            (
+                self.shallow_since.is_some(),
+                "deepen-since",
+                "--shallow-since",
+            ),
+            (
+                !self.shallow_exclude.is_empty(),
+                "deepen-not",
+                "--shallow-exclude",
+            ),
+        ];
+        for (used, capability, option) in features {
+            if used {
+                if !has(capability) {
+                    return Err(eyre!(tr!("Server does not support %s", option)));
+                }
+                capabilities.push(capability);
+            }
+        }
+        if self.filter.is_some() {
+            match has("filter") {
+                true => capabilities.push("filter"),
+                false => eprintln!(
+                    "{}",
+                    tr!("warning: filtering not recognized by server, ignoring")
+                ),
+            }
+        }
+        Ok(capabilities)
+    }
+
+    /// Returns how to negotiate the commits the local repository has in
+    /// common with the server advertising `advertised`, and the advertised
+    /// refs known to be common.
+    fn negotiation<'a>(
+        &self,
+        config: &Config,
+        advertised: &'a transport::Refs,
+    ) -> eyre::Result<(Algorithm, &'a [(String, String)])> {
+        match self.no_dependents {
+            true => Ok((Algorithm::Noop, &[])),
+            false => Ok((Algorithm::from_config(config)?, &advertised.refs)),
+        }
+    }
+}
+
+/// The objects a fetch asks for: by sha, and by the name of the ref pointing
+/// to them, from servers supporting `ref-in-want`.
+#[derive(Debug, Default)]
+struct Wants {
+    shas: Vec<String>,
+    refs: Vec<String>,
+}
+
+impl Wants {
+    fn is_empty(&self) -> bool {
+        self.shas.is_empty() && self.refs.is_empty()
+    }
+}
+
+/// The `(name, sha)` pairs of the refs wanted by name, as a server resolved
+/// them.
+type WantedRefs = Vec<(String, String)>;
+
+/// How a fetch changes the shallow commits of the local repository, as the
+/// server tells it.
+#[derive(Debug, Default)]
+struct ShallowUpdate {
+    /// The fetched commits whose parents were left out.
+    shallow: Vec<String>,
+    /// The shallow commits whose parents were fetched.
+    unshallow: Vec<String>,
+}
+
+impl ShallowUpdate {
+    /// Reads a `shallow` or `unshallow` line of the server. Returns false if
+    /// `line` is neither.
+    fn parse(&mut self, line: &str) -> eyre::Result<bool> {
+        let (list, sha) = match line.split_once(' ') {
+            Some(("shallow", sha)) => (&mut self.shallow, sha),
+            Some(("unshallow", sha)) => (&mut self.unshallow, sha),
+            _ => return Ok(false),
+        };
+        if !refs::is_sha(sha) {
+            return Err(eyre!(tr!("git fetch-pack: expected shallow list")));
+        }
+        list.push(sha.to_string());
+        Ok(true)
+    }
+
+    /// Reads the update a version 0 server sends, up to a flush packet.
+    fn read(reader: &mut pktline::Reader<impl Read>) -> eyre::Result<Self> {
+        let mut update = Self::default();
+        while let Some(line) = reader.read_line()? {
+            if !update.parse(&line)? {
+                return Err(eyre!(tr!("git fetch-pack: expected shallow list")));
+            }
+        }
+        Ok(update)
+    }
+}
+
+/// Fetches the refs of `remote` selected by its refspecs: copies the objects
+/// missing locally, updates the remote-tracking refs and records the fetched
+/// refs in `FETCH_HEAD`, marking the remote refs listed in `merge` for merge.
+/// The commits whose parents `options` leaves out are recorded in
+/// `.git/shallow`, and filtering the objects makes the repository a partial
+/// clone of `remote`.
+pub fn fetch(
+    git_dir: &Path,
+    config: &Config,
+    remote: &Remote,
+    merge: &[String],
+    options: &FetchOptions,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<()> {
+    // Objects the repository doesn't have are looked for, not fetched
+    partial::without_lazy_fetch(|| fetch_refs(git_dir, config, remote, merge, options, progress))
+}
+
+/// Fetches the refs of `remote` for [`fetch`].
+fn fetch_refs(
+    git_dir: &Path,
+    config: &Config,
+    remote: &Remote,
+    merge: &[String],
+    options: &FetchOptions,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<()> {
+    // Remote repositories on the local filesystem are read directly, the
+    // objects of each fetched ref being copied as the refs are updated
+    let (remote_refs, local) = match transport::is_remote(&remote.url) {
+        true => {
+            let mut transport = transport::open(config, &remote.url)?;
+            let prefixes = remote
+                .refspecs
+                .iter()
+                .map(|refspec| refspec.prefix().to_string())
+                .collect::<Vec<_>>();
+            let advertised = transport.advertisement("git-upload-pack", &prefixes)?;
+
+            // Fetches from the promisor remote of a partial clone keep
+            // leaving out what it did
+            let promisor = config.get_bool(&format!("remote.{}.promisor", remote.name))?;
+            let filter = match (options.filter, promisor) {
+                (Some(filter), _) => Some(filter),
+                (None, Some(true)) => config
+                    .get(&format!("remote.{}.partialclonefilter", remote.name))
+                    .map(str::parse)
+                    .transpose()?,
+                (None, _) => None,
+            };
+            if let (Some(filter), None | Some(false)) = (options.filter, promisor) {
+                partial::register(git_dir, &remote.name, filter)?;
+            }
+            let options = &FetchOptions {
+                filter,
+                ..options.clone()
+            };
+
+            let mut remote_refs = advertised
+                .refs
+                .iter()
+                .filter(|(name, _)| name.starts_with("refs/"))
+                .cloned()
+                .collect::<Vec<_>>();
+            // Deepening needs the refs the local repository already has too,
+            // to walk down from them. The refs named by refspecs are wanted
+            // by name where the server can, so that a ref updated since it
+            // was advertised is fetched as it is now.
+            let by_name = advertised.version == 2 && advertised.has_feature("fetch", "ref-in-want");
+            let mut wants = Wants::default();
+            for (name, sha) in &remote_refs {
+                let selected = remote.refspecs.iter().any(|r| r.map(name).is_some());
+                let needed = options.is_deepening() || !has_object(git_dir, sha);
+                if !selected || !needed {
+                    continue;
+                }
+                match by_name && remote.refspecs.iter().any(|r| r.src == *name) {
+                    true => wants.refs.push(name.clone()),
+                    false if !wants.shas.contains(sha) => wants.shas.push(sha.clone()),
+                    false => {}
+                }
+            }
+            if !wants.is_empty() {
+                match advertised.dumb {
+                    true if options.is_deepening() => {
+                        return Err(eyre!(tr!(
+                            "dumb http transport does not support shallow capabilities"
+                        )))
+                    }
+                    true => {
+                        if options.filter.is_some() {
+                            eprintln!(
+                                "{}",
+                                tr!("warning: filtering not recognized by server, ignoring")
+                            );
+                        }
+                        transport.download(git_dir, &wants.shas)?
+                    }
+                    false => {
+                        let wanted = fetch_pack(
+                            git_dir,
+                            config,
+                            transport.as_mut(),
+                            &advertised,
+                            &wants,
+                            options,
+                            progress,
+                        )?;
+                        for (name, sha) in wanted {
+                            if let Some(entry) = remote_refs.iter_mut().find(|(n, _)| *n == name) {
+                                entry.1 = sha;
+                            }
+                        }
+                    }
+                }
+            }
+            (remote_refs, None)
+        }
+        false => {
+            if options.filter.is_some() {
+                eprintln!(
+                    "{}",
+                    tr!("warning: filtering not supported for local repositories, ignoring")
+                );
+            }
+            let remote_dir = local_git_dir(&remote.url)?;
+            let remote_refs = refs::list_refs(&remote_dir, "refs/")?;
+            let algorithm = Algorithm::from_config(config)?;
+            let common = negotiate(git_dir, algorithm, &remote_refs, |_, haves| {
+                Ok(haves
+                    .iter()
+                    .filter(|have| is_commit(&remote_dir, have))
+                    .cloned()
+                    .collect())
+            })?;
+            let tips = remote_refs
+                .iter()
+                .filter(|(name, _)| remote.refspecs.iter().any(|r| r.map(name).is_some()))
+                .map(|(_, sha)| sha.clone())
+                .collect::<Vec<_>>();
+            let boundary = Boundary::new(git_dir, &remote_dir, &tips, options)?;
+
+            // The shallow commits whose parents the fetch keeps get them
+            let unshallow = boundary.unshallow(git_dir, &remote_dir)?;
+            let mut shallow = Vec::new();
+            for sha in &unshallow {
+                for parent in Commit::parse(&git::read_object(&remote_dir, sha)?.1)?.parents {
+                    shallow.extend(copy_objects(
+                        git_dir,
+                        &remote_dir,
+                        &parent,
+                        &common,
+                        &boundary,
+                    )?);
+                }
+            }
+            shallow::update(git_dir, shallow, &unshallow)?;
+            (remote_refs, Some((remote_dir, common, boundary)))
+        }
+    };
+
+    let mut walk = RevWalk::new();
+    let mut fetch_head = Vec::new();
+    let mut updates = Vec::new();
+    for (name, sha) in remote_refs {
+        let Some(refspec) = remote.refspecs.iter().find(|r| r.map(&name).is_some()) else {
+            continue;
+        };
+        if let Some((remote_dir, common, boundary)) = &local {
+            let shallow = copy_objects(git_dir, remote_dir, &sha, common, boundary)?;
+            shallow::extend(git_dir, shallow)?;
+        }
+
+        let (kind, short) = match name.strip_prefix("refs/heads/") {
+            Some(branch) => ("branch", branch),
+            None => match name.strip_prefix("refs/tags/") {
+                Some(tag) => ("tag", tag),
+                None => ("ref", name.as_str()),
+            },
+        };
+        fetch_head.push(FetchHead {
+            sha: sha.clone(),
+            for_merge: merge.contains(&name),
+            description: format!("{kind} '{short}' of {}", remote.url),
+        });
+
+        // Without a destination the ref is only recorded in FETCH_HEAD
+        let local = refspec.map(&name).unwrap_or_default();
+        if local.is_empty() {
+            continue;
+        }
+        let old = refs::read_ref(git_dir, &local)?;
+        if old.as_deref() == Some(sha.as_str()) {
+            continue;
+        }
+        let display = ["refs/remotes/", "refs/tags/"]
+            .iter()
+            .find_map(|prefix| local.strip_prefix(prefix))
+            .unwrap_or(&local);
+        let status = match &old {
+            None => format!(" * {:<17} {short} -> {display}", format!("[new {kind}]")),
+            Some(old) if walk.is_ancestor(git_dir, old, &sha)? => {
+                format!("   {}..{}  {short} -> {display}", &old[..7], &sha[..7])
+            }
+            Some(old) if refspec.force => {
+                format!(
+                    " + {}...{} {short} -> {display}  (forced update)",
+                    &old[..7],
+                    &sha[..7]
+                )
+            }
+            Some(_) => {
+                updates.push(format!(
+                    " ! [rejected]        {short} -> {display}  (non-fast-forward)"
+                ));
+                continue;
+            }
+        };
+        refs::update_ref(git_dir, &local, &sha)?;
+        updates.push(status);
+    }
+
+    FetchHead::write(git_dir, &mut fetch_head)?;
+    if !updates.is_empty() {
+        eprintln!("{}", tr!("From %s", remote.url));
+        updates.iter().for_each(|u| eprintln!("{u}"));
+    }
+    Ok(())
+}
+
+/// The refs a remote repository has, as a fetch would find them.
+#[derive(Debug)]
+pub struct Advertisement {
+    /// The `(name, sha)` pairs of the refs, sorted by name.
+    pub refs: Vec<(String, String)>,
+    /// The branches the `HEAD` of the remote may be: the one it points to,
+    /// or the branches at its commit if it's detached.
+    pub head: Vec<String>,
+}
+
+/// Lists the refs of `remote`, asking the remote repository itself rather
+/// than looking at the remote-tracking refs.
+pub fn ls_remote(config: &Config, remote: &Remote) -> eyre::Result<Advertisement> {
+    let (refs, head) = match transport::is_remote(&remote.url) {
+        true => {
+            let advertised =
+                transport::open(config, &remote.url)?.advertisement("git-upload-pack", &[])?;
+            let head = match advertised.symref("HEAD") {
+                Some(target) => format!("ref: {target}"),
+                None => advertised
+                    .refs
+                    .iter()
+                    .find(|(name, _)| name == "HEAD")
+                    .map(|(_, sha)| sha.clone())
+                    .unwrap_or_default(),
+            };
+            let refs = advertised
+                .refs
+                .into_iter()
+                .filter(|(name, _)| name.starts_with("refs/"))
+                .collect::<Vec<_>>();
+            (refs, head)
+        }
+        false => {
+            let remote_dir = local_git_dir(&remote.url)?;
+            let refs = refs::list_refs(&remote_dir, "refs/")?;
+            (refs, fs::read_to_string(remote_dir.join("HEAD"))?)
+        }
+    };
+    let head = match head.trim_end().strip_prefix("ref: ") {
+        Some(target) => refs
+            .iter()
+            .filter(|(name, _)| name == target)
+            .map(|(name, _)| name.clone())
+            .collect(),
+        None => refs
+            .iter()
+            .filter(|(name, sha)| name.starts_with("refs/heads/") && *sha == head.trim_end())
+            .map(|(name, _)| name.clone())
+            .collect(),
+    };
+    Ok(Advertisement { refs, head })
+}
+
+/// Returns the remote-tracking refs of `remote` whose remote ref is gone
+/// from `advertisement`, sorted by name.
+pub fn stale_refs(
+    git_dir: &Path,
+    remote: &Remote,
+    advertisement: &Advertisement,
+) -> eyre::Result<Vec<String>> {
+    let mut stale = Vec::new();
+    for (name, _) in refs::list_refs(git_dir, "refs/")? {
+        // Symbolic refs like `refs/remotes/origin/HEAD` follow their target
+        if refs::symbolic_target(git_dir, &name)?.is_some() {
+            continue;
+        }
+        let Some(source) = remote.refspecs.iter().find_map(|r| r.map_back(&name)) else {
+            continue;
+        };
+        if !advertisement.refs.iter().any(|(r, _)| *r == source) {
+            stale.push(name);
+        }
+    }
+    Ok(stale)
+}
+
+/// Returns the git directory of the repository at the given url, on the
+/// local filesystem.
+pub fn local_git_dir(url: &str) -> eyre::Result<PathBuf> {
+    if url.contains("://") && !url.starts_with("file://") {
+        return Err(eyre!(tr!("unsupported protocol in url %s", url)));
+    }
+    let path = Path::new(url.strip_prefix("file://").unwrap_or(url));
+    if path.join(".git").is_dir() {
+        Ok(path.join(".git"))
+    } else if let Some(git_dir) = repository::read_gitfile(&path.join(".git"))? {
+        Ok(git_dir)
+    } else if path.join("objects").is_dir() {
+        Ok(path.to_path_buf())
+    } else {
+        Err(eyre!(tr!(
+            "'%s' does not appear to be a git repository",
+            url
+        )))
+    }
+}
+
+/// The number of `have` lines of the first round of a negotiation.
+const INITIAL_FLUSH: usize = 16;
+/// The number of `have` lines without acknowledgement after which the
+/// negotiation gives up looking for more common commits.
+const MAX_IN_VAIN: usize = 256;
+
+/// Finds the commits the local repository has in common with a remote one,
+/// whose refs are `remote_refs`, like the `have`/`ACK` exchange of a fetch:
+/// the local commits chosen by `algorithm` are sent to `ask` in rounds of
+/// growing size, along with the common commits found so far, and it
+/// returns the ones the remote acknowledges.
+fn negotiate(
+    git_dir: &Path,
+    algorithm: Algorithm,
+    remote_refs: &[(String, String)],
+    mut ask: impl FnMut(&HashSet<String>, &[String]) -> eyre::Result<Vec<String>>,
+) -> eyre::Result<HashSet<String>> {
+    let tips = refs::list_refs(git_dir, "refs/")?
+        .into_iter()
+        .map(|(_, sha)| sha)
+        .chain(refs::read_ref(git_dir, "HEAD")?)
+        .filter(|sha| is_commit(git_dir, sha))
+        .collect::<Vec<_>>();
+    let mut negotiator = Negotiator::new(git_dir, algorithm, &tips)?;
+
+    // The advertised refs the local repository already has need no round
+    let mut common = HashSet::new();
+    for (_, sha) in remote_refs {
+        if is_commit(git_dir, sha) {
+            negotiator.known_common(git_dir, sha)?;
+            common.insert(sha.clone());
+        }
+    }
+
+    let mut flush = INITIAL_FLUSH;
+    let mut in_vain = 0;
+    loop {
+        let mut haves = Vec::new();
+        while haves.len() < flush {
+            match negotiator.next_have(git_dir)? {
+                Some(sha) => haves.push(sha),
+                None => break,
+            }
+        }
+        if haves.is_empty() {
+            break;
+        }
+        let acked = ask(&common, &haves)?;
+        for have in haves {
+            if acked.contains(&have) {
+                negotiator.ack(git_dir, &have)?;
+                common.insert(have);
+                in_vain = 0;
+            } else {
+                in_vain += 1;
+            }
+        }
+        if !common.is_empty() && in_vain >= MAX_IN_VAIN {
+            break;
+        }
+        flush *= 2;
+    }
+    Ok(common)
+}
+
+/// Returns true if the object `sha` of the repository at `git_dir` is a
+/// commit.
+fn is_commit(git_dir: &Path, sha: &str) -> bool {
+    git::read_object(git_dir, sha).is_ok_and(|(kind, _)| kind == "commit")
+}
+
+/// Returns true if the local repository has the object `sha`.
+fn has_object(git_dir: &Path, sha: &str) -> bool {
+    git::read_object(git_dir, sha).is_ok()
+}
+
+/// Fetches the objects `wants` of `remote`, leaving out the ones they
+/// reference that its `partialclonefilter` doesn't keep, as partial clones
+/// do with the objects they miss.
+pub fn fetch_objects(
+    git_dir: &Path,
+    config: &Config,
+    remote: &Remote,
+    wants: &[String],
+) -> eyre::Result<()> {
+    // Only the capabilities of the server are needed, not its refs
+    let mut transport = transport::open(config, &remote.url)?;
+    let advertised = transport.connect("git-upload-pack")?;
+    if advertised.dumb {
+        return transport.download(git_dir, wants);
+    }
+    let options = FetchOptions {
+        filter: config
+            .get(&format!("remote.{}.partialclonefilter", remote.name))
+            .map(str::parse)
+            .transpose()?,
+        no_dependents: true,
+        ..Default::default()
+    };
+    let wants = Wants {
+        shas: wants.to_vec(),
+        refs: Vec::new(),
+    };
+    fetch_pack(
+        git_dir,
+        config,
+        transport.as_mut(),
+        &advertised,
+        &wants,
+        &options,
+        None,
+    )?;
+    Ok(())
+}
+
+/// The number of objects below which fetched packs are unpacked into loose
+/// objects, unless `fetch.unpackLimit` or `transfer.unpackLimit` say
+/// otherwise. Pushes follow `receive.unpackLimit` instead.
+pub const UNPACK_LIMIT: u32 = 100;
+
+/// Downloads the objects of `wants` from the `git-upload-pack` service of
+/// `transport`, which advertised `advertised`, and stores them in the local
+/// repository, along with the shallow commits of the history `options`
+/// keeps. The packs of partial clones are kept whole, marked as coming from
+/// their promisor remote. Returns the refs wanted by name, as the server
+/// resolved them.
+fn fetch_pack(
+    git_dir: &Path,
+    config: &Config,
+    transport: &mut dyn Transport,
+    advertised: &transport::Refs,
+    wants: &Wants,
+    options: &FetchOptions,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<WantedRefs> {
+    let (pack, wanted, update) = match advertised.version {
+        2 => pack_v2(
+            git_dir, config, transport, advertised, wants, options, progress,
+        )?,
+        _ => {
+            let (pack, update) = pack_v0(
+                git_dir,
+                config,
+                transport,
+                advertised,
+                &wants.shas,
+                options,
+                progress,
+            )?;
+            (pack, Vec::new(), update)
+        }
+    };
+
+    let limit = match config
+        .get("fetch.unpackLimit")
+        .or(config.get("transfer.unpackLimit"))
+    {
+        Some(limit) => limit.parse()?,
+        None => UNPACK_LIMIT,
+    };
+    let count = pack
+        .get(8..12)
+        .map_or(0, |count| u32::from_be_bytes(count.try_into().unwrap()));
+    let promisor = options.filter.is_some() || options.no_dependents;
+    match count < limit && !promisor {
+        true => {
+            pack::unpack_objects(git_dir, &pack, false, progress)?;
+        }
+        false => {
+            let name = pack::store(git_dir, &pack, progress)?;
+            if promisor {
+                let dir = git_dir.join("objects/pack");
+                fs::write(dir.join(format!("pack-{name}.promisor")), "")?;
+            }
+        }
+    }
+    shallow::update(git_dir, update.shallow, &update.unshallow)?;
+    Ok(wanted)
+}
+
+/// Returns the pack of `wants` from a server speaking version 0 of the
+/// protocol. Over stateless transports, each round of the negotiation is a
+/// request of its own, so it repeats the wants and the common commits found
+/// so far, which a connection remembers instead. The server answers the
+/// requests with the `deepen` lines of `options` with the update of the
+/// shallow commits, returned along with the pack.
+fn pack_v0(
+    git_dir: &Path,
+    config: &Config,
+    transport: &mut dyn Transport,
+    advertised: &transport::Refs,
+    wants: &[String],
+    options: &FetchOptions,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<(Vec<u8>, ShallowUpdate)> {
+    let capabilities = ["multi_ack_detailed", "side-band-64k", "ofs-delta"]
+        .into_iter()
+        .filter(|capability| advertised.has(capability))
+        .chain(options.capabilities(git_dir, advertised)?)
+        .chain([transport::AGENT])
+        .collect::<Vec<_>>();
+    let deepen = options.deepen_lines();
+    let sideband = advertised.has("side-band-64k");
+    let mut update = ShallowUpdate::default();
+
+    let mut request = pktline::Writer::new(Vec::new());
+    for (i, want) in wants.iter().enumerate() {
+        match i {
+            0 => request.write_line(&format!("want {want} {}", capabilities.join(" ")))?,
+            _ => request.write_line(&format!("want {want}"))?,
+        }
+    }
+    // The server mustn't count on the parents of shallow commits
+    for sha in shallow::read(git_dir)? {
+        request.write_line(&format!("shallow {sha}"))?;
+    }
+    for line in &deepen {
+        request.write_line(line)?;
+    }
+    if let Some(filter) = options.filter.filter(|_| capabilities.contains(&"filter")) {
+        request.write_line(&format!("filter {filter}"))?;
+    }
+    request.write_flush()?;
+    let request = request.into_inner();
+    let haves = |request: &mut Vec<u8>, haves: Vec<&String>| {
+        let mut request = pktline::Writer::new(request);
+        haves
+            .into_iter()
+            .try_for_each(|have| request.write_line(&format!("have {have}")))
+    };
+
+    let stateless = transport.is_stateless();
+    let mut started = false;
+    let (algorithm, known) = options.negotiation(config, advertised)?;
+    let common = negotiate(git_dir, algorithm, known, |common, new| {
+        let mut round = match stateless || !started {
+            true => request.clone(),
+            false => Vec::new(),
+        };
+        match stateless {
+            true => haves(&mut round, common.iter().chain(new).collect())?,
+            false => haves(&mut round, new.iter().collect())?,
+        }
+        pktline::Writer::new(&mut round).write_flush()?;
+        let mut response = pktline::Reader::new(transport.request("git-upload-pack", &round)?);
+        // Requests with the wants are first answered with the shallow
+        // commits
+        if !deepen.is_empty() && (stateless || !started) {
+            update = ShallowUpdate::read(&mut response)?;
+        }
+        started = true;
+        // The round ends with a NAK, unless the server only acknowledges
+        // a single commit
+        let mut acked = Vec::new();
+        while let Some(line) = response.read_line()? {
+            match line.split(' ').collect::<Vec<_>>()[..] {
+                ["ACK", sha, ..] => acked.push(sha.to_string()),
+                ["NAK"] => break,
+                _ => return Err(eyre!(tr!("expected ACK/NAK, got '%s'", &line))),
+            }
+            if !advertised.has("multi_ack_detailed") {
+                break;
+            }
+        }
+        Ok(acked)
+    })?;
+
+    let wanting = stateless || !started;
+    let mut request = match wanting {
+        true => request,
+        false => Vec::new(),
+    };
+    if stateless {
+        haves(&mut request, common.iter().collect())?;
+    }
+    pktline::Writer::new(&mut request).write_line("done")?;
+    let response = transport.request("git-upload-pack", &request)?;
+    let mut response = pktline::Reader::new(progress::Reader::new(response, progress));
+    if !deepen.is_empty() && wanting {
+        update = ShallowUpdate::read(&mut response)?;
+    }
+
+    // The common commits are acknowledged again, up to the final ACK or NAK
+    // the pack follows
+    loop {
+        let line = response.read_line()?.unwrap_or_default();
+        match line.split(' ').collect::<Vec<_>>()[..] {
+            ["NAK"] | ["ACK", _] => break,
+            ["ACK", _, _] => {}
+            _ => return Err(eyre!(tr!("expected ACK/NAK, got '%s'", &line))),
+        }
+    }
+
+    // With a side-band, the pack comes on band 1, progress on band 2 and
+    // errors on band 3
+    let pack = match sideband {
+        true => response.read_sideband(|messages| {
+            if let Some(progress) = progress {
+                progress.remote_message(messages);
+            }
+            Ok(())
+        })?,
+        false => {
+            let mut pack = Vec::new();
+            response.get_mut().read_to_end(&mut pack)?;
+            pack
+        }
+    };
+    Ok((pack, update))
+}
+
+/// Returns the pack of `wants` from a server speaking version 2 of the
+/// protocol, where each round is a `fetch` command repeating the wants and
+/// the common commits found so far. The server sends the pack once it has
+/// found enough of them, or once told `done`, along with the shas of the
+/// refs wanted by name and the update of the shallow commits.
+fn pack_v2(
+    git_dir: &Path,
+    config: &Config,
+    transport: &mut dyn Transport,
+    advertised: &transport::Refs,
+    wants: &Wants,
+    options: &FetchOptions,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<(Vec<u8>, WantedRefs, ShallowUpdate)> {
+    let capabilities = options.capabilities(git_dir, advertised)?;
+    let mut request = pktline::Writer::new(Vec::new());
+    request.write_line("command=fetch")?;
+    request.write_line(transport::AGENT)?;
+    if advertised.has("object-format=sha1") {
+        request.write_line("object-format=sha1")?;
+    }
+    request.write_delim()?;
+    request.write_line("ofs-delta")?;
+    if options.deepen.is_some() {
+        request.write_line("deepen-relative")?;
+    }
+    for want in &wants.shas {
+        request.write_line(&format!("want {want}"))?;
+    }
+    for name in &wants.refs {
+        request.write_line(&format!("want-ref {name}"))?;
+    }
+    // The server mustn't count on the parents of shallow commits
+    for sha in shallow::read(git_dir)? {
+        request.write_line(&format!("shallow {sha}"))?;
+    }
+    for line in options.deepen_lines() {
+        request.write_line(&line)?;
+    }
+    if let Some(filter) = options.filter.filter(|_| capabilities.contains(&"filter")) {
+        request.write_line(&format!("filter {filter}"))?;
+    }
+    let request = request.into_inner();
+    let round = |haves: Vec<&String>, done: bool| {
+        let mut round = pktline::Writer::new(request.clone());
+        for have in haves {
+            round.write_line(&format!("have {have}"))?;
+        }
+        if done {
+            round.write_line("done")?;
+        }
+        round.write_flush()?;
+        eyre::Ok(round.into_inner())
+    };
+
+    let mut pack = None;
+    let mut wanted = Vec::new();
+    let mut update = ShallowUpdate::default();
+    let (algorithm, known) = options.negotiation(config, advertised)?;
+    let common = negotiate(git_dir, algorithm, known, |common, new| {
+        // Once the server is ready, its answer came with the pack
+        if pack.is_some() {
+            return Ok(new.to_vec());
+        }
+        let request = round(common.iter().chain(new).collect(), false)?;
+        let response = transport.request("git-upload-pack", &request)?;
+        let (acked, ready) = read_fetch_response(response, &mut update, &mut wanted, progress)?;
+        pack = ready;
+        Ok(acked)
+    })?;
+    if let Some(pack) = pack {
+        return Ok((pack, wanted, update));
+    }
+
+    let request = round(common.iter().collect(), true)?;
+    let response = transport.request("git-upload-pack", &request)?;
+    let (_, pack) = read_fetch_response(response, &mut update, &mut wanted, progress)?;
+    let pack = pack.ok_or(eyre!(tr!("git fetch-pack: expected packfile in response")))?;
+    Ok((pack, wanted, update))
+}
+
+/// Reads the response to a version 2 `fetch` command, made of sections
+/// starting with their name: returns the commits the `acknowledgments`
+/// acknowledge, and the pack of the `packfile` if there's one, reading the
+/// `shallow-info` into `update` and the `wanted-refs` into `wanted`.
+fn read_fetch_response(
+    response: impl Read,
+    update: &mut ShallowUpdate,
+    wanted: &mut WantedRefs,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<(Vec<String>, Option<Vec<u8>>)> {
+    let mut reader = pktline::Reader::new(progress::Reader::new(response, progress));
+    let mut acked = Vec::new();
+    while let Some(section) = reader.read_line()? {
+        if section == "packfile" {
+            let pack = reader.read_sideband(|messages| {
+                if let Some(progress) = progress {
+                    progress.remote_message(messages);
+                }
+                Ok(())
+            })?;
+            return Ok((acked, Some(pack)));
+        }
+        // A delimiter separates the sections, the last one ends the response
+        loop {
+            let line = match reader.read()? {
+                Packet::Data(data) => pktline::lossy(&data),
+                Packet::Delim => break,
+                _ => return Ok((acked, None)),
+            };
+            if section == "shallow-info" {
+                if !update.parse(&line)? {
+                    return Err(eyre!(tr!("expected shallow/unshallow, got %s", &line)));
+                }
+                continue;
+            }
+            if section == "wanted-refs" {
+                let (sha, name) = line
+                    .split_once(' ')
+                    .filter(|(sha, _)| refs::is_sha(sha))
+                    .ok_or(eyre!(tr!("expected wanted-ref, got '%s'", &line)))?;
+                wanted.push((name.to_string(), sha.to_string()));
+                continue;
+            }
+            if section != "acknowledgments" {
+                continue;
+            }
+            match line.split(' ').collect::<Vec<_>>()[..] {
+                ["ACK", sha] => acked.push(sha.to_string()),
+                ["NAK"] | ["ready"] => {}
+                _ => return Err(eyre!(tr!("expected ACK/NAK, got '%s'", &line))),
+            }
+        }
+    }
+    Ok((acked, None))
+}
+
+/// Where a shallow fetch cuts the history of the remote.
+struct Boundary {
+    /// Commits older than this timestamp are left out.
+    since: Option<i64>,
+    /// The commits of the remote left out because they're reachable from an
+    /// excluded ref.
+    excluded: HashSet<String>,
+    /// With a depth, the commits within it.
+    within: Option<HashSet<String>>,
+    /// The commits whose parents are left out: the ones at the end of the
+    /// depth, and the shallow commits of the remote.
+    cut: HashSet<String>,
+    /// Whether the fetch cuts or deepens the history.
+    deepening: bool,
+}
+
+impl Boundary {
+    /// Resolves the excluded refs of `options` in the repository at
+    /// `remote_dir`, and walks its history down to the depth of `options`
+    /// from the fetched `tips`, or from the shallow commits of the local
+    /// repository when deepening them.
+    fn new(
+        git_dir: &Path,
+        remote_dir: &Path,
+        tips: &[String],
+        options: &FetchOptions,
+    ) -> eyre::Result<Self> {
+        let mut pending = Vec::new();
+        for name in &options.shallow_exclude {
+            let candidates = [
+                name.to_string(),
+                format!("refs/{name}"),
+                format!("refs/tags/{name}"),
+                format!("refs/heads/{name}"),
+            ];
+            let mut found = None;
+            for candidate in candidates {
+                found = refs::read_ref(remote_dir, &candidate)?;
+                if found.is_some() {
+                    break;
+                }
+            }
+            pending.push(found.ok_or(eyre!(tr!("deepen-not is not a ref: %s", name)))?);
+        }
+
+        let mut excluded = HashSet::new();
+        while let Some(sha) = pending.pop() {
+            if !excluded.insert(sha.clone()) {
+                continue;
+            }
+            if let GitFileContent::Commit(commit) = GitFile::read(remote_dir, &sha)?.file_content {
+                pending.extend(commit.parents);
+            }
+        }
+
+        let mut cut = shallow::read(remote_dir)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let (starts, depth) = match (options.depth, options.deepen) {
+            (Some(depth), _) => (tips.to_vec(), depth),
+            (None, Some(deepen)) => {
+                let shallow = shallow::read(git_dir)?;
+                (shallow.into_iter().collect(), deepen.saturating_add(1))
+            }
+            (None, None) => (Vec::new(), 0),
+        };
+        // The walk is breadth first, finding each commit at its smallest
+        // depth
+        let mut pending = VecDeque::new();
+        let mut seen = HashSet::new();
+        for sha in starts {
+            let sha = revision::peel(remote_dir, &sha)?;
+            if is_commit(remote_dir, &sha) && seen.insert(sha.clone()) {
+                pending.push_back((sha, 1));
+            }
+        }
+        while let Some((sha, current)) = pending.pop_front() {
+            if current >= depth || cut.contains(&sha) {
+                cut.insert(sha);
+                continue;
+            }
+            for parent in Commit::parse(&git::read_object(remote_dir, &sha)?.1)?.parents {
+                if seen.insert(parent.clone()) {
+                    pending.push_back((parent, current + 1));
+                }
+            }
+        }
+
+        Ok(Self {
+            since: options.shallow_since,
+            excluded,
+            within: options.depth.map(|_| seen),
+            cut,
+            deepening: options.is_deepening(),
+        })
+    }
+
+    /// Returns true if the fetch leaves out the commit `sha` of the remote.
+    fn excludes(&self, sha: &str, commit: &Commit) -> bool {
+        self.excluded.contains(sha)
+            || self
+                .since
+                .is_some_and(|since| commit.committer.time < since)
+            || self
+                .within
+                .as_ref()
+                .is_some_and(|within| !within.contains(sha))
+    }
+
+    /// Returns the shallow commits of the local repository whose parents
+    /// the fetch keeps, in the repository at `remote_dir`.
+    fn unshallow(&self, git_dir: &Path, remote_dir: &Path) -> eyre::Result<Vec<String>> {
+        let mut unshallow = Vec::new();
+        if !self.deepening {
+            return Ok(unshallow);
+        }
+        for sha in shallow::read(git_dir)? {
+            let Some(content) = git::read_object_as(remote_dir, &sha, "commit")? else {
+                continue;
+            };
+            let commit = Commit::parse(&content)?;
+            if self.cut.contains(&sha) || self.excludes(&sha, &commit) {
+                continue;
+            }
+            let mut kept = true;
+            for parent in &commit.parents {
+                let parent_commit = Commit::parse(&git::read_object(remote_dir, parent)?.1)?;
+                kept &= !self.excludes(parent, &parent_commit);
+            }
+            if kept {
+                unshallow.push(sha);
+            }
+        }
+        Ok(unshallow)
+    }
+}
+
+/// Copies the objects reachable from `tip` that are missing locally from
+/// the repository at `from`. The history of the `common` commits, which
+/// the local repository has, isn't walked, nor the commits `boundary`
+/// leaves out. Returns the copied commits whose parents were left out.
+fn copy_objects(
+    git_dir: &Path,
+    from: &Path,
+    tip: &str,
+    common: &HashSet<String>,
+    boundary: &Boundary,
+) -> eyre::Result<Vec<String>> {
+    let mut pending = vec![tip.to_string()];
+    let mut seen = HashSet::new();
+    let mut missing = Vec::new();
+    let mut shallow = Vec::new();
+
+    while let Some(sha) = pending.pop() {
+        let path = git::object_path(git_dir, &sha.parse()?);
+        if common.contains(&sha) || path.exists() || !seen.insert(sha.clone()) {
+            continue;
+        }
+
+        let compressed = fs::read(git::object_path(from, &sha.parse()?))
+            .map_err(|_| eyre!("object {sha} not found in the remote repository"))?;
+        match GitFile::read(from, &sha)?.file_content {
+            GitFileContent::Commit(commit) => {
+                if sha == tip && boundary.excludes(&sha, &commit) {
+                    return Err(eyre!(tr!("no commits selected for shallow requests")));
+                }
+                pending.push(commit.tree);
+                if boundary.cut.contains(&sha) {
+                    shallow.push(sha.clone());
+                    missing.push((path, compressed));
+                    continue;
+                }
+                for parent in commit.parents {
+                    let have = common.contains(&parent)
+                        || git::object_path(git_dir, &parent.parse()?).exists();
+                    if boundary.deepening && !have {
+                        if let GitFileContent::Commit(parent_commit) =
+                            GitFile::read(from, &parent)?.file_content
+                        {
+                            if boundary.excludes(&parent, &parent_commit) {
+                                if shallow.last() != Some(&sha) {
+                                    shallow.push(sha.clone());
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    pending.push(parent);
+                }
+            }
+            GitFileContent::Tree(entries) => pending.extend(
+                entries
+                    .iter()
+                    .filter(|e| e.mode != MODE_GITLINK)
+                    .map(|e| hex::encode(&e.sha)),
+            ),
+            GitFileContent::Tag(tag) => pending.push(tag.object),
+            GitFileContent::Blob(_) => {}
+        }
+        missing.push((path, compressed));
+    }
+
+    // Objects are written after the objects they reference, so an
+    // interrupted fetch never leaves an object with missing dependencies
+    for (path, compressed) in missing.into_iter().rev() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let _temporary = Temporary::new(&path);
+        fs::write(&path, compressed)?;
+    }
+    Ok(shallow)
+}
@@ -0,0 +1,135 @@
+use crate::pktline;
+use eyre::eyre;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+/// What a filter did with the file it was asked to convert.
+#[derive(Debug)]
+pub enum Outcome {
+    /// The converted content.
+    Done(Vec<u8>),
+    /// The content will be ready later, the file being listed by
+    /// [`Filter::available`] once it is.
+    Delayed,
+}
+
+/// A filter converting files between the working tree and the repository,
+/// like the ones set by `filter.<driver>.process`.
+pub trait Filter {
+    /// Converts the content of the file at `path` from the working tree to
+    /// the repository.
+    fn clean(&mut self, path: &str, content: Vec<u8>) -> eyre::Result<Vec<u8>>;
+
+    /// Converts the content of the file at `path` from the repository to
+    /// the working tree. With `can_delay`, the filter may answer later.
+    fn smudge(&mut self, path: &str, content: Vec<u8>, can_delay: bool) -> eyre::Result<Outcome>;
+
+    /// Returns the delayed files whose content is ready. Git asks for them
+    /// again, without content nor the option to delay.
+    fn available(&mut self) -> eyre::Result<Vec<String>>;
+}
+
+/// Serves `filter` to git over the long-running filter process protocol,
+/// until git closes `input`. Files the filter fails to convert are reported
+/// to git rather than ending the process.
+pub fn serve(filter: &mut impl Filter, input: impl Read, output: impl Write) -> eyre::Result<()> {
+    let mut reader = pktline::Reader::new(BufReader::new(input));
+    let mut writer = pktline::Writer::new(BufWriter::new(output));
+
+    // Both sides say who they are and agree on a version, then on what the
+    // filter can do
+    let hello = read_list(&mut reader)?.ok_or(eyre!("missing filter handshake"))?;
+    if hello.first().map(String::as_str) != Some("git-filter-client")
+        || !hello.iter().any(|line| line == "version=2")
+    {
+        return Err(eyre!(tr!("unsupported filter protocol")));
+    }
+    write_list(&mut writer, ["git-filter-server", "version=2"])?;
+    writer.get_mut().flush()?;
+    let capabilities = read_list(&mut reader)?.ok_or(eyre!("missing filter capabilities"))?;
+    let supported = ["capability=clean", "capability=smudge", "capability=delay"]
+        .into_iter()
+        .filter(|capability| capabilities.iter().any(|c| c == capability));
+    write_list(&mut writer, supported)?;
+    writer.get_mut().flush()?;
+
+    while let Some(headers) = read_list(&mut reader)? {
+        let header = |key: &str| {
+            headers
+                .iter()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        };
+        let path = header("pathname").unwrap_or_default();
+        let outcome = match header("command") {
+            Some("clean") => {
+                let content = read_content(&mut reader)?;
+                filter.clean(path, content).map(Outcome::Done)
+            }
+            Some("smudge") => {
+                let content = read_content(&mut reader)?;
+                filter.smudge(path, content, header("can-delay") == Some("1"))
+            }
+            Some("list_available_blobs") => {
+                let paths = filter.available()?;
+                write_list(&mut writer, paths.iter().map(|p| format!("pathname={p}")))?;
+                write_list(&mut writer, ["status=success"])?;
+                writer.get_mut().flush()?;
+                continue;
+            }
+            command => return Err(eyre!("unknown filter command: {}", command.unwrap_or(""))),
+        };
+
+        match outcome {
+            Ok(Outcome::Done(content)) => {
+                write_list(&mut writer, ["status=success"])?;
+                for chunk in content.chunks(pktline::MAX_DATA) {
+                    writer.write(chunk)?;
+                }
+                writer.write_flush()?;
+                // The status stays the same after the content
+                writer.write_flush()?;
+            }
+            Ok(Outcome::Delayed) => write_list(&mut writer, ["status=delayed"])?,
+            Err(e) => {
+                eprintln!("{path}: {e}");
+                write_list(&mut writer, ["status=error"])?;
+            }
+        }
+        writer.get_mut().flush()?;
+    }
+    Ok(())
+}
+
+/// Reads text packets up to a flush packet, without their line feeds.
+/// Returns `None` if the input ends before the first packet.
+fn read_list(
+    reader: &mut pktline::Reader<BufReader<impl Read>>,
+) -> eyre::Result<Option<Vec<String>>> {
+    if reader.get_mut().fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut lines = Vec::new();
+    while let Some(line) = reader.read_line()? {
+        lines.push(line);
+    }
+    Ok(Some(lines))
+}
+
+/// Reads the content of a file, sent in packets up to a flush packet.
+fn read_content(reader: &mut pktline::Reader<impl Read>) -> eyre::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    while let Some(packet) = reader.read_data()? {
+        content.extend(packet);
+    }
+    Ok(content)
+}
+
+/// Writes text packets followed by a flush packet.
+fn write_list<S: AsRef<str>>(
+    writer: &mut pktline::Writer<impl Write>,
+    lines: impl IntoIterator<Item = S>,
+) -> eyre::Result<()> {
+    for line in lines {
+        writer.write_line(line.as_ref())?;
+    }
+    writer.write_flush()
+}
@@ -0,0 +1,112 @@
+use eyre::eyre;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+/// Returns the repositories under `dir`, `dir` included, sorted by path:
+/// the directories with a `.git` directory, or a `.git` file pointing to
+/// one. Git directories aren't searched, nor symbolic links followed.
+pub fn discover(dir: &Path) -> eyre::Result<Vec<PathBuf>> {
+    let mut repositories = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        if dir.join(".git").exists() {
+            repositories.push(dir.clone());
+        }
+        // Directories that can't be read can't hold repositories either
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() && entry.file_name() != ".git" {
+                pending.push(entry.path());
+            }
+        }
+    }
+    repositories.sort();
+    Ok(repositories)
+}
+
+/// Runs the command `args` in each of the repositories under `dir`, `jobs`
+/// at a time. The output of each repository is printed once its command is
+/// done, in order, after an `Entering '<path>'` line, and a summary of the
+/// commands that failed follows. Returns the number of failures.
+pub fn for_each(dir: &Path, args: &[String], jobs: usize) -> eyre::Result<usize> {
+    let repositories = discover(dir)?;
+    if repositories.is_empty() {
+        return Err(eyre!(tr!("no repository found in '%s'", dir.display())));
+    }
+    // The commands are the ones of this program
+    let program = std::env::current_exe()?;
+
+    let next = &AtomicUsize::new(0);
+    let repositories = &repositories;
+    let (sender, receiver) = mpsc::channel();
+    let failed = std::thread::scope(|scope| -> eyre::Result<Vec<(String, String)>> {
+        for _ in 0..jobs.clamp(1, repositories.len()) {
+            let sender = sender.clone();
+            let program = &program;
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(repository) = repositories.get(i) else {
+                    break;
+                };
+                let output = Command::new(program)
+                    .args(args)
+                    .current_dir(repository)
+                    .output();
+                if sender.send((i, output)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        // Outputs arriving early wait for the ones of the repositories
+        // before them
+        let mut done = HashMap::new();
+        let mut failed = Vec::new();
+        for (i, repository) in repositories.iter().enumerate() {
+            let output = loop {
+                if let Some(output) = done.remove(&i) {
+                    break output;
+                }
+                let (j, output) = receiver.recv()?;
+                done.insert(j, output);
+            };
+            let output = output?;
+            let path = match repository.strip_prefix(dir) {
+                Ok(path) if path.as_os_str().is_empty() => ".".to_string(),
+                Ok(path) => path.display().to_string(),
+                Err(_) => repository.display().to_string(),
+            };
+            println!("{}", tr!("Entering '%s'", &path));
+            std::io::stdout().write_all(&output.stdout)?;
+            std::io::stderr().write_all(&output.stderr)?;
+            if !output.status.success() {
+                failed.push((path, output.status.to_string()));
+            }
+        }
+        Ok(failed)
+    })?;
+
+    println!();
+    println!(
+        "{}",
+        tr!(
+            "%d repositories: %d succeeded, %d failed",
+            repositories.len(),
+            repositories.len() - failed.len(),
+            failed.len()
+        )
+    );
+    for (path, status) in &failed {
+        println!("  {path} ({status})");
+    }
+    Ok(failed.len())
+}
@@ -0,0 +1,113 @@
+use crate::commit::Signature;
+use crate::commit_graph::CommitGraph;
+use crate::git::GitFile;
+use crate::midx::MultiPackIndex;
+use crate::odb;
+use crate::pack;
+use eyre::eyre;
+use std::path::Path;
+
+/// Checks the integrity of the repository at `git_dir`: that loose objects
+/// parse and match their sha, that packs match their checksum, and that the
+/// commit-graph and multi-pack-index agree with the objects they describe.
+/// Returns a description of every problem found.
+pub fn fsck(git_dir: &Path) -> eyre::Result<Vec<String>> {
+    let mut errors = Vec::new();
+
+    let packs = pack::packs(git_dir)?;
+    for pack in &packs {
+        if !pack.checksum_matches() {
+            errors.push(tr!("pack %s is corrupt", pack.path.display()));
+        }
+    }
+
+    // Packed objects are covered by the checksum of their pack
+    for sha in odb::objects(git_dir, &[], true)? {
+        match GitFile::read(git_dir, &sha) {
+            Ok(file) if hex::encode(&file.sha) != sha => errors.push(tr!(
+                "hash mismatch for %s (expected %s)",
+                crate::git::object_path(git_dir, &sha.parse()?).display(),
+                sha
+            )),
+            Ok(_) => {}
+            Err(e) => errors.push(tr!("unable to parse object %s: %s", sha, e)),
+        }
+    }
+
+    if let Some(graph) = CommitGraph::load(git_dir)? {
+        errors.extend(graph.verify(git_dir)?);
+    }
+    if let Some(midx) = MultiPackIndex::load(git_dir)? {
+        errors.extend(midx.verify()?);
+    }
+    Ok(errors)
+}
+
+/// Checks that `content` is a well-formed object of the type `kind`, as git
+/// does before writing an object it's handed.
+pub fn check_object(kind: &str, content: &[u8]) -> eyre::Result<()> {
+    let is_sha = |s: &str| s.len() == 40 && s.bytes().all(|b| b.is_ascii_hexdigit());
+    match kind {
+        "blob" => Ok(()),
+        "tree" => {
+            let mut rest = content;
+            while !rest.is_empty() {
+                let (Some(space), Some(nul)) = (
+                    rest.iter().position(|b| *b == b' '),
+                    rest.iter().position(|b| *b == 0),
+                ) else {
+                    return Err(eyre!(tr!("too-short tree object")));
+                };
+                if space > nul || rest.len() < nul + 21 {
+                    return Err(eyre!(tr!("too-short tree object")));
+                }
+                if space == 0 || !rest[..space].iter().all(u8::is_ascii_digit) {
+                    return Err(eyre!(tr!("malformed mode in tree entry")));
+                }
+                if nul == space + 1 {
+                    return Err(eyre!(tr!("empty filename in tree entry")));
+                }
+                rest = &rest[nul + 21..];
+            }
+            Ok(())
+        }
+        "commit" => {
+            let content = String::from_utf8_lossy(content);
+            let mut lines = content.lines();
+            let valid = lines
+                .next()
+                .and_then(|line| line.strip_prefix("tree "))
+                .is_some_and(is_sha)
+                && lines
+                    .find(|line| !line.strip_prefix("parent ").is_some_and(&is_sha))
+                    .and_then(|line| line.strip_prefix("author "))
+                    .is_some_and(|author| Signature::parse(author).is_ok())
+                && lines
+                    .next()
+                    .and_then(|line| line.strip_prefix("committer "))
+                    .is_some_and(|committer| Signature::parse(committer).is_ok());
+            match valid {
+                true => Ok(()),
+                false => Err(eyre!(tr!("corrupt commit"))),
+            }
+        }
+        "tag" => {
+            let content = String::from_utf8_lossy(content);
+            let mut lines = content.lines();
+            let valid = lines
+                .next()
+                .and_then(|line| line.strip_prefix("object "))
+                .is_some_and(is_sha)
+                && lines
+                    .next()
+                    .and_then(|line| line.strip_prefix("type "))
+                    .is_some_and(|kind| ["blob", "tree", "commit", "tag"].contains(&kind))
+                && lines.next().is_some_and(|line| line.starts_with("tag "));
+            match valid {
+                true => Ok(()),
+                false => Err(eyre!(tr!("corrupt tag"))),
+            }
+        }
+        _ => Err(eyre!(tr!("invalid object type \"%s\"", kind))),
+    }
+}
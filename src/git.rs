@@ -1,23 +1,271 @@
+use crate::attributes;
+use crate::commit::Commit;
+use crate::config::Config;
+use crate::fsck;
+use crate::odb::{self, Odb};
+use crate::pack;
+use crate::partial;
+use crate::refs;
+use crate::repository;
+use crate::tag::Tag;
+use crate::tree::{self, TreeIter};
 use eyre::eyre;
 use sha1::Digest;
 use std::fmt::Formatter;
 use std::fs;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The sha of the tree without entries. Git knows this object even when it
+/// isn't stored in the object database.
+pub const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+/// The sha of the blob without content, known like [`EMPTY_TREE`].
+pub const EMPTY_BLOB: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+
+/// The sha used in place of a missing object, e.g. the old side of an
+/// added file.
+pub const NULL_SHA: &str = "0000000000000000000000000000000000000000";
+
+/// Returns the path of the loose object `sha` in the repository at `git_dir`.
+pub fn object_path(git_dir: &Path, oid: &Oid) -> PathBuf {
+    git_dir.join("objects").join(&oid[..2]).join(&oid[2..])
+}
+
+/// Returns the sha of an object of the given kind and content, and the
+/// object itself, header included.
+pub fn hash_object(kind: &str, content: &[u8]) -> (Oid, Vec<u8>) {
+    let header = format!("{kind} {}\0", content.len());
+    let object = [header.as_bytes(), content].concat();
+
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(&object);
+    (Oid(hex::encode(hasher.finalize())), object)
+}
+
+/// How `hash_file` treats content.
+#[derive(Debug, Default)]
+pub struct HashOptions {
+    /// Writes the object to the repository.
+    pub write: bool,
+    /// Hashes blobs as they are, without the conversions of their path.
+    pub no_filters: bool,
+    /// Skips checking that the content is a valid object of its type.
+    pub literally: bool,
+}
+
+/// Returns the sha of an object of type `kind` with `content`, a blob being
+/// converted like the working tree file at `path`, given from the directory
+/// `prefix` of the working tree, is when staged.
+pub fn hash_file(
+    git_dir: &Path,
+    config: &Config,
+    kind: &str,
+    content: Vec<u8>,
+    prefix: &Path,
+    path: Option<&Path>,
+    options: &HashOptions,
+) -> eyre::Result<Oid> {
+    let content = match path {
+        Some(path) if kind == "blob" && !options.no_filters => {
+            let path = repository::resolve_path(prefix, &path.to_string_lossy())?;
+            attributes::to_git(git_dir, config, Path::new(&path), content)?
+        }
+        _ => content,
+    };
+    if !options.literally {
+        fsck::check_object(kind, &content)?;
+    }
+    match options.write {
+        true => Odb::new(git_dir).write(kind, &content),
+        false => Ok(hash_object(kind, &content).0),
+    }
+}
+
+/// Reads the content of the blob with the given sha.
+pub fn read_blob(git_dir: &Path, sha: &str) -> eyre::Result<Vec<u8>> {
+    match GitFile::read(git_dir, sha)?.file_content {
+        GitFileContent::Blob(content) => Ok(content),
+        _ => Err(eyre!("object {sha} is not a blob")),
+    }
+}
+
+/// Reads the object `sha` of the repository at `git_dir`, loose or packed,
+/// and returns its kind and its content, unparsed.
+pub fn read_object(git_dir: &Path, sha: &str) -> eyre::Result<(String, Vec<u8>)> {
+    let object = read_raw(git_dir, sha)?;
+    let (header, content) = split_header(&object)?;
+    let kind = header.split(' ').next().unwrap_or_default().to_string();
+    Ok((kind, content.to_vec()))
+}
+
+/// Returns the decompressed object `sha` of the repository at `git_dir`,
+/// header included. Objects missing from partial clones are fetched from
+/// their promisor remote first.
+fn read_raw(git_dir: &Path, sha: &str) -> eyre::Result<Vec<u8>> {
+    match read_stored(git_dir, sha) {
+        Err(e) if is_not_found(&e) && partial::fetch_missing(git_dir, &[sha.to_string()])? => {
+            read_stored(git_dir, sha)
+        }
+        result => result,
+    }
+}
+
+/// Returns the decompressed object `sha` of the repository at `git_dir`,
+/// header included. Objects missing from the loose objects are looked for
+/// in the packs.
+fn read_stored(git_dir: &Path, sha: &str) -> eyre::Result<Vec<u8>> {
+    match odb::read_loose(&object_path(git_dir, &sha.parse()?)) {
+        Ok(object) => Ok(object),
+        Err(e) if is_not_found(&e) => match pack::read_object(git_dir, sha)? {
+            Some((kind, content)) => {
+                let header = format!("{kind} {}\0", content.len());
+                Ok([header.as_bytes(), &content].concat())
+            }
+            // The empty tree and blob exist even if they were never written
+            None if sha == EMPTY_TREE => Ok(b"tree 0\0".to_vec()),
+            None if sha == EMPTY_BLOB => Ok(b"blob 0\0".to_vec()),
+            None => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the content of the object `sha` of the repository at `git_dir`
+/// as an object of type `kind`, dereferencing tags to their object and
+/// commits to their tree until one of that type is found. Returns `None`
+/// if there's none.
+pub fn read_object_as(git_dir: &Path, sha: &str, kind: &str) -> eyre::Result<Option<Vec<u8>>> {
+    let mut sha = sha.to_string();
+    loop {
+        let (found, content) = read_object(git_dir, &sha)?;
+        if found == kind {
+            return Ok(Some(content));
+        }
+        sha = match found.as_str() {
+            "tag" => Tag::parse(&content)?.object,
+            "commit" if kind == "tree" => Commit::parse(&content)?.tree,
+            _ => return Ok(None),
+        };
+    }
+}
+
+/// Whether `error` is about a missing file.
+fn is_not_found(error: &eyre::Report) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+/// Splits an object into its `kind size` header and its content.
+fn split_header(object: &[u8]) -> eyre::Result<(&str, &[u8])> {
+    let zero_byte_pos = object
+        .iter()
+        .position(|x| x == &b'\0')
+        .ok_or(eyre!("missing \0 byte"))?;
+    let header = std::str::from_utf8(&object[..zero_byte_pos])?;
+    Ok((header, &object[zero_byte_pos + 1..]))
+}
+
+/// The sha of an object given in full, checked to be 40 hexadecimal digits
+/// when parsed, unlike revisions which may be any name of an object.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Oid(String);
+
+impl FromStr for Oid {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        match refs::is_sha(s) {
+            true => Ok(Self(s.to_lowercase())),
+            false => Err(eyre!(tr!("not a valid object name: '%s'", s))),
+        }
+    }
+}
+
+impl std::ops::Deref for Oid {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Oid> for String {
+    fn from(oid: Oid) -> Self {
+        oid.0
+    }
+}
+
+impl std::fmt::Display for Oid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Checks that an object name given on the command line is well formed, to
+/// be used as the `value_parser` of arguments naming objects: each side of
+/// a range or exclusion is a sha, checked as an [`Oid`] when it's long
+/// enough to be one, or a valid ref name, followed by `~<n>` and `^<n>`
+/// suffixes. The path of `<rev>:<path>` may be anything.
+pub fn parse_object_name(name: &str) -> eyre::Result<String> {
+    let invalid = || eyre!(tr!("not a valid object name: '%s'", name));
+    let revision = name.split_once(':').map_or(name, |(revision, _)| revision);
+    let revision = revision.strip_prefix('^').unwrap_or(revision);
+    for side in revision.split("...").flat_map(|side| side.split("..")) {
+        let (base, suffix) = side.split_at(side.find(['~', '^']).unwrap_or(side.len()));
+        let base = match base {
+            "" | "@" => true,
+            base if base.len() >= 40 && base.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                base.parse::<Oid>().is_ok()
+            }
+            base => refs::is_valid_name(base),
+        };
+        let suffix = suffix
+            .chars()
+            .all(|c| c.is_ascii_digit() || "~^".contains(c));
+        if !base || !suffix {
+            return Err(invalid());
+        }
+    }
+    Ok(name.to_string())
+}
 
 /// A file in the git file system.
 #[derive(Debug)]
 pub struct GitFile {
-    pub(crate) file_content: GitFileContent,
-    pub(crate) sha: Vec<u8>,
+    pub file_content: GitFileContent,
+    pub sha: Vec<u8>,
 }
 
 /// The content of a tree for a git file.
 #[derive(Debug, Clone)]
 pub struct TreeContent {
-    mode: u32,
-    name: String,
-    sha: Vec<u8>,
+    pub mode: u32,
+    pub name: String,
+    pub sha: Vec<u8>,
+}
+
+impl TreeContent {
+    /// Returns the content of a tree object (without the object header) made
+    /// of the given entries.
+    pub fn serialize(entries: &[TreeContent]) -> Vec<u8> {
+        // Entries are sorted by name, directories sorting as if their name
+        // ended with a '/'
+        let mut entries = entries.to_vec();
+        entries.sort_by_cached_key(|t| match t.mode {
+            40000 => format!("{}/", t.name),
+            _ => t.name.clone(),
+        });
+
+        // Tree files are split into MODE NAME\0SHA-1
+        entries
+            .into_iter()
+            .flat_map(|t| {
+                let s = format!("{} {}\0", t.mode, t.name);
+                [s.as_bytes(), &t.sha].concat()
+            })
+            .collect()
+    }
 }
 
 impl std::fmt::Display for GitFile {
@@ -33,23 +281,41 @@ impl std::fmt::Display for GitFile {
                 });
                 Ok(())
             }
-            _ => Ok(()),
+            GitFileContent::Commit(commit) => write!(f, "{commit}"),
+            GitFileContent::Tag(tag) => write!(f, "{tag}"),
         }
     }
 }
 
 impl GitFile {
-    /// Returns a [`GitFile`] with the content from the file located at
-    /// `".git/objects/sha[..2]/sha[2..]"`.
-    pub fn new(sha: String) -> eyre::Result<Self> {
-        // Create the object input
-        let path = format!(".git/objects/{}/{}", &sha[..2], &sha[2..]);
-        let compressed = fs::read(path)?;
-
-        // Decode the compressed file to a string
-        let mut decoder = flate2::read::ZlibDecoder::new(&compressed[..]);
-        let mut s = Vec::new();
-        decoder.read_to_end(&mut s)?;
+    /// Returns the object the way `cat-file -p` prints it: blobs as they
+    /// are, trees as a `MODE TYPE SHA\tNAME` line per entry, and commits and
+    /// tags as their text, commits in their own encoding.
+    pub fn pretty(&self) -> Vec<u8> {
+        match &self.file_content {
+            GitFileContent::Blob(content) => content.clone(),
+            GitFileContent::Tree(entries) => entries
+                .iter()
+                .map(|entry| {
+                    format!(
+                        "{:06} {} {}\t{}\n",
+                        entry.mode,
+                        tree::kind_of(entry.mode),
+                        hex::encode(&entry.sha),
+                        entry.name
+                    )
+                })
+                .collect::<String>()
+                .into_bytes(),
+            GitFileContent::Commit(commit) => commit.serialize(),
+            GitFileContent::Tag(tag) => tag.serialize(),
+        }
+    }
+
+    /// Returns a [`GitFile`] with the content of the object `sha` from the
+    /// repository at `git_dir`.
+    pub fn read(git_dir: &Path, sha: &str) -> eyre::Result<Self> {
+        let s = read_raw(git_dir, sha)?;
 
         // Hash the git file
         let mut hasher = sha1::Sha1::new();
@@ -57,32 +323,26 @@ impl GitFile {
         let sha = hasher.finalize();
 
         // Split the header and the content
-        let zero_byte_pos = &s
-            .iter()
-            .position(|x| x == &b'\0')
-            .ok_or(eyre!("missing \0 byte"))?;
-        let header = std::str::from_utf8(&s[..zero_byte_pos + 1])?.to_string();
-        let mut content = &s[zero_byte_pos + 1..];
+        let (header, content) = split_header(&s)?;
 
         // Read the content of the git file.
         // For a tree, we split the input into a [`TreeContent`] structure.
         let content = if header.contains("tree") {
-            let mut tree_content = Vec::new();
-            while let (Some(zero_byte), Some(space)) = (
-                content.iter().position(|x| x == &b'\0'),
-                content.iter().position(|x| x == &b' '),
-            ) {
-                // Tree files are split into MODE NAME\0SHA-1
-                let mode = std::str::from_utf8(&content[..space])?.parse::<u32>()?;
-                let name = std::str::from_utf8(&content[space + 1..zero_byte])?.to_string();
-                let sha = content[zero_byte + 1..zero_byte + 1 + 20].to_vec();
-                let item = TreeContent { mode, name, sha };
-                tree_content.push(item);
-                content = &content[zero_byte + 1 + 20..];
-            }
+            let tree_content = TreeIter::new(content)
+                .map(|entry| {
+                    let entry = entry?;
+                    Ok(TreeContent {
+                        mode: entry.mode,
+                        name: entry.name.to_string(),
+                        sha: entry.oid.to_vec(),
+                    })
+                })
+                .collect::<eyre::Result<_>>()?;
             GitFileContent::Tree(tree_content)
         } else if header.contains("commit") {
-            GitFileContent::Commit
+            GitFileContent::Commit(Box::new(Commit::parse(content)?))
+        } else if header.starts_with("tag ") {
+            GitFileContent::Tag(Box::new(Tag::parse(content)?))
         } else {
             GitFileContent::Blob(content.to_vec())
         };
@@ -93,28 +353,21 @@ impl GitFile {
         })
     }
 
-    /// Returns a [`GitFile`] from the content of the file at the provided path.
-    pub fn from_file(path: PathBuf) -> eyre::Result<Self> {
+    /// Returns a [`GitFile`] with the content of the file at the provided
+    /// path, storing the blob in `odb`.
+    pub fn from_file(odb: &Odb, path: PathBuf) -> eyre::Result<Self> {
         let content = fs::read(path)?;
-        let header = format!("blob {}\0", content.len());
-
-        let git_file_content = [header.as_bytes(), content.as_slice()].concat();
-
-        // Hash the git file
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(&git_file_content);
-        let sha = hasher.finalize();
-
-        let content = GitFileContent::Blob(git_file_content);
+        let sha = odb.write("blob", &content)?;
 
         Ok(Self {
-            file_content: content,
-            sha: sha.to_vec(),
+            file_content: GitFileContent::Blob(content),
+            sha: hex::decode(&*sha)?,
         })
     }
 
-    /// Returns a [`GitFile`] with a content corresponding to the created tree
-    pub fn from_directory(path: PathBuf) -> eyre::Result<Self> {
+    /// Returns a [`GitFile`] with a content corresponding to the created
+    /// tree, storing it in `odb` along with its sub-trees and blobs.
+    pub fn from_directory(odb: &Odb, path: PathBuf) -> eyre::Result<Self> {
         if !path.is_dir() {
             return Err(eyre!("expected dir path"));
         }
@@ -124,12 +377,7 @@ impl GitFile {
         let items = files
             .filter_map(|e| {
                 let entry = e.ok()?;
-                let name = entry
-                    .path()
-                    .file_stem()
-                    .unwrap_or_default()
-                    .to_string_lossy()
-                    .to_string();
+                let name = entry.file_name().to_string_lossy().to_string();
 
                 // Ignore the directory itself
                 if entry.path() == path {
@@ -141,51 +389,36 @@ impl GitFile {
                 }
 
                 if entry.path().is_dir() {
-                    let tree = Self::from_directory(entry.path()).ok()?;
+                    let tree = Self::from_directory(odb, entry.path()).ok()?;
+
+                    // Git doesn't track directories, so empty ones are left
+                    // out of the tree
+                    if hex::encode(&tree.sha) == EMPTY_TREE {
+                        return None;
+                    }
                     let sha = tree.sha;
                     let mode = 40000;
                     Some(TreeContent { mode, sha, name })
                 } else {
-                    let blob = Self::from_file(entry.path()).ok()?;
+                    let blob = Self::from_file(odb, entry.path()).ok()?;
                     let sha = blob.sha;
                     let mode = 100644;
                     Some(TreeContent { mode, sha, name })
                 }
             })
-            .collect();
+            .collect::<Vec<_>>();
 
-        let content = GitFileContent::Tree(items);
-        let c = content.content();
-
-        // Hash the git file
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(c);
-        let sha = hasher.finalize();
+        // The empty tree is known without being stored
+        let sha = match items.is_empty() {
+            true => EMPTY_TREE.parse()?,
+            false => odb.write("tree", &TreeContent::serialize(&items))?,
+        };
 
         Ok(Self {
-            file_content: content,
-            sha: sha.to_vec(),
+            file_content: GitFileContent::Tree(items),
+            sha: hex::decode(&*sha)?,
         })
     }
-
-    /// Returns the compressed content of the file.
-    pub fn compress(&self) -> eyre::Result<Vec<u8>> {
-        // Compress the object
-        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
-
-        encoder.write_all(&self.content())?;
-        Ok(encoder.finish()?)
-    }
-
-    /// Returns the sha-1 hash of the file.
-    pub fn hash(&self) -> &[u8] {
-        &self.sha
-    }
-
-    /// Returns the raw content of the file.
-    pub fn content(&self) -> Vec<u8> {
-        self.file_content.content()
-    }
 }
 
 /// The content of a git file.
@@ -193,29 +426,6 @@ impl GitFile {
 pub enum GitFileContent {
     Blob(Vec<u8>),
     Tree(Vec<TreeContent>),
-    Commit,
-}
-
-impl GitFileContent {
-    /// Returns the raw content of the file.
-    pub fn content(&self) -> Vec<u8> {
-        match &self {
-            GitFileContent::Blob(c) => c.clone(),
-            GitFileContent::Tree(trees) => {
-                // Tree files are split into MODE NAME\0SHA-1
-                let mut trees = trees.clone();
-                trees.sort_by(|a, b| a.name.cmp(&b.name));
-                let content = trees
-                    .into_iter()
-                    .flat_map(|t| {
-                        let s = format!("{} {}\0", t.mode, t.name);
-                        [s.as_bytes(), &t.sha].concat()
-                    })
-                    .collect::<Vec<_>>();
-                let header = format!("tree {}\0", content.len());
-                [header.as_bytes(), &content].concat()
-            }
-            _ => vec![],
-        }
-    }
+    Commit(Box<Commit>),
+    Tag(Box<Tag>),
 }
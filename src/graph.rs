@@ -0,0 +1,199 @@
+use crate::commit::{Commit, Signature};
+use crate::git;
+use crate::mailmap::Mailmap;
+use crate::refs;
+use crate::revision;
+use crate::revwalk::RevWalk;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+
+/// Which commits `graph-export` exports, and how.
+#[derive(Debug, Default)]
+pub struct GraphOptions {
+    /// Exports the history of every ref and of `HEAD`, along with the
+    /// revisions.
+    pub all: bool,
+    /// Writes a Graphviz DOT graph instead of JSON.
+    pub dot: bool,
+}
+
+/// A commit of the exported graph, with the refs pointing at it.
+struct Node {
+    sha: String,
+    commit: Commit,
+    labels: Vec<String>,
+}
+
+/// Writes the commit DAG reachable from `revisions`, `HEAD` by default, for
+/// visualization tools: each commit with its parents, author, committer,
+/// summary and the refs pointing at it, newest first, then the edges from
+/// commits to their exported parents. Authors and committers are mapped by
+/// `mailmap`.
+pub fn export(
+    git_dir: &Path,
+    mailmap: &Mailmap,
+    revisions: &[String],
+    options: &GraphOptions,
+) -> eyre::Result<()> {
+    let (mut include, exclude) = match (revisions.is_empty(), options.all) {
+        (true, false) => revision::resolve_ranges(git_dir, &["HEAD".to_string()])?,
+        _ => revision::resolve_ranges(git_dir, revisions)?,
+    };
+    let decorations = decorations(git_dir)?;
+    if options.all {
+        include.extend(decorations.keys().cloned());
+    }
+
+    let mut walk = RevWalk::new();
+    let mut nodes = Vec::new();
+    for sha in walk.list(git_dir, &include, &exclude)? {
+        let mut commit = walk.commit(git_dir, &sha)?.clone();
+        for signature in [&mut commit.author, &mut commit.committer] {
+            (signature.name, signature.email) = mailmap.map(&signature.name, &signature.email);
+        }
+        let labels = decorations.get(&sha).cloned().unwrap_or_default();
+        nodes.push(Node {
+            sha,
+            commit,
+            labels,
+        });
+    }
+
+    let exported = nodes.iter().map(|n| n.sha.as_str()).collect::<HashSet<_>>();
+    let edges = nodes
+        .iter()
+        .flat_map(|node| node.commit.parents.iter().map(|p| (&node.sha, p)))
+        .filter(|(_, parent)| exported.contains(parent.as_str()))
+        .collect::<Vec<_>>();
+
+    let out = match options.dot {
+        true => to_dot(&nodes, &edges),
+        false => to_json(&nodes, &edges),
+    };
+    std::io::stdout().lock().write_all(out.as_bytes())?;
+    Ok(())
+}
+
+/// Returns the names of the refs pointing at each commit, shortened, like
+/// `log --decorate` shows them: `HEAD` first, then the refs sorted by name,
+/// tags prefixed with `tag: `.
+fn decorations(git_dir: &Path) -> eyre::Result<HashMap<String, Vec<String>>> {
+    let mut refs = refs::list_refs(git_dir, "refs/")?;
+    if let Some(head) = refs::read_ref(git_dir, "HEAD")? {
+        refs.insert(0, ("HEAD".to_string(), head));
+    }
+
+    let mut decorations = HashMap::<_, Vec<_>>::new();
+    for (name, sha) in refs {
+        // Annotated tags decorate the commit they tag
+        let sha = revision::peel(git_dir, &sha)?;
+        if git::read_object(git_dir, &sha)?.0 != "commit" {
+            continue;
+        }
+        let label = match name.starts_with("refs/tags/") {
+            true => format!("tag: {}", refs::shorten(git_dir, &name)?),
+            false if name == "HEAD" => name,
+            false => refs::shorten(git_dir, &name)?,
+        };
+        decorations.entry(sha).or_default().push(label);
+    }
+    Ok(decorations)
+}
+
+/// Formats the graph as a JSON object with a `nodes` array and an `edges`
+/// array of `from`/`to` pairs.
+fn to_json(nodes: &[Node], edges: &[(&String, &String)]) -> String {
+    let strings = |values: &[String]| {
+        let values = values.iter().map(|v| json_string(v)).collect::<Vec<_>>();
+        format!("[{}]", values.join(", "))
+    };
+    let signature = |signature: &Signature| {
+        format!(
+            "{{\"name\": {}, \"email\": {}, \"time\": {}, \"offset\": {}}}",
+            json_string(&signature.name),
+            json_string(&signature.email),
+            signature.time,
+            signature.offset
+        )
+    };
+
+    let nodes = nodes
+        .iter()
+        .map(|node| {
+            format!(
+                "    {{\"id\": {}, \"parents\": {}, \"author\": {}, \"committer\": {}, \"summary\": {}, \"refs\": {}}}",
+                json_string(&node.sha),
+                strings(&node.commit.parents),
+                signature(&node.commit.author),
+                signature(&node.commit.committer),
+                json_string(node.commit.summary()),
+                strings(&node.labels)
+            )
+        })
+        .collect::<Vec<_>>();
+    let edges = edges
+        .iter()
+        .map(|(from, to)| {
+            format!(
+                "    {{\"from\": {}, \"to\": {}}}",
+                json_string(from),
+                json_string(to)
+            )
+        })
+        .collect::<Vec<_>>();
+    format!(
+        "{{\n  \"nodes\": [\n{}\n  ],\n  \"edges\": [\n{}\n  ]\n}}\n",
+        nodes.join(",\n"),
+        edges.join(",\n")
+    )
+}
+
+/// Formats the graph as a Graphviz digraph, commits labeled with their
+/// abbreviated sha, summary and refs, and pointing at their parents.
+fn to_dot(nodes: &[Node], edges: &[(&String, &String)]) -> String {
+    let mut out = String::from("digraph commits {\n  node [shape=box];\n");
+    for node in nodes {
+        let mut label = format!("{} {}", &node.sha[..7], node.commit.summary());
+        if !node.labels.is_empty() {
+            label.push_str(&format!("\n({})", node.labels.join(", ")));
+        }
+        out.push_str(&format!(
+            "  \"{}\" [label={}];\n",
+            node.sha,
+            dot_string(&label)
+        ));
+    }
+    for (from, to) in edges {
+        out.push_str(&format!("  \"{from}\" -> \"{to}\";\n"));
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Quotes `s` as a DOT string, newlines becoming line breaks.
+fn dot_string(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{escaped}\"")
+}
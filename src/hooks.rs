@@ -0,0 +1,257 @@
+use crate::commit;
+use crate::config::{self, Config};
+use eyre::eyre;
+use regex::Regex;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+
+/// An update of a ref, `None` standing for a missing ref.
+#[derive(Debug, Clone, Copy)]
+pub struct RefUpdate<'a> {
+    pub name: &'a str,
+    pub old: Option<&'a str>,
+    pub new: Option<&'a str>,
+}
+
+/// Callbacks run at points of the lifecycle of a repository, letting policy
+/// be enforced in-process. Returning an error from a hook aborts the
+/// operation it was called for. Every callback does nothing by default.
+pub trait Hook: Send + Sync {
+    /// Called before a commit is created in the repository at `git_dir`,
+    /// `merge` telling whether it's a merge commit.
+    fn pre_commit(&self, _git_dir: &Path, _merge: bool) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    /// Called with the message of a commit about to be created, after
+    /// `pre_commit`, which the hook may edit.
+    fn commit_msg(&self, _git_dir: &Path, _message: &mut String) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    /// Called before the ref `name` of the repository at `git_dir` is moved
+    /// from `old` to `new`, `None` meaning the ref doesn't exist.
+    fn ref_update(
+        &self,
+        _git_dir: &Path,
+        _name: &str,
+        _old: Option<&str>,
+        _new: Option<&str>,
+    ) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    /// Called before the refs pushed to the repository at `git_dir` are
+    /// updated, with the updates its rules let through. Failing refuses all
+    /// of them.
+    fn pre_receive(&self, _git_dir: &Path, _updates: &[RefUpdate]) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    /// Called once the refs pushed to the repository at `git_dir` are
+    /// updated, with the updates that were applied. Failing only warns the
+    /// client, since the refs are already updated.
+    fn post_receive(&self, _git_dir: &Path, _updates: &[RefUpdate]) -> eyre::Result<()> {
+        Ok(())
+    }
+}
+
+/// The registered hooks, starting with the built-in checks of commit
+/// messages and the one running the scripts of the hooks directory.
+fn registry() -> &'static Mutex<Vec<Box<dyn Hook>>> {
+    static HOOKS: OnceLock<Mutex<Vec<Box<dyn Hook>>>> = OnceLock::new();
+    HOOKS.get_or_init(|| Mutex::new(vec![Box::new(Lint), Box::new(ScriptHooks)]))
+}
+
+/// Adds a hook, run after the hooks registered before it. Only programs
+/// embedding the crate register hooks.
+pub fn register(hook: impl Hook + 'static) {
+    registry()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(Box::new(hook));
+}
+
+/// Runs `callback` on every registered hook in order, stopping at the first
+/// one that fails.
+pub fn run(mut callback: impl FnMut(&dyn Hook) -> eyre::Result<()>) -> eyre::Result<()> {
+    let hooks = registry().lock().unwrap_or_else(|e| e.into_inner());
+    hooks.iter().try_for_each(|hook| callback(hook.as_ref()))
+}
+
+/// Checks the messages of commits against the policy of `commit.lint`:
+/// `conventional` for the conventional commits format, or a regex their
+/// subject must match. Unset or false, messages aren't checked.
+struct Lint;
+
+impl Hook for Lint {
+    fn commit_msg(&self, git_dir: &Path, message: &mut String) -> eyre::Result<()> {
+        let config = Config::load(git_dir)?;
+        let Some(policy) = config.get("commit.lint") else {
+            return Ok(());
+        };
+        let subject = message.lines().next().unwrap_or_default();
+        match (policy, config::parse_bool(policy)) {
+            (_, Ok(false)) => Ok(()),
+            ("conventional", _) | (_, Ok(true)) => check_conventional(message),
+            (pattern, _) => {
+                let regex = Regex::new(pattern)
+                    .map_err(|e| eyre!(tr!("invalid commit.lint regex '%s': %s", pattern, e)))?;
+                match regex.is_match(subject) {
+                    true => Ok(()),
+                    false => Err(eyre!(tr!(
+                        "commit message '%s' doesn't match commit.lint '%s'",
+                        subject,
+                        pattern
+                    ))),
+                }
+            }
+        }
+    }
+}
+
+/// Checks that `message` follows the conventional commits format: a
+/// `<type>(<scope>)!: <description>` subject, the scope and `!` being
+/// optional, then a blank line before the body. The messages git writes
+/// for merges, reverts and autosquash fixups are let through.
+fn check_conventional(message: &str) -> eyre::Result<()> {
+    let mut lines = message.lines();
+    let subject = lines.next().unwrap_or_default();
+    let generated = ["Merge ", "Revert \"", "fixup! ", "squash! ", "amend! "];
+    if generated.iter().any(|prefix| subject.starts_with(prefix)) {
+        return Ok(());
+    }
+    let format = Regex::new(r"^[[:alpha:]]+(\([^()]+\))?!?: \S")?;
+    if !format.is_match(subject) {
+        return Err(eyre!(tr!(
+            "commit message '%s' is not a conventional commit, like 'feat(scope): description'",
+            subject
+        )));
+    }
+    if lines.next().is_some_and(|line| !line.is_empty()) {
+        return Err(eyre!(tr!(
+            "the subject of a conventional commit must be followed by a blank line"
+        )));
+    }
+    Ok(())
+}
+
+/// Runs the executable scripts of `.git/hooks`, or of the directory set by
+/// `core.hooksPath`, like git does.
+struct ScriptHooks;
+
+impl ScriptHooks {
+    /// Runs the script of the hook `name` of the repository at `git_dir`, if
+    /// there is one, feeding it `stdin`. Returns whether the script
+    /// succeeded.
+    fn run(git_dir: &Path, name: &str, args: &[&str], stdin: &str) -> eyre::Result<bool> {
+        let dir = Config::load(git_dir)?
+            .get("core.hooksPath")
+            .map(PathBuf::from)
+            .unwrap_or(git_dir.join("hooks"));
+        let path = dir.join(name);
+        if !is_executable(&path) {
+            return Ok(true);
+        }
+
+        // Like git, the output of scripts goes to stderr, so that it doesn't
+        // mix with the protocol of servers
+        let mut child = Command::new(&path)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(std::io::stderr())
+            .spawn()
+            .map_err(|e| eyre!("cannot run hook {}: {e}", path.display()))?;
+        if let Some(mut input) = child.stdin.take() {
+            // Hooks may exit without reading their input
+            let _ = input.write_all(stdin.as_bytes());
+        }
+        Ok(child.wait()?.success())
+    }
+}
+
+impl Hook for ScriptHooks {
+    fn pre_commit(&self, git_dir: &Path, merge: bool) -> eyre::Result<()> {
+        let name = if merge {
+            "pre-merge-commit"
+        } else {
+            "pre-commit"
+        };
+        match Self::run(git_dir, name, &[], "")? {
+            true => Ok(()),
+            false => Err(eyre!(tr!("the %s hook refused the commit", name))),
+        }
+    }
+
+    fn commit_msg(&self, git_dir: &Path, message: &mut String) -> eyre::Result<()> {
+        // The script gets the message in a file it may edit
+        let path = git_dir.join("COMMIT_EDITMSG");
+        fs::write(&path, &message)?;
+        if !Self::run(git_dir, "commit-msg", &[&path.to_string_lossy()], "")? {
+            return Err(eyre!(tr!("the %s hook refused the commit", "commit-msg")));
+        }
+        *message = commit::cleanup_message(&fs::read_to_string(&path)?);
+        match message.is_empty() {
+            true => Err(eyre!(tr!("Aborting commit due to empty commit message."))),
+            false => Ok(()),
+        }
+    }
+
+    fn ref_update(
+        &self,
+        git_dir: &Path,
+        name: &str,
+        old: Option<&str>,
+        new: Option<&str>,
+    ) -> eyre::Result<()> {
+        let input = update_lines(&[RefUpdate { name, old, new }]);
+        match Self::run(git_dir, "reference-transaction", &["prepared"], &input)? {
+            true => Ok(()),
+            false => Err(eyre!(tr!(
+                "in '%s' phase, update aborted by the reference-transaction hook",
+                "prepared"
+            ))),
+        }
+    }
+
+    fn pre_receive(&self, git_dir: &Path, updates: &[RefUpdate]) -> eyre::Result<()> {
+        match Self::run(git_dir, "pre-receive", &[], &update_lines(updates))? {
+            true => Ok(()),
+            false => Err(eyre!(tr!("pre-receive hook declined"))),
+        }
+    }
+
+    fn post_receive(&self, git_dir: &Path, updates: &[RefUpdate]) -> eyre::Result<()> {
+        // The refs are updated whatever the script exits with
+        Self::run(git_dir, "post-receive", &[], &update_lines(updates))?;
+        Ok(())
+    }
+}
+
+/// Returns the `<old> <new> <name>` lines scripts read `updates` as, with
+/// null shas for missing refs.
+fn update_lines(updates: &[RefUpdate]) -> String {
+    let null = crate::git::NULL_SHA;
+    updates
+        .iter()
+        .map(|u| {
+            let (old, new) = (u.old.unwrap_or(null), u.new.unwrap_or(null));
+            format!("{old} {new} {}\n", u.name)
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn is_executable(path: &std::path::Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &std::path::Path) -> bool {
+    path.is_file()
+}
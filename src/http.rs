@@ -0,0 +1,369 @@
+use crate::config::Config;
+use crate::credential::Credential;
+use crate::git::{self, GitFile, GitFileContent};
+use crate::odb::{self, Odb};
+use crate::pack::{self, PackIndex};
+use crate::pktline;
+use crate::transport::{self, Refs, Transport};
+use crate::tree::MODE_GITLINK;
+use base64::Engine;
+use eyre::eyre;
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::Path;
+
+/// The `User-Agent` of requests, which servers expect to start with `git/`
+/// to speak the smart protocol.
+const USER_AGENT: &str = concat!(
+    "git/",
+    env!("CARGO_PKG_NAME"),
+    "-",
+    env!("CARGO_PKG_VERSION")
+);
+
+/// Returns true if `url` names a repository served over HTTP.
+pub fn is_http(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// A repository served over the smart HTTP protocol, where each exchange
+/// is a request: the refs are advertised at `info/refs`, and the services
+/// answer posts at their name. Credentials are asked for once the server
+/// refuses a request.
+pub struct Client<'a> {
+    config: &'a Config,
+    url: String,
+    /// The version of the protocol asked for with `protocol.version`, which
+    /// only fetches have a version 2 of.
+    version: u32,
+    agent: ureq::Agent,
+    credential: Option<Credential>,
+    /// Whether the credential was accepted and stored by the helpers.
+    approved: bool,
+    /// The headers sent with every request, from `http.extraHeader`,
+    /// `GIT_HTTP_EXTRA_HEADER` and `GIT_HTTP_BEARER_TOKEN`.
+    headers: Vec<(String, String)>,
+}
+
+impl<'a> Client<'a> {
+    pub fn new(config: &'a Config, url: &str) -> eyre::Result<Self> {
+        Ok(Self {
+            config,
+            url: url.trim_end_matches('/').to_string(),
+            version: transport::version(config)?,
+            agent: ureq::AgentBuilder::new().user_agent(USER_AGENT).build(),
+            credential: None,
+            approved: false,
+            headers: extra_headers(config)?,
+        })
+    }
+
+    /// Sends a request for `service` to `url`, a post of the body of the
+    /// given content type or a get, which finds nothing if the file isn't
+    /// there. A refused request is sent again with the credential of the
+    /// url, which the helpers store once it works and erase if it doesn't.
+    fn send(
+        &mut self,
+        url: &str,
+        service: &str,
+        body: Option<(&str, &[u8])>,
+    ) -> eyre::Result<Option<ureq::Response>> {
+        loop {
+            let mut request = match body {
+                Some((content_type, _)) => self
+                    .agent
+                    .post(url)
+                    .set("Content-Type", content_type)
+                    .set("Accept", &content_type.replace("-request", "-result")),
+                None => self.agent.get(url),
+            };
+            if self.version == 2 && service == "git-upload-pack" {
+                request = request.set("Git-Protocol", "version=2");
+            }
+            for (name, value) in &self.headers {
+                request = request.set(name, value);
+            }
+            if let Some(credential) = &self.credential {
+                let login = format!(
+                    "{}:{}",
+                    credential.username.as_deref().unwrap_or_default(),
+                    credential.password.as_deref().unwrap_or_default()
+                );
+                let login = base64::engine::general_purpose::STANDARD.encode(login);
+                request = request.set("Authorization", &format!("Basic {login}"));
+            }
+            let result = match body {
+                Some((_, data)) => request.send_bytes(data),
+                None => request.call(),
+            };
+
+            match result {
+                Ok(response) => {
+                    if let Some(credential) = self.credential.as_mut().filter(|_| !self.approved) {
+                        credential.approve(self.config)?;
+                        self.approved = true;
+                    }
+                    return Ok(Some(response));
+                }
+                Err(ureq::Error::Status(401, _)) if self.credential.is_none() => {
+                    let mut credential = Credential::from_url(&self.url);
+                    credential.fill(self.config)?;
+                    self.credential = Some(credential);
+                }
+                Err(ureq::Error::Status(401, _)) => {
+                    if let Some(credential) = self.credential.as_mut() {
+                        credential.reject(self.config)?;
+                    }
+                    return Err(eyre!(tr!("Authentication failed for '%s'", &self.url)));
+                }
+                Err(ureq::Error::Status(404, _)) if body.is_none() => return Ok(None),
+                Err(ureq::Error::Status(code, _)) => {
+                    return Err(eyre!(tr!(
+                        "unable to access '%s': The requested URL returned error: %d",
+                        &self.url,
+                        code
+                    )));
+                }
+                Err(e) => {
+                    return Err(eyre!(tr!(
+                        "unable to access '%s': %s",
+                        &self.url,
+                        &e.to_string()
+                    )));
+                }
+            }
+        }
+    }
+}
+
+/// Returns the headers to send with every request: the ones of
+/// `http.extraHeader`, where an empty value drops the ones before it, then
+/// the lines of `GIT_HTTP_EXTRA_HEADER`, and the token of
+/// `GIT_HTTP_BEARER_TOKEN` as `Authorization`, for non-interactive logins.
+fn extra_headers(config: &Config) -> eyre::Result<Vec<(String, String)>> {
+    let mut lines = Vec::new();
+    for value in config.get_all("http.extraheader") {
+        match value.is_empty() {
+            true => lines.clear(),
+            false => lines.push(value.to_string()),
+        }
+    }
+    if let Ok(headers) = std::env::var("GIT_HTTP_EXTRA_HEADER") {
+        lines.extend(headers.lines().filter(|l| !l.is_empty()).map(String::from));
+    }
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .filter(|(name, _)| !name.trim().is_empty())
+            .ok_or(eyre!(tr!("invalid extra header '%s'", &line)))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    if let Ok(token) = std::env::var("GIT_HTTP_BEARER_TOKEN") {
+        headers.push(("Authorization".to_string(), format!("Bearer {token}")));
+    }
+    Ok(headers)
+}
+
+impl Client<'_> {
+    /// Returns the content of the file at `path` in the repository, if it's
+    /// there.
+    fn get(&mut self, path: &str) -> eyre::Result<Option<Vec<u8>>> {
+        let url = format!("{}/{path}", self.url);
+        let Some(response) = self.send(&url, "", None)? else {
+            return Ok(None);
+        };
+        let mut content = Vec::new();
+        response.into_reader().read_to_end(&mut content)?;
+        Ok(Some(content))
+    }
+
+    /// Parses the file `info/refs` of a server without the smart protocol,
+    /// `content`, listing a `<sha>\t<name>` line by ref, along with the ref
+    /// `HEAD` points to.
+    fn dumb_refs(&mut self, content: &str) -> eyre::Result<Refs> {
+        let mut refs = Refs {
+            dumb: true,
+            ..Refs::default()
+        };
+        for line in content.lines() {
+            let (sha, name) = line
+                .split_once('\t')
+                .ok_or(eyre!(tr!("invalid ref advertisement line '%s'", line)))?;
+            match name.strip_suffix("^{}") {
+                Some(tag) => refs.peeled.push((tag.to_string(), sha.to_string())),
+                None => refs.refs.push((name.to_string(), sha.to_string())),
+            }
+        }
+
+        let head = self.get("HEAD")?.unwrap_or_default();
+        let head = String::from_utf8_lossy(&head).trim_end().to_string();
+        let sha = match head.strip_prefix("ref: ") {
+            Some(target) => {
+                refs.symrefs.push(("HEAD".to_string(), target.to_string()));
+                refs.refs
+                    .iter()
+                    .find(|(name, _)| name == target)
+                    .map(|(_, sha)| sha.clone())
+            }
+            None => Some(head).filter(|head| !head.is_empty()),
+        };
+        if let Some(sha) = sha {
+            refs.refs.insert(0, ("HEAD".to_string(), sha));
+        }
+        Ok(refs)
+    }
+
+    /// Downloads the object `sha` from a server without the smart protocol,
+    /// from its loose object file, or else with the pack of `packs` that
+    /// has it, whose objects are added to `downloaded`.
+    fn download_object(
+        &mut self,
+        git_dir: &Path,
+        sha: &str,
+        packs: &mut Option<Vec<(String, PackIndex)>>,
+        downloaded: &mut HashSet<String>,
+    ) -> eyre::Result<()> {
+        let oid = sha.parse::<git::Oid>()?;
+        if let Some(compressed) = self.get(&format!("objects/{}/{}", &oid[..2], &oid[2..]))? {
+            let object = odb::inflate(&compressed, compressed.len() * 2)?;
+            let (kind, content) = object
+                .iter()
+                .position(|b| *b == 0)
+                .and_then(|nul| {
+                    let header = std::str::from_utf8(&object[..nul]).ok()?;
+                    Some((header.split(' ').next()?, &object[nul + 1..]))
+                })
+                .ok_or(eyre!(tr!("object file %s is corrupt", sha)))?;
+            if *Odb::new(git_dir).write(kind, content)? != *sha {
+                return Err(eyre!(tr!("object file %s is corrupt", sha)));
+            }
+            return Ok(());
+        }
+
+        if packs.is_none() {
+            let list = self.get("objects/info/packs")?.unwrap_or_default();
+            let mut indexes = Vec::new();
+            for line in String::from_utf8_lossy(&list).lines() {
+                let Some(name) = line
+                    .strip_prefix("P ")
+                    .and_then(|p| p.strip_suffix(".pack"))
+                else {
+                    continue;
+                };
+                let index = self
+                    .get(&format!("objects/pack/{name}.idx"))?
+                    .ok_or(eyre!(tr!("unable to get pack index %s", name)))?;
+                indexes.push((name.to_string(), PackIndex::parse(&index)?));
+            }
+            *packs = Some(indexes);
+        }
+        let packs = packs.as_mut().unwrap();
+        let i = packs
+            .iter()
+            .position(|(_, index)| index.offset(sha).is_some())
+            .ok_or(eyre!(tr!("Unable to find %s under %s", sha, &self.url)))?;
+        let (name, index) = packs.remove(i);
+        let pack = self
+            .get(&format!("objects/pack/{name}.pack"))?
+            .ok_or(eyre!(tr!("unable to get pack file %s", &name)))?;
+        pack::store(git_dir, &pack, None)?;
+        downloaded.extend(index.entries.into_iter().map(|(sha, _)| sha));
+        Ok(())
+    }
+}
+
+impl Transport for Client<'_> {
+    fn connect(&mut self, service: &str) -> eyre::Result<Refs> {
+        let url = format!("{}/info/refs?service={service}", self.url);
+        let response = self
+            .send(&url, service, None)?
+            .ok_or(eyre!(tr!("repository '%s' not found", &self.url)))?;
+        // Servers without the smart protocol serve the file `info/refs`
+        if response.content_type() != format!("application/x-{service}-advertisement") {
+            if service != "git-upload-pack" {
+                return Err(eyre!(tr!(
+                    "dumb HTTP transport does not support pushing to %s",
+                    &self.url
+                )));
+            }
+            let mut content = String::new();
+            response.into_reader().read_to_string(&mut content)?;
+            return self.dumb_refs(&content);
+        }
+        let mut reader = pktline::Reader::new(response.into_reader());
+
+        // The advertisement is preceded by the service announcing itself,
+        // except in version 2
+        let line = reader.read_line()?.unwrap_or_default();
+        if line == format!("# service={service}") {
+            reader.read()?;
+            let line = reader.read_line()?;
+            return transport::read_refs(&mut reader, line);
+        } else if line != "version 2" {
+            return Err(eyre!(tr!("invalid server response; got '%s'", &line)));
+        }
+        transport::read_refs(&mut reader, Some(line))
+    }
+
+    /// Posts `request` to `service` and returns its result.
+    fn request(&mut self, service: &str, request: &[u8]) -> eyre::Result<Box<dyn Read + '_>> {
+        let url = format!("{}/{service}", self.url);
+        let content_type = format!("application/x-{service}-request");
+        let response = self
+            .send(&url, service, Some((&content_type, request)))?
+            .ok_or(eyre!(tr!("repository '%s' not found", &self.url)))?;
+        if response.content_type() != format!("application/x-{service}-result") {
+            return Err(eyre!(tr!(
+                "invalid content-type: '%s'",
+                response.content_type()
+            )));
+        }
+        Ok(Box::new(response.into_reader()))
+    }
+
+    fn is_stateless(&self) -> bool {
+        true
+    }
+
+    /// Downloads the objects the commits, trees and tags reachable from
+    /// `wants` point to, from the loose object files or else from the pack
+    /// that has them, until reaching the objects the local repository had.
+    fn download(&mut self, git_dir: &Path, wants: &[String]) -> eyre::Result<()> {
+        // The packs of the server, whose indexes are only downloaded once
+        // a loose object is missing
+        let mut packs = None;
+        let mut downloaded = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut pending = wants.to_vec();
+        while let Some(sha) = pending.pop() {
+            if !seen.insert(sha.clone()) {
+                continue;
+            }
+            let local = git::read_object(git_dir, &sha).is_ok();
+            if local && !downloaded.contains(&sha) {
+                continue;
+            }
+            if !local {
+                self.download_object(git_dir, &sha, &mut packs, &mut downloaded)?;
+            }
+
+            match GitFile::read(git_dir, &sha)?.file_content {
+                GitFileContent::Commit(commit) => {
+                    pending.push(commit.tree);
+                    pending.extend(commit.parents);
+                }
+                GitFileContent::Tree(entries) => pending.extend(
+                    entries
+                        .iter()
+                        .filter(|entry| entry.mode != MODE_GITLINK)
+                        .map(|entry| hex::encode(&entry.sha)),
+                ),
+                GitFileContent::Tag(tag) => pending.push(tag.object),
+                GitFileContent::Blob(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Translates a user-facing message into the language of the user,
+/// substituting the `%s` and `%d` conversions with the arguments, like git's
+/// `_()`. Messages without a translation are printed as is.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! tr {
+    ($msgid:expr) => {
+        $crate::translate($msgid, &[])
+    };
+    ($msgid:expr, $($arg:expr),+ $(,)?) => {
+        $crate::translate($msgid, &[$(&$arg as &dyn std::fmt::Display),+])
+    };
+}
+
+/// Where message catalogs are installed when `GIT_TEXTDOMAINDIR` isn't set.
+const LOCALE_DIR: &str = "/usr/share/locale";
+
+/// Returns the translation of `msgid` substituted with `args`.
+pub fn translate(msgid: &str, args: &[&dyn Display]) -> String {
+    static CATALOG: OnceLock<HashMap<String, String>> = OnceLock::new();
+    let catalog = CATALOG.get_or_init(|| load().unwrap_or_default());
+    let message = catalog.get(msgid).map(String::as_str).unwrap_or(msgid);
+    format(message, args)
+}
+
+/// Substitutes the printf-style conversions of a message. Translations
+/// may reorder the arguments with `%1$s`.
+fn format(message: &str, args: &[&dyn Display]) -> String {
+    let mut out = String::new();
+    let mut next = 0;
+    let mut rest = message;
+    while let Some(start) = rest.find('%') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        if let Some(after) = rest.strip_prefix('%') {
+            out.push('%');
+            rest = after;
+            continue;
+        }
+
+        // An optional `N$` position, then the conversion character
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (index, spec) = match rest[digits..].strip_prefix('$') {
+            Some(spec) if digits > 0 => (
+                rest[..digits]
+                    .parse::<usize>()
+                    .unwrap_or(1)
+                    .saturating_sub(1),
+                spec,
+            ),
+            _ => {
+                next += 1;
+                (next - 1, rest)
+            }
+        };
+        match (spec.chars().next(), args.get(index)) {
+            (Some('s' | 'd'), Some(arg)) => {
+                out.push_str(&arg.to_string());
+                rest = &spec[1..];
+            }
+            _ => out.push('%'),
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Loads the `git.mo` catalog of the first language of the user that has
+/// one.
+fn load() -> Option<HashMap<String, String>> {
+    let dir = std::env::var_os("GIT_TEXTDOMAINDIR")
+        .map(PathBuf::from)
+        .unwrap_or(PathBuf::from(LOCALE_DIR));
+    languages().into_iter().find_map(|language| {
+        let content = std::fs::read(dir.join(&language).join("LC_MESSAGES/git.mo")).ok()?;
+        parse_mo(&content)
+    })
+}
+
+/// Returns the languages to look for catalogs in, by order of preference,
+/// from the locale set by `LC_ALL`, `LC_MESSAGES` or `LANG`, overridden by
+/// the list in `LANGUAGE` like gettext does. A language with a territory,
+/// like `pt_BR`, falls back to the language alone.
+fn languages() -> Vec<String> {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()))
+        .unwrap_or_default();
+    if locale.is_empty() || locale == "C" || locale == "POSIX" {
+        return Vec::new();
+    }
+
+    let preferred = std::env::var("LANGUAGE")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or(locale);
+
+    let mut languages = Vec::new();
+    for language in preferred.split(':') {
+        // Drop the codeset and modifier, as in `de_DE.UTF-8@euro`
+        let language = language.split(['.', '@']).next().unwrap_or_default();
+        languages.push(language.to_string());
+        if let Some((base, _)) = language.split_once('_') {
+            languages.push(base.to_string());
+        }
+    }
+    languages.retain(|l| !l.is_empty());
+    languages
+}
+
+/// Parses a gettext `.mo` file into a map from messages to their
+/// translations. Only the singular form of plural messages is kept.
+fn parse_mo(content: &[u8]) -> Option<HashMap<String, String>> {
+    let word = |offset: usize, big_endian: bool| -> Option<usize> {
+        let bytes: [u8; 4] = content.get(offset..offset + 4)?.try_into().ok()?;
+        Some(match big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        } as usize)
+    };
+    let big_endian = match word(0, false)? {
+        0x950412de => false,
+        0xde120495 => true,
+        _ => return None,
+    };
+
+    let count = word(8, big_endian)?;
+    let originals = word(12, big_endian)?;
+    let translations = word(16, big_endian)?;
+
+    // Each table entry is the length and offset of a string
+    let string = |table: usize, i: usize| -> Option<String> {
+        let length = word(table + i * 8, big_endian)?;
+        let offset = word(table + i * 8 + 4, big_endian)?;
+        let bytes = content.get(offset..offset + length)?;
+        let singular = bytes.split(|b| *b == 0).next().unwrap_or_default();
+        Some(String::from_utf8_lossy(singular).to_string())
+    };
+
+    let mut catalog = HashMap::new();
+    for i in 0..count {
+        let msgid = string(originals, i)?;
+        // Skip the header and messages with a context
+        if msgid.is_empty() || msgid.contains('\u{4}') {
+            continue;
+        }
+        catalog.insert(msgid, string(translations, i)?);
+    }
+    Some(catalog)
+}
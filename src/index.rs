@@ -0,0 +1,1020 @@
+use crate::attributes;
+use crate::config::{self, Config};
+use crate::exclude::Excludes;
+use crate::git;
+use crate::odb::Odb;
+use crate::repository;
+use crate::tempfile::Temporary;
+use crate::tree::{self, Files, MODE_FILE, MODE_SYMLINK};
+use crate::worktree;
+use eyre::eyre;
+use sha1::Digest;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Flag of entries whose worktree file is assumed unchanged.
+const FLAG_ASSUME_VALID: u16 = 0x8000;
+/// Flag of entries followed by a second word of flags, from version 3.
+const FLAG_EXTENDED: u16 = 0x4000;
+/// Extended flag of entries without a worktree file, like sparse paths.
+const FLAG_SKIP_WORKTREE: u16 = 0x4000;
+/// The bits of the flags holding the length of the path, capped at the mask.
+const NAME_MASK: u16 = 0x0fff;
+/// The size of an entry before its path: stat fields, sha and flags.
+const ENTRY_HEADER: usize = 62;
+
+/// A file staged in the index, with the stat information of its worktree
+/// file when it was staged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    /// Seconds and nanoseconds of the last status change of the file.
+    pub ctime: (u32, u32),
+    /// Seconds and nanoseconds of the last modification of the file.
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    /// The mode of the file, as in trees.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// The size of the file, truncated to 32 bits.
+    pub size: u32,
+    pub sha: String,
+    /// The assume-valid and extended flags, and the stage in bits 12-13.
+    pub flags: u16,
+    /// The flags of version 3, like skip-worktree.
+    pub extended_flags: u16,
+    /// The path from the top of the working tree, with `/` separators.
+    pub path: String,
+}
+
+impl Entry {
+    /// Returns the entry of the worktree file at `path` described by
+    /// `metadata`, whose content is stored in the blob `sha`.
+    pub fn new(path: String, sha: String, mode: u32, metadata: &fs::Metadata) -> Self {
+        let mut entry = Self {
+            mode,
+            sha,
+            path,
+            ..Self::default()
+        };
+        // Timestamps and sizes are truncated to 32 bits, like git does
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            entry.ctime = (metadata.ctime() as u32, metadata.ctime_nsec() as u32);
+            entry.mtime = (metadata.mtime() as u32, metadata.mtime_nsec() as u32);
+            entry.dev = metadata.dev() as u32;
+            entry.ino = metadata.ino() as u32;
+            entry.uid = metadata.uid();
+            entry.gid = metadata.gid();
+        }
+        entry.size = metadata.len() as u32;
+        entry
+    }
+
+    /// Returns the merge stage of the entry, 0 unless it's conflicted.
+    pub fn stage(&self) -> u16 {
+        (self.flags >> 12) & 3
+    }
+
+    /// Returns true if the worktree file is assumed to have the content of
+    /// the entry, so it's never read.
+    pub fn assume_unchanged(&self) -> bool {
+        self.flags & FLAG_ASSUME_VALID != 0
+    }
+
+    /// Returns true if the file is left out of the working tree, so its
+    /// absence there isn't a deletion.
+    pub fn skip_worktree(&self) -> bool {
+        self.extended_flags & FLAG_SKIP_WORKTREE != 0
+    }
+
+    /// Returns true if the worktree file isn't compared with the entry,
+    /// being assumed unchanged or skipped.
+    pub fn ignores_worktree(&self) -> bool {
+        self.assume_unchanged() || self.skip_worktree()
+    }
+
+    /// Returns the letter `ls-files -v` shows the entry with: `H` for
+    /// cached files, `S` for skipped ones and `M` for conflicted ones, in
+    /// lowercase if assumed unchanged.
+    pub fn tag(&self) -> char {
+        let tag = match (self.stage(), self.skip_worktree()) {
+            (1.., _) => 'M',
+            (0, true) => 'S',
+            (0, false) => 'H',
+        };
+        match self.assume_unchanged() {
+            true => tag.to_ascii_lowercase(),
+            false => tag,
+        }
+    }
+}
+
+/// The bits `update_index` sets or clears, left as they are when `None`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlagUpdate {
+    pub assume_unchanged: Option<bool>,
+    pub skip_worktree: Option<bool>,
+}
+
+/// The base, our and their versions of a conflicted path, with their mode
+/// and blob sha, missing where a side doesn't have the path.
+pub type ConflictVersions = [Option<(u32, String)>; 3];
+
+/// The index at `.git/index`, listing the staged files sorted by path and
+/// stage. Extensions are kept as they are, except for the cached trees
+/// which are invalidated by any change, and the resolve-undo extension
+/// which is read into `resolve_undo`.
+#[derive(Debug, Clone)]
+pub struct Index {
+    version: u32,
+    pub entries: Vec<Entry>,
+    /// The versions of the conflicted paths that were resolved, by path, so
+    /// that their conflicts can be recreated.
+    pub resolve_undo: BTreeMap<String, ConflictVersions>,
+    extensions: Vec<([u8; 4], Vec<u8>)>,
+    /// When the index file was last written, as seconds and nanoseconds.
+    written: Option<(u32, u32)>,
+}
+
+impl Default for Index {
+    /// An index without entries.
+    fn default() -> Self {
+        Self {
+            version: 2,
+            entries: Vec::new(),
+            resolve_undo: BTreeMap::new(),
+            extensions: Vec::new(),
+            written: None,
+        }
+    }
+}
+
+impl Index {
+    /// Reads `.git/index`, an index without entries if it doesn't exist.
+    pub fn read(git_dir: &Path) -> eyre::Result<Self> {
+        match fs::read(git_dir.join("index")) {
+            Ok(content) => {
+                let mut index = Self::parse(&content)?;
+                let modified = fs::metadata(git_dir.join("index"))?.modified()?;
+                let since_epoch = modified.duration_since(std::time::UNIX_EPOCH)?;
+                index.written = Some((since_epoch.as_secs() as u32, since_epoch.subsec_nanos()));
+                Ok(index)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Parses an index of version 2 to 4: a `DIRC` header with the version
+    /// and the number of entries, the entries, extensions made of a
+    /// signature, a size and data, and the checksum of all that.
+    pub fn parse(content: &[u8]) -> eyre::Result<Self> {
+        let corrupt = || eyre!(tr!("index file corrupt"));
+        if !content.starts_with(b"DIRC") {
+            return Err(eyre!(tr!("index file has a bad signature")));
+        }
+        if content.len() < 32 {
+            return Err(corrupt());
+        }
+        let (body, checksum) = content.split_at(content.len() - 20);
+        if sha1::Sha1::digest(body).as_slice() != checksum {
+            return Err(eyre!(tr!("bad index file sha1 signature")));
+        }
+
+        let word = |offset: usize| -> eyre::Result<u32> {
+            let bytes = body.get(offset..offset + 4).ok_or_else(corrupt)?;
+            Ok(u32::from_be_bytes(bytes.try_into()?))
+        };
+        let half = |offset: usize| -> eyre::Result<u16> {
+            let bytes = body.get(offset..offset + 2).ok_or_else(corrupt)?;
+            Ok(u16::from_be_bytes(bytes.try_into()?))
+        };
+        let version = word(4)?;
+        if !(2..=4).contains(&version) {
+            return Err(eyre!(tr!("bad index version %d", version)));
+        }
+
+        let count = word(8)?;
+        let mut entries = Vec::with_capacity(count as usize);
+        let mut offset = 12;
+        let mut previous = String::new();
+        for _ in 0..count {
+            let start = offset;
+            let mut entry = Entry {
+                ctime: (word(offset)?, word(offset + 4)?),
+                mtime: (word(offset + 8)?, word(offset + 12)?),
+                dev: word(offset + 16)?,
+                ino: word(offset + 20)?,
+                mode: format!("{:o}", word(offset + 24)?).parse()?,
+                uid: word(offset + 28)?,
+                gid: word(offset + 32)?,
+                size: word(offset + 36)?,
+                sha: hex::encode(body.get(offset + 40..offset + 60).ok_or_else(corrupt)?),
+                flags: half(offset + 60)? & !NAME_MASK,
+                extended_flags: 0,
+                path: String::new(),
+            };
+            offset += ENTRY_HEADER;
+            if entry.flags & FLAG_EXTENDED != 0 {
+                if version < 3 {
+                    return Err(corrupt());
+                }
+                entry.extended_flags = half(offset)?;
+                offset += 2;
+            }
+
+            // Version 4 paths only store what differs from the previous
+            // path, after the number of bytes to drop from its end
+            let mut path = Vec::new();
+            if version == 4 {
+                let (strip, length) = read_varint(body.get(offset..).ok_or_else(corrupt)?)?;
+                offset += length;
+                let kept = previous.len().checked_sub(strip).ok_or_else(corrupt)?;
+                path.extend_from_slice(&previous.as_bytes()[..kept]);
+            }
+            let rest = body.get(offset..).ok_or_else(corrupt)?;
+            let end = rest.iter().position(|b| *b == 0).ok_or_else(corrupt)?;
+            path.extend_from_slice(&rest[..end]);
+            entry.path = String::from_utf8(path)?;
+            offset += end + 1;
+
+            // Entries of older versions are padded with NULs to a multiple
+            // of 8 bytes
+            if version < 4 {
+                offset = start + padded_length(offset - 1 - start);
+            }
+            previous = entry.path.clone();
+            entries.push(entry);
+        }
+
+        let mut resolve_undo = BTreeMap::new();
+        let mut extensions = Vec::new();
+        while offset < body.len() {
+            let mut signature = [0; 4];
+            signature.copy_from_slice(body.get(offset..offset + 4).ok_or_else(corrupt)?);
+            let size = word(offset + 4)? as usize;
+            let data = body
+                .get(offset + 8..offset + 8 + size)
+                .ok_or_else(corrupt)?;
+            // Extensions starting with a capital letter are optional, the
+            // others change how the index is to be read
+            if !signature[0].is_ascii_uppercase() {
+                return Err(eyre!(tr!(
+                    "index uses %s extension, which we do not understand",
+                    String::from_utf8_lossy(&signature)
+                )));
+            }
+            match &signature {
+                b"REUC" => resolve_undo = parse_resolve_undo(data).ok_or_else(corrupt)?,
+                _ => extensions.push((signature, data.to_vec())),
+            }
+            offset += 8 + size;
+        }
+
+        Ok(Self {
+            version,
+            entries,
+            resolve_undo,
+            extensions,
+            written: None,
+        })
+    }
+
+    /// Serializes the index in its version, upgraded to 3 if an entry has
+    /// extended flags, and back to 2 once none has. Entries with a mode or
+    /// a sha that can't be stored are an error.
+    pub fn serialize(&self) -> eyre::Result<Vec<u8>> {
+        let extended = self.entries.iter().any(|e| e.extended_flags != 0);
+        let version = match (extended, self.version) {
+            (true, 2) => 3,
+            (false, 3) => 2,
+            (_, version) => version,
+        };
+
+        let mut out = b"DIRC".to_vec();
+        out.extend(version.to_be_bytes());
+        out.extend((self.entries.len() as u32).to_be_bytes());
+        let mut previous = "";
+        for entry in &self.entries {
+            let start = out.len();
+            let mode = u32::from_str_radix(&entry.mode.to_string(), 8)
+                .map_err(|_| eyre!(tr!("invalid mode %s for '%s'", entry.mode, &entry.path)))?;
+            for word in [
+                entry.ctime.0,
+                entry.ctime.1,
+                entry.mtime.0,
+                entry.mtime.1,
+                entry.dev,
+                entry.ino,
+                mode,
+                entry.uid,
+                entry.gid,
+                entry.size,
+            ] {
+                out.extend(word.to_be_bytes());
+            }
+            out.extend(decode_sha(&entry.sha, &entry.path)?);
+
+            let mut flags = entry.flags & !(NAME_MASK | FLAG_EXTENDED);
+            flags |= entry.path.len().min(NAME_MASK as usize) as u16;
+            if entry.extended_flags != 0 {
+                flags |= FLAG_EXTENDED;
+            }
+            out.extend(flags.to_be_bytes());
+            if entry.extended_flags != 0 {
+                out.extend(entry.extended_flags.to_be_bytes());
+            }
+
+            if version == 4 {
+                let common = previous
+                    .bytes()
+                    .zip(entry.path.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                write_varint(&mut out, previous.len() - common);
+                out.extend(&entry.path.as_bytes()[common..]);
+                out.push(0);
+            } else {
+                out.extend(entry.path.as_bytes());
+                let length = padded_length(out.len() - start);
+                out.resize(start + length, 0);
+            }
+            previous = &entry.path;
+        }
+
+        for (signature, data) in &self.extensions {
+            if signature == b"TREE" {
+                continue;
+            }
+            out.extend(signature);
+            out.extend((data.len() as u32).to_be_bytes());
+            out.extend(data);
+        }
+        if !self.resolve_undo.is_empty() {
+            let data = serialize_resolve_undo(&self.resolve_undo)?;
+            out.extend(b"REUC");
+            out.extend((data.len() as u32).to_be_bytes());
+            out.extend(data);
+        }
+
+        let checksum = sha1::Sha1::digest(&out);
+        out.extend(checksum);
+        Ok(out)
+    }
+
+    /// Writes the index to `.git/index`, through `.git/index.lock` so that
+    /// it's never seen half written nor written by two commands at once.
+    pub fn write(&self, git_dir: &Path) -> eyre::Result<()> {
+        let content = self.serialize()?;
+        let lock = git_dir.join("index.lock");
+        let (_temporary, mut file) = match Temporary::create(&lock) {
+            Ok(created) => created,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                let path = lock.canonicalize().unwrap_or(lock.to_path_buf());
+                return Err(eyre!(tr!(
+                    "Unable to create '%s': File exists.",
+                    path.display()
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Err(e) = file.write_all(&content) {
+            fs::remove_file(&lock)?;
+            return Err(e.into());
+        }
+        fs::rename(&lock, git_dir.join("index"))?;
+        Ok(())
+    }
+
+    /// Returns the files staged at stage 0, with their mode and blob sha.
+    pub fn files(&self) -> Files {
+        self.entries
+            .iter()
+            .filter(|e| e.stage() == 0)
+            .map(|e| (PathBuf::from(&e.path), (e.mode, e.sha.clone())))
+            .collect()
+    }
+
+    /// Returns true if the worktree file described by `metadata` is known
+    /// to have the content of `entry`: its stat information hasn't changed
+    /// since it was staged. Files modified in the same instant the index was
+    /// written could have changed unnoticed, so they're never known. Files
+    /// assumed unchanged or skipped always are.
+    pub fn is_up_to_date(&self, entry: &Entry, metadata: &fs::Metadata) -> bool {
+        if entry.ignores_worktree() {
+            return true;
+        }
+        let current = Entry::new(String::new(), String::new(), entry.mode, metadata);
+        let racy = self.written.map_or(true, |written| entry.mtime >= written);
+        !racy
+            && current.mtime == entry.mtime
+            && current.ctime == entry.ctime
+            && current.ino == entry.ino
+            && current.size == entry.size
+            && metadata.is_symlink() == (entry.mode == MODE_SYMLINK)
+    }
+
+    /// Returns the entry of `path` at stage 0.
+    pub fn get(&self, path: &str) -> Option<&Entry> {
+        self.get_stage(path, 0)
+    }
+
+    /// Returns the entry of `path` at the merge stage `stage`.
+    pub fn get_stage(&self, path: &str, stage: u16) -> Option<&Entry> {
+        self.position(path, stage).ok().map(|i| &self.entries[i])
+    }
+
+    /// Stages `entry`, replacing the entries of its path, and those of the
+    /// files it replaces: the ones under it if it was a directory, and its
+    /// parents if they were files. Staging a conflicted path at stage 0
+    /// resolves its conflict.
+    pub fn add(&mut self, entry: Entry) {
+        if entry.stage() == 0 {
+            self.record_resolution(&entry.path);
+        }
+        let directory = format!("{}/", entry.path);
+        self.entries.retain(|e| {
+            e.path != entry.path
+                && !e.path.starts_with(&directory)
+                && !entry.path.starts_with(&format!("{}/", e.path))
+        });
+        let position = self
+            .position(&entry.path, entry.stage())
+            .unwrap_or_else(|i| i);
+        self.entries.insert(position, entry);
+    }
+
+    /// Replaces the entries of `path` with the base, our and their versions
+    /// of a conflict, at stages 1 to 3. Versions missing from a side, like
+    /// a file deleted by it, have no entry. The entries have no stat
+    /// information, since none of them is the content of the worktree file.
+    pub fn add_conflict(&mut self, path: &str, versions: &ConflictVersions) {
+        self.entries.retain(|e| e.path != path);
+        self.resolve_undo.remove(path);
+        for (stage, version) in (1..).zip(versions) {
+            let Some((mode, sha)) = version else {
+                continue;
+            };
+            let entry = Entry {
+                mode: *mode,
+                sha: sha.clone(),
+                flags: stage << 12,
+                path: path.to_string(),
+                ..Entry::default()
+            };
+            let position = self.position(path, stage).unwrap_or_else(|i| i);
+            self.entries.insert(position, entry);
+        }
+    }
+
+    /// Recreates the conflict of `path` from the versions recorded when it
+    /// was resolved, returning false if there are none.
+    pub fn unresolve(&mut self, path: &str) -> bool {
+        let Some(versions) = self.resolve_undo.get(path).cloned() else {
+            return false;
+        };
+        self.add_conflict(path, &versions);
+        true
+    }
+
+    /// Unstages `path`, at every stage.
+    pub fn remove(&mut self, path: &str) {
+        self.record_resolution(path);
+        self.entries.retain(|e| e.path != path);
+    }
+
+    /// Records the versions of `path` at stages 1 to 3 in `resolve_undo`,
+    /// if it's conflicted, before its conflict is resolved.
+    fn record_resolution(&mut self, path: &str) {
+        let versions: ConflictVersions =
+            [1, 2, 3].map(|stage| self.get_stage(path, stage).map(|e| (e.mode, e.sha.clone())));
+        if versions.iter().any(Option::is_some) {
+            self.resolve_undo.insert(path.to_string(), versions);
+        }
+    }
+
+    /// Returns the position of the entry of `path` at `stage`, or where it
+    /// would be inserted.
+    fn position(&self, path: &str, stage: u16) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by(|e| (e.path.as_str(), e.stage()).cmp(&(path, stage)))
+    }
+}
+
+/// Stages the files of `pathspec`, given from the directory `prefix` of the
+/// working tree, and the files under them, and unstages the ones removed
+/// from the working tree. With `dry_run` the index is left as it is; with
+/// `verbose` each change is listed.
+pub fn add(
+    git_dir: &Path,
+    config: &Config,
+    prefix: &Path,
+    pathspec: &[PathBuf],
+    dry_run: bool,
+    verbose: bool,
+) -> eyre::Result<()> {
+    let mut index = Index::read(git_dir)?;
+    let specs = repository::resolve_pathspec(prefix, pathspec)?
+        .iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let matches = |path: &str| specs.iter().any(|spec| path_matches(spec, path));
+
+    // Ignored files are only staged if they're tracked already
+    let mut excludes = Excludes::load(git_dir, config)?;
+    let mut files = index
+        .entries
+        .iter()
+        .filter(|e| matches(&e.path) && fs::symlink_metadata(&e.path).is_ok_and(|m| !m.is_dir()))
+        .map(|e| e.path.clone())
+        .collect::<Vec<_>>();
+    let mut ignored = Vec::new();
+    for (spec, path) in specs.iter().zip(pathspec) {
+        let top = spec.trim_end_matches('/');
+        let root = match top.is_empty() {
+            true => Path::new("."),
+            false => Path::new(top),
+        };
+        if !top.is_empty() && excludes.is_excluded(top, root.is_dir()) {
+            if !files.iter().any(|f| path_matches(spec, f)) {
+                ignored.push(path);
+            }
+            continue;
+        }
+        walk(root, top, &mut excludes, &mut files)?;
+    }
+    files.sort();
+    files.dedup();
+
+    // Files the checkout renamed because they collide with others by case
+    // are left as they are staged
+    let renames = worktree::collision_renames(git_dir, config, &index.files())?;
+    files.retain(|file| !renames.values().any(|renamed| renamed == Path::new(file)));
+
+    // Every path must name a file of the working tree or of the index
+    for (spec, path) in specs.iter().zip(pathspec) {
+        let found = ignored.contains(&path)
+            || files.iter().any(|f| path_matches(spec, f))
+            || index.entries.iter().any(|e| path_matches(spec, &e.path));
+        if !found {
+            return Err(eyre!(tr!(
+                "pathspec '%s' did not match any files",
+                path.display()
+            )));
+        }
+    }
+
+    // Files assumed unchanged or skipped, or renamed, are left as they are
+    // staged
+    let removed = index
+        .entries
+        .iter()
+        .filter(|e| !e.ignores_worktree() && !renames.contains_key(Path::new(&e.path)))
+        .map(|e| e.path.clone())
+        .filter(|path| matches(path) && files.binary_search(path).is_err())
+        .collect::<Vec<_>>();
+    for path in removed {
+        if verbose || dry_run {
+            println!("remove '{path}'");
+        }
+        index.remove(&path);
+    }
+
+    for path in files {
+        if index.get(&path).is_some_and(Entry::ignores_worktree) {
+            continue;
+        }
+        let metadata = fs::symlink_metadata(&path)?;
+        let (mode, content) = worktree_file(git_dir, config, &index, &path, &metadata)?;
+
+        let sha = match dry_run {
+            true => git::hash_object("blob", &content).0,
+            false => Odb::new(git_dir).write("blob", &content)?,
+        };
+        let entry = Entry::new(path.clone(), sha.into(), mode, &metadata);
+        let unchanged = index
+            .get(&path)
+            .is_some_and(|e| e.sha == entry.sha && e.mode == entry.mode);
+        if !unchanged && (verbose || dry_run) {
+            println!("add '{path}'");
+        }
+        index.add(entry);
+    }
+
+    if !dry_run {
+        index.write(git_dir)?;
+    }
+    if !ignored.is_empty() {
+        let list = ignored
+            .iter()
+            .map(|path| format!("{}\n", path.display()))
+            .collect::<String>();
+        return Err(eyre!(tr!(
+            "The following paths are ignored by one of your .gitignore files:\n%s",
+            list.trim_end()
+        )));
+    }
+    Ok(())
+}
+
+/// Lists the staged files matching `pathspec`, given from the directory
+/// `prefix` of the working tree, or without pathspec the ones under
+/// `prefix`, relative to it. With `stage`, each entry is listed with its
+/// mode, blob and stage, so conflicted files are listed once per side. With
+/// `tags`, each entry is preceded by its [`Entry::tag`]. Returns the paths of
+/// `pathspec` that matched no file.
+pub fn ls_files(
+    git_dir: &Path,
+    prefix: &Path,
+    pathspec: &[PathBuf],
+    stage: bool,
+    tags: bool,
+) -> eyre::Result<Vec<PathBuf>> {
+    let index = Index::read(git_dir)?;
+    let spec = match prefix.as_os_str().is_empty() {
+        true => String::new(),
+        false => format!("{}/", prefix.to_string_lossy().replace('\\', "/")),
+    };
+    let resolved = repository::resolve_pathspec(prefix, pathspec)?;
+    let matches = |path: &str| match resolved.is_empty() {
+        true => path_matches(&spec, path),
+        false => tree::pathspec_matches(&resolved, Path::new(path)),
+    };
+    let unmatched = pathspec
+        .iter()
+        .zip(&resolved)
+        .filter(|(_, spec)| {
+            !index
+                .entries
+                .iter()
+                .any(|e| tree::pathspec_matches(std::slice::from_ref(*spec), Path::new(&e.path)))
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+
+    let mut previous = None;
+    for entry in index.entries.iter().filter(|e| matches(&e.path)) {
+        let path = repository::relative_path(&entry.path, prefix);
+        let tag = match tags {
+            true => format!("{} ", entry.tag()),
+            false => String::new(),
+        };
+        match stage {
+            true => println!(
+                "{tag}{:06} {} {}\t{path}",
+                entry.mode,
+                entry.sha,
+                entry.stage()
+            ),
+            // Conflicted files are listed once
+            false if previous != Some(&entry.path) => println!("{tag}{path}"),
+            false => {}
+        }
+        previous = Some(&entry.path);
+    }
+    Ok(unmatched)
+}
+
+/// Sets or clears the bits of `update` on the staged files at `paths`, given
+/// from the directory `prefix` of the working tree.
+pub fn update_flags(
+    git_dir: &Path,
+    prefix: &Path,
+    paths: &[PathBuf],
+    update: FlagUpdate,
+) -> eyre::Result<()> {
+    let mut index = Index::read(git_dir)?;
+    let resolved = repository::resolve_pathspec(prefix, paths)?;
+    for (path, name) in paths.iter().zip(&resolved) {
+        let name = name.to_string_lossy().replace('\\', "/");
+        let position = index
+            .position(&name, 0)
+            .map_err(|_| eyre!(tr!("Unable to mark file %s", path.display())))?;
+        let entry = &mut index.entries[position];
+        if let Some(set) = update.assume_unchanged {
+            match set {
+                true => entry.flags |= FLAG_ASSUME_VALID,
+                false => entry.flags &= !FLAG_ASSUME_VALID,
+            }
+        }
+        if let Some(set) = update.skip_worktree {
+            match set {
+                true => entry.extended_flags |= FLAG_SKIP_WORKTREE,
+                false => entry.extended_flags &= !FLAG_SKIP_WORKTREE,
+            }
+        }
+    }
+    index.write(git_dir)
+}
+
+/// Replaces the staged files by the files of the tree `sha`, or by none
+/// without a tree.
+pub fn read_tree(git_dir: &Path, sha: Option<&str>) -> eyre::Result<()> {
+    let mut index = Index::default();
+    if let Some(sha) = sha {
+        for (path, (mode, sha)) in tree::flatten_tree(git_dir, sha)? {
+            index.add(Entry {
+                mode,
+                sha,
+                path: path.to_string_lossy().into_owned(),
+                ..Default::default()
+            });
+        }
+    }
+    index.write(git_dir)
+}
+
+/// Writes the tree objects of the staged files and returns the sha of the
+/// root tree, or of the tree of the directory `prefix`. Files with
+/// conflicts can't be written.
+pub fn write_tree(git_dir: &Path, prefix: Option<&str>) -> eyre::Result<String> {
+    let index = Index::read(git_dir)?;
+    if index.entries.iter().any(|e| e.stage() != 0) {
+        return Err(eyre!(tr!("git-write-tree: error building trees")));
+    }
+    let Some(prefix) = prefix else {
+        return tree::write_tree(git_dir, &index.files());
+    };
+    let dir = Path::new(prefix.trim_end_matches('/'));
+    let files = index
+        .files()
+        .into_iter()
+        .filter_map(|(path, entry)| Some((path.strip_prefix(dir).ok()?.to_path_buf(), entry)))
+        .collect::<Files>();
+    if files.is_empty() {
+        return Err(eyre!(tr!("git-write-tree: prefix %s not found", prefix)));
+    }
+    tree::write_tree(git_dir, &files)
+}
+
+/// Returns the mode of the worktree file at `path` described by `metadata`,
+/// and its content as stored in the repository: the target of symlinks,
+/// and the content of files converted following their attributes.
+pub fn worktree_file(
+    git_dir: &Path,
+    config: &Config,
+    index: &Index,
+    path: &str,
+    metadata: &fs::Metadata,
+) -> eyre::Result<(u32, Vec<u8>)> {
+    if metadata.is_symlink() {
+        let target = fs::read_link(path)?;
+        return Ok((MODE_SYMLINK, target.to_string_lossy().as_bytes().to_vec()));
+    }
+
+    let content = attributes::to_git(git_dir, config, Path::new(path), fs::read(path)?)?;
+    // Without `core.fileMode`, executable bits are kept from the index
+    let file_mode = match config.get("core.filemode") {
+        Some(value) => config::parse_bool(value)?,
+        None => true,
+    };
+    let mode = match (file_mode, index.get(path)) {
+        (false, Some(entry)) if entry.mode != MODE_SYMLINK => entry.mode,
+        (false, _) => MODE_FILE,
+        (true, _) => worktree::file_mode(metadata),
+    };
+    Ok((mode, content))
+}
+
+/// Returns true if the pathspec `spec`, resolved from the top of the
+/// working tree, names `path` or one of its directories.
+fn path_matches(spec: &str, path: &str) -> bool {
+    if spec.is_empty() {
+        return true;
+    }
+    match spec.strip_suffix('/') {
+        Some(directory) => path.starts_with(directory) && path[directory.len()..].starts_with('/'),
+        None => path == spec || path.strip_prefix(spec).is_some_and(|r| r.starts_with('/')),
+    }
+}
+
+/// Lists the files and symlinks at `path`, named `name` from the top of the
+/// working tree, and under it. Git directories are skipped, and so are the
+/// files and directories under it that `excludes` ignores.
+pub fn walk(
+    path: &Path,
+    name: &str,
+    excludes: &mut Excludes,
+    files: &mut Vec<String>,
+) -> eyre::Result<()> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.is_dir() {
+        files.push(name.to_string());
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if file_name == ".git" {
+            continue;
+        }
+        let child = match name.is_empty() {
+            true => file_name,
+            false => format!("{name}/{file_name}"),
+        };
+        if excludes.is_excluded(&child, entry.file_type()?.is_dir()) {
+            continue;
+        }
+        walk(&entry.path(), &child, excludes, files)?;
+    }
+    Ok(())
+}
+
+/// Parses the resolve-undo extension: for each path, the path and the modes
+/// of its three versions in octal, each ending with a NUL, `0` for missing
+/// versions, then the shas of the versions that aren't missing.
+fn parse_resolve_undo(mut data: &[u8]) -> Option<BTreeMap<String, ConflictVersions>> {
+    let next_field = |data: &mut &[u8]| -> Option<String> {
+        let end = data.iter().position(|b| *b == 0)?;
+        let field = String::from_utf8(data[..end].to_vec()).ok();
+        *data = &data[end + 1..];
+        field
+    };
+    let mut resolve_undo = BTreeMap::new();
+    while !data.is_empty() {
+        let path = next_field(&mut data)?;
+        let mut modes = [0; 3];
+        for mode in &mut modes {
+            *mode = next_field(&mut data)?.parse().ok()?;
+        }
+        let mut versions: ConflictVersions = [None, None, None];
+        for (version, mode) in versions.iter_mut().zip(modes) {
+            if mode == 0 {
+                continue;
+            }
+            let sha = hex::encode(data.get(..20)?);
+            data = &data[20..];
+            *version = Some((mode, sha));
+        }
+        resolve_undo.insert(path, versions);
+    }
+    Some(resolve_undo)
+}
+
+/// Serializes the resolve-undo extension, as [`parse_resolve_undo`] reads
+/// it.
+fn serialize_resolve_undo(
+    resolve_undo: &BTreeMap<String, ConflictVersions>,
+) -> eyre::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for (path, versions) in resolve_undo {
+        data.extend(path.as_bytes());
+        data.push(0);
+        for version in versions {
+            let mode = version.as_ref().map_or(0, |(mode, _)| *mode);
+            data.extend(mode.to_string().as_bytes());
+            data.push(0);
+        }
+        for (_, sha) in versions.iter().flatten() {
+            data.extend(decode_sha(sha, path)?);
+        }
+    }
+    Ok(data)
+}
+
+/// Returns the 20 bytes of the `sha` staged for `path`.
+fn decode_sha(sha: &str, path: &str) -> eyre::Result<Vec<u8>> {
+    hex::decode(sha)
+        .ok()
+        .filter(|bytes| bytes.len() == 20)
+        .ok_or_else(|| eyre!(tr!("invalid object %s for '%s'", sha, path)))
+}
+
+/// Returns the length of an entry of `length` bytes once padded with NULs
+/// to a multiple of 8 bytes, with at least one NUL ending its path.
+fn padded_length(length: usize) -> usize {
+    (length + 8) & !7
+}
+
+/// Reads a number encoded in the offset encoding of packs, returning it and
+/// the number of bytes it took.
+fn read_varint(bytes: &[u8]) -> eyre::Result<(usize, usize)> {
+    let corrupt = || eyre!(tr!("index file corrupt"));
+    let mut byte = *bytes.first().ok_or_else(corrupt)?;
+    let mut value = (byte & 0x7f) as usize;
+    let mut length = 1;
+    while byte & 0x80 != 0 {
+        byte = *bytes.get(length).ok_or_else(corrupt)?;
+        value = ((value + 1) << 7) | (byte & 0x7f) as usize;
+        length += 1;
+    }
+    Ok((value, length))
+}
+
+/// Writes a number in the offset encoding of packs.
+fn write_varint(out: &mut Vec<u8>, value: usize) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut value = value >> 7;
+    while value != 0 {
+        value -= 1;
+        bytes.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, sha: char, flags: u16, extended_flags: u16) -> Entry {
+        Entry {
+            ctime: (1, 2),
+            mtime: (3, 4),
+            dev: 5,
+            ino: 6,
+            mode: MODE_FILE,
+            uid: 7,
+            gid: 8,
+            size: 9,
+            sha: sha.to_string().repeat(40),
+            flags,
+            extended_flags,
+            path: path.to_string(),
+        }
+    }
+
+    fn index(version: u32, extended: bool) -> Index {
+        // Entries read with extended flags have the flag telling so
+        let (flags, extended_flags) = match extended {
+            true => (FLAG_EXTENDED, FLAG_SKIP_WORKTREE),
+            false => (0, 0),
+        };
+        let mut resolve_undo = BTreeMap::new();
+        resolve_undo.insert(
+            "dir/resolved".to_string(),
+            [
+                None,
+                Some((MODE_FILE, "b".repeat(40))),
+                Some((MODE_SYMLINK, "c".repeat(40))),
+            ],
+        );
+        Index {
+            version,
+            entries: vec![
+                entry("a-file-with-a-long-name", 'a', FLAG_ASSUME_VALID, 0),
+                entry("dir/file", 'b', flags, extended_flags),
+                entry("dir/file2", 'c', 2 << 12, 0),
+                entry("dir/sub/file", 'd', 0, 0),
+                entry("e", 'e', 0, 0),
+            ],
+            resolve_undo,
+            extensions: vec![(*b"UNTR", b"unknown data".to_vec())],
+            written: None,
+        }
+    }
+
+    #[test]
+    fn indexes_round_trip() -> eyre::Result<()> {
+        for (version, extended) in [(2, false), (3, true), (4, false), (4, true)] {
+            let index = index(version, extended);
+            let parsed = Index::parse(&index.serialize()?)?;
+            assert_eq!(parsed.version, version);
+            assert_eq!(parsed.entries, index.entries);
+            assert_eq!(parsed.resolve_undo, index.resolve_undo);
+            assert_eq!(parsed.extensions, index.extensions);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn versions_follow_the_extended_flags() -> eyre::Result<()> {
+        assert_eq!(Index::parse(&index(2, true).serialize()?)?.version, 3);
+        assert_eq!(Index::parse(&index(3, false).serialize()?)?.version, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_indexes_are_errors() -> eyre::Result<()> {
+        let mut data = index(2, false).serialize()?;
+        data[40] ^= 1;
+        assert!(Index::parse(&data).is_err());
+        assert!(Index::parse(&data[..data.len() - 1]).is_err());
+        assert!(Index::parse(b"DIRC").is_err());
+
+        let mut index = index(2, false);
+        index.entries[0].sha = "not a sha".to_string();
+        assert!(index.serialize().is_err());
+        index.entries[0].sha = "a".repeat(40);
+        index.entries[0].mode = 100648;
+        assert!(index.serialize().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn varints_round_trip() -> eyre::Result<()> {
+        for value in [0, 1, 127, 128, 255, 16511, 16512, 1 << 30] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            assert_eq!(read_varint(&out)?, (value, out.len()));
+        }
+        Ok(())
+    }
+}
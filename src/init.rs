@@ -0,0 +1,104 @@
+use crate::config::{self, Config};
+use crate::repository;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How `init` creates the repository.
+#[derive(Debug, Default)]
+pub struct InitOptions {
+    /// The template directory, overriding `init.templateDir`.
+    pub template: Option<PathBuf>,
+    /// Where to put the git directory instead of `.git`, which becomes a
+    /// file pointing to it.
+    pub separate_git_dir: Option<PathBuf>,
+}
+
+/// Creates an empty repository at `.git` in the directory `dir`, with the
+/// content of the template directory if there's one. Run again, it keeps what the repository has
+/// and only adds what's missing, like new template files, moving the git
+/// directory if asked to. Returns the git directory, and whether it
+/// already existed.
+pub fn init(dir: &Path, config: &Config, options: &InitOptions) -> eyre::Result<(PathBuf, bool)> {
+    let dot_git = &dir.join(".git");
+    let existing = match dot_git.is_file() {
+        true => repository::read_gitfile(dot_git)?,
+        false => dot_git.is_dir().then(|| dot_git.to_path_buf()),
+    };
+    let reinitialized = existing
+        .as_ref()
+        .is_some_and(|git_dir| git_dir.join("HEAD").is_file());
+
+    let git_dir = match &options.separate_git_dir {
+        Some(separate) => {
+            match existing {
+                Some(existing) if !separate.exists() => {
+                    if let Some(parent) = separate.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::rename(existing, separate)?;
+                }
+                _ => fs::create_dir_all(separate)?,
+            }
+            let separate = separate.canonicalize()?;
+            fs::write(dot_git, format!("gitdir: {}\n", separate.display()))?;
+            separate
+        }
+        None => existing.unwrap_or(dot_git.to_path_buf()),
+    };
+    fs::create_dir_all(git_dir.join("objects"))?;
+    fs::create_dir_all(git_dir.join("refs"))?;
+    if !git_dir.join("HEAD").exists() {
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n")?;
+    }
+
+    if let Some(template) = template_dir(config, options.template.as_deref()) {
+        copy_template(&template, &git_dir)?;
+    }
+    Ok((git_dir, reinitialized))
+}
+
+/// Returns the template directory: `--template`, else `GIT_TEMPLATE_DIR`,
+/// else `init.templateDir`. An empty one means no template.
+fn template_dir(config: &Config, template: Option<&Path>) -> Option<PathBuf> {
+    let dir = match template {
+        Some(template) => template.to_path_buf(),
+        None => match std::env::var_os("GIT_TEMPLATE_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => config::expand_home(config.get("init.templateDir")?),
+        },
+    };
+    (!dir.as_os_str().is_empty()).then_some(dir)
+}
+
+/// Copies the template directory `from` into the git directory `to`, like
+/// its hooks and `info/exclude`, keeping their permissions. Files starting
+/// with a dot are skipped, and files already in the git directory are left
+/// as they are. A missing template is ignored, like git warns and goes on.
+fn copy_template(from: &Path, to: &Path) -> eyre::Result<()> {
+    let Ok(entries) = fs::read_dir(from) else {
+        eprintln!(
+            "{}",
+            tr!("warning: templates not found in %s", from.display())
+        );
+        return Ok(());
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let target = to.join(entry.file_name());
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            fs::create_dir_all(&target)?;
+            copy_template(&entry.path(), &target)?;
+        } else if target.symlink_metadata().is_ok() {
+            continue;
+        } else if kind.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,287 @@
+use crate::diff::{self, Hunk};
+use crate::git;
+use crate::odb::Odb;
+use crate::tree::Change;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+/// The wording of the questions asked while selecting hunks, which depends
+/// on what is done with the selected ones.
+pub struct PatchMode {
+    pub hunk: &'static str,
+    pub mode_change: &'static str,
+    pub deletion: &'static str,
+    pub addition: &'static str,
+    /// The help of the `y`, `n`, `q`, `a` and `d` answers.
+    pub help: [&'static str; 5],
+}
+
+/// The questions of `stash push -p`.
+pub const STASH: PatchMode = PatchMode {
+    hunk: "Stash this hunk",
+    mode_change: "Stash mode change",
+    deletion: "Stash deletion",
+    addition: "Stash addition",
+    help: [
+        "y - stash this hunk",
+        "n - do not stash this hunk",
+        "q - quit; do not stash this hunk or any of the remaining ones",
+        "a - stash this hunk and all later hunks in the file",
+        "d - do not stash this hunk or any of the later hunks in the file",
+    ],
+};
+
+/// The questions of `checkout -p` and `restore -p` restoring from `HEAD`.
+pub const DISCARD: PatchMode = PatchMode {
+    hunk: "Discard this hunk from worktree",
+    mode_change: "Discard mode change from worktree",
+    deletion: "Discard deletion from worktree",
+    addition: "Discard addition from worktree",
+    help: [
+        "y - discard this hunk from worktree",
+        "n - do not discard this hunk from worktree",
+        "q - quit; do not discard this hunk or any of the remaining ones",
+        "a - discard this hunk and all later hunks in the file",
+        "d - do not discard this hunk or any of the later hunks in the file",
+    ],
+};
+
+/// The questions of `checkout -p` and `restore -p` restoring from another
+/// commit.
+pub const APPLY: PatchMode = PatchMode {
+    hunk: "Apply this hunk to worktree",
+    mode_change: "Apply mode change to worktree",
+    deletion: "Apply deletion to worktree",
+    addition: "Apply addition to worktree",
+    help: [
+        "y - apply this hunk to worktree",
+        "n - do not apply this hunk to worktree",
+        "q - quit; do not apply this hunk or any of the remaining ones",
+        "a - apply this hunk and all later hunks in the file",
+        "d - do not apply this hunk or any of the later hunks in the file",
+    ],
+};
+
+/// The questions of `restore --staged -p` restoring from `HEAD`.
+pub const UNSTAGE: PatchMode = PatchMode {
+    hunk: "Unstage this hunk",
+    mode_change: "Unstage mode change",
+    deletion: "Unstage deletion",
+    addition: "Unstage addition",
+    help: [
+        "y - unstage this hunk",
+        "n - do not unstage this hunk",
+        "q - quit; do not unstage this hunk or any of the remaining ones",
+        "a - unstage this hunk and all later hunks in the file",
+        "d - do not unstage this hunk or any of the later hunks in the file",
+    ],
+};
+
+/// The questions of `restore --staged -p` restoring from another commit.
+pub const APPLY_TO_INDEX: PatchMode = PatchMode {
+    hunk: "Apply this hunk to index",
+    mode_change: "Apply mode change to index",
+    deletion: "Apply deletion to index",
+    addition: "Apply addition to index",
+    help: [
+        "y - apply this hunk to index",
+        "n - do not apply this hunk to index",
+        "q - quit; do not apply this hunk or any of the remaining ones",
+        "a - apply this hunk and all later hunks in the file",
+        "d - do not apply this hunk or any of the later hunks in the file",
+    ],
+};
+
+/// A change split in two by the hunks selected from it.
+#[derive(Debug)]
+pub struct Split {
+    /// The file with only the selected parts of the change applied.
+    pub selected: Option<(u32, String)>,
+    /// The file with only the parts not selected applied.
+    pub rest: Option<(u32, String)>,
+}
+
+/// Asks on stdin which hunks of changes to select, like `git add -p`.
+pub struct HunkSelector {
+    mode: &'static PatchMode,
+    /// Set once the user quits, leaving the remaining hunks unselected.
+    quit: bool,
+}
+
+/// The answer to a question about a hunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Answer {
+    Yes,
+    No,
+    Quit,
+    All,
+    Done,
+}
+
+impl HunkSelector {
+    pub fn new(mode: &'static PatchMode) -> Self {
+        Self { mode, quit: false }
+    }
+
+    /// Shows the hunks of `change` and asks which ones to select. Mode
+    /// changes, and additions or deletions of whole files, make a single
+    /// hunk.
+    pub fn split(&mut self, git_dir: &Path, change: &Change) -> eyre::Result<Split> {
+        let unselected = || Split {
+            selected: change.old.clone(),
+            rest: change.new.clone(),
+        };
+        if self.quit {
+            return Ok(unselected());
+        }
+
+        let patch = diff::patch(git_dir, change)?;
+        let (Some(old), Some(new)) = (&change.old, &change.new) else {
+            // Whole files are shown at once
+            print!("{patch}");
+            let question = match change.old {
+                Some(_) => self.mode.deletion,
+                None => self.mode.addition,
+            };
+            return match self.ask(question, 1, 1)? {
+                Answer::Yes | Answer::All => Ok(Split {
+                    selected: change.new.clone(),
+                    rest: change.old.clone(),
+                }),
+                _ => Ok(unselected()),
+            };
+        };
+
+        // Mode changes are shown with their question
+        let (modes, header) = patch
+            .split_inclusive('\n')
+            .take_while(|line| !line.starts_with("@@") && !line.starts_with("Binary"))
+            .partition::<Vec<_>, _>(|line| {
+                line.starts_with("old mode") || line.starts_with("new mode")
+            });
+        print!("{}", header.concat());
+
+        let (old_content, new_content) = match old.1 == new.1 {
+            true => (Vec::new(), Vec::new()),
+            false => (
+                git::read_blob(git_dir, &old.1)?,
+                git::read_blob(git_dir, &new.1)?,
+            ),
+        };
+        let binary = diff::is_binary(&old_content) || diff::is_binary(&new_content);
+        let (a, b) = (diff::lines(&old_content), diff::lines(&new_content));
+        let hunks = match binary {
+            true => Vec::new(),
+            false => diff::hunks(&a, &b, 3),
+        };
+
+        // A mode change comes first, binary content is a single hunk
+        let mode_changed = old.0 != new.0;
+        let content_changed = old.1 != new.1;
+        let total = mode_changed as usize
+            + match binary {
+                true => content_changed as usize,
+                false => hunks.len(),
+            };
+        // `a`, `d` and `q` answer for the remaining hunks of the file
+        let questions = self.mode;
+        let mut number = 0;
+        let mut pending = None;
+        let mut next = |selector: &mut Self, question: &str| -> eyre::Result<bool> {
+            number += 1;
+            let answer = match pending {
+                Some(answer) => answer,
+                None => selector.ask(question, number, total)?,
+            };
+            pending = match answer {
+                Answer::All => Some(Answer::Yes),
+                Answer::Done | Answer::Quit => Some(Answer::No),
+                _ => pending,
+            };
+            Ok(matches!(answer, Answer::Yes | Answer::All))
+        };
+
+        print!("{}", modes.concat());
+        let mode = match mode_changed && next(self, questions.mode_change)? {
+            true => (new.0, old.0),
+            false => (old.0, new.0),
+        };
+        let content = if binary {
+            if content_changed {
+                println!("Binary files a/{0} and b/{0} differ", change.path.display());
+            }
+            match content_changed && next(self, questions.hunk)? {
+                true => (new.1.clone(), old.1.clone()),
+                false => (old.1.clone(), new.1.clone()),
+            }
+        } else {
+            let mut selected = Vec::new();
+            for hunk in &hunks {
+                print!("{hunk}");
+                selected.push(next(self, questions.hunk)?);
+            }
+            let rest = selected.iter().map(|s| !s).collect::<Vec<_>>();
+            (
+                apply(git_dir, &a, &hunks, &selected, &old.1)?,
+                apply(git_dir, &a, &hunks, &rest, &old.1)?,
+            )
+        };
+
+        Ok(Split {
+            selected: Some((mode.0, content.0)),
+            rest: Some((mode.1, content.1)),
+        })
+    }
+
+    /// Asks `question` about hunk `number` of `total` until a valid answer
+    /// is given. The end of the input quits.
+    fn ask(&mut self, question: &str, number: usize, total: usize) -> eyre::Result<Answer> {
+        let stdin = std::io::stdin();
+        loop {
+            print!("({number}/{total}) {} [y,n,q,a,d,?]? ", tr!(question));
+            std::io::stdout().flush()?;
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                println!();
+                self.quit = true;
+                return Ok(Answer::Quit);
+            }
+            let answer = match line.trim().chars().next() {
+                Some('y') => Answer::Yes,
+                Some('n') => Answer::No,
+                Some('q') => Answer::Quit,
+                Some('a') => Answer::All,
+                Some('d') => Answer::Done,
+                _ => {
+                    for help in self.mode.help {
+                        println!("{}", tr!(help));
+                    }
+                    println!("{}", tr!("? - print help"));
+                    continue;
+                }
+            };
+            if answer == Answer::Quit {
+                self.quit = true;
+            }
+            return Ok(answer);
+        }
+    }
+}
+
+/// Writes the blob of the lines `old` with the `selected` hunks applied,
+/// returning `sha`, the blob of `old`, if none is.
+fn apply(
+    git_dir: &Path,
+    old: &[&[u8]],
+    hunks: &[Hunk],
+    selected: &[bool],
+    sha: &str,
+) -> eyre::Result<String> {
+    if !selected.contains(&true) {
+        return Ok(sha.to_string());
+    }
+    Odb::new(git_dir)
+        .write("blob", &diff::apply_hunks(old, hunks, selected))
+        .map(String::from)
+}
@@ -0,0 +1,188 @@
+use crate::filter::{Filter, Outcome};
+use eyre::eyre;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The first line of pointer files.
+const POINTER_VERSION: &str = "version https://git-lfs.github.com/spec/v1";
+
+/// Git LFS: files are stored in `lfs/objects` of the git dir, keyed by the sha-256 of
+/// their content, with small pointer files in the repository in their place.
+/// Objects are only read from the local store, pointers to missing ones
+/// being checked out as they are.
+#[derive(Debug)]
+pub struct Lfs {
+    /// The git directory of the repository, holding the object store.
+    git_dir: PathBuf,
+    /// The pointers of the files whose checkout is delayed, by path.
+    delayed: BTreeMap<String, Vec<u8>>,
+}
+
+/// A pointer file, naming an object by sha-256 and size.
+#[derive(Debug, PartialEq, Eq)]
+struct Pointer {
+    oid: String,
+    size: u64,
+}
+
+impl Pointer {
+    fn parse(content: &[u8]) -> Option<Self> {
+        let content = std::str::from_utf8(content).ok()?;
+        let mut lines = content.lines();
+        if lines.next()? != POINTER_VERSION {
+            return None;
+        }
+        let oid = lines.next()?.strip_prefix("oid sha256:")?;
+        let size = lines.next()?.strip_prefix("size ")?.parse().ok()?;
+        Some(Self {
+            oid: oid.to_string(),
+            size,
+        })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        format!(
+            "{POINTER_VERSION}\noid sha256:{}\nsize {}\n",
+            self.oid, self.size
+        )
+        .into_bytes()
+    }
+
+    /// Returns where the object is stored, `objects/aa/bb/aabb...`.
+    fn path(&self, git_dir: &Path) -> PathBuf {
+        git_dir
+            .join("lfs/objects")
+            .join(&self.oid[..2])
+            .join(&self.oid[2..4])
+            .join(&self.oid)
+    }
+}
+
+impl Lfs {
+    pub fn new(git_dir: &Path) -> Self {
+        Self {
+            git_dir: git_dir.to_path_buf(),
+            delayed: BTreeMap::new(),
+        }
+    }
+}
+
+impl Filter for Lfs {
+    fn clean(&mut self, _path: &str, content: Vec<u8>) -> eyre::Result<Vec<u8>> {
+        // Pointers are already clean
+        if Pointer::parse(&content).is_some() {
+            return Ok(content);
+        }
+
+        let pointer = Pointer {
+            oid: hex::encode(sha256(&content)),
+            size: content.len() as u64,
+        };
+        let path = pointer.path(&self.git_dir);
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, &content)?;
+        }
+        Ok(pointer.serialize())
+    }
+
+    fn smudge(&mut self, path: &str, content: Vec<u8>, can_delay: bool) -> eyre::Result<Outcome> {
+        // Objects are read in one batch, once git has handed all of them
+        if can_delay {
+            self.delayed.insert(path.to_string(), content);
+            return Ok(Outcome::Delayed);
+        }
+
+        let content = self.delayed.remove(path).unwrap_or(content);
+        let Some(pointer) = Pointer::parse(&content) else {
+            return Ok(Outcome::Done(content));
+        };
+        match fs::read(pointer.path(&self.git_dir)) {
+            Ok(object) if object.len() as u64 == pointer.size => Ok(Outcome::Done(object)),
+            Ok(_) => Err(eyre!(tr!("corrupt LFS object %s", &pointer.oid))),
+            Err(_) => Ok(Outcome::Done(content)),
+        }
+    }
+
+    fn available(&mut self) -> eyre::Result<Vec<String>> {
+        Ok(self.delayed.keys().cloned().collect())
+    }
+}
+
+/// Returns the sha-256 digest of `data`.
+fn sha256(data: &[u8]) -> [u8; 32] {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    // The message is padded with a 1 bit, zeros and its length in bits to a
+    // multiple of 64 bytes
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(majority);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 32];
+    for (chunk, s) in digest.chunks_mut(4).zip(state) {
+        chunk.copy_from_slice(&s.to_be_bytes());
+    }
+    digest
+}
@@ -0,0 +1,83 @@
+//! A git implementation. The [`Repository`] type opens and creates
+//! repositories and reads and writes their objects and refs, and the
+//! modules have the rest of what the `git` command line does with them.
+
+#[macro_use]
+mod i18n;
+
+pub mod attributes;
+pub mod bisect;
+pub mod blame;
+pub mod branch;
+pub mod cat_file;
+pub mod clone;
+mod column;
+pub mod commit;
+pub mod commit_graph;
+pub mod config;
+pub mod credential;
+mod daemon;
+pub mod diff;
+mod exclude;
+pub mod fetch;
+pub mod filter;
+pub mod for_each;
+pub mod fsck;
+pub mod git;
+pub mod graph;
+pub mod hooks;
+mod http;
+pub mod index;
+pub mod init;
+mod interactive;
+pub mod lfs;
+pub mod log;
+pub mod mailmap;
+pub mod merge;
+pub mod midx;
+mod negotiator;
+mod netrc;
+pub mod odb;
+pub mod pack;
+pub mod partial;
+pub mod pktline;
+pub mod progress;
+pub mod pull;
+pub mod push;
+pub mod rebase;
+pub mod receive_pack;
+pub mod ref_filter;
+pub mod reflog;
+pub mod refs;
+pub mod remote;
+pub mod repository;
+pub mod reset;
+pub mod restore;
+pub mod rev_list;
+pub mod revision;
+pub mod revwalk;
+pub mod search;
+pub mod server;
+mod shallow;
+pub mod signature;
+mod ssh;
+pub mod stash;
+pub mod stats;
+pub mod status;
+pub mod tag;
+pub mod transport;
+pub mod tree;
+pub mod upload_pack;
+mod wildmatch;
+pub mod worktree;
+
+pub use repository::Repository;
+
+// For the program, which shares the messages, the measures and the cleanup
+// of the temporary files with the library
+#[doc(hidden)]
+pub use i18n::translate;
+#[doc(hidden)]
+pub mod measure;
+#[doc(hidden)]
+pub mod tempfile;
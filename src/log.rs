@@ -0,0 +1,533 @@
+use crate::blame;
+use crate::commit::{self, Commit};
+use crate::config::Config;
+use crate::diff;
+use crate::git::{self, GitFile};
+use crate::mailmap::Mailmap;
+use crate::refs;
+use crate::revision;
+use crate::revwalk::RevWalk;
+use crate::search::MessageFilter;
+#[cfg(feature = "search-index")]
+use crate::search::SearchIndex;
+use crate::tree::{self, MODE_GITLINK, MODE_TREE};
+use eyre::eyre;
+use regex::Regex;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+
+/// Which commits `log` shows, and how.
+#[derive(Debug, Default)]
+pub struct LogOptions {
+    /// Stops after that many commits.
+    pub max_count: Option<usize>,
+    /// Shows each commit as its abbreviated sha and summary.
+    pub oneline: bool,
+    /// Only shows the commits whose message this filter selects.
+    pub grep: MessageFilter,
+    /// The `-L` ranges of lines whose history is shown instead, like
+    /// `10,20:src/main.rs` or `:main:src/main.rs`.
+    pub line_ranges: Vec<String>,
+}
+
+/// Prints the commits reachable from `revisions`, `HEAD` by default, newest
+/// first by committer date, in the log output encoding.
+pub fn log(
+    git_dir: &Path,
+    config: &Config,
+    revisions: &[String],
+    options: &LogOptions,
+) -> eyre::Result<()> {
+    let head = ["HEAD".to_string()];
+    let revisions = match revisions.is_empty() {
+        true => &head[..],
+        false => revisions,
+    };
+    if revisions == head && refs::read_ref(git_dir, "HEAD")?.is_none() {
+        let branch = refs::symbolic_target(git_dir, "HEAD")?.unwrap_or_default();
+        let branch = branch.strip_prefix("refs/heads/").unwrap_or(&branch);
+        return Err(eyre!(tr!(
+            "your current branch '%s' does not have any commits yet",
+            branch
+        )));
+    }
+
+    let (include, exclude) = revision::resolve_ranges(git_dir, revisions)?;
+    if !options.line_ranges.is_empty() {
+        return line_log(git_dir, config, &include, &exclude, options);
+    }
+    let mut walk = RevWalk::new();
+    let commits = walk.list(git_dir, &include, &exclude)?;
+    let mailmap = Mailmap::load(config);
+    let encoding = commit::log_output_encoding(config);
+    let mut stdout = std::io::stdout().lock();
+    let count = options.max_count.unwrap_or(usize::MAX);
+
+    // The search index rules out the commits whose message can't match
+    // without reading them
+    #[cfg(feature = "search-index")]
+    let index = match options.grep.is_empty() {
+        true => None,
+        false => SearchIndex::load(git_dir)?,
+    };
+    #[cfg(feature = "search-index")]
+    let candidates = index
+        .as_ref()
+        .and_then(|index| index.candidates(&options.grep));
+
+    let mut shown = 0;
+    for sha in &commits {
+        if shown == count {
+            break;
+        }
+        #[cfg(feature = "search-index")]
+        if candidates.as_ref().is_some_and(|may_match| !may_match(sha)) {
+            continue;
+        }
+        let commit = walk.commit(git_dir, sha)?;
+        if !options.grep.matches(&commit.message) {
+            continue;
+        }
+        let out = match options.oneline {
+            true => format!("{} {}\n", &sha[..7], commit.summary()),
+            // Commits are separated by a blank line
+            false if shown > 0 => format!("\n{}", format_header(&mailmap, sha, commit)),
+            false => format_header(&mailmap, sha, commit),
+        };
+        stdout.write_all(&commit::encode(&out, encoding)?)?;
+        shown += 1;
+    }
+    Ok(())
+}
+
+/// The ranges of lines `log -L` follows in a commit, by path, as the lines
+/// from `start` to `end` excluded, from 0.
+type LineRanges = BTreeMap<String, Vec<(usize, usize)>>;
+
+/// Shows the commits changing the `-L` ranges of lines of `options`, newest
+/// first, each with the diff of the lines, following the lines through
+/// the changes of their file from the commit `include` resolves to.
+fn line_log(
+    git_dir: &Path,
+    config: &Config,
+    include: &[String],
+    exclude: &[String],
+    options: &LogOptions,
+) -> eyre::Result<()> {
+    let tip = match include {
+        [tip] => tip,
+        _ => return Err(eyre!(tr!("more than one commit to dig from"))),
+    };
+    let mut walk = RevWalk::new();
+    let excluded = walk
+        .list(git_dir, exclude, &[])?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let mut ranges = LineRanges::new();
+    for spec in &options.line_ranges {
+        // Function names are between colons, ranges before one
+        let (range, path) = match spec.strip_prefix(':') {
+            Some(rest) => rest.split_once(':'),
+            None => spec.split_once(':'),
+        }
+        .ok_or(eyre!(tr!("invalid -L argument '%s'", spec)))?;
+        let content = file_content(git_dir, &mut walk, tip, path)?
+            .ok_or(eyre!(tr!("There is no path %s in the commit", path)))?;
+        let lines = diff::lines(&content);
+        let range = match spec.starts_with(':') {
+            true => function_range(range, &lines, spec)?,
+            false => blame::parse_range(range, lines.len(), path)?,
+        };
+        let file = ranges.entry(path.to_string()).or_default();
+        file.push(range);
+        *file = merge_ranges(std::mem::take(file));
+    }
+
+    let mailmap = Mailmap::load(config);
+    let encoding = commit::log_output_encoding(config);
+    let mut stdout = std::io::stdout().lock();
+    let count = options.max_count.unwrap_or(usize::MAX);
+    let mut shown = 0;
+    let mut queue = BinaryHeap::from([(walk.commit(git_dir, tip)?.committer.time, tip.clone())]);
+    let mut pending = HashMap::from([(tip.clone(), ranges)]);
+    'commits: while let Some((_, sha)) = queue.pop() {
+        if shown == count {
+            break;
+        }
+        let Some(ranges) = pending.remove(&sha) else {
+            continue;
+        };
+        if excluded.contains(&sha) {
+            continue;
+        }
+        let commit = walk.commit(git_dir, &sha)?.clone();
+
+        // Merges changing the lines from every parent show no diff and
+        // pass them to all their parents, but the ones where they're
+        // unchanged from a parent only pass them to it
+        let mut traced = Vec::new();
+        let parents = match commit.parents.is_empty() {
+            true => vec![None],
+            false => commit.parents.iter().map(Some).collect(),
+        };
+        for parent in parents {
+            let mut patch = Vec::new();
+            let mut parent_ranges = LineRanges::new();
+            for (path, ranges) in &ranges {
+                let new = file_content(git_dir, &mut walk, &sha, path)?.unwrap_or_default();
+                let old = match parent {
+                    Some(parent) => file_content(git_dir, &mut walk, parent, path)?,
+                    None => None,
+                };
+                let (diff, old_ranges) = trace_ranges(path, old.as_deref(), &new, ranges);
+                patch.extend(diff);
+                if !old_ranges.is_empty() {
+                    parent_ranges.insert(path.clone(), old_ranges);
+                }
+            }
+            if let (true, Some(parent)) = (patch.is_empty(), parent) {
+                push_ranges(
+                    git_dir,
+                    &mut walk,
+                    &mut queue,
+                    &mut pending,
+                    parent,
+                    parent_ranges,
+                )?;
+                continue 'commits;
+            }
+            traced.push((parent, patch, parent_ranges));
+        }
+        let mut patch = Vec::new();
+        for (parent, parent_patch, parent_ranges) in traced {
+            if let Some(parent) = parent {
+                push_ranges(
+                    git_dir,
+                    &mut walk,
+                    &mut queue,
+                    &mut pending,
+                    parent,
+                    parent_ranges,
+                )?;
+            }
+            if commit.parents.len() < 2 {
+                patch = parent_patch;
+            }
+        }
+
+        if !options.grep.matches(&commit.message) {
+            continue;
+        }
+        let header = match options.oneline {
+            true => format!("{} {}\n", &sha[..7], commit.summary()),
+            // Commits are separated by a blank line
+            false if shown > 0 => format!("\n{}", format_header(&mailmap, &sha, &commit)),
+            false => format_header(&mailmap, &sha, &commit),
+        };
+        stdout.write_all(&commit::encode(&header, encoding)?)?;
+        writeln!(stdout)?;
+        stdout.write_all(&patch)?;
+        shown += 1;
+    }
+    Ok(())
+}
+
+/// Adds `ranges` to the ranges to follow into the commit `sha`, queueing
+/// it by committer date.
+fn push_ranges(
+    git_dir: &Path,
+    walk: &mut RevWalk,
+    queue: &mut BinaryHeap<(i64, String)>,
+    pending: &mut HashMap<String, LineRanges>,
+    sha: &str,
+    ranges: LineRanges,
+) -> eyre::Result<()> {
+    if ranges.is_empty() {
+        return Ok(());
+    }
+    let followed = pending.entry(sha.to_string()).or_default();
+    for (path, ranges) in ranges {
+        let file = followed.entry(path).or_default();
+        file.extend(ranges);
+        *file = merge_ranges(std::mem::take(file));
+    }
+    queue.push((walk.commit(git_dir, sha)?.committer.time, sha.to_string()));
+    Ok(())
+}
+
+/// Returns the content of the file at `path` in the commit `sha`, if it
+/// has the file.
+fn file_content(
+    git_dir: &Path,
+    walk: &mut RevWalk,
+    sha: &str,
+    path: &str,
+) -> eyre::Result<Option<Vec<u8>>> {
+    let tree = walk.commit(git_dir, sha)?.tree.clone();
+    match tree::entry_at(git_dir, &tree, Path::new(path))? {
+        Some((mode, blob)) if mode != MODE_TREE && mode != MODE_GITLINK => {
+            Ok(Some(git::read_object(git_dir, &blob)?.1))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Sorts `ranges` and merges the ones overlapping or touching.
+fn merge_ranges(mut ranges: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    ranges.sort();
+    let mut merged = Vec::<(usize, usize)>::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Returns the range of the function `pattern` matches the name of in
+/// `lines`: from the first line starting like a function definition,
+/// with a letter, `_` or `$`, that it matches, to the next such line.
+fn function_range(pattern: &str, lines: &[&[u8]], spec: &str) -> eyre::Result<(usize, usize)> {
+    let regex = Regex::new(pattern)?;
+    let is_definition = |line: &[u8]| matches!(line.first(), Some(c) if c.is_ascii_alphabetic() || *c == b'_' || *c == b'$');
+    let start = lines
+        .iter()
+        .position(|line| is_definition(line) && regex.is_match(&String::from_utf8_lossy(line)))
+        .ok_or(eyre!(tr!("-L parameter '%s': no match", spec)))?;
+    let end = (start + 1..lines.len())
+        .find(|i| is_definition(lines[*i]))
+        .unwrap_or(lines.len());
+    Ok((start, end))
+}
+
+/// Returns the diff of the lines in `ranges` of `new`, the content of the
+/// file at `path` in a commit, against `old`, its content in the parent if
+/// it has the file, with the ranges of `old` the lines come from. The diff
+/// is empty if the lines didn't change.
+fn trace_ranges(
+    path: &str,
+    old: Option<&[u8]>,
+    new: &[u8],
+    ranges: &[(usize, usize)],
+) -> (Vec<u8>, Vec<(usize, usize)>) {
+    let new_lines = diff::lines(new);
+    let old_lines = old.map(diff::lines).unwrap_or_default();
+    let pairs = match old {
+        Some(_) => diff::matching(&old_lines, &new_lines),
+        None => Vec::new(),
+    };
+    let old_of = pairs
+        .iter()
+        .map(|(i, j)| (*j, *i))
+        .collect::<HashMap<_, _>>();
+    let unchanged = pairs.iter().map(|(i, _)| *i).collect::<HashSet<_>>();
+
+    let mut hunks = Vec::new();
+    let mut old_ranges = Vec::new();
+    for &(start, end) in ranges {
+        // Ranges starting or ending in a change take all of it
+        let old_start = match (old, old_of.get(&start)) {
+            (None, _) => 0,
+            (_, Some(i)) => *i,
+            _ => pairs
+                .iter()
+                .rev()
+                .find(|(_, j)| *j < start)
+                .map_or(0, |(i, _)| i + 1),
+        };
+        let old_end = match (old, old_of.get(&(end - 1))) {
+            (None, _) => 0,
+            (_, Some(i)) => i + 1,
+            _ => pairs
+                .iter()
+                .find(|(_, j)| *j >= end)
+                .map_or(old_lines.len(), |(i, _)| *i),
+        };
+        if old_start < old_end {
+            old_ranges.push((old_start, old_end));
+        }
+        let changed = (start..end).any(|j| !old_of.contains_key(&j))
+            || (old_start..old_end).any(|i| !unchanged.contains(&i));
+        if !changed {
+            continue;
+        }
+
+        // Empty ranges start at the line preceding them
+        let old_header = match old_end - old_start {
+            0 => format!("{old_start},0"),
+            count => format!("{},{count}", old_start + 1),
+        };
+        hunks.extend(format!("@@ -{old_header} +{},{} @@\n", start + 1, end - start).bytes());
+        let (mut i, mut j) = (old_start, start);
+        let within = pairs
+            .iter()
+            .filter(|(i, j)| (old_start..old_end).contains(i) && (start..end).contains(j));
+        for &(old_line, new_line) in within.chain([&(old_end, end)]) {
+            for line in &old_lines[i..old_line] {
+                push_line(&mut hunks, b'-', line);
+            }
+            for line in &new_lines[j..new_line] {
+                push_line(&mut hunks, b'+', line);
+            }
+            if new_line < end {
+                push_line(&mut hunks, b' ', new_lines[new_line]);
+            }
+            (i, j) = (old_line + 1, new_line + 1);
+        }
+    }
+
+    if hunks.is_empty() {
+        return (hunks, old_ranges);
+    }
+    let old_name = match old {
+        Some(_) => format!("a/{path}"),
+        None => "/dev/null".to_string(),
+    };
+    let mut patch =
+        format!("diff --git a/{path} b/{path}\n--- {old_name}\n+++ b/{path}\n").into_bytes();
+    patch.extend(hunks);
+    (patch, merge_ranges(old_ranges))
+}
+
+/// Adds a line of a diff, marked with `marker`, ending it with a newline
+/// if it has none.
+fn push_line(out: &mut Vec<u8>, marker: u8, line: &[u8]) {
+    out.push(marker);
+    out.extend(line);
+    if !line.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+}
+
+/// Shows the objects `names` resolve to, in order: commits with the changes
+/// they introduce, the other objects as `cat-file -p` does. With ranges like
+/// `A..B` or exclusions like `^A`, the selected commits are shown instead,
+/// newest first.
+pub fn show(git_dir: &Path, config: &Config, names: &[String]) -> eyre::Result<()> {
+    let mut walk = RevWalk::new();
+    let shas = revision::resolve_objects(git_dir, &mut walk, names)?;
+
+    let mailmap = Mailmap::load(config);
+    let encoding = commit::log_output_encoding(config);
+    let mut stdout = std::io::stdout().lock();
+    let mut shown_commit = false;
+    for sha in shas {
+        if git::read_object(git_dir, &sha)?.0 != "commit" {
+            write!(stdout, "{}", GitFile::read(git_dir, &sha)?)?;
+            continue;
+        }
+        // Commits are separated by a blank line
+        if shown_commit {
+            writeln!(stdout)?;
+        }
+        shown_commit = true;
+        let commit = walk.commit(git_dir, &sha)?.clone();
+        let header = format_header(&mailmap, &sha, &commit);
+        stdout.write_all(&commit::encode(&header, encoding)?)?;
+
+        // Like git, merges show no diff, and root commits show their whole
+        // tree as added
+        let parent_tree = match commit.parents.as_slice() {
+            [] => git::EMPTY_TREE.to_string(),
+            [parent] => walk.commit(git_dir, parent)?.tree.clone(),
+            _ => continue,
+        };
+        let changes = tree::diff_files(
+            &tree::flatten_tree(git_dir, &parent_tree)?,
+            &tree::flatten_tree(git_dir, &commit.tree)?,
+        );
+        if !changes.is_empty() {
+            writeln!(stdout)?;
+        }
+        for change in changes {
+            write!(stdout, "{}", diff::patch(git_dir, &change)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// How `shortlog` summarizes commits.
+#[derive(Debug, Default)]
+pub struct ShortlogOptions {
+    /// Sorts the authors by their number of commits rather than by name.
+    pub numbered: bool,
+    /// Only shows the number of commits of each author.
+    pub summary: bool,
+    /// Shows the email of each author after their name.
+    pub email: bool,
+}
+
+/// Prints the subjects of the commits reachable from `revisions`, grouped
+/// by author, oldest first.
+pub fn shortlog(
+    git_dir: &Path,
+    config: &Config,
+    revisions: &[String],
+    options: &ShortlogOptions,
+) -> eyre::Result<()> {
+    let mailmap = Mailmap::load(config);
+    let revisions = revisions
+        .iter()
+        .map(|r| revision::resolve(git_dir, r))
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    // Group subjects by author, oldest commit first
+    let mut walk = RevWalk::new();
+    let mut authors = BTreeMap::<String, Vec<String>>::new();
+    for sha in walk.list(git_dir, &revisions, &[])?.iter().rev() {
+        let commit = walk.commit(git_dir, sha)?;
+        let (name, mail) = mailmap.map(&commit.author.name, &commit.author.email);
+        let author = match options.email {
+            true => format!("{name} <{mail}>"),
+            false => name,
+        };
+        authors
+            .entry(author)
+            .or_default()
+            .push(commit.summary().to_string());
+    }
+
+    let mut authors = authors.into_iter().collect::<Vec<_>>();
+    if options.numbered {
+        authors.sort_by_key(|(_, subjects)| std::cmp::Reverse(subjects.len()));
+    }
+    let mut out = String::new();
+    for (author, subjects) in authors {
+        if options.summary {
+            out.push_str(&format!("{:6}\t{author}\n", subjects.len()));
+            continue;
+        }
+        out.push_str(&format!("{author} ({}):\n", subjects.len()));
+        subjects
+            .iter()
+            .for_each(|s| out.push_str(&format!("      {s}\n")));
+        out.push('\n');
+    }
+    let encoding = commit::log_output_encoding(config);
+    std::io::stdout().write_all(&commit::encode(&out, encoding)?)?;
+    Ok(())
+}
+
+/// Formats the commit `sha` the way `log` and `show` do by default: its sha,
+/// its parents if it's a merge, its author mapped by `mailmap`, its author
+/// date and its indented message.
+pub fn format_header(mailmap: &Mailmap, sha: &str, commit: &Commit) -> String {
+    let mut header = format!("commit {sha}\n");
+    if commit.parents.len() > 1 {
+        let parents = commit.parents.iter().map(|p| &p[..7]).collect::<Vec<_>>();
+        header.push_str(&format!("Merge: {}\n", parents.join(" ")));
+    }
+    let (name, email) = mailmap.map(&commit.author.name, &commit.author.email);
+    header.push_str(&format!("Author: {name} <{email}>\n"));
+    header.push_str(&format!(
+        "Date:   {}\n\n",
+        commit::format_date(commit.author.time, commit.author.offset)
+    ));
+    for line in commit.message.lines() {
+        header.push_str(&format!("    {line}\n"));
+    }
+    header
+}
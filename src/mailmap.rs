@@ -0,0 +1,120 @@
+use crate::config::Config;
+use eyre::eyre;
+use std::fs;
+
+/// A line of a mailmap file, replacing the name and/or email of the
+/// identities matching an email and optionally a name.
+#[derive(Debug)]
+struct Mapping {
+    /// The name to match, lowercased. Without a name, any identity with the
+    /// email matches.
+    old_name: Option<String>,
+    /// The email to match, lowercased.
+    old_email: String,
+    name: Option<String>,
+    email: Option<String>,
+}
+
+/// Maps the identities found in commits to canonical ones, as listed in
+/// `.mailmap` and the file set by `mailmap.file`.
+#[derive(Debug, Default)]
+pub struct Mailmap {
+    mappings: Vec<Mapping>,
+}
+
+impl Mailmap {
+    /// Loads `.mailmap` at the root of the working tree, then the file set
+    /// by `mailmap.file`. Missing files are ignored.
+    pub fn load(config: &Config) -> Self {
+        let mut mailmap = Self::default();
+        let files = [Some(".mailmap"), config.get("mailmap.file")];
+        for path in files.into_iter().flatten() {
+            if let Ok(content) = fs::read_to_string(path) {
+                mailmap.parse(&content);
+            }
+        }
+        mailmap
+    }
+
+    /// Parses the lines of a mailmap file, of one of the forms:
+    ///
+    /// ```text
+    /// Proper Name <commit@email>
+    /// <proper@email> <commit@email>
+    /// Proper Name <proper@email> <commit@email>
+    /// Proper Name <proper@email> Commit Name <commit@email>
+    /// ```
+    fn parse(&mut self, content: &str) {
+        for line in content.lines() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let Some((name, email, rest)) = parse_contact(line) else {
+                continue;
+            };
+
+            let mapping = match parse_contact(rest) {
+                Some((old_name, old_email, _)) => Mapping {
+                    old_name: old_name.map(|n| n.to_lowercase()),
+                    old_email: old_email.to_lowercase(),
+                    name,
+                    email: Some(email.to_string()),
+                },
+                None => Mapping {
+                    old_name: None,
+                    old_email: email.to_lowercase(),
+                    name,
+                    email: None,
+                },
+            };
+            self.mappings.push(mapping);
+        }
+    }
+
+    /// Returns the canonical name and email of an identity. Emails and
+    /// names are matched case-insensitively, a mapping for both the name
+    /// and the email taking precedence over a mapping for the email only.
+    pub fn map(&self, name: &str, email: &str) -> (String, String) {
+        let (name_key, email_key) = (name.to_lowercase(), email.to_lowercase());
+        let mut matching = self
+            .mappings
+            .iter()
+            .rev()
+            .filter(|m| m.old_email == email_key);
+        let mapping = matching
+            .clone()
+            .find(|m| m.old_name.as_ref() == Some(&name_key))
+            .or_else(|| matching.find(|m| m.old_name.is_none()));
+
+        match mapping {
+            Some(m) => (
+                m.name.clone().unwrap_or(name.to_string()),
+                m.email.clone().unwrap_or(email.to_string()),
+            ),
+            None => (name.to_string(), email.to_string()),
+        }
+    }
+
+    /// Returns the canonical form of the contact `Name <email>`, the name
+    /// being optional.
+    pub fn map_contact(&self, contact: &str) -> eyre::Result<String> {
+        let (name, email, _) =
+            parse_contact(contact).ok_or(eyre!(tr!("unable to parse contact: %s", contact)))?;
+        let (name, email) = self.map(name.as_deref().unwrap_or_default(), email);
+        Ok(match name.is_empty() {
+            true => format!("<{email}>"),
+            false => format!("{name} <{email}>"),
+        })
+    }
+}
+
+/// Parses a contact of the form `Name <email>` at the start of `s`, the name
+/// being optional. Returns the name, the email and what follows the
+/// contact.
+pub fn parse_contact(s: &str) -> Option<(Option<String>, &str, &str)> {
+    let (name, rest) = s.split_once('<')?;
+    let (email, rest) = rest.split_once('>')?;
+    let name = name.trim();
+    let name = (!name.is_empty()).then(|| name.to_string());
+    Some((name, email.trim(), rest))
+}
@@ -1,137 +1,2046 @@
-mod git;
+mod serve;
 
-use crate::git::GitFile;
-use clap::{Parser, Subcommand};
-use sha1::Digest;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use codecrafters_git::config::Config;
+use codecrafters_git::fetch::Remote;
+use codecrafters_git::mailmap::Mailmap;
+use codecrafters_git::repository::Repository;
+use codecrafters_git::{
+    bisect, blame, branch, cat_file, clone, commit, commit_graph, credential, fetch, filter,
+    for_each, fsck, git, graph, index, init, lfs, log, measure, merge, midx, pack, partial,
+    progress, pull, push, rebase, receive_pack, ref_filter, reflog, refs, remote, repository,
+    reset, restore, rev_list, revision, search, server, stash, stats, status, tag, tempfile, tr,
+    transport, tree, upload_pack,
+};
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
+// With `GIT_COMPAT` set, the command line is parsed the way git parses it,
+// for scripts written for git: long options may be abbreviated, `git
+// <command> -h` prints the usage of the command, and both that and usage
+// errors exit with 129.
 #[derive(Parser)]
 pub struct Args {
+    // Prints the time spent in each phase of the command to stderr, like
+    // finding the repository and loading its config
+    #[clap(long)]
+    measure: bool,
     #[clap(subcommand)]
     subcommand: Command,
 }
 
 #[derive(Subcommand)]
 pub enum Command {
-    Init,
-    // Reads the content of the file at sha
+    Init {
+        // Copies the hooks and other files of the directory into the
+        // repository
+        #[clap(long, value_name = "template-directory")]
+        template: Option<PathBuf>,
+        // Puts the git directory there, `.git` being a file pointing to it
+        #[clap(long, value_name = "git-dir")]
+        separate_git_dir: Option<PathBuf>,
+    },
+    #[clap(group(clap::ArgGroup::new("batch_mode").args(["batch", "batch_check"])))]
+    // Prints objects, or with a type as first argument, the content of an
+    // object of that type, dereferencing tags and commits to get to it
     CatFile {
-        #[clap(short = 'p', long = "path")]
-        sha: String,
+        // Pretty-prints the objects, whatever their type
+        #[clap(short = 'p', long = "pretty", alias = "path")]
+        pretty: bool,
+        // Prints the type of the objects
+        #[clap(short = 't', conflicts_with_all = ["pretty", "size"])]
+        kind: bool,
+        // Prints the size of the objects
+        #[clap(short = 's', conflicts_with = "pretty")]
+        size: bool,
+        // Only exits with 0 if the object exists, and 1 otherwise
+        #[clap(short = 'e', conflicts_with_all = ["pretty", "kind", "size"])]
+        exists: bool,
+        // Prints the part of a commit or tag covered by its signature, for
+        // external signing tools
+        #[clap(long, conflicts_with_all = ["pretty", "kind", "size", "exists"])]
+        signature_payload: bool,
+        // Prints the sha, type, size and content of the objects named on
+        // stdin
+        #[clap(long, conflicts_with_all = ["pretty", "kind", "size", "signature_payload"])]
+        batch: bool,
+        // Like --batch, without the content
+        #[clap(long, conflicts_with_all = ["pretty", "kind", "size", "signature_payload", "batch"])]
+        batch_check: bool,
+        // Processes every object of the repository instead of stdin
+        #[clap(long, requires = "batch_mode")]
+        batch_all_objects: bool,
+        // Lists the objects in storage order instead of sorted by sha
+        #[clap(long, requires = "batch_all_objects")]
+        unordered: bool,
+        // The objects to print, ranges like `A..B` printing the commits they
+        // select
+        #[clap(required_unless_present_any = ["batch", "batch_check"], value_parser = git::parse_object_name)]
+        objects: Vec<String>,
     },
+    // Computes the sha of the objects made of files, or of stdin
     HashObject {
-        #[clap(short = 'w', long = "write")]
-        path: PathBuf,
+        // Writes the objects to the database
+        #[clap(short = 'w')]
+        write: bool,
+        #[clap(short = 't', default_value = "blob")]
+        kind: String,
+        #[clap(long)]
+        stdin: bool,
+        // Converts the content as if it were the file at this path, instead
+        // of the path of the file itself
+        #[clap(long, conflicts_with = "no_filters")]
+        path: Option<PathBuf>,
+        // Hashes the content as is, without converting it
+        #[clap(long)]
+        no_filters: bool,
+        // Hashes content that isn't a valid object of its type, or of an
+        // unknown type
+        #[clap(long)]
+        literally: bool,
+        files: Vec<PathBuf>,
     },
+    // Lists the entries of a tree, restricted to the given paths, which
+    // are relative to the current directory
     LsTree {
-        #[clap(long)]
+        // Recurses into sub-trees
+        #[clap(short)]
+        recursive: bool,
+        // Only lists trees
+        #[clap(short = 'd')]
+        only_trees: bool,
+        // Lists the trees recursed into along with their entries
+        #[clap(short = 't')]
+        show_trees: bool,
+        #[clap(long, alias = "name-status")]
         name_only: bool,
+        // Shows paths from the root of the tree
+        #[clap(long)]
+        full_name: bool,
+        // Ignores the current directory, for paths and output alike
+        #[clap(long)]
+        full_tree: bool,
+        #[clap(value_parser = git::parse_object_name)]
         sha: String,
+        paths: Vec<String>,
+    },
+    // Lists the files of the index under the current directory, or
+    // matching the given paths
+    LsFiles {
+        // Shows the mode, sha and stage of each entry
+        #[clap(short, long)]
+        stage: bool,
+        // Fails if one of the paths matches no file of the index
+        #[clap(long)]
+        error_unmatch: bool,
+        // Precedes each entry by a letter of its status, in lowercase for
+        // the files assumed unchanged
+        #[clap(short = 'v')]
+        tags: bool,
+        pathspec: Vec<PathBuf>,
+    },
+    // Marks files of the index as assumed unchanged, or as left out of the
+    // working tree, so that their worktree file is never compared
+    UpdateIndex {
+        #[clap(long)]
+        assume_unchanged: bool,
+        #[clap(long, conflicts_with = "assume_unchanged")]
+        no_assume_unchanged: bool,
+        #[clap(long)]
+        skip_worktree: bool,
+        #[clap(long, conflicts_with = "skip_worktree")]
+        no_skip_worktree: bool,
+        paths: Vec<PathBuf>,
+    },
+    // Writes the tree of the index, or with a prefix, the tree of that
+    // directory in the index
+    WriteTree {
+        #[clap(long)]
+        prefix: Option<String>,
     },
-    WriteTree,
     CommitTree {
+        #[clap(value_parser = git::parse_object_name)]
         tree_sha: String,
+        // Root commits, like the first commit of an orphan branch, have
+        // no parent, merges several
+        #[clap(short, value_parser = git::parse_object_name)]
+        parent_sha: Vec<String>,
+        // Each message is a paragraph, the message being read from stdin
+        // without any
         #[clap(short)]
-        parent_sha: String,
+        message: Vec<String>,
+        // Signs the commit, with the given key or the default one
+        #[clap(short = 'S', long, num_args = 0..=1, default_missing_value = "")]
+        gpg_sign: Option<String>,
+    },
+    // Builds a tree from `ls-tree` formatted lines read from stdin
+    Mktree,
+    // Packs the objects whose shas are read from stdin, into
+    // `<base-name>-<sha>.pack` and its index, or to stdout
+    PackObjects {
+        #[clap(required_unless_present = "stdout")]
+        base_name: Option<String>,
+        #[clap(long, conflicts_with = "base_name")]
+        stdout: bool,
+        // The number of objects tried as the delta base of each object
+        #[clap(long, default_value_t = 10)]
+        window: usize,
+        // The maximum length of delta chains
+        #[clap(long, default_value_t = 50)]
+        depth: usize,
+        // Stores deltas against the offset of their base instead of its sha
+        #[clap(long)]
+        delta_base_offset: bool,
+    },
+    // Writes the index of a pack, next to it or where -o says, or with
+    // --stdin, stores the pack read from stdin in the repository
+    IndexPack {
+        #[clap(short = 'o')]
+        index: Option<PathBuf>,
+        #[clap(long)]
+        stdin: bool,
+        #[clap(required_unless_present = "stdin")]
+        pack: Option<PathBuf>,
+    },
+    // Writes the objects of the pack read from stdin as loose objects
+    UnpackObjects {
+        // Checks the pack without writing the objects
+        #[clap(short = 'n')]
+        dry_run: bool,
+        #[clap(short)]
+        quiet: bool,
+    },
+    // Stages the content of files, and of the files under directories
+    Add {
+        // Lists the files that would be staged without staging them
+        #[clap(short = 'n', long)]
+        dry_run: bool,
+        // Lists the files as they're staged
+        #[clap(short, long)]
+        verbose: bool,
+        pathspec: Vec<PathBuf>,
+    },
+    // Shows the changes staged in the index, the changes of the working
+    // tree and the untracked files
+    Status {
+        // Prints a line per path with a two-letter code
+        #[clap(short, long)]
+        short: bool,
+        // Prints the branch and its upstream in the short format
+        #[clap(short, long)]
+        branch: bool,
+    },
+    // Replaces the index with the files of a tree
+    ReadTree {
+        // Empties the index, unstaging every file
+        #[clap(long, conflicts_with = "tree")]
+        empty: bool,
+        #[clap(required_unless_present = "empty", value_parser = git::parse_object_name)]
+        tree: Option<String>,
+    },
+    // Lists the files that differ between two trees, or between a commit
+    // and its first parent
+    DiffTree {
+        // Recurses into sub-trees
         #[clap(short)]
-        message: String,
+        recursive: bool,
+        // Diffs a root commit against the empty tree
+        #[clap(long)]
+        root: bool,
+        #[clap(num_args = 1..=2, required = true, value_parser = git::parse_object_name)]
+        trees: Vec<String>,
+    },
+    // Shows the commits reachable from the given revisions, newest first
+    Log {
+        // Stops after that many commits
+        #[clap(short = 'n', long)]
+        max_count: Option<usize>,
+        // Shows each commit on one line
+        #[clap(long)]
+        oneline: bool,
+        // Only shows the commits whose message matches the regex
+        #[clap(long)]
+        grep: Vec<String>,
+        // Matches the regexes regardless of case
+        #[clap(short = 'i', long)]
+        regexp_ignore_case: bool,
+        // Only shows the commits matching every regex
+        #[clap(long)]
+        all_match: bool,
+        // Only shows the commits matching none of the regexes
+        #[clap(long)]
+        invert_grep: bool,
+        // Shows the history of the lines from start to end of the file, as
+        // blame takes them, or of the function whose name matches the regex
+        #[clap(short = 'L', value_name = "start,end:file or :funcname:file")]
+        line_ranges: Vec<String>,
+        #[clap(value_parser = git::parse_object_name)]
+        revisions: Vec<String>,
+    },
+    // Indexes the messages of the commits reachable from the refs, for
+    // log --grep to skip the commits that can't match
+    #[cfg(feature = "search-index")]
+    SearchIndex,
+    // Shows commits and the changes they introduce, and other objects
+    Show {
+        #[clap(default_value = "HEAD", value_parser = git::parse_object_name)]
+        revisions: Vec<String>,
+    },
+    // Summarizes the commits reachable from the given revisions by author
+    Shortlog {
+        // Sorts authors by number of commits instead of by name
+        #[clap(short, long)]
+        numbered: bool,
+        // Only prints the number of commits of each author
+        #[clap(short, long)]
+        summary: bool,
+        #[clap(short, long)]
+        email: bool,
+        #[clap(default_value = "HEAD", value_parser = git::parse_object_name)]
+        revisions: Vec<String>,
+    },
+    // Shows the commit that last changed each line of a file, as of the
+    // revision if given, else in the working tree
+    Blame {
+        // Only blames the lines from start to end, end being a line number,
+        // or a number of lines after or before start with + or -
+        #[clap(short = 'L', value_name = "start,end")]
+        ranges: Vec<String>,
+        // Shows the lines in groups after the details of their commit, for
+        // programs
+        #[clap(short, long)]
+        porcelain: bool,
+        // Shows the groups of lines as their commit is found, without the
+        // lines
+        #[clap(long, conflicts_with = "porcelain")]
+        incremental: bool,
+        // The revision, if any, then the file
+        #[clap(num_args = 1..=2, required = true)]
+        args: Vec<String>,
+    },
+    // Exports the commit graph as JSON, or DOT for Graphviz, with the refs
+    // pointing at each commit
+    GraphExport {
+        // Exports the history of every ref too
+        #[clap(long)]
+        all: bool,
+        #[clap(long)]
+        dot: bool,
+        #[clap(value_parser = git::parse_object_name)]
+        revisions: Vec<String>,
+    },
+    // Reports the size of the history, objects and refs of the repository
+    Stats {
+        // Attributes the size of blobs to the paths they appear at
+        #[clap(long)]
+        blame_size: bool,
+    },
+    // Checks the integrity of the objects and of the files indexing them
+    Fsck,
+    // Records the staged changes in a new commit on the current branch
+    Commit {
+        // Stages the changes of every tracked file first
+        #[clap(short, long, conflicts_with_all = ["only", "include", "pathspec"])]
+        all: bool,
+        // Paragraphs of the message, concluding a merge in progress with
+        // its message by default
+        #[clap(short, long)]
+        message: Vec<String>,
+        // Commits only the changes of the given paths (the default with
+        // paths)
+        #[clap(short, long, requires = "pathspec")]
+        only: bool,
+        // Commits the changes of the given paths along with the staged
+        // ones
+        #[clap(short, long, requires = "pathspec", conflicts_with = "only")]
+        include: bool,
+        // Credits the given `Name <email>` as author
+        #[clap(long)]
+        author: Option<String>,
+        // Overrides the author date
+        #[clap(long)]
+        date: Option<String>,
+        // Signs the commit, with the given key or the default one
+        #[clap(short = 'S', long, num_args = 0..=1, default_missing_value = "")]
+        gpg_sign: Option<String>,
+        pathspec: Vec<PathBuf>,
+    },
+    // Saves local changes away and restores them
+    Stash {
+        #[clap(subcommand)]
+        command: StashCommand,
+    },
+    // Manages the logs of ref updates
+    Reflog {
+        #[clap(subcommand)]
+        command: ReflogCommand,
+    },
+    // Stores large files outside of the repository, as Git LFS does
+    Lfs {
+        #[clap(subcommand)]
+        command: LfsCommand,
+    },
+    // Gets, stores or erases the credential described on stdin, through the
+    // helpers of `credential.helper`
+    Credential {
+        #[clap(subcommand)]
+        command: CredentialCommand,
+    },
+    // Manages the commit-graph file caching the structure of history
+    CommitGraph {
+        #[clap(subcommand)]
+        command: VerifyCommand,
+    },
+    // Manages the index of objects spanning several packs
+    MultiPackIndex {
+        #[clap(subcommand)]
+        command: VerifyCommand,
+    },
+    // Prints the canonical form of contacts according to the mailmap
+    CheckMailmap {
+        #[clap(required = true)]
+        contacts: Vec<String>,
+    },
+    // Points a ref at the given sha, creating the branch an unborn HEAD
+    // points to if needed
+    UpdateRef {
+        name: String,
+        #[clap(value_parser = git::parse_object_name)]
+        sha: String,
+    },
+    // Prints the best common ancestor of two commits
+    MergeBase {
+        // Prints all the best common ancestors
+        #[clap(short, long)]
+        all: bool,
+        // Only exits with 0 if the first commit is an ancestor of the second
+        // one, and 1 otherwise
+        #[clap(long, conflicts_with = "all")]
+        is_ancestor: bool,
+        // Prints the commits that can't be reached from any other one
+        #[clap(long, conflicts_with_all = ["all", "is_ancestor"])]
+        independent: bool,
+        #[clap(required = true, value_parser = git::parse_object_name)]
+        commits: Vec<String>,
+    },
+    // Lists commits reachable from the given revisions
+    RevList {
+        #[clap(long)]
+        left_right: bool,
+        #[clap(long)]
+        count: bool,
+        #[clap(value_parser = git::parse_object_name)]
+        revisions: Vec<String>,
+    },
+    // Prints the sha each revision resolves to. Options are handled in
+    // order like git: `--git-dir`, `--show-toplevel`,
+    // `--is-inside-work-tree` and `--is-bare-repository` describe the
+    // repository, `--abbrev-ref` and `--symbolic-full-name` print the ref
+    // names of the revisions after them instead of their sha
+    RevParse {
+        #[clap(allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    // Moves the current branch to the given commit, resetting the index to
+    // it unless `--soft`, and the working tree too with `--hard`
+    Reset {
+        #[clap(long)]
+        soft: bool,
+        #[clap(long, conflicts_with = "soft")]
+        hard: bool,
+        #[clap(default_value = "HEAD", value_parser = git::parse_object_name)]
+        commit: String,
+    },
+    // Manages the remotes of `remote.<name>.*`
+    Remote {
+        #[clap(subcommand)]
+        command: RemoteCommand,
+    },
+    // Lists, creates, deletes or renames branches
+    Branch {
+        #[clap(short = 'l', long)]
+        list: bool,
+        // Deletes the branches, which must be merged into `HEAD` unless forced
+        #[clap(short = 'd', long, conflicts_with_all = ["list", "move_"])]
+        delete: bool,
+        // Same as `--delete --force`
+        #[clap(short = 'D', conflicts_with_all = ["list", "move_"])]
+        delete_force: bool,
+        // Renames a branch, the current one by default
+        #[clap(short = 'm', long = "move", conflicts_with = "list")]
+        move_: bool,
+        // Same as `--move --force`
+        #[clap(short = 'M', conflicts_with = "list")]
+        move_force: bool,
+        // Resets an existing branch when creating it, replaces one when
+        // renaming to it, or deletes an unmerged one
+        #[clap(short = 'f', long)]
+        force: bool,
+        // Orders by the fields of `--format`, reversed with a leading `-`
+        #[clap(long)]
+        sort: Vec<String>,
+        // Shows each branch with a `for-each-ref` format
+        #[clap(long)]
+        format: Option<String>,
+        // Shows the commit of each branch and how it compares with its
+        // upstream, naming the upstream when given twice
+        #[clap(short, long, action = clap::ArgAction::Count)]
+        verbose: u8,
+        // Lays the branches out in columns, with `column.ui` options
+        #[clap(long, num_args = 0..=1, default_missing_value = "", conflicts_with = "verbose")]
+        column: Option<String>,
+        #[clap(long, conflicts_with = "column")]
+        no_column: bool,
+        patterns: Vec<String>,
+    },
+    // Shows the refs matching the patterns, which match the refs they are a
+    // prefix of
+    ForEachRef {
+        #[clap(long)]
+        format: Option<String>,
+        #[clap(long)]
+        sort: Vec<String>,
+        // Stops after that many refs
+        #[clap(long)]
+        count: Option<usize>,
+        patterns: Vec<String>,
+    },
+    // Lists the tags, or creates the tag `<name> [<object>]` of HEAD by
+    // default
+    Tag {
+        #[clap(short = 'l', long)]
+        list: bool,
+        // Creates a tag object, with a message and a tagger
+        #[clap(short, long, conflicts_with = "list")]
+        annotate: bool,
+        // Paragraphs of the message, implying `--annotate`
+        #[clap(short, long, conflicts_with = "list")]
+        message: Vec<String>,
+        // Replaces the tag if it exists
+        #[clap(short, long)]
+        force: bool,
+        // Orders by `refname` or `v:refname`, reversed with a leading `-`
+        #[clap(long)]
+        sort: Vec<String>,
+        // Only lists the tags containing the commit
+        #[clap(long, value_parser = git::parse_object_name)]
+        contains: Option<String>,
+        // Only lists the tags of the object
+        #[clap(long, value_parser = git::parse_object_name)]
+        points_at: Option<String>,
+        patterns: Vec<String>,
+    },
+    // Copies a repository into a new directory and checks out its default
+    // branch
+    Clone {
+        // Only fetches that many commits of history, the repository
+        // becoming shallow
+        #[clap(long, value_name = "depth")]
+        depth: Option<u32>,
+        // Leaves out the objects the filter spec names, like `blob:none`,
+        // fetching them when they're needed
+        #[clap(long, value_name = "filter-spec")]
+        filter: Option<String>,
+        url: String,
+        directory: Option<PathBuf>,
+    },
+    // Downloads objects and refs from a remote
+    Fetch {
+        // Only keeps that many commits of the history of the fetched refs,
+        // the ones at the end becoming the boundary of a shallow repository
+        #[clap(long, value_name = "depth", conflicts_with_all = ["deepen", "shallow_since", "shallow_exclude"])]
+        depth: Option<u32>,
+        // Fetches that many more commits behind the boundary of a shallow
+        // repository
+        #[clap(long, value_name = "depth", conflicts_with_all = ["shallow_since", "shallow_exclude"])]
+        deepen: Option<u32>,
+        // Leaves out the commits older than the date, the ones left
+        // becoming the boundary of a shallow repository
+        #[clap(long, value_name = "date")]
+        shallow_since: Option<String>,
+        // Leaves out the commits reachable from the remote ref
+        #[clap(long, value_name = "ref")]
+        shallow_exclude: Vec<String>,
+        // Leaves out the objects the filter spec names, making the
+        // repository a partial clone of the remote
+        #[clap(long, value_name = "filter-spec")]
+        filter: Option<String>,
+        remote: Option<String>,
+    },
+    // Lists the refs of a remote repository, the remote of the current
+    // branch or `origin` by default
+    LsRemote {
+        // Only lists branches
+        #[clap(long, short = 'b', alias = "branches")]
+        heads: bool,
+        // Only lists tags
+        #[clap(short, long)]
+        tags: bool,
+        // Shows the ref symbolic refs like `HEAD` point to
+        #[clap(long)]
+        symref: bool,
+        // A remote name, url or path
+        repository: Option<String>,
+        // Only lists the refs ending with one of the patterns
+        patterns: Vec<String>,
+    },
+    // Updates the refs of a remote with local refs, sending the objects
+    // they need
+    Push {
+        // Updates the remote refs even if they aren't fast-forwards
+        #[clap(short, long)]
+        force: bool,
+        remote: Option<String>,
+        refspecs: Vec<String>,
+    },
+    // Serves the refs and objects of the repository to a fetch
+    UploadPack {
+        // Only advertises the refs and capabilities
+        #[clap(long, alias = "http-backend-info-refs")]
+        advertise_refs: bool,
+        // Answers a single request, after the refs were advertised by an
+        // earlier run, as over HTTP
+        #[clap(long)]
+        stateless_rpc: bool,
+        directory: String,
+    },
+    // Serves repositories over the smart HTTP protocol, each under the name
+    // of its directory, like `http://127.0.0.1:8080/project.git`
+    Serve {
+        // The address and port to listen on
+        #[clap(long, default_value = "127.0.0.1:8080", value_name = "address")]
+        listen: String,
+        // Receives pushes too, unless a repository sets `http.receivePack`
+        // to false
+        #[clap(long)]
+        read_write: bool,
+        // The repositories, the current one by default
+        directories: Vec<PathBuf>,
+    },
+    // Receives the refs and objects of a push into the repository
+    ReceivePack {
+        // Only advertises the refs and capabilities
+        #[clap(long, alias = "http-backend-info-refs")]
+        advertise_refs: bool,
+        // Answers a single request, after the refs were advertised by an
+        // earlier run, as over HTTP
+        #[clap(long)]
+        stateless_rpc: bool,
+        directory: String,
+    },
+    // Joins the history of the given commit into the current branch
+    Merge {
+        #[clap(long)]
+        ff_only: bool,
+        // Throws away the local changes in the way
+        #[clap(short, long)]
+        force: bool,
+        #[clap(value_parser = git::parse_object_name)]
+        commit: String,
+    },
+    // Replays the commits of the current branch on top of another commit
+    Rebase {
+        #[clap(long)]
+        autostash: bool,
+        #[clap(long, conflicts_with = "autostash")]
+        no_autostash: bool,
+        // Keeps the author date of the replayed commits as committer date
+        #[clap(long)]
+        committer_date_is_author_date: bool,
+        // Resets the author date of the replayed commits to now
+        #[clap(long, alias = "reset-author-date")]
+        ignore_date: bool,
+        // Throws away the local changes in the way
+        #[clap(short, long)]
+        force: bool,
+        #[clap(value_parser = git::parse_object_name)]
+        upstream: String,
+    },
+    // Finds the commit that introduced a change by binary search, between a
+    // commit with the change and commits without it
+    Bisect {
+        #[clap(subcommand)]
+        command: BisectCommand,
+    },
+    // Switches to a branch or commit, or, given paths, restores files of the
+    // working tree from HEAD, or of the working tree and the index from the
+    // given commit
+    Checkout {
+        // Asks which hunks of the changes to restore
+        #[clap(short, long)]
+        patch: bool,
+        // Creates a branch at the commit and switches to it
+        #[clap(short = 'b', conflicts_with = "detach")]
+        create: Option<String>,
+        // Creates or resets a branch at the commit and switches to it
+        #[clap(short = 'B', conflicts_with_all = ["create", "detach"])]
+        force_create: Option<String>,
+        // Detaches HEAD at the commit, even if it's a branch
+        #[clap(long)]
+        detach: bool,
+        // Throws away local changes
+        #[clap(short, long)]
+        force: bool,
+        // Recreates the conflicts of the given paths, even resolved ones
+        #[clap(short, long, requires = "pathspec", conflicts_with_all = ["patch", "revision"])]
+        merge: bool,
+        revision: Option<String>,
+        #[clap(last = true)]
+        pathspec: Vec<PathBuf>,
+    },
+    // Restores files of the working tree from the index, or of the index
+    // from HEAD, or of either from the given commit
+    Restore {
+        // Asks which hunks of the changes to restore
+        #[clap(short, long)]
+        patch: bool,
+        #[clap(short, long)]
+        source: Option<String>,
+        // Restores the index
+        #[clap(short = 'S', long)]
+        staged: bool,
+        // Restores the working tree, the default without --staged
+        #[clap(short = 'W', long)]
+        worktree: bool,
+        pathspec: Vec<PathBuf>,
+    },
+    // Switches to a branch, or to a commit with `--detach`
+    Switch {
+        // Creates a branch at the commit and switches to it
+        #[clap(short, long, conflicts_with_all = ["detach", "orphan"])]
+        create: Option<String>,
+        // Creates or resets a branch at the commit and switches to it
+        #[clap(short = 'C', long, conflicts_with_all = ["create", "detach", "orphan"])]
+        force_create: Option<String>,
+        // Detaches HEAD at the commit
+        #[clap(short, long, conflicts_with = "orphan")]
+        detach: bool,
+        // Creates the branch without history, HEAD pointing to an unborn ref
+        #[clap(long, conflicts_with = "target")]
+        orphan: Option<String>,
+        // Throws away local changes
+        #[clap(short, long, alias = "discard-changes")]
+        force: bool,
+        target: Option<String>,
+    },
+    // Fetches the upstream of the current branch and integrates it
+    Pull {
+        #[clap(short, long)]
+        rebase: bool,
+        #[clap(long, conflicts_with = "rebase")]
+        no_rebase: bool,
+        #[clap(long)]
+        ff_only: bool,
+        #[clap(long)]
+        autostash: bool,
+        #[clap(long, conflicts_with = "autostash")]
+        no_autostash: bool,
+        // Throws away the local changes in the way
+        #[clap(short, long)]
+        force: bool,
+    },
+    // Runs a command in each repository under a directory, several at a
+    // time, and sums up the ones that failed
+    ForEach {
+        // The number of commands run at a time, one per processor by
+        // default
+        #[clap(short, long)]
+        jobs: Option<usize>,
+        // Looks for repositories there rather than in the current directory
+        #[clap(short = 'C', long, value_name = "dir")]
+        directory: Option<PathBuf>,
+        // The command and its arguments, like `status` or `fetch origin`
+        #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
     },
 }
 
-fn main() -> eyre::Result<()> {
-    // Uncomment this block to pass the first stage
-    let args = Args::parse();
-    match args.subcommand {
-        Command::Init => {
-            // Create the git structure
-            fs::create_dir(".git")?;
-            fs::create_dir(".git/objects")?;
-            fs::create_dir(".git/refs")?;
-            fs::write(".git/HEAD", "ref: refs/heads/main\n")?;
-
-            println!("Initialized git directory");
-            Ok(())
-        }
-        Command::CatFile { sha } => {
-            // Read the file and start the decoder
-            let git_file = GitFile::new(sha)?;
+impl Command {
+    /// Returns true if the command works on the repository the current
+    /// directory is in, rather than on none, a new one or one given on the
+    /// command line.
+    fn in_repository(&self) -> bool {
+        !matches!(
+            self,
+            Command::Init { .. }
+                | Command::Clone { .. }
+                | Command::UploadPack { .. }
+                | Command::ReceivePack { .. }
+                | Command::Serve { .. }
+                | Command::ForEach { .. }
+                | Command::Credential { .. }
+                | Command::HashObject { write: false, .. }
+        )
+    }
+}
 
-            print!("{}", git_file);
-            Ok(())
-        }
-        Command::HashObject { path } => {
-            // Read the file at the given path
-            let file = GitFile::from_file(path)?;
+#[derive(Subcommand)]
+pub enum VerifyCommand {
+    // Checks the file against the object database
+    Verify,
+}
 
-            // Get the hash
-            let hash = hex::encode(file.hash());
+#[derive(Subcommand)]
+pub enum StashCommand {
+    // Stashes the changes of tracked files, or only of the given paths
+    Push {
+        // Asks which hunks of the changes to stash
+        #[clap(short, long)]
+        patch: bool,
+        #[clap(short, long)]
+        message: Option<String>,
+        pathspec: Vec<PathBuf>,
+    },
+    // Creates a branch at the commit the stash was created on, applies the
+    // stash there and drops it
+    Branch {
+        name: String,
+        stash: Option<String>,
+    },
+}
 
-            // Compress the file
-            let compressed = file.compress()?;
+#[derive(Subcommand)]
+pub enum ReflogCommand {
+    // Prunes old entries, by default from `gc.reflogExpire` and
+    // `gc.reflogExpireUnreachable`, which `gc.<pattern>.*` override per ref
+    Expire {
+        #[clap(long)]
+        expire: Option<String>,
+        #[clap(long)]
+        expire_unreachable: Option<String>,
+        // Also prunes entries pointing to missing or broken objects
+        #[clap(long)]
+        stale_fix: bool,
+        #[clap(long)]
+        rewrite: bool,
+        #[clap(long)]
+        updateref: bool,
+        #[clap(short = 'n', long)]
+        dry_run: bool,
+        #[clap(long)]
+        verbose: bool,
+        #[clap(long, conflicts_with = "refs")]
+        all: bool,
+        refs: Vec<String>,
+    },
+}
 
-            // Write the compressed data to output
-            let base_path = format!(".git/objects/{}", &hash[..2]);
-            let output_path = format!("{}/{}", base_path, &hash[2..]);
-            let _ = fs::create_dir(base_path);
-            fs::write(output_path, compressed)?;
+#[derive(Subcommand)]
+pub enum LfsCommand {
+    // Runs the LFS filter for git, to be set as `filter.lfs.process`
+    FilterProcess,
+}
 
-            print!("{}", hash);
-            Ok(())
+#[derive(Subcommand)]
+pub enum RemoteCommand {
+    // Prints the url of the remote, after `insteadOf` rewrites
+    GetUrl {
+        // Prints the url to push to instead
+        #[clap(long)]
+        push: bool,
+        name: String,
+    },
+    // Describes the remote and how local branches relate to its branches
+    Show {
+        name: String,
+    },
+    // Deletes the remote-tracking branches whose branch the remote deleted
+    Prune {
+        #[clap(short = 'n', long)]
+        dry_run: bool,
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum BisectCommand {
+    // Starts bisecting, between the first commit, with the change, and the
+    // others, without it
+    Start {
+        // The term for the commits with the change, `bad` by default
+        #[clap(long, alias = "term-bad")]
+        term_new: Option<String>,
+        // The term for the commits without the change, `good` by default
+        #[clap(long, alias = "term-good")]
+        term_old: Option<String>,
+        #[clap(value_parser = git::parse_object_name)]
+        revisions: Vec<String>,
+    },
+    // Marks commits that can't be tested
+    Skip {
+        #[clap(value_parser = git::parse_object_name)]
+        revisions: Vec<String>,
+    },
+    // Ends bisecting, switching back to the original branch or the commit
+    Reset {
+        commit: Option<String>,
+    },
+    // Prints the commands of the bisection so far
+    Log,
+    // Runs the commands of a bisection log again
+    Replay {
+        file: PathBuf,
+    },
+    // Prints the terms of the bisection
+    Terms {
+        #[clap(long, alias = "term-bad")]
+        term_new: bool,
+        #[clap(long, alias = "term-good", conflicts_with = "term_new")]
+        term_old: bool,
+    },
+    // Marks commits with a term: `bad` or `new` for the ones with the
+    // change, `good` or `old` for the others, or the terms given to `start`
+    #[clap(external_subcommand)]
+    Mark(Vec<String>),
+}
+
+#[derive(Subcommand)]
+pub enum CredentialCommand {
+    // Completes the credential from the helpers or the user, and prints it
+    Fill,
+    // Tells the helpers to store the credential
+    Approve,
+    // Tells the helpers to erase the credential
+    Reject,
+}
+
+fn main() {
+    // Like git, failures exit with 128, leaving 1 to the commands answering
+    // no, like `merge-base --is-ancestor`
+    if let Err(e) = run() {
+        eprintln!("Error: {e:?}");
+        std::process::exit(128);
+    }
+}
+
+fn run() -> eyre::Result<()> {
+    let start = Instant::now();
+    tempfile::install()?;
+    let args = match std::env::var_os("GIT_COMPAT") {
+        Some(_) => parse_compat(),
+        None => Args::parse(),
+    };
+    if args.measure {
+        measure::enable();
+        measure::record("parse", start.elapsed());
+    }
+
+    // Commands run from the top of the working tree, paths given from a
+    // subdirectory being resolved against its prefix. The ones working on
+    // that repository fail outside of one, the others don't look for it.
+    let cwd = std::env::current_dir()?;
+    let repository = match args.subcommand.in_repository() {
+        true => Ok(measure::phase("discover", || Repository::discover(&cwd))?),
+        false => Err(eyre::eyre!(tr!(
+            "not a git repository (or any of the parent directories): %s",
+            ".git"
+        ))),
+    };
+    let prefix = match &repository {
+        Ok(repository) => {
+            partial::enable_lazy_fetch(&repository.git_dir);
+            repository.prefix(&cwd)
+        }
+        Err(_) => PathBuf::new(),
+    };
+    if let Ok(Repository {
+        work_tree: Some(work_tree),
+        ..
+    }) = &repository
+    {
+        if !prefix.as_os_str().is_empty() {
+            std::env::set_current_dir(work_tree)?;
         }
-        Command::LsTree { sha, .. } => {
-            let file = GitFile::new(sha)?;
+    }
+
+    let result = measure::phase("command", || dispatch(args, cwd, repository, prefix));
+    measure::report(start);
+    result
+}
 
-            print!("{}", file);
+/// Runs the command, from the top of the working tree if it has one.
+fn dispatch(
+    args: Args,
+    cwd: PathBuf,
+    repository: eyre::Result<Repository>,
+    prefix: PathBuf,
+) -> eyre::Result<()> {
+    // The commands run outside of a repository only read the config of the
+    // one of the current directory, if any
+    let git_dir = &match &repository {
+        Ok(repository) => repository.git_dir.clone(),
+        Err(_) => PathBuf::from(".git"),
+    };
+    // The progress of remotes is shown on stderr
+    let progress = progress::Stderr::default();
+    match args.subcommand {
+        Command::Init {
+            template,
+            separate_git_dir,
+        } => {
+            let options = init::InitOptions {
+                template,
+                separate_git_dir,
+            };
+            match init::init(Path::new(""), &Config::load(git_dir)?, &options)? {
+                (git_dir, true) => println!(
+                    "{}",
+                    tr!(
+                        "Reinitialized existing Git repository in %s/",
+                        git_dir.canonicalize()?.display()
+                    )
+                ),
+                (_, false) => println!("{}", tr!("Initialized git directory")),
+            }
             Ok(())
         }
-        Command::WriteTree => {
-            let file = GitFile::from_directory(PathBuf::from("."))?;
-
-            // Write the compressed data to output
-            let hash = hex::encode(&file.sha);
-            let base_path = format!(".git/objects/{}", &hash[..2]);
-            let output_path = format!("{}/{}", base_path, &hash[2..]);
-            let _ = fs::create_dir(base_path);
-            fs::write(output_path, file.compress()?)?;
+        Command::CatFile {
+            batch,
+            batch_check,
+            batch_all_objects,
+            unordered,
+            ..
+        } if batch || batch_check => {
+            let options = cat_file::BatchOptions {
+                contents: batch,
+                all: batch_all_objects,
+                unordered,
+            };
+            let out = std::io::BufWriter::new(std::io::stdout().lock());
+            cat_file::batch(git_dir, std::io::stdin().lock(), &options, out)
+        }
+        Command::CatFile {
+            exists: true,
+            objects,
+            ..
+        } => {
+            let [object] = objects.as_slice() else {
+                return Err(eyre::eyre!(tr!("<object> required with '-e'")));
+            };
+            if !cat_file::exists(git_dir, object)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::CatFile {
+            pretty: false,
+            kind: false,
+            size: false,
+            signature_payload: false,
+            objects,
+            ..
+        } => {
+            let [kind, object] = objects.as_slice() else {
+                return Err(eyre::eyre!(tr!(
+                    "only two arguments allowed in <type> <object> mode, not %s",
+                    objects.len()
+                )));
+            };
+            cat_file::cat_file_as(git_dir, kind, object, std::io::stdout().lock())
+        }
+        Command::CatFile {
+            kind,
+            size,
+            signature_payload,
+            objects,
+            ..
+        } => {
+            let mode = match (kind, size, signature_payload) {
+                (true, _, _) => cat_file::Mode::Type,
+                (_, true, _) => cat_file::Mode::Size,
+                (_, _, true) => cat_file::Mode::SignaturePayload,
+                _ => cat_file::Mode::Pretty,
+            };
+            cat_file::cat_file(git_dir, &objects, mode, std::io::stdout().lock())
+        }
+        Command::HashObject {
+            write,
+            kind,
+            stdin,
+            path,
+            no_filters,
+            literally,
+            files,
+        } => {
+            // Content from stdin is only converted for an explicit path
+            let mut inputs = Vec::new();
+            if stdin {
+                let mut content = Vec::new();
+                std::io::stdin().read_to_end(&mut content)?;
+                inputs.push((content, path.clone()));
+            }
+            for file in files {
+                let content = fs::read(prefix.join(&file))?;
+                inputs.push((content, path.clone().or(Some(file))));
+            }
 
-            println!("{}", hash);
+            let config = Config::load(git_dir)?;
+            let options = git::HashOptions {
+                write,
+                no_filters,
+                literally,
+            };
+            for (content, path) in inputs {
+                let sha = git::hash_file(
+                    git_dir,
+                    &config,
+                    &kind,
+                    content,
+                    &prefix,
+                    path.as_deref(),
+                    &options,
+                )?;
+                println!("{sha}");
+            }
+            Ok(())
+        }
+        Command::LsFiles {
+            stage,
+            error_unmatch,
+            tags,
+            pathspec,
+        } => {
+            let unmatched = index::ls_files(git_dir, &prefix, &pathspec, stage, tags)?;
+            if error_unmatch && !unmatched.is_empty() {
+                for path in unmatched {
+                    eprintln!(
+                        "{}",
+                        tr!(
+                            "error: pathspec '%s' did not match any file(s) known to git",
+                            path.display()
+                        )
+                    );
+                }
+                eprintln!("{}", tr!("Did you forget to 'git add'?"));
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::UpdateIndex {
+            assume_unchanged,
+            no_assume_unchanged,
+            skip_worktree,
+            no_skip_worktree,
+            paths,
+        } => {
+            // Each pair of flags sets or clears its bit, or leaves it alone
+            let flag = |set: bool, clear: bool| match (set, clear) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            };
+            let update = index::FlagUpdate {
+                assume_unchanged: flag(assume_unchanged, no_assume_unchanged),
+                skip_worktree: flag(skip_worktree, no_skip_worktree),
+            };
+            index::update_flags(git_dir, &prefix, &paths, update)
+        }
+        Command::LsTree {
+            recursive,
+            only_trees,
+            show_trees,
+            name_only,
+            full_name,
+            full_tree,
+            sha,
+            paths,
+        } => {
+            let prefix = match full_tree {
+                true => PathBuf::new(),
+                false => prefix,
+            };
+            // Without paths, the current directory is listed
+            let paths = match paths.is_empty() {
+                true => vec![repository::resolve_path(&prefix, "")?],
+                false => paths
+                    .iter()
+                    .map(|path| repository::resolve_path(&prefix, path))
+                    .collect::<eyre::Result<_>>()?,
+            };
+            let options = tree::LsTreeOptions {
+                recursive,
+                only_trees,
+                show_trees,
+                name_only,
+                relative_to: (!full_name).then_some(prefix),
+            };
+            tree::ls_tree(
+                git_dir,
+                &tree::peel_to_tree(git_dir, &revision::resolve(git_dir, &sha)?)?,
+                &paths,
+                &options,
+            )
+        }
+        Command::WriteTree { prefix } => {
+            println!("{}", index::write_tree(git_dir, prefix.as_deref())?);
             Ok(())
         }
         Command::CommitTree {
             parent_sha,
             message,
             tree_sha,
+            gpg_sign,
         } => {
-            let content = format!(
-                "tree {tree_sha}\nparent {parent_sha}\nauthor Greg <greg@notyourbusiness.com +0000\n\n{message}\n"
-            );
-            let content = content.as_bytes();
-            let header = format!("commit {}\0", content.len());
-
-            let commit = [header.as_bytes(), content].concat();
-
-            // Hash the git file
-            let mut hasher = sha1::Sha1::new();
-            hasher.update(&commit);
-            let hash = hasher.finalize();
-            let hash = hex::encode(hash);
-
-            // Compress the file
-            let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
-
-            encoder.write_all(&commit)?;
-            let content = encoder.finish()?;
-
-            let base_path = format!(".git/objects/{}", &hash[..2]);
-            let output_path = format!("{}/{}", base_path, &hash[2..]);
-            let _ = fs::create_dir(base_path);
-            fs::write(output_path, content)?;
-
-            println!("{}", hash);
+            let message = match message.is_empty() {
+                true => {
+                    let mut message = String::new();
+                    std::io::stdin().read_to_string(&mut message)?;
+                    message
+                }
+                false => format!("{}\n", message.join("\n\n")),
+            };
+            let sha = commit::commit_tree(
+                git_dir,
+                &Config::load(git_dir)?,
+                &tree_sha,
+                &parent_sha,
+                message,
+                gpg_sign.as_deref(),
+            )?;
+            println!("{sha}");
+            Ok(())
+        }
+        Command::MergeBase {
+            all,
+            is_ancestor,
+            independent,
+            commits,
+        } => {
+            let options = merge::MergeBaseOptions {
+                all,
+                is_ancestor,
+                independent,
+            };
+            if !merge::merge_base(git_dir, &commits, &options)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::RevList {
+            left_right,
+            count,
+            revisions,
+        } => rev_list::rev_list(
+            git_dir,
+            &revisions,
+            &rev_list::RevListOptions { left_right, count },
+        ),
+        Command::RevParse { args } => revision::rev_parse(&repository?, &cwd, &args),
+        Command::Reset { soft, hard, commit } => {
+            let mode = match (soft, hard) {
+                (true, _) => reset::Mode::Soft,
+                (_, true) => reset::Mode::Hard,
+                _ => reset::Mode::Mixed,
+            };
+            reset::reset(git_dir, &commit, mode)
+        }
+        Command::Remote { command } => {
+            let config = Config::load(git_dir)?;
+            match command {
+                RemoteCommand::GetUrl { push, name } => {
+                    let remote = Remote::load(&config, &name)?;
+                    println!("{}", if push { remote.push_url } else { remote.url });
+                    Ok(())
+                }
+                RemoteCommand::Show { name } => {
+                    remote::show(git_dir, &config, &Remote::load(&config, &name)?)
+                }
+                RemoteCommand::Prune { dry_run, name } => {
+                    remote::prune(git_dir, &config, &Remote::load(&config, &name)?, dry_run)
+                }
+            }
+        }
+        Command::Branch {
+            list,
+            delete,
+            delete_force,
+            move_,
+            move_force,
+            force,
+            sort,
+            format,
+            verbose,
+            column,
+            no_column,
+            patterns,
+        } => {
+            let config = Config::load(git_dir)?;
+            if delete || delete_force {
+                if patterns.is_empty() {
+                    return Err(eyre::eyre!(tr!("branch name required")));
+                }
+                for name in &patterns {
+                    branch::delete(git_dir, name, force || delete_force)?;
+                }
+                return Ok(());
+            }
+            if move_ || move_force {
+                let force = force || move_force;
+                return match patterns.as_slice() {
+                    [new] => branch::rename(git_dir, &config, None, new, force),
+                    [old, new] => branch::rename(git_dir, &config, Some(old), new, force),
+                    _ => Err(eyre::eyre!(tr!(
+                        "too many arguments for a rename operation"
+                    ))),
+                };
+            }
+            if !list && !patterns.is_empty() {
+                return match patterns.as_slice() {
+                    [name] => branch::create(git_dir, &config, name, None, force),
+                    [name, start] => branch::create(git_dir, &config, name, Some(start), force),
+                    _ => Err(eyre::eyre!(tr!("too many arguments"))),
+                };
+            }
+            let options = branch::ListOptions {
+                patterns,
+                sort,
+                format,
+                verbose,
+                column,
+                no_column,
+            };
+            branch::list(git_dir, &config, &options)
+        }
+        Command::ForEachRef {
+            format,
+            sort,
+            count,
+            patterns,
+        } => {
+            let options = ref_filter::ForEachRefOptions {
+                format,
+                sort,
+                count,
+                patterns,
+            };
+            ref_filter::for_each_ref(
+                git_dir,
+                &Config::load(git_dir)?,
+                &options,
+                std::io::stdout().lock(),
+            )
+        }
+        Command::Tag {
+            list,
+            annotate,
+            message,
+            force,
+            sort,
+            contains,
+            points_at,
+            patterns,
+        } => {
+            let config = Config::load(git_dir)?;
+            // Filters imply listing
+            let listing = list || contains.is_some() || points_at.is_some();
+            if let (false, [name, object @ ..]) = (listing, patterns.as_slice()) {
+                let object = match object {
+                    [] => "HEAD",
+                    [object] => object.as_str(),
+                    _ => return Err(eyre::eyre!(tr!("too many arguments"))),
+                };
+                let target = revision::resolve(git_dir, object)?;
+                if annotate || !message.is_empty() {
+                    let message = message.join("\n\n");
+                    return tag::create_annotated(git_dir, &config, name, &target, &message, force)
+                        .map(|_| ());
+                }
+                return tag::create(git_dir, name, &target, force);
+            }
+            if annotate || !message.is_empty() {
+                return Err(eyre::eyre!(tr!("a tag name is required")));
+            }
+            let options = tag::ListOptions {
+                patterns,
+                sort,
+                contains,
+                points_at,
+            };
+            tag::list(git_dir, &config, &options)?
+                .iter()
+                .for_each(|name| println!("{name}"));
+            Ok(())
+        }
+        Command::Clone {
+            depth,
+            filter,
+            url,
+            directory,
+        } => {
+            let options = fetch::FetchOptions {
+                depth,
+                filter: filter.map(|filter| filter.parse()).transpose()?,
+                ..Default::default()
+            };
+            clone::clone(&url, directory.as_deref(), &options, Some(&progress))
+        }
+        Command::Fetch {
+            depth,
+            deepen,
+            shallow_since,
+            shallow_exclude,
+            filter,
+            remote,
+        } => {
+            let config = Config::load(git_dir)?;
+            let remote = match remote {
+                Some(remote) => remote,
+                None => branch::upstream_remote(git_dir, &config)?,
+            };
+            let options = fetch::FetchOptions {
+                depth,
+                deepen,
+                shallow_since: shallow_since
+                    .map(|date| commit::parse_date(&date).map(|(time, _)| time))
+                    .transpose()?,
+                shallow_exclude,
+                filter: filter.map(|filter| filter.parse()).transpose()?,
+                ..Default::default()
+            };
+            fetch::fetch(
+                git_dir,
+                &config,
+                &Remote::load(&config, &remote)?,
+                &[],
+                &options,
+                Some(&progress),
+            )
+        }
+        Command::LsRemote {
+            heads,
+            tags,
+            symref,
+            repository,
+            patterns,
+        } => {
+            let config = Config::load(git_dir)?;
+            let remote = match repository {
+                Some(repository) => repository,
+                None => branch::upstream_remote(git_dir, &config)?,
+            };
+            // Remotes are looked up by name first, paths being given from
+            // where the command was run
+            let url = match config.get(&format!("remote.{remote}.url")) {
+                Some(_) => Remote::load(&config, &remote)?.url,
+                None if transport::is_remote(&remote) || remote.contains("://") => remote,
+                None => cwd.join(&remote).to_string_lossy().into_owned(),
+            };
+            let options = remote::ListOptions {
+                heads,
+                tags,
+                symref,
+                patterns,
+            };
+            remote::list(&config, &url, &options)
+        }
+        Command::Push {
+            force,
+            remote,
+            refspecs,
+        } => {
+            let config = Config::load(git_dir)?;
+            let remote = match remote {
+                Some(remote) => remote,
+                None => branch::upstream_remote(git_dir, &config)?,
+            };
+            push::push(
+                git_dir,
+                &config,
+                &Remote::load(&config, &remote)?,
+                &refspecs,
+                force,
+                Some(&progress),
+            )
+        }
+        Command::UploadPack { ref directory, .. } | Command::ReceivePack { ref directory, .. } => {
+            let service = match args.subcommand {
+                Command::UploadPack { .. } => server::Service::UploadPack,
+                _ => server::Service::ReceivePack,
+            };
+            // The directory is given from where the command was run
+            let git_dir = fetch::local_git_dir(&cwd.join(directory).to_string_lossy())?;
+            let config = Config::load(&git_dir)?;
+            let version = server::requested_version();
+            let mut out = std::io::stdout().lock();
+            match args.subcommand {
+                Command::UploadPack {
+                    advertise_refs: false,
+                    stateless_rpc,
+                    ..
+                } => upload_pack::upload_pack(
+                    &config,
+                    &git_dir,
+                    version,
+                    stateless_rpc,
+                    std::io::stdin().lock(),
+                    out,
+                ),
+                Command::ReceivePack {
+                    advertise_refs: false,
+                    stateless_rpc,
+                    ..
+                } => receive_pack::receive_pack(
+                    &config,
+                    &git_dir,
+                    stateless_rpc,
+                    std::io::stdin().lock(),
+                    out,
+                ),
+                _ => server::advertise_refs(&config, &git_dir, service, version, &mut out),
+            }
+        }
+        Command::Serve {
+            listen,
+            read_write,
+            directories,
+        } => {
+            let directories = match directories.is_empty() {
+                true => vec![cwd.clone()],
+                false => directories.iter().map(|dir| cwd.join(dir)).collect(),
+            };
+            serve::serve(&listen, &directories, read_write)
+        }
+        Command::Merge {
+            ff_only,
+            force,
+            commit,
+        } => merge::merge_revision(
+            git_dir,
+            &Config::load(git_dir)?,
+            &commit,
+            merge::MergeOptions { ff_only, force },
+        ),
+        Command::Bisect { command } => {
+            let config = Config::load(git_dir)?;
+            match command {
+                BisectCommand::Start {
+                    term_new,
+                    term_old,
+                    revisions,
+                } => bisect::start(
+                    git_dir,
+                    &config,
+                    term_new.as_deref(),
+                    term_old.as_deref(),
+                    &revisions,
+                ),
+                BisectCommand::Skip { revisions } => bisect::skip(git_dir, &config, &revisions),
+                BisectCommand::Reset { commit } => {
+                    bisect::reset(git_dir, &config, commit.as_deref())
+                }
+                BisectCommand::Log => {
+                    print!("{}", bisect::log(git_dir)?);
+                    Ok(())
+                }
+                BisectCommand::Replay { file } => bisect::replay(git_dir, &config, &file),
+                BisectCommand::Terms { term_new, term_old } => {
+                    bisect::terms(git_dir, term_new, term_old)
+                }
+                BisectCommand::Mark(args) => bisect::mark(git_dir, &config, &args[0], &args[1..]),
+            }
+        }
+        Command::Rebase {
+            autostash,
+            no_autostash,
+            committer_date_is_author_date,
+            ignore_date,
+            force,
+            upstream,
+        } => {
+            let options = rebase::RebaseOptions {
+                committer_date_is_author_date,
+                ignore_date,
+                force,
+            };
+            let config = Config::load(git_dir)?;
+            let upstream = revision::resolve(git_dir, &upstream)?;
 
+            // `--autostash` and `--no-autostash` take precedence over
+            // `rebase.autoStash`
+            let autostash = autostash
+                || (!no_autostash && config.get_bool("rebase.autoStash")?.unwrap_or(false));
+            if autostash {
+                stash::with_autostash(git_dir, &config, || {
+                    rebase::rebase(git_dir, &config, &upstream, options)
+                })
+            } else {
+                rebase::rebase(git_dir, &config, &upstream, options)
+            }
+        }
+        Command::Mktree => {
+            println!("{}", tree::mktree(git_dir, std::io::stdin().lock())?);
+            Ok(())
+        }
+        Command::PackObjects {
+            base_name,
+            stdout,
+            window,
+            depth,
+            delta_base_offset,
+        } => {
+            let objects = pack::read_object_list(std::io::stdin().lock())?;
+            let options = pack::PackOptions {
+                window,
+                depth,
+                delta_base_offset,
+            };
+            match base_name.filter(|_| !stdout) {
+                Some(base_name) => {
+                    println!(
+                        "{}",
+                        pack::write_pack(git_dir, &objects, &options, Some(&progress), &base_name)?
+                    )
+                }
+                None => {
+                    pack::pack_objects(
+                        git_dir,
+                        &objects,
+                        &options,
+                        Some(&progress),
+                        std::io::stdout().lock(),
+                    )?;
+                }
+            }
+            Ok(())
+        }
+        Command::IndexPack { index, stdin, pack } => {
+            // Without a pack to read, it comes from stdin
+            let input = (stdin || pack.is_none()).then(|| std::io::stdin().lock());
+            let name = pack::index_pack_file(
+                git_dir,
+                pack.as_deref(),
+                index.as_deref(),
+                input,
+                Some(&progress),
+            )?;
+            match stdin {
+                true => println!("pack\t{name}"),
+                false => println!("{name}"),
+            }
             Ok(())
         }
+        Command::UnpackObjects { dry_run, quiet } => {
+            let mut data = Vec::new();
+            std::io::stdin().read_to_end(&mut data)?;
+            let count = pack::unpack_objects(git_dir, &data, dry_run, Some(&progress))?;
+            if !quiet {
+                eprintln!(
+                    "{}",
+                    tr!("Unpacking objects: 100%% (%d/%d), done.", count, count)
+                );
+            }
+            Ok(())
+        }
+        Command::Add {
+            dry_run,
+            verbose,
+            pathspec,
+        } => {
+            if pathspec.is_empty() {
+                eprintln!("{}", tr!("Nothing specified, nothing added."));
+                eprintln!("{}", tr!("hint: Maybe you wanted to say 'git add .'?"));
+                return Ok(());
+            }
+            index::add(
+                git_dir,
+                &Config::load(git_dir)?,
+                &prefix,
+                &pathspec,
+                dry_run,
+                verbose,
+            )
+        }
+        Command::Status { short, branch } => {
+            let config = Config::load(git_dir)?;
+            let status = status::Status::read(git_dir, &config)?;
+            match short {
+                true => status::print_short(git_dir, &config, &status, &prefix, branch),
+                false => status::print_long(git_dir, &config, &status, &prefix),
+            }
+        }
+        Command::ReadTree { tree, .. } => {
+            let tree = tree
+                .map(|tree| tree::peel_to_tree(git_dir, &revision::resolve(git_dir, &tree)?))
+                .transpose()?;
+            index::read_tree(git_dir, tree.as_deref())
+        }
+        Command::DiffTree {
+            recursive,
+            trees,
+            root,
+        } => tree::diff_tree(git_dir, &trees, recursive, root),
+        Command::Log {
+            max_count,
+            oneline,
+            grep,
+            regexp_ignore_case,
+            all_match,
+            invert_grep,
+            line_ranges,
+            revisions,
+        } => {
+            let options = log::LogOptions {
+                max_count,
+                oneline,
+                grep: search::MessageFilter::new(
+                    &grep,
+                    regexp_ignore_case,
+                    all_match,
+                    invert_grep,
+                )?,
+                line_ranges,
+            };
+            log::log(git_dir, &Config::load(git_dir)?, &revisions, &options)
+        }
+        #[cfg(feature = "search-index")]
+        Command::SearchIndex => {
+            let added = search::SearchIndex::update(git_dir)?;
+            println!("{}", tr!("Indexed %d new commits", added));
+            Ok(())
+        }
+        Command::Show { revisions } => log::show(git_dir, &Config::load(git_dir)?, &revisions),
+        Command::Shortlog {
+            numbered,
+            summary,
+            email,
+            revisions,
+        } => {
+            let options = log::ShortlogOptions {
+                numbered,
+                summary,
+                email,
+            };
+            log::shortlog(git_dir, &Config::load(git_dir)?, &revisions, &options)
+        }
+        Command::Blame {
+            ranges,
+            porcelain,
+            incremental,
+            args,
+        } => {
+            let (revision, path) = match args.as_slice() {
+                [path] => (None, path),
+                [revision, path] => (Some(revision.as_str()), path),
+                _ => unreachable!(),
+            };
+            let options = blame::BlameOptions {
+                ranges,
+                format: match (porcelain, incremental) {
+                    (true, _) => blame::Format::Porcelain,
+                    (_, true) => blame::Format::Incremental,
+                    _ => blame::Format::Human,
+                },
+            };
+            let path = repository::resolve_path(&prefix, path)?;
+            let mailmap = Mailmap::load(&Config::load(git_dir)?);
+            blame::blame(git_dir, &mailmap, revision, &path, &options)
+        }
+        Command::GraphExport {
+            all,
+            dot,
+            revisions,
+        } => {
+            let mailmap = Mailmap::load(&Config::load(git_dir)?);
+            graph::export(
+                git_dir,
+                &mailmap,
+                &revisions,
+                &graph::GraphOptions { all, dot },
+            )
+        }
+        Command::Stats { blame_size } => {
+            stats::stats(git_dir, &Mailmap::load(&Config::load(git_dir)?), blame_size)
+        }
+        Command::Fsck => report(fsck::fsck(git_dir)?),
+        Command::Commit {
+            all,
+            message,
+            include,
+            author,
+            date,
+            gpg_sign,
+            pathspec,
+            ..
+        } => {
+            let message = (!message.is_empty()).then(|| message.join("\n\n"));
+            let options = commit::CommitOptions {
+                all,
+                pathspec: repository::resolve_pathspec(&prefix, &pathspec)?,
+                include,
+                author,
+                date,
+                gpg_sign,
+            };
+            commit::commit(
+                git_dir,
+                &Config::load(git_dir)?,
+                message.as_deref(),
+                &options,
+            )
+        }
+        Command::Stash {
+            command:
+                StashCommand::Push {
+                    patch,
+                    message,
+                    pathspec,
+                },
+        } => {
+            let selection = stash::Selection {
+                pathspec: repository::resolve_pathspec(&prefix, &pathspec)?,
+                patch,
+            };
+            stash::push(
+                git_dir,
+                &Config::load(git_dir)?,
+                message.as_deref(),
+                &selection,
+            )
+        }
+        Command::Stash {
+            command: StashCommand::Branch { name, stash },
+        } => stash::branch(git_dir, &name, stash.as_deref()),
+        Command::Lfs {
+            command: LfsCommand::FilterProcess,
+        } => filter::serve(
+            &mut lfs::Lfs::new(git_dir),
+            std::io::stdin().lock(),
+            std::io::stdout().lock(),
+        ),
+        Command::Credential { command } => {
+            let config = Config::load(git_dir)?;
+            let mut credential = credential::Credential::read(std::io::stdin().lock())?;
+            match command {
+                CredentialCommand::Fill => {
+                    credential.fill(&config)?;
+                    credential.write(&mut std::io::stdout().lock())
+                }
+                CredentialCommand::Approve => credential.approve(&config),
+                CredentialCommand::Reject => credential.reject(&config),
+            }
+        }
+        Command::Reflog {
+            command:
+                ReflogCommand::Expire {
+                    expire,
+                    expire_unreachable,
+                    stale_fix,
+                    rewrite,
+                    updateref,
+                    dry_run,
+                    verbose,
+                    all,
+                    refs,
+                },
+        } => {
+            let options = reflog::ExpireOptions {
+                stale_fix,
+                rewrite,
+                update_ref: updateref,
+                dry_run,
+                verbose,
+            };
+            let names = match all {
+                true => reflog::list(git_dir)?,
+                false => refs,
+            };
+            reflog::expire_refs(
+                git_dir,
+                &Config::load(git_dir)?,
+                &names,
+                expire.as_deref(),
+                expire_unreachable.as_deref(),
+                &options,
+            )
+        }
+        Command::CommitGraph {
+            command: VerifyCommand::Verify,
+        } => match commit_graph::CommitGraph::load(git_dir)? {
+            Some(graph) => report(graph.verify(git_dir)?),
+            None => Ok(()),
+        },
+        Command::MultiPackIndex {
+            command: VerifyCommand::Verify,
+        } => match midx::MultiPackIndex::load(git_dir)? {
+            Some(midx) => report(midx.verify()?),
+            None => Ok(()),
+        },
+        Command::CheckMailmap { contacts } => {
+            let mailmap = Mailmap::load(&Config::load(git_dir)?);
+            for contact in contacts {
+                println!("{}", mailmap.map_contact(&contact)?);
+            }
+            Ok(())
+        }
+        Command::UpdateRef { name, sha } => {
+            let sha = revision::resolve(git_dir, &sha)?;
+            refs::update_ref(git_dir, &name, &sha)
+        }
+        Command::Checkout {
+            patch,
+            create,
+            force_create,
+            detach,
+            force,
+            merge,
+            revision,
+            pathspec,
+        } => {
+            if merge {
+                let pathspec = repository::resolve_pathspec(&prefix, &pathspec)?;
+                return restore::checkout_merge(git_dir, &pathspec);
+            }
+            let switching = revision.is_some() || create.is_some() || force_create.is_some();
+            if pathspec.is_empty() && !patch && (switching || detach) {
+                let options = branch::SwitchOptions {
+                    force_create: force_create.is_some(),
+                    create: create.or(force_create),
+                    detach,
+                    detach_commits: true,
+                    force,
+                    quiet: false,
+                };
+                return branch::switch(
+                    git_dir,
+                    &Config::load(git_dir)?,
+                    revision.as_deref(),
+                    &options,
+                    Some(&progress),
+                );
+            }
+            let pathspec = repository::resolve_pathspec(&prefix, &pathspec)?;
+            // Files checked out from a commit are staged too
+            let options = restore::RestoreOptions {
+                staged: revision.is_some(),
+                worktree: true,
+                source: revision,
+                patch,
+            };
+            restore::restore(git_dir, &pathspec, &options)
+        }
+        Command::Restore {
+            patch,
+            source,
+            staged,
+            worktree,
+            pathspec,
+        } => {
+            let pathspec = repository::resolve_pathspec(&prefix, &pathspec)?;
+            let options = restore::RestoreOptions {
+                source,
+                staged,
+                worktree,
+                patch,
+            };
+            restore::restore(git_dir, &pathspec, &options)
+        }
+        Command::Switch {
+            create,
+            force_create,
+            detach,
+            orphan: None,
+            force,
+            target,
+        } => {
+            let options = branch::SwitchOptions {
+                force_create: force_create.is_some(),
+                create: create.or(force_create),
+                detach,
+                detach_commits: false,
+                force,
+                quiet: false,
+            };
+            branch::switch(
+                git_dir,
+                &Config::load(git_dir)?,
+                target.as_deref(),
+                &options,
+                Some(&progress),
+            )
+        }
+        Command::Switch {
+            orphan: Some(orphan),
+            force,
+            ..
+        } => branch::switch_orphan(git_dir, &orphan, force),
+        Command::Pull {
+            rebase,
+            no_rebase,
+            ff_only,
+            autostash,
+            no_autostash,
+            force,
+        } => {
+            let options = pull::PullOptions {
+                rebase: match (rebase, no_rebase) {
+                    (true, _) => Some(true),
+                    (false, true) => Some(false),
+                    (false, false) => None,
+                },
+                ff_only,
+                autostash: match (autostash, no_autostash) {
+                    (true, _) => Some(true),
+                    (false, true) => Some(false),
+                    (false, false) => None,
+                },
+                force,
+            };
+            pull::pull(git_dir, &Config::load(git_dir)?, &options, Some(&progress))
+        }
+        Command::ForEach {
+            jobs,
+            directory,
+            command,
+        } => {
+            // The directory is given from where the command was run
+            let dir = cwd.join(directory.unwrap_or_default());
+            let jobs = match jobs {
+                Some(jobs) if jobs > 0 => jobs,
+                _ => std::thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+            };
+            if for_each::for_each(&dir, &command, jobs)? > 0 {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Parses the command line like git, see [`Args`].
+fn parse_compat() -> Args {
+    let mut command = Args::command().bin_name("git").infer_long_args(true);
+    command.build();
+    let args = std::env::args().collect::<Vec<_>>();
+    // Only `-h` alone asks for the usage, it's a short option otherwise
+    if let [_, name, help] = &args[..] {
+        if let Some(subcommand) = command.find_subcommand_mut(name).filter(|_| help == "-h") {
+            println!("{}", subcommand.render_usage());
+            std::process::exit(129);
+        }
+    }
+    let matches = command
+        .try_get_matches()
+        .unwrap_or_else(|e| match e.kind() {
+            clap::error::ErrorKind::DisplayHelp | clap::error::ErrorKind::DisplayVersion => {
+                e.exit()
+            }
+            _ => {
+                let _ = e.print();
+                std::process::exit(129);
+            }
+        });
+    Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+}
+
+/// Prints the problems found by a verification, failing if there are any.
+fn report(errors: Vec<String>) -> eyre::Result<()> {
+    for error in &errors {
+        eprintln!("error: {error}");
+    }
+    match errors.len() {
+        0 => Ok(()),
+        count => Err(eyre::eyre!(tr!("%d problems found", count))),
     }
 }
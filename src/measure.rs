@@ -0,0 +1,66 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The time spent in a phase of the command and how many times it ran.
+type Phase = (&'static str, Duration, usize);
+
+/// The phases of the command, in the order they first ended, when
+/// `--measure` was given.
+static PHASES: Mutex<Option<Vec<Phase>>> = Mutex::new(None);
+
+/// Starts recording the phases of the command.
+pub fn enable() {
+    *PHASES.lock().unwrap_or_else(|e| e.into_inner()) = Some(Vec::new());
+}
+
+/// Adds `elapsed` to the time spent in `phase`, if recording.
+pub fn record(phase: &'static str, elapsed: Duration) {
+    if let Some(phases) = PHASES.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        match phases.iter_mut().find(|(name, _, _)| *name == phase) {
+            Some((_, total, count)) => {
+                *total += elapsed;
+                *count += 1;
+            }
+            None => phases.push((phase, elapsed, 1)),
+        }
+    }
+}
+
+/// Runs `f` as part of `phase`, whose time is recorded if recording.
+pub fn phase<T>(phase: &'static str, f: impl FnOnce() -> T) -> T {
+    if PHASES.lock().unwrap_or_else(|e| e.into_inner()).is_none() {
+        return f();
+    }
+    let start = Instant::now();
+    let value = f();
+    record(phase, start.elapsed());
+    value
+}
+
+/// Prints the recorded phases to stderr, then the `total` time since
+/// `start`. The phases run while running the command are part of its time
+/// too.
+pub fn report(start: Instant) {
+    let total = start.elapsed();
+    let Some(phases) = PHASES.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    let width = phases
+        .iter()
+        .map(|(name, _, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max("total".len());
+    for (name, elapsed, count) in phases {
+        let count = match count {
+            1 => String::new(),
+            _ => format!(" ({count} times)"),
+        };
+        eprintln!("{name:width$}  {:>9.3} ms{count}", millis(elapsed));
+    }
+    eprintln!("{:width$}  {:>9.3} ms", "total", millis(total));
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
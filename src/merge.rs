@@ -0,0 +1,340 @@
+use crate::branch;
+use crate::commit::{Commit, Signature};
+use crate::config::Config;
+use crate::diff;
+use crate::git;
+use crate::hooks;
+use crate::index::{ConflictVersions, Entry, Index};
+use crate::odb::Odb;
+use crate::refs;
+use crate::revision;
+use crate::revwalk::RevWalk;
+use crate::tree::{self, Files};
+use crate::worktree::{self, Operation};
+use eyre::eyre;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The outcome of a three-way merge of trees.
+pub struct TreeMerge {
+    /// The merged files. Conflicted files hold their content with conflict
+    /// markers.
+    pub files: Files,
+    /// The paths that couldn't be merged automatically.
+    pub conflicts: Vec<PathBuf>,
+    /// The base, our and their versions of the conflicted paths, missing
+    /// where the path is absent from a tree.
+    pub versions: BTreeMap<PathBuf, ConflictVersions>,
+}
+
+/// Merges the changes made from tree `base` to `ours` and to `theirs`.
+/// `labels` name our and their side in conflict markers.
+pub fn merge_trees(
+    git_dir: &Path,
+    base: Option<&str>,
+    ours: &str,
+    theirs: &str,
+    labels: (&str, &str),
+) -> eyre::Result<TreeMerge> {
+    let base = match base {
+        Some(sha) => tree::flatten_tree(git_dir, sha)?,
+        None => Files::new(),
+    };
+    let ours = tree::flatten_tree(git_dir, ours)?;
+    let theirs = tree::flatten_tree(git_dir, theirs)?;
+
+    let paths = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    let mut files = Files::new();
+    let mut conflicts = Vec::new();
+    let mut versions = BTreeMap::new();
+    for path in paths {
+        let (b, o, t) = (base.get(&path), ours.get(&path), theirs.get(&path));
+
+        // Take the side that changed, if only one did
+        let merged = if o == t || b == t {
+            o.cloned()
+        } else if b == o {
+            t.cloned()
+        } else {
+            match (o, t) {
+                // Both sides changed the file, merge the contents
+                (Some(o), Some(t)) => {
+                    let base_content = match b {
+                        Some((_, sha)) => git::read_blob(git_dir, sha)?,
+                        None => Vec::new(),
+                    };
+                    let merge = diff::merge3(
+                        &base_content,
+                        &git::read_blob(git_dir, &o.1)?,
+                        &git::read_blob(git_dir, &t.1)?,
+                        labels,
+                    );
+                    if merge.conflicts > 0 {
+                        conflicts.push(path.clone());
+                        versions
+                            .insert(path.clone(), [b.cloned(), Some(o.clone()), Some(t.clone())]);
+                    }
+
+                    // Keep the mode change made by their side, if any
+                    let mode = match b {
+                        Some((mode, _)) if *mode == o.0 => t.0,
+                        _ => o.0,
+                    };
+                    Some((
+                        mode,
+                        Odb::new(git_dir).write("blob", &merge.content)?.into(),
+                    ))
+                }
+                // One side deleted the file while the other modified it, keep
+                // the modified version for the user to decide
+                (Some(kept), None) | (None, Some(kept)) => {
+                    conflicts.push(path.clone());
+                    versions.insert(path.clone(), [b.cloned(), o.cloned(), t.cloned()]);
+                    Some(kept.clone())
+                }
+                (None, None) => None,
+            }
+        };
+
+        if let Some(entry) = merged {
+            files.insert(path, entry);
+        }
+    }
+
+    Ok(TreeMerge {
+        files,
+        conflicts,
+        versions,
+    })
+}
+
+/// How `merge` joins the history of a commit.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MergeOptions {
+    /// Refuses to create a merge commit, only fast-forwarding.
+    pub ff_only: bool,
+    /// Throws away the local changes in the way of the merge.
+    pub force: bool,
+}
+
+/// Merges the commit the revision `revision` names into `HEAD`, like
+/// `merge`, describing it as the branch it names, if any.
+pub fn merge_revision(
+    git_dir: &Path,
+    config: &Config,
+    revision: &str,
+    options: MergeOptions,
+) -> eyre::Result<()> {
+    let sha = revision::resolve(git_dir, revision)?;
+    let name = match refs::expand(git_dir, revision)?
+        .as_deref()
+        .and_then(|r| r.strip_prefix("refs/heads/"))
+    {
+        Some(branch) => format!("branch '{branch}'"),
+        None => format!("commit '{revision}'"),
+    };
+    merge(git_dir, config, &sha, &name, options)
+}
+
+/// Merges the commit `theirs` into `HEAD`, fast-forwarding when possible.
+/// `name` describes the merged commit in the merge message (e.g.
+/// `branch 'main' of ../upstream`).
+pub fn merge(
+    git_dir: &Path,
+    config: &Config,
+    theirs: &str,
+    name: &str,
+    options: MergeOptions,
+) -> eyre::Result<()> {
+    let Some(head) = refs::read_ref(git_dir, "HEAD")? else {
+        // Nothing to merge into on an unborn branch, start from theirs
+        let theirs_files = tree::flatten_tree(git_dir, &Commit::read(git_dir, theirs)?.tree)?;
+        let current = worktree::prepare_update(
+            git_dir,
+            &Files::new(),
+            &theirs_files,
+            Operation::Merge,
+            options.force,
+        )?;
+        worktree::checkout_files(git_dir, &current, &theirs_files, None)?;
+        branch::update_index(git_dir, &Files::new(), &theirs_files, options.force)?;
+        refs::update_ref(git_dir, "HEAD", theirs)?;
+        return Ok(());
+    };
+
+    let mut walk = RevWalk::new();
+    let bases = walk.merge_bases(git_dir, &head, theirs)?;
+    if bases.iter().any(|b| b == theirs) {
+        println!("{}", tr!("Already up to date."));
+        return Ok(());
+    }
+
+    let head_tree = Commit::read(git_dir, &head)?.tree;
+    if bases.contains(&head) {
+        println!("{}", tr!("Updating %s..%s", &head[..7], &theirs[..7]));
+        let (ours, theirs_files) = (
+            tree::flatten_tree(git_dir, &head_tree)?,
+            tree::flatten_tree(git_dir, &Commit::read(git_dir, theirs)?.tree)?,
+        );
+        let current = worktree::prepare_update(
+            git_dir,
+            &ours,
+            &theirs_files,
+            Operation::Merge,
+            options.force,
+        )?;
+        println!("{}", tr!("Fast-forward"));
+        worktree::checkout_files(git_dir, &current, &theirs_files, None)?;
+        branch::update_index(git_dir, &ours, &theirs_files, options.force)?;
+        refs::record_orig_head(git_dir)?;
+        refs::update_ref(git_dir, "HEAD", theirs)?;
+        return Ok(());
+    }
+
+    if options.ff_only {
+        return Err(eyre!(tr!("Not possible to fast-forward, aborting.")));
+    }
+
+    // Without a single best common ancestor, merge from the first one
+    let base = match bases.first() {
+        Some(sha) => Some(Commit::read(git_dir, sha)?.tree),
+        None => None,
+    };
+    let theirs_tree = Commit::read(git_dir, theirs)?.tree;
+    let result = merge_trees(
+        git_dir,
+        base.as_deref(),
+        &head_tree,
+        &theirs_tree,
+        ("HEAD", name),
+    )?;
+
+    let ours = tree::flatten_tree(git_dir, &head_tree)?;
+    let current = worktree::prepare_update(
+        git_dir,
+        &ours,
+        &result.files,
+        Operation::Merge,
+        options.force,
+    )?;
+    worktree::checkout_files(git_dir, &current, &result.files, None)?;
+    stage_merge(git_dir, &ours, &result)?;
+    refs::record_orig_head(git_dir)?;
+
+    let mut message = format!("Merge {name}\n");
+    if !result.conflicts.is_empty() {
+        // Leave the merge in progress for the user to resolve and commit
+        refs::update_ref(git_dir, "MERGE_HEAD", theirs)?;
+        fs::write(git_dir.join("MERGE_MSG"), &message)?;
+        for path in &result.conflicts {
+            println!(
+                "{}",
+                tr!(
+                    "CONFLICT (%s): Merge conflict in %s",
+                    "content",
+                    path.display()
+                )
+            );
+        }
+        return Err(eyre!(tr!(
+            "Automatic merge failed; fix conflicts and then commit the result."
+        )));
+    }
+
+    hooks::run(|hook| hook.pre_commit(git_dir, true))?;
+    hooks::run(|hook| hook.commit_msg(git_dir, &mut message))?;
+    let commit = Commit {
+        tree: tree::write_tree(git_dir, &result.files)?,
+        parents: vec![head, theirs.to_string()],
+        author: Signature::now(config, "AUTHOR")?,
+        committer: Signature::now(config, "COMMITTER")?,
+        encoding: Commit::encoding_for(config)?,
+        extra_headers: Vec::new(),
+        message,
+    };
+    refs::update_ref(git_dir, "HEAD", &commit.write(git_dir)?)?;
+    println!("{}", tr!("Merge made by the '%s' strategy.", "resolve"));
+    Ok(())
+}
+
+/// Stages the files the merge `result` changed from `ours`, and the base,
+/// our and their versions of its conflicted paths at stages 1 to 3, for the
+/// user to resolve them.
+fn stage_merge(git_dir: &Path, ours: &Files, result: &TreeMerge) -> eyre::Result<()> {
+    let mut index = Index::read(git_dir)?;
+    // Conflicts resolved before the merge can't be recreated anymore
+    index.resolve_undo.clear();
+    for path in ours.keys().filter(|path| !result.files.contains_key(*path)) {
+        index.remove(&path.to_string_lossy().replace('\\', "/"));
+    }
+    for (path, (mode, sha)) in &result.files {
+        if ours.get(path) == Some(&(*mode, sha.clone())) {
+            continue;
+        }
+        let name = path.to_string_lossy().replace('\\', "/");
+        index.add(Entry::new(
+            name,
+            sha.clone(),
+            *mode,
+            &fs::symlink_metadata(path)?,
+        ));
+    }
+    for (path, versions) in &result.versions {
+        index.add_conflict(&path.to_string_lossy().replace('\\', "/"), versions);
+    }
+    index.write(git_dir)
+}
+
+/// What `merge_base` looks for.
+#[derive(Debug, Default)]
+pub struct MergeBaseOptions {
+    /// Prints every best common ancestor rather than one.
+    pub all: bool,
+    /// Prints nothing, only checking whether the first commit is an
+    /// ancestor of the second.
+    pub is_ancestor: bool,
+    /// Prints the commits that can't be reached from any of the others,
+    /// for any number of commits.
+    pub independent: bool,
+}
+
+/// Prints the best common ancestor of the two commits the revisions
+/// `commits` name, or what `options` asks for. Returns false if they have
+/// no common ancestor, or with `is_ancestor` if the first commit isn't an
+/// ancestor of the second.
+pub fn merge_base(
+    git_dir: &Path,
+    commits: &[String],
+    options: &MergeBaseOptions,
+) -> eyre::Result<bool> {
+    let commits = commits
+        .iter()
+        .map(|commit| revision::peel(git_dir, &revision::resolve(git_dir, commit)?))
+        .collect::<eyre::Result<Vec<_>>>()?;
+    let mut walk = RevWalk::new();
+    if options.independent {
+        for commit in walk.independent(git_dir, &commits)? {
+            println!("{commit}");
+        }
+        return Ok(true);
+    }
+    let [a, b] = &commits[..] else {
+        return Err(eyre!(tr!("merge-base needs exactly two commits")));
+    };
+    if options.is_ancestor {
+        return Ok(walk.contains(git_dir, std::slice::from_ref(b), a)?[0]);
+    }
+    let bases = walk.merge_bases(git_dir, a, b)?;
+    let count = if options.all { bases.len() } else { 1 };
+    for base in bases.iter().take(count) {
+        println!("{base}");
+    }
+    Ok(!bases.is_empty())
+}
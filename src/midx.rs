@@ -0,0 +1,176 @@
+use crate::pack::PackIndex;
+use eyre::eyre;
+use sha1::Digest;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A multi-pack-index, listing the objects of several packs in a single
+/// sorted table with the pack and offset of each.
+#[derive(Debug)]
+pub struct MultiPackIndex {
+    path: PathBuf,
+    data: Vec<u8>,
+    /// The names of the indexes of the covered packs.
+    packs: Vec<String>,
+    count: usize,
+    oid_fanout: usize,
+    oid_lookup: usize,
+    offsets: usize,
+    large_offsets: Option<usize>,
+}
+
+impl MultiPackIndex {
+    /// Loads the multi-pack-index of the repository at `git_dir`, if it has
+    /// one.
+    pub fn load(git_dir: &Path) -> eyre::Result<Option<Self>> {
+        let path = git_dir.join("objects/pack/multi-pack-index");
+        let Ok(data) = fs::read(&path) else {
+            return Ok(None);
+        };
+        let invalid = || eyre!("invalid multi-pack-index file {}", path.display());
+        if !data.starts_with(b"MIDX") || data.len() < 12 {
+            return Err(invalid());
+        }
+
+        // The header ends with the chunk and pack counts, followed by the
+        // chunk table and an entry marking the end of the last chunk
+        let chunk_count = data[6] as usize;
+        let mut chunks = Vec::new();
+        for i in 0..chunk_count {
+            let entry = data
+                .get(12 + i * 12..12 + i * 12 + 12)
+                .ok_or_else(invalid)?;
+            let offset = u64::from_be_bytes(entry[4..].try_into()?) as usize;
+            chunks.push((&entry[..4], offset));
+        }
+        let chunk = |id: &[u8]| chunks.iter().find(|(c, _)| *c == id).map(|(_, o)| *o);
+
+        let pack_count = u32::from_be_bytes(data[8..12].try_into()?) as usize;
+        let names = chunk(b"PNAM").ok_or_else(invalid)?;
+        let packs = data[names..]
+            .split(|b| *b == 0)
+            .take(pack_count)
+            .map(|name| String::from_utf8_lossy(name).to_string())
+            .collect();
+
+        let oid_fanout = chunk(b"OIDF").ok_or_else(invalid)?;
+        let count = data
+            .get(oid_fanout + 255 * 4..oid_fanout + 256 * 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .ok_or_else(invalid)?;
+        Ok(Some(Self {
+            packs,
+            count,
+            oid_fanout,
+            oid_lookup: chunk(b"OIDL").ok_or_else(invalid)?,
+            offsets: chunk(b"OOFF").ok_or_else(invalid)?,
+            large_offsets: chunk(b"LOFF"),
+            path,
+            data,
+        }))
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> eyre::Result<&[u8]> {
+        self.data.get(offset..offset + len).ok_or(eyre!(
+            "truncated multi-pack-index file {}",
+            self.path.display()
+        ))
+    }
+
+    fn word(&self, offset: usize) -> eyre::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(offset, 4)?.try_into()?))
+    }
+
+    fn oid(&self, i: usize) -> eyre::Result<String> {
+        Ok(hex::encode(self.bytes(self.oid_lookup + i * 20, 20)?))
+    }
+
+    /// Returns the position of the pack holding the object at position `i`,
+    /// and its offset in that pack.
+    fn location(&self, i: usize) -> eyre::Result<(usize, u64)> {
+        let pack = self.word(self.offsets + i * 8)? as usize;
+        let offset = self.word(self.offsets + i * 8 + 4)?;
+
+        // Offsets that don't fit 31 bits are stored in the large offsets
+        // chunk, indexed by the lower bits
+        let offset = match (offset & 0x8000_0000, self.large_offsets) {
+            (0, _) => offset as u64,
+            (_, Some(large)) => {
+                let entry = large + (offset & 0x7fff_ffff) as usize * 8;
+                u64::from_be_bytes(self.bytes(entry, 8)?.try_into()?)
+            }
+            (_, None) => return Err(eyre!("multi-pack-index has no large offsets chunk")),
+        };
+        Ok((pack, offset))
+    }
+
+    /// Checks the index against the indexes of the packs it covers,
+    /// returning a description of every mismatch found.
+    pub fn verify(&self) -> eyre::Result<Vec<String>> {
+        let mut errors = Vec::new();
+
+        let (content, checksum) = self.data.split_at(self.data.len().saturating_sub(20));
+        if sha1::Sha1::digest(content).as_slice() != checksum {
+            errors.push(tr!("incorrect checksum"));
+        }
+
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let mut indexes = Vec::new();
+        for name in &self.packs {
+            match fs::read(dir.join(name)) {
+                Ok(data) => indexes.push(Some(PackIndex::parse(&data)?)),
+                Err(_) => {
+                    errors.push(tr!("failed to load pack-index for packfile %s", name));
+                    indexes.push(None);
+                }
+            }
+        }
+
+        for byte in 1..256 {
+            let previous = self.word(self.oid_fanout + (byte - 1) * 4)?;
+            let fanout = self.word(self.oid_fanout + byte * 4)?;
+            if previous > fanout {
+                errors.push(tr!(
+                    "oid fanout out of order: fanout[%d] = %s > %s = fanout[%d]",
+                    byte - 1,
+                    format!("{previous:x}"),
+                    format!("{fanout:x}"),
+                    byte
+                ));
+            }
+        }
+
+        for i in 0..self.count {
+            let oid = self.oid(i)?;
+            if i > 0 {
+                let previous = self.oid(i - 1)?;
+                if previous >= oid {
+                    errors.push(tr!(
+                        "oid lookup out of order: oid[%d] = %s >= %s = oid[%d]",
+                        i - 1,
+                        previous,
+                        oid,
+                        i
+                    ));
+                }
+            }
+
+            let (pack, offset) = self.location(i)?;
+            let Some(Some(index)) = indexes.get(pack) else {
+                continue;
+            };
+            match index.offset(&oid) {
+                None => errors.push(tr!("failed to load pack entry for oid[%d] = %s", i, oid)),
+                Some(actual) if actual != offset => errors.push(tr!(
+                    "incorrect object offset for oid[%d] = %s: %s != %s",
+                    i,
+                    oid,
+                    format!("{offset:x}"),
+                    format!("{actual:x}")
+                )),
+                _ => {}
+            }
+        }
+        Ok(errors)
+    }
+}
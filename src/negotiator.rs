@@ -0,0 +1,178 @@
+use crate::config::Config;
+use crate::revwalk::RevWalk;
+use eyre::eyre;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::path::Path;
+
+/// How `fetch` picks the commits it tells the remote it has, from
+/// `fetch.negotiationAlgorithm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Every commit, newest first, the default.
+    Consecutive,
+    /// Commits further and further apart as the walk goes without finding
+    /// common ones, so diverged histories are crossed in few rounds.
+    Skipping,
+    /// No commit at all, the remote sends everything the wanted refs need.
+    Noop,
+}
+
+impl Algorithm {
+    /// Reads `fetch.negotiationAlgorithm`.
+    pub fn from_config(config: &Config) -> eyre::Result<Self> {
+        match config.get("fetch.negotiationAlgorithm") {
+            None | Some("consecutive" | "default") => Ok(Self::Consecutive),
+            Some("skipping") => Ok(Self::Skipping),
+            Some("noop") => Ok(Self::Noop),
+            Some(other) => Err(eyre!(tr!(
+                "unknown fetch negotiation algorithm '%s'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Walks the local history from the local refs, newest first, choosing the
+/// `have` lines of a fetch. Commits the remote acknowledges are common, and
+/// so are their ancestors, which are never sent.
+pub struct Negotiator {
+    algorithm: Algorithm,
+    walk: RevWalk,
+    queue: BinaryHeap<(i64, String)>,
+    /// The `(original_ttl, ttl)` of the queued commits: a commit is sent
+    /// once its ttl drops to 0, the commits after it in the walk then being
+    /// skipped for longer than it was.
+    ttls: HashMap<String, (u16, u16)>,
+    seen: HashSet<String>,
+    popped: HashSet<String>,
+    common: HashSet<String>,
+    /// How many queued commits aren't common, the walk ends at 0.
+    non_common: usize,
+}
+
+impl Negotiator {
+    /// Starts a walk from the local commits `tips`.
+    pub fn new(git_dir: &Path, algorithm: Algorithm, tips: &[String]) -> eyre::Result<Self> {
+        let mut negotiator = Self {
+            algorithm,
+            walk: RevWalk::new(),
+            queue: BinaryHeap::new(),
+            ttls: HashMap::new(),
+            seen: HashSet::new(),
+            popped: HashSet::new(),
+            common: HashSet::new(),
+            non_common: 0,
+        };
+        for tip in tips {
+            negotiator.push(git_dir, tip)?;
+        }
+        Ok(negotiator)
+    }
+
+    /// Records that the remote has the local commit `sha`, e.g. because it
+    /// advertised a ref pointing to it.
+    pub fn known_common(&mut self, git_dir: &Path, sha: &str) -> eyre::Result<()> {
+        self.push(git_dir, sha)?;
+        self.mark_common(git_dir, sha)
+    }
+
+    /// Records that the remote acknowledged the commit `sha`. Returns false
+    /// if it was already known to be common.
+    pub fn ack(&mut self, git_dir: &Path, sha: &str) -> eyre::Result<bool> {
+        if self.common.contains(sha) {
+            return Ok(false);
+        }
+        self.mark_common(git_dir, sha)?;
+        Ok(true)
+    }
+
+    /// Returns the next commit to send as `have`, or `None` once every
+    /// commit left is common.
+    pub fn next_have(&mut self, git_dir: &Path) -> eyre::Result<Option<String>> {
+        if self.algorithm == Algorithm::Noop {
+            return Ok(None);
+        }
+        loop {
+            if self.non_common == 0 {
+                return Ok(None);
+            }
+            let Some((_, sha)) = self.queue.pop() else {
+                return Ok(None);
+            };
+            self.popped.insert(sha.clone());
+            let (original_ttl, ttl) = self.ttls.remove(&sha).unwrap_or_default();
+            let common = self.common.contains(&sha);
+            if !common {
+                self.non_common -= 1;
+            }
+
+            let parents = self.walk.commit(git_dir, &sha)?.parents.clone();
+            let mut pushed = false;
+            for parent in parents {
+                // A parent already popped is older than its child by the
+                // clock only, it's left out
+                if self.popped.contains(&parent) {
+                    continue;
+                }
+                self.push(git_dir, &parent)?;
+                pushed = true;
+                if common {
+                    self.mark_common(git_dir, &parent)?;
+                } else if self.algorithm == Algorithm::Skipping {
+                    let new_original_ttl = match ttl {
+                        0 => original_ttl * 3 / 2 + 1,
+                        _ => original_ttl,
+                    };
+                    let new_ttl = match ttl {
+                        0 => new_original_ttl,
+                        _ => ttl - 1,
+                    };
+                    if let Some(entry) = self.ttls.get_mut(&parent) {
+                        if entry.0 < new_original_ttl {
+                            *entry = (new_original_ttl, new_ttl);
+                        }
+                    }
+                }
+            }
+
+            // Commits without parents left to walk are sent even when
+            // skipped, nothing else would cover their history
+            if !common && (ttl == 0 || !pushed) {
+                return Ok(Some(sha));
+            }
+        }
+    }
+
+    /// Queues the commit `sha` unless it was already seen.
+    fn push(&mut self, git_dir: &Path, sha: &str) -> eyre::Result<()> {
+        if !self.seen.insert(sha.to_string()) {
+            return Ok(());
+        }
+        let time = self.walk.commit(git_dir, sha)?.committer.time;
+        self.queue.push((time, sha.to_string()));
+        self.ttls.insert(sha.to_string(), (0, 0));
+        if !self.common.contains(sha) {
+            self.non_common += 1;
+        }
+        Ok(())
+    }
+
+    /// Marks `sha` and its ancestors seen so far as common.
+    fn mark_common(&mut self, git_dir: &Path, sha: &str) -> eyre::Result<()> {
+        let mut pending = vec![sha.to_string()];
+        while let Some(sha) = pending.pop() {
+            if !self.common.insert(sha.clone()) {
+                continue;
+            }
+            if self.ttls.contains_key(&sha) {
+                self.non_common -= 1;
+            }
+            if !self.seen.contains(&sha) {
+                continue;
+            }
+            let parents = self.walk.commit(git_dir, &sha)?.parents.clone();
+            pending.extend(parents.into_iter().filter(|p| self.seen.contains(p)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// The login and password `~/.netrc` gives for a machine.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub login: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Returns the `~/.netrc` entry for `host`, or the `default` entry, the
+/// file being named by `NETRC` if set. With a `login`, only the entries for
+/// that login match. A missing file has no entries.
+pub fn lookup(host: &str, login: Option<&str>) -> Option<Entry> {
+    let path = match std::env::var_os("NETRC") {
+        Some(path) => PathBuf::from(path),
+        None => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".netrc"),
+            None => return None,
+        },
+    };
+    let content = fs::read_to_string(path).ok()?;
+
+    let entries = parse(&content);
+    let found = entries
+        .iter()
+        .filter(|(machine, _)| machine.as_deref().map_or(true, |machine| machine == host))
+        .map(|(_, entry)| entry)
+        .find(|entry| login.is_none() || entry.login.as_deref() == login);
+    found.cloned()
+}
+
+/// Parses the entries of a netrc file, with the machine they are for,
+/// `None` for the `default` entry. Tokens are separated by any whitespace,
+/// and macro definitions run up to an empty line.
+fn parse(content: &str) -> Vec<(Option<String>, Entry)> {
+    let mut entries = Vec::new();
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        let mut tokens = line.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" => {
+                    let machine = tokens.next().unwrap_or_default().to_string();
+                    entries.push((Some(machine), Entry::default()));
+                }
+                "default" => entries.push((None, Entry::default())),
+                "login" | "password" | "account" => {
+                    let value = tokens.next().map(str::to_string);
+                    let Some((_, entry)) = entries.last_mut() else {
+                        continue;
+                    };
+                    match token {
+                        "login" => entry.login = value,
+                        "password" => entry.password = value,
+                        _ => {}
+                    }
+                }
+                "macdef" => {
+                    // The macro's body ends the line and runs to an empty one
+                    for line in lines.by_ref() {
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    entries
+}
@@ -0,0 +1,227 @@
+use crate::git::{self, Oid};
+use crate::pack::{ObjectInfo, Pack};
+use crate::tempfile::Temporary;
+use eyre::eyre;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// The largest buffer kept in the pool between objects, so that a single
+/// large blob doesn't hold on to its memory.
+const MAX_POOLED_BUFFER: usize = 1 << 20;
+
+thread_local! {
+    static ZLIB: RefCell<Zlib> = RefCell::new(Zlib::new());
+}
+
+/// The object database of a repository, storing objects loose under
+/// `objects/xx/yyyy...`, `xx` being the first byte of their sha.
+#[derive(Debug, Clone)]
+pub struct Odb {
+    git_dir: PathBuf,
+}
+
+impl Odb {
+    pub fn new(git_dir: &Path) -> Self {
+        Self {
+            git_dir: git_dir.to_path_buf(),
+        }
+    }
+
+    /// Stores an object of the given kind and payload and returns its sha.
+    /// Objects are immutable so existing ones are left as they are, and new
+    /// ones are written to a temporary file first so that they're never
+    /// seen half written.
+    pub fn write(&self, kind: &str, payload: &[u8]) -> eyre::Result<Oid> {
+        let (sha, object) = git::hash_object(kind, payload);
+        let path = git::object_path(&self.git_dir, &sha);
+        if path.exists() {
+            return Ok(sha);
+        }
+
+        // The fanout directory is shared with other objects
+        let fanout = path.parent().ok_or(eyre!("invalid object path"))?;
+        fs::create_dir_all(fanout)?;
+        let temporary = fanout.join(format!("tmp_obj_{}_{}", std::process::id(), &sha[2..]));
+        let _temporary = Temporary::new(&temporary);
+        ZLIB.with_borrow_mut(|zlib| -> eyre::Result<()> {
+            fs::write(&temporary, zlib.deflate(&object)?)?;
+            Ok(())
+        })?;
+        fs::rename(&temporary, &path)?;
+        Ok(sha)
+    }
+}
+
+/// Reads and inflates the loose object file at `path`, header included.
+pub fn read_loose(path: &Path) -> eyre::Result<Vec<u8>> {
+    let mut file = fs::File::open(path)?;
+    ZLIB.with_borrow_mut(|zlib| {
+        zlib.buffer.clear();
+        file.read_to_end(&mut zlib.buffer)?;
+        // Objects usually compress to less than half their size
+        let capacity = zlib.buffer.len() * 2 + 64;
+        let inflated = inflate_with(&mut zlib.decompress, &zlib.buffer, capacity);
+        zlib.buffer.clear();
+        zlib.buffer.shrink_to(MAX_POOLED_BUFFER);
+        inflated
+    })
+}
+
+/// Compresses `data` into a zlib stream.
+pub fn deflate(data: &[u8]) -> eyre::Result<Vec<u8>> {
+    ZLIB.with_borrow_mut(|zlib| Ok(zlib.deflate(data)?.to_vec()))
+}
+
+/// Inflates the zlib stream at the start of `compressed`, whose data is
+/// about `size` bytes, ignoring what follows the stream.
+pub fn inflate(compressed: &[u8], size: usize) -> eyre::Result<Vec<u8>> {
+    ZLIB.with_borrow_mut(|zlib| inflate_with(&mut zlib.decompress, compressed, size))
+}
+
+/// Inflates the zlib stream at the start of `compressed` like [`inflate`],
+/// returning the length of the stream along with its data.
+pub fn inflate_stream(compressed: &[u8], size: usize) -> eyre::Result<(Vec<u8>, usize)> {
+    ZLIB.with_borrow_mut(|zlib| {
+        let inflated = inflate_with(&mut zlib.decompress, compressed, size)?;
+        Ok((inflated, zlib.decompress.total_in() as usize))
+    })
+}
+
+/// The zlib streams and the buffer of compressed bytes reused by the objects
+/// read and written on a thread, resetting them being cheaper than
+/// allocating new ones for every object of bulk operations.
+struct Zlib {
+    compress: Compress,
+    decompress: Decompress,
+    buffer: Vec<u8>,
+}
+
+impl Zlib {
+    fn new() -> Self {
+        Self {
+            compress: Compress::new(Compression::default(), true),
+            decompress: Decompress::new(true),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Compresses `data` into the buffer and returns it.
+    fn deflate(&mut self, data: &[u8]) -> eyre::Result<&[u8]> {
+        self.compress.reset();
+        self.buffer.clear();
+        self.buffer.shrink_to(MAX_POOLED_BUFFER);
+        loop {
+            // Incompressible data grows a little
+            self.buffer.reserve(data.len() / 2 + 64);
+            let read = self.compress.total_in() as usize;
+            let status = self.compress.compress_vec(
+                &data[read..],
+                &mut self.buffer,
+                FlushCompress::Finish,
+            )?;
+            if status == Status::StreamEnd {
+                return Ok(&self.buffer);
+            }
+        }
+    }
+}
+
+/// Returns the sha of every object of the repository at `git_dir`, loose
+/// objects first then the objects of `packs`, each listed once. With
+/// `sorted`, the shas are sorted instead.
+pub fn objects(git_dir: &Path, packs: &[Pack], sorted: bool) -> eyre::Result<Vec<String>> {
+    let mut seen = HashSet::new();
+    let mut objects = Vec::new();
+    let mut push = |sha: String| {
+        if seen.insert(sha.clone()) {
+            objects.push(sha);
+        }
+    };
+
+    // Loose objects are stored in `objects/xx/yyyy...`, `xx` being the first
+    // byte of their sha
+    let objects_dir = git_dir.join("objects");
+    for fanout in 0..=255u8 {
+        let prefix = format!("{fanout:02x}");
+        let Ok(entries) = fs::read_dir(objects_dir.join(&prefix)) else {
+            continue;
+        };
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.len() == 38 && name.bytes().all(|b| b.is_ascii_hexdigit()) {
+                push(format!("{prefix}{name}"));
+            }
+        }
+    }
+
+    for pack in packs {
+        pack.index
+            .entries
+            .iter()
+            .for_each(|(sha, _)| push(sha.clone()));
+    }
+
+    if sorted {
+        objects.sort();
+    }
+    Ok(objects)
+}
+
+/// Returns the type, size and delta depth of the object `sha`, stored loose
+/// in the repository at `git_dir` or in one of `packs`.
+pub fn object_info(git_dir: &Path, packs: &[Pack], sha: &str) -> eyre::Result<ObjectInfo> {
+    let path = git::object_path(git_dir, &sha.parse()?);
+    if path.exists() {
+        let (kind, content) = git::read_object(git_dir, sha)?;
+        let kind = match kind.as_str() {
+            "commit" => "commit",
+            "tree" => "tree",
+            "blob" => "blob",
+            "tag" => "tag",
+            _ => return Err(eyre!("object {sha} has an invalid type {kind}")),
+        };
+        return Ok(ObjectInfo {
+            kind,
+            size: content.len() as u64,
+            delta_depth: 0,
+            disk_size: fs::metadata(path)?.len(),
+        });
+    }
+
+    packs
+        .iter()
+        .find_map(|pack| Some(pack.object_info(pack.index.offset(sha)?)))
+        .unwrap_or(Err(eyre!("object {sha} not found")))
+}
+
+/// Inflates the zlib stream at the start of `input` with `decompress`,
+/// starting with room for `capacity` bytes.
+fn inflate_with(
+    decompress: &mut Decompress,
+    input: &[u8],
+    capacity: usize,
+) -> eyre::Result<Vec<u8>> {
+    decompress.reset(true);
+    let mut output = Vec::with_capacity(capacity.max(64));
+    loop {
+        if output.len() == output.capacity() {
+            output.reserve(output.capacity());
+        }
+        let read = decompress.total_in() as usize;
+        let written = output.len();
+        let status =
+            decompress.decompress_vec(&input[read..], &mut output, FlushDecompress::None)?;
+        if status == Status::StreamEnd {
+            return Ok(output);
+        }
+        // With room left for output, no progress means the stream is cut
+        // short
+        if decompress.total_in() as usize == read && output.len() == written {
+            return Err(eyre!("truncated zlib stream"));
+        }
+    }
+}
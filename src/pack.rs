@@ -0,0 +1,1409 @@
+use crate::git::{self, GitFile, GitFileContent};
+use crate::measure;
+use crate::odb::{self, Odb};
+use crate::partial::Filter;
+use crate::progress::Progress;
+use crate::tempfile::Temporary;
+use crate::tree::{MODE_GITLINK, MODE_TREE};
+use eyre::eyre;
+use sha1::Digest;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Pack entry type of an object stored as a delta against the object at a
+/// relative offset in the same pack.
+const OFS_DELTA: u8 = 6;
+/// Pack entry type of an object stored as a delta against an object named
+/// by its sha.
+const REF_DELTA: u8 = 7;
+
+/// The index of a pack, mapping the sha of each object of the pack to its
+/// offset in the pack, sorted by sha.
+#[derive(Debug)]
+pub struct PackIndex {
+    pub entries: Vec<(String, u64)>,
+}
+
+impl PackIndex {
+    /// Parses a pack index, in version 1 or 2.
+    pub fn parse(index: &[u8]) -> eyre::Result<Self> {
+        let truncated = || eyre!("truncated pack index");
+        let word = |offset: usize| -> eyre::Result<u32> {
+            let bytes = index.get(offset..offset + 4).ok_or_else(truncated)?;
+            Ok(u32::from_be_bytes(bytes.try_into()?))
+        };
+        let sha = |offset: usize| -> eyre::Result<String> {
+            Ok(hex::encode(
+                index.get(offset..offset + 20).ok_or_else(truncated)?,
+            ))
+        };
+
+        // Version 2 starts with a magic number and the version, version 1
+        // directly with the fanout table
+        if !index.starts_with(b"\xfftOc") {
+            let count = word(255 * 4)? as usize;
+            let entries = (0..count)
+                .map(|i| {
+                    let entry = 256 * 4 + i * 24;
+                    Ok((sha(entry + 4)?, word(entry)? as u64))
+                })
+                .collect::<eyre::Result<_>>()?;
+            return Ok(Self { entries });
+        }
+
+        let count = word(8 + 255 * 4)? as usize;
+        let shas = 8 + 256 * 4;
+        let offsets = shas + count * 24;
+        let large_offsets = offsets + count * 4;
+        let entries = (0..count)
+            .map(|i| {
+                // Offsets past 2GiB are stored in a table of 64 bit offsets,
+                // indexed by the lower bits
+                let offset = word(offsets + i * 4)?;
+                let offset = match offset & 0x8000_0000 {
+                    0 => offset as u64,
+                    _ => {
+                        let large = large_offsets + (offset & 0x7fff_ffff) as usize * 8;
+                        let bytes = index.get(large..large + 8).ok_or_else(truncated)?;
+                        u64::from_be_bytes(bytes.try_into()?)
+                    }
+                };
+                Ok((sha(shas + i * 20)?, offset))
+            })
+            .collect::<eyre::Result<_>>()?;
+        Ok(Self { entries })
+    }
+
+    /// Returns the offset of the object `sha` in the pack, if it's there.
+    pub fn offset(&self, sha: &str) -> Option<u64> {
+        let i = self
+            .entries
+            .binary_search_by(|(s, _)| s.as_str().cmp(sha))
+            .ok()?;
+        Some(self.entries[i].1)
+    }
+}
+
+/// What the header of a pack entry says about an object, without inflating
+/// it.
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub kind: &'static str,
+    /// The size of the object once its deltas are resolved.
+    pub size: u64,
+    /// The number of deltas to apply to get the object, 0 if it's stored
+    /// whole.
+    pub delta_depth: usize,
+    /// The number of bytes the object takes on disk, compressed and
+    /// possibly as a delta.
+    pub disk_size: u64,
+}
+
+/// A pack file with its index.
+#[derive(Debug)]
+pub struct Pack {
+    pub path: PathBuf,
+    pub index: PackIndex,
+    data: Vec<u8>,
+    /// The offsets of the entries, sorted, each entry ending where the next
+    /// one starts.
+    offsets: Vec<u64>,
+}
+
+impl Pack {
+    /// Opens the pack of the given index.
+    pub fn open(index_path: &Path) -> eyre::Result<Self> {
+        let index = PackIndex::parse(&fs::read(index_path)?)?;
+        let path = index_path.with_extension("pack");
+        let data = fs::read(&path)?;
+        if !data.starts_with(b"PACK") {
+            return Err(eyre!("{} is not a pack file", path.display()));
+        }
+        let mut offsets = index.entries.iter().map(|(_, o)| *o).collect::<Vec<_>>();
+        offsets.sort();
+        Ok(Self {
+            path,
+            index,
+            data,
+            offsets,
+        })
+    }
+
+    /// Returns the type, size and delta depth of the object at `offset`.
+    pub fn object_info(&self, offset: u64) -> eyre::Result<ObjectInfo> {
+        // The last entry ends with the checksum of the pack
+        let end = match self.offsets.binary_search(&offset) {
+            Ok(i) if i + 1 < self.offsets.len() => self.offsets[i + 1],
+            _ => self.data.len().saturating_sub(20) as u64,
+        };
+        let disk_size = end.saturating_sub(offset);
+
+        let mut offset = offset as usize;
+        let mut delta_depth = 0;
+        let mut size = None;
+        loop {
+            let (kind, entry_size, position) = self.entry_header(offset)?;
+            if kind != OFS_DELTA && kind != REF_DELTA {
+                return Ok(ObjectInfo {
+                    kind: object_kind(kind)?,
+                    size: size.unwrap_or(entry_size),
+                    delta_depth,
+                    disk_size,
+                });
+            }
+            let (base, position) = self.delta_base(offset, kind, position)?;
+
+            // The size of the result is at the start of the outermost delta
+            if size.is_none() {
+                size = Some(delta_result_size(&self.data[position..])?);
+            }
+            delta_depth += 1;
+            offset = base;
+        }
+    }
+
+    /// Returns the type and content of the object at `offset`, applying
+    /// its deltas to their bases.
+    pub fn read_object(&self, offset: u64) -> eyre::Result<(&'static str, Vec<u8>)> {
+        // The deltas are collected down to the object stored whole, then
+        // applied from there
+        let mut deltas = Vec::new();
+        let mut offset = offset as usize;
+        let (kind, mut content) = loop {
+            let (kind, size, position) = self.entry_header(offset)?;
+            if kind != OFS_DELTA && kind != REF_DELTA {
+                break (object_kind(kind)?, self.inflate(offset, position, size)?);
+            }
+            // A chain longer than the pack has entries goes round in circles
+            if deltas.len() > self.offsets.len() {
+                return Err(eyre!("delta chain loop in {}", self.path.display()));
+            }
+            let (base, position) = self.delta_base(offset, kind, position)?;
+            deltas.push(self.inflate(offset, position, size)?);
+            offset = base;
+        };
+        for delta in deltas.iter().rev() {
+            content = apply_delta(&content, delta)?;
+        }
+        Ok((kind, content))
+    }
+
+    /// Returns the offset of the base of the delta at `offset`, of the entry
+    /// type `kind`, and where the delta data starts, the base being named
+    /// from `position`.
+    fn delta_base(&self, offset: usize, kind: u8, position: usize) -> eyre::Result<(usize, usize)> {
+        match kind {
+            OFS_DELTA => {
+                let (distance, read) = base_distance(&self.data[position..])?;
+                let base = offset
+                    .checked_sub(distance)
+                    .filter(|_| distance > 0)
+                    .ok_or(eyre!("invalid delta base offset"))?;
+                Ok((base, position + read))
+            }
+            _ => {
+                let base = hex::encode(self.bytes(position, 20)?);
+                let base = self
+                    .index
+                    .offset(&base)
+                    .ok_or(eyre!("delta base {base} is not in the pack"))?;
+                Ok((base as usize, position + 20))
+            }
+        }
+    }
+
+    /// Inflates the data of the entry at `offset`, starting at `position`,
+    /// checking that it's `size` bytes.
+    fn inflate(&self, offset: usize, position: usize, size: u64) -> eyre::Result<Vec<u8>> {
+        let data = odb::inflate(&self.data[position..], size as usize)?;
+        if data.len() as u64 != size {
+            return Err(eyre!(
+                "object at offset {offset} in {} has the wrong size",
+                self.path.display()
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Parses the header of the entry at `offset`, returning the entry type,
+    /// the size of its inflated data and where its data starts.
+    fn entry_header(&self, offset: usize) -> eyre::Result<(u8, u64, usize)> {
+        let mut position = offset;
+        let mut byte = *self.bytes(position, 1)?.first().unwrap_or(&0);
+        let kind = (byte >> 4) & 7;
+        let mut size = (byte & 0xf) as u64;
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            position += 1;
+            byte = *self.bytes(position, 1)?.first().unwrap_or(&0);
+            size |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+        }
+        Ok((kind, size, position + 1))
+    }
+
+    /// Returns true if the pack ends with the checksum of its content.
+    pub fn checksum_matches(&self) -> bool {
+        let (content, checksum) = self.data.split_at(self.data.len().saturating_sub(20));
+        sha1::Sha1::digest(content).as_slice() == checksum
+    }
+
+    fn bytes(&self, position: usize, len: usize) -> eyre::Result<&[u8]> {
+        self.data
+            .get(position..position + len)
+            .ok_or(eyre!("truncated pack {}", self.path.display()))
+    }
+}
+
+/// An object written to a pack, as its index lists it.
+#[derive(Debug, Clone)]
+pub struct PackedObject {
+    pub sha: String,
+    pub offset: u64,
+    /// The CRC-32 of the entry, header included, which lets an entry be
+    /// copied to another pack without inflating it.
+    pub crc: u32,
+}
+
+/// Writes a version 2 pack to `out`, object by object, the number of objects
+/// being fixed upfront by the header.
+pub struct PackWriter<W: Write> {
+    out: W,
+    hasher: sha1::Sha1,
+    offset: u64,
+    remaining: u32,
+    objects: Vec<PackedObject>,
+    /// The offsets of the objects written, by sha.
+    offsets: HashMap<String, u64>,
+}
+
+impl<W: Write> PackWriter<W> {
+    /// Starts a pack of `count` objects.
+    pub fn new(out: W, count: u32) -> eyre::Result<Self> {
+        let mut writer = Self {
+            out,
+            hasher: sha1::Sha1::new(),
+            offset: 0,
+            remaining: count,
+            objects: Vec::new(),
+            offsets: HashMap::new(),
+        };
+        let mut header = b"PACK".to_vec();
+        header.extend(2u32.to_be_bytes());
+        header.extend(count.to_be_bytes());
+        writer.write(&header)?;
+        Ok(writer)
+    }
+
+    /// Writes the object `sha` of the given kind and content, compressed.
+    pub fn add(&mut self, sha: &str, kind: &str, content: &[u8]) -> eyre::Result<()> {
+        self.add_entry(sha, entry_type(kind)?, &[], content)
+    }
+
+    /// Writes the object `sha` as `delta` against the object `base`: with
+    /// `offset`, as an offset delta against the entry of `base` if it was
+    /// written earlier in the pack, else as a ref delta naming it.
+    pub fn add_delta(
+        &mut self,
+        sha: &str,
+        base: &str,
+        delta: &[u8],
+        offset: bool,
+    ) -> eyre::Result<()> {
+        match self.offsets.get(base).filter(|_| offset) {
+            Some(base_offset) => {
+                let distance = encode_base_distance(self.offset - base_offset);
+                self.add_entry(sha, OFS_DELTA, &distance, delta)
+            }
+            None => self.add_entry(sha, REF_DELTA, &hex::decode(base)?, delta),
+        }
+    }
+
+    /// Writes an entry of the pack entry type `kind`, made of `base`, which
+    /// names the base of deltas, and of `data` compressed.
+    fn add_entry(&mut self, sha: &str, kind: u8, base: &[u8], data: &[u8]) -> eyre::Result<()> {
+        if self.remaining == 0 {
+            return Err(eyre!("too many objects for the pack"));
+        }
+        self.remaining -= 1;
+
+        let mut entry = entry_header(kind, data.len() as u64);
+        entry.extend(base);
+        entry.extend(odb::deflate(data)?);
+
+        let mut crc = flate2::Crc::new();
+        crc.update(&entry);
+        self.offsets.insert(sha.to_string(), self.offset);
+        self.objects.push(PackedObject {
+            sha: sha.to_string(),
+            offset: self.offset,
+            crc: crc.sum(),
+        });
+        self.write(&entry)
+    }
+
+    /// Ends the pack with its checksum, returning the checksum and the
+    /// objects written.
+    pub fn finish(mut self) -> eyre::Result<([u8; 20], Vec<PackedObject>)> {
+        if self.remaining != 0 {
+            return Err(eyre!("{} objects missing from the pack", self.remaining));
+        }
+        let checksum: [u8; 20] = self.hasher.clone().finalize().into();
+        self.out.write_all(&checksum)?;
+        self.out.flush()?;
+        Ok((checksum, self.objects))
+    }
+
+    fn write(&mut self, data: &[u8]) -> eyre::Result<()> {
+        self.out.write_all(data)?;
+        self.hasher.update(data);
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// How `pack_objects` looks for deltas.
+#[derive(Debug, Clone)]
+pub struct PackOptions {
+    /// The number of objects preceding an object that are tried as its
+    /// delta base.
+    pub window: usize,
+    /// The maximum length of the chains of deltas.
+    pub depth: usize,
+    /// Writes deltas against the offset of their base rather than its sha.
+    pub delta_base_offset: bool,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        Self {
+            window: 10,
+            depth: 50,
+            delta_base_offset: false,
+        }
+    }
+}
+
+/// Writes a pack of the objects `objects` of the repository at `git_dir` to
+/// `out`, each object once. Objects come with the path they were found at,
+/// empty if unknown. Objects are stored as deltas against one of the objects
+/// before them when it's smaller, after sorting them by type, path and size
+/// so that similar objects are close. Returns the checksum of the pack and
+/// the objects written, reporting them to `progress` as they're counted and
+/// written.
+pub fn pack_objects(
+    git_dir: &Path,
+    objects: &[(String, String)],
+    options: &PackOptions,
+    progress: Option<&dyn Progress>,
+    out: impl Write,
+) -> eyre::Result<([u8; 20], Vec<PackedObject>)> {
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+    for (sha, path) in objects {
+        if !seen.insert(sha.as_str()) {
+            continue;
+        }
+        let (kind, content) = git::read_object(git_dir, sha)?;
+        candidates.push((sha, kind, path, content));
+        if let Some(progress) = progress {
+            progress.objects_counted(candidates.len());
+        }
+    }
+    // Versions of a file share its name, the bigger ones being the bases
+    // of the smaller ones, which tend to be deltas removing lines
+    let file_name = |path: &str| path.rsplit('/').next().unwrap_or_default().to_string();
+    candidates.sort_by_cached_key(|(_, kind, path, content)| {
+        (
+            kind.clone(),
+            file_name(path),
+            path.to_string(),
+            Reverse(content.len()),
+        )
+    });
+
+    let mut writer = PackWriter::new(out, candidates.len() as u32)?;
+    let mut depths = vec![0; candidates.len()];
+    for (i, (sha, kind, _, content)) in candidates.iter().enumerate() {
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        for base in i.saturating_sub(options.window)..i {
+            let (_, base_kind, _, base_content) = &candidates[base];
+            if base_kind != kind || depths[base] >= options.depth {
+                continue;
+            }
+            let delta = create_delta(base_content, content);
+            if best
+                .as_ref()
+                .map_or(true, |(_, best)| delta.len() < best.len())
+            {
+                best = Some((base, delta));
+            }
+        }
+        match best.filter(|(_, delta)| delta.len() < content.len()) {
+            Some((base, delta)) => {
+                depths[i] = depths[base] + 1;
+                let base = candidates[base].0;
+                writer.add_delta(sha, base, &delta, options.delta_base_offset)?;
+            }
+            None => writer.add(sha, kind, content)?,
+        }
+        if let Some(progress) = progress {
+            progress.objects_written(i + 1, candidates.len());
+        }
+    }
+    writer.finish()
+}
+
+/// Adds the tree `sha` of the repository at `git_dir`, found at `path`, and
+/// the objects it contains to `objects`, for [`pack_objects`], leaving out
+/// the `seen` ones. Submodule commits aren't
+/// objects of the repository.
+pub fn add_tree(
+    git_dir: &Path,
+    sha: &str,
+    path: &str,
+    seen: &mut HashSet<String>,
+    objects: &mut Vec<(String, String)>,
+) -> eyre::Result<()> {
+    add_filtered_tree(git_dir, sha, path, None, 0, seen, objects)
+}
+
+/// Adds the tree `sha`, `depth` trees below the root tree of a commit, and
+/// the objects it contains to `objects` like [`add_tree`], leaving out the
+/// ones `filter` doesn't keep. Trees left out aren't marked as seen, since
+/// they may be kept where they're found less deep.
+pub fn add_filtered_tree(
+    git_dir: &Path,
+    sha: &str,
+    path: &str,
+    filter: Option<Filter>,
+    depth: u64,
+    seen: &mut HashSet<String>,
+    objects: &mut Vec<(String, String)>,
+) -> eyre::Result<()> {
+    if filter.is_some_and(|filter| !filter.keeps_tree(depth)) || !seen.insert(sha.to_string()) {
+        return Ok(());
+    }
+    objects.push((sha.to_string(), path.to_string()));
+    let GitFileContent::Tree(entries) = GitFile::read(git_dir, sha)?.file_content else {
+        return Err(eyre!("object {sha} is not a tree"));
+    };
+    for entry in entries.iter().filter(|e| e.mode != MODE_GITLINK) {
+        let child = hex::encode(&entry.sha);
+        let child_path = match path.is_empty() {
+            true => entry.name.clone(),
+            false => format!("{path}/{}", entry.name),
+        };
+        if entry.mode == MODE_TREE {
+            add_filtered_tree(
+                git_dir,
+                &child,
+                &child_path,
+                filter,
+                depth + 1,
+                seen,
+                objects,
+            )?;
+            continue;
+        }
+        let kept = match filter {
+            Some(filter) => filter.keeps_blob(git_dir, &child, depth + 1)?,
+            None => true,
+        };
+        if kept && seen.insert(child.clone()) {
+            objects.push((child, child_path));
+        }
+    }
+    Ok(())
+}
+
+/// What names the base of a delta read by [`index_pack`].
+enum DeltaBase {
+    Offset(usize),
+    Sha(String),
+}
+
+/// An entry read by [`index_pack`], before its deltas are resolved.
+struct RawEntry {
+    offset: usize,
+    kind: u8,
+    base: Option<DeltaBase>,
+    /// The inflated data, the delta for deltas.
+    data: Vec<u8>,
+    crc: u32,
+}
+
+/// Reads the objects to pack from `input`, one sha per line, optionally
+/// followed by the path the object was found at, like `rev-list --objects`
+/// prints them, which helps finding deltas.
+pub fn read_object_list(input: impl BufRead) -> eyre::Result<Vec<(String, String)>> {
+    let mut objects = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let (sha, path) = line.split_once(' ').unwrap_or((&line, ""));
+        if !sha.is_empty() {
+            let sha = sha.parse::<git::Oid>()?;
+            objects.push((sha.to_string(), path.to_string()));
+        }
+    }
+    Ok(objects)
+}
+
+/// Writes a pack of the objects `objects`, see [`pack_objects`], to
+/// `<base_name>-<name>.pack` along with its index, and returns its name,
+/// the hex of its checksum.
+pub fn write_pack(
+    git_dir: &Path,
+    objects: &[(String, String)],
+    options: &PackOptions,
+    progress: Option<&dyn Progress>,
+    base_name: &str,
+) -> eyre::Result<String> {
+    let mut data = Vec::new();
+    let (checksum, objects) = pack_objects(git_dir, objects, options, progress, &mut data)?;
+    let name = hex::encode(checksum);
+    fs::write(format!("{base_name}-{name}.pack"), data)?;
+    fs::write(
+        format!("{base_name}-{name}.idx"),
+        write_index(&checksum, &objects)?,
+    )?;
+    Ok(name)
+}
+
+/// Reads the pack `data`, read from `path`, computing the sha of each
+/// object with its deltas resolved. Returns the checksum of the pack and
+/// its objects, as its index lists them. The bases of the deltas must be in
+/// the pack.
+pub fn index_pack(
+    path: &Path,
+    data: &[u8],
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<([u8; 20], Vec<PackedObject>)> {
+    let (checksum, objects, _) = read_pack(path, data, None, progress, |_, _, _| Ok(()))?;
+    Ok((checksum, objects))
+}
+
+/// Writes the index of a pack to `index`, next to the pack by default, and
+/// returns the name of the pack, the hex of its checksum. The pack is read
+/// from `input` and written to `pack`, with the packs of the repository at
+/// `git_dir` by default, or read from `pack` without `input`.
+pub fn index_pack_file(
+    git_dir: &Path,
+    pack: Option<&Path>,
+    index: Option<&Path>,
+    input: Option<impl Read>,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<String> {
+    let (data, stored) = match (pack, input) {
+        (Some(pack), None) => (fs::read(pack)?, true),
+        (_, Some(mut input)) => {
+            let mut data = Vec::new();
+            input.read_to_end(&mut data)?;
+            (data, false)
+        }
+        (None, None) => return Err(eyre!(tr!("a pack or its input is required"))),
+    };
+    let (checksum, objects) = index_pack(pack.unwrap_or(Path::new("<stdin>")), &data, progress)?;
+    let name = hex::encode(checksum);
+    let pack = match (stored, pack) {
+        (true, Some(pack)) => pack.to_path_buf(),
+        (_, pack) => {
+            let pack = pack
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| git_dir.join(format!("objects/pack/pack-{name}.pack")));
+            if let Some(parent) = pack.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&pack, &data)?;
+            pack
+        }
+    };
+    let index = index
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| pack.with_extension("idx"));
+    fs::write(index, write_index(&checksum, &objects)?)?;
+    Ok(name)
+}
+
+/// Stores the pack `data` with the packs of the repository at `git_dir`,
+/// along with its index, and returns its name, the hex of its checksum.
+/// Thin packs, whose deltas have bases in the repository, are completed
+/// with those bases first, since a stored pack must be read on its own.
+pub fn store(git_dir: &Path, data: &[u8], progress: Option<&dyn Progress>) -> eyre::Result<String> {
+    let dir = git_dir.join("objects/pack");
+    let (checksum, objects, bases) =
+        read_pack(&dir, data, Some(git_dir), progress, |_, _, _| Ok(()))?;
+    let (data, checksum, objects) = match bases.is_empty() {
+        true => (data.to_vec(), checksum, objects),
+        false => {
+            let data = complete(git_dir, data, &bases)?;
+            let (checksum, objects) = index_pack(&dir, &data, progress)?;
+            (data, checksum, objects)
+        }
+    };
+    let name = hex::encode(checksum);
+    fs::create_dir_all(&dir)?;
+    // The pack is only looked at once its index exists
+    let pack = dir.join(format!("pack-{name}.pack"));
+    let index = dir.join(format!("pack-{name}.idx"));
+    let _temporary = (Temporary::new(&pack), Temporary::new(&index));
+    fs::write(&pack, &data)?;
+    fs::write(&index, write_index(&checksum, &objects)?)?;
+    Ok(name)
+}
+
+/// Returns the thin pack `data` with the objects `bases` of the repository
+/// at `git_dir` added at its end, whole, and its header and checksum
+/// updated.
+fn complete(git_dir: &Path, data: &[u8], bases: &[String]) -> eyre::Result<Vec<u8>> {
+    let mut pack = data[..data.len() - 20].to_vec();
+    let count = u32::from_be_bytes(pack[8..12].try_into()?) + bases.len() as u32;
+    pack[8..12].copy_from_slice(&count.to_be_bytes());
+    for sha in bases {
+        let (kind, content) = git::read_object(git_dir, sha)?;
+        pack.extend(entry_header(entry_type(&kind)?, content.len() as u64));
+        pack.extend(odb::deflate(&content)?);
+    }
+    let checksum: [u8; 20] = sha1::Sha1::digest(&pack).into();
+    pack.extend(checksum);
+    Ok(pack)
+}
+
+/// Reads a pack from `input`, which may go on after it, and returns its
+/// data. The pack is only parsed as far as finding its end takes, it's
+/// checked when indexed or unpacked.
+pub fn read_stream(input: &mut impl BufRead) -> eyre::Result<Vec<u8>> {
+    let mut input = Recorder {
+        inner: input,
+        data: Vec::new(),
+    };
+    let mut header = [0; 12];
+    input.read_exact(&mut header)?;
+    if !header.starts_with(b"PACK") {
+        return Err(eyre!("protocol error (pack signature mismatch detected)"));
+    }
+    let mut byte = [0];
+    for _ in 0..u32::from_be_bytes(header[8..12].try_into()?) {
+        // The size ends at the first byte without its high bit, like the
+        // distance to the base of offset deltas
+        input.read_exact(&mut byte)?;
+        let kind = (byte[0] >> 4) & 7;
+        while byte[0] & 0x80 != 0 {
+            input.read_exact(&mut byte)?;
+        }
+        match kind {
+            OFS_DELTA => loop {
+                input.read_exact(&mut byte)?;
+                if byte[0] & 0x80 == 0 {
+                    break;
+                }
+            },
+            REF_DELTA => input.read_exact(&mut [0; 20])?,
+            _ => {}
+        }
+        // The decoder only consumes the zlib stream of the entry
+        std::io::copy(
+            &mut flate2::bufread::ZlibDecoder::new(&mut input),
+            &mut std::io::sink(),
+        )?;
+    }
+    input.read_exact(&mut [0; 20])?;
+    Ok(input.data)
+}
+
+/// Reads through `inner`, keeping a copy of the data read.
+struct Recorder<R> {
+    inner: R,
+    data: Vec<u8>,
+}
+
+impl<R: BufRead> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.inner.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Recorder<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // What is consumed was returned by the last `fill_buf`, and is still
+        // buffered
+        if let Ok(buffer) = self.inner.fill_buf() {
+            self.data.extend_from_slice(&buffer[..amt]);
+        }
+        self.inner.consume(amt);
+    }
+}
+
+/// Writes the objects of the pack `data` as loose objects of the repository
+/// at `git_dir`, returning how many there are. Bases of deltas that aren't
+/// in the pack, left out of thin packs, are read from the repository. With
+/// `dry_run`, the pack is only checked.
+pub fn unpack_objects(
+    git_dir: &Path,
+    data: &[u8],
+    dry_run: bool,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<usize> {
+    let odb = Odb::new(git_dir);
+    let (_, objects, _) = read_pack(
+        Path::new("<stdin>"),
+        data,
+        Some(git_dir),
+        progress,
+        |_, kind, content| {
+            if !dry_run {
+                odb.write(kind, content)?;
+            }
+            Ok(())
+        },
+    )?;
+    Ok(objects.len())
+}
+
+/// Reads the pack `data` like [`index_pack`], calling `visit` with the sha,
+/// type and content of each object once its deltas are resolved. With
+/// `thin_bases`, the bases of deltas missing from the pack are read from
+/// the repository at that git directory, and returned last. The objects
+/// resolved are reported to `progress`.
+fn read_pack(
+    path: &Path,
+    data: &[u8],
+    thin_bases: Option<&Path>,
+    progress: Option<&dyn Progress>,
+    mut visit: impl FnMut(&str, &'static str, &[u8]) -> eyre::Result<()>,
+) -> eyre::Result<([u8; 20], Vec<PackedObject>, Vec<String>)> {
+    let pack = Pack {
+        path: path.to_path_buf(),
+        index: PackIndex {
+            entries: Vec::new(),
+        },
+        data: data.to_vec(),
+        offsets: Vec::new(),
+    };
+    if !pack.data.starts_with(b"PACK") || pack.data.len() < 32 {
+        return Err(eyre!("{} is not a pack file", path.display()));
+    }
+    let word =
+        |offset: usize| u32::from_be_bytes(pack.data[offset..offset + 4].try_into().unwrap());
+    if !(2..=3).contains(&word(4)) {
+        return Err(eyre!("pack version {} unsupported", word(4)));
+    }
+    if !pack.checksum_matches() {
+        return Err(eyre!("pack is corrupted (SHA1 mismatch)"));
+    }
+
+    // Each entry starts where the zlib stream of the previous one ends
+    let end = pack.data.len() - 20;
+    let mut entries = Vec::new();
+    let mut offset = 12;
+    for _ in 0..word(8) {
+        let (kind, size, position) = pack.entry_header(offset)?;
+        let (base, position) = match kind {
+            OFS_DELTA => {
+                let (base, position) = pack.delta_base(offset, kind, position)?;
+                (Some(DeltaBase::Offset(base)), position)
+            }
+            REF_DELTA => {
+                let base = hex::encode(pack.bytes(position, 20)?);
+                (Some(DeltaBase::Sha(base)), position + 20)
+            }
+            _ => (None, position),
+        };
+        let compressed = pack
+            .data
+            .get(position..end)
+            .ok_or(eyre!("truncated pack"))?;
+        let (data, length) = odb::inflate_stream(compressed, size as usize)?;
+        if data.len() as u64 != size {
+            return Err(eyre!("inflate returned a wrong size at offset {offset}"));
+        }
+        let mut crc = flate2::Crc::new();
+        crc.update(&pack.data[offset..position + length]);
+        entries.push(RawEntry {
+            offset,
+            kind,
+            base,
+            data,
+            crc: crc.sum(),
+        });
+        offset = position + length;
+    }
+    if offset != end {
+        return Err(eyre!("pack has junk at the end"));
+    }
+
+    // Deltas are resolved once their base is, which takes a single pass
+    // when bases come first, as offset deltas always do
+    let by_offset = (entries.iter().enumerate())
+        .map(|(i, entry)| (entry.offset, i))
+        .collect::<HashMap<_, _>>();
+    let mut by_sha = HashMap::<String, usize>::new();
+    let mut resolved: Vec<Option<(&'static str, Vec<u8>)>> = vec![None; entries.len()];
+    let mut shas = vec![String::new(); entries.len()];
+    let mut external = HashMap::new();
+    let mut done = 0;
+    loop {
+        let mut advanced = false;
+        for (i, entry) in entries.iter().enumerate() {
+            if resolved[i].is_some() {
+                continue;
+            }
+            let object = match &entry.base {
+                None => (object_kind(entry.kind)?, entry.data.clone()),
+                Some(base) => {
+                    let base = match base {
+                        DeltaBase::Offset(offset) => {
+                            by_offset.get(offset).and_then(|b| resolved[*b].as_ref())
+                        }
+                        DeltaBase::Sha(sha) => by_sha
+                            .get(sha)
+                            .and_then(|b| resolved[*b].as_ref())
+                            .or_else(|| external.get(sha)),
+                    };
+                    let Some((kind, content)) = base else {
+                        continue;
+                    };
+                    (*kind, apply_delta(content, &entry.data)?)
+                }
+            };
+            shas[i] = git::hash_object(object.0, &object.1).0.to_string();
+            visit(&shas[i], object.0, &object.1)?;
+            done += 1;
+            if let Some(progress) = progress {
+                progress.objects_resolved(done, entries.len());
+            }
+            by_sha.insert(shas[i].clone(), i);
+            resolved[i] = Some(object);
+            advanced = true;
+        }
+        if advanced {
+            continue;
+        }
+
+        // Once nothing else resolves, the remaining bases named by sha are
+        // looked up in the repository
+        let Some(git_dir) = thin_bases else {
+            break;
+        };
+        let missing = (entries.iter().zip(&resolved))
+            .filter(|(_, object)| object.is_none())
+            .filter_map(|(entry, _)| match &entry.base {
+                Some(DeltaBase::Sha(sha)) if !external.contains_key(sha) => Some(sha.clone()),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        let mut found = false;
+        for sha in missing {
+            if let Ok((kind, content)) = git::read_object(git_dir, &sha) {
+                external.insert(sha, (object_kind(entry_type(&kind)?)?, content));
+                found = true;
+            }
+        }
+        if !found {
+            break;
+        }
+    }
+    let unresolved = resolved.iter().filter(|object| object.is_none()).count();
+    if unresolved > 0 {
+        return Err(eyre!("pack has {unresolved} unresolved deltas"));
+    }
+
+    let checksum = pack.data[end..].try_into()?;
+    let objects = entries
+        .iter()
+        .zip(shas)
+        .map(|(entry, sha)| PackedObject {
+            sha,
+            offset: entry.offset as u64,
+            crc: entry.crc,
+        })
+        .collect();
+    let mut bases = external.into_keys().collect::<Vec<_>>();
+    bases.sort();
+    Ok((checksum, objects, bases))
+}
+
+/// Returns the version 2 index of the pack with the given checksum and
+/// objects.
+pub fn write_index(checksum: &[u8; 20], objects: &[PackedObject]) -> eyre::Result<Vec<u8>> {
+    let mut objects = objects.iter().collect::<Vec<_>>();
+    objects.sort_by(|a, b| a.sha.cmp(&b.sha));
+    let shas = objects
+        .iter()
+        .map(|object| hex::decode(&object.sha))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut index = b"\xfftOc".to_vec();
+    index.extend(2u32.to_be_bytes());
+    // The fanout table counts the objects whose sha starts with a byte up
+    // to each value
+    for byte in 0..=255u8 {
+        let count = shas.partition_point(|sha| sha[0] <= byte);
+        index.extend((count as u32).to_be_bytes());
+    }
+    for sha in &shas {
+        index.extend(sha);
+    }
+    for object in &objects {
+        index.extend(object.crc.to_be_bytes());
+    }
+    // Offsets past 2GiB go to a table of 64 bit offsets, the 32 bit one
+    // holding their position there
+    let mut large_offsets = Vec::new();
+    for object in &objects {
+        let offset = match u32::try_from(object.offset) {
+            Ok(offset) if offset & 0x8000_0000 == 0 => offset,
+            _ => {
+                large_offsets.extend(object.offset.to_be_bytes());
+                (large_offsets.len() / 8 - 1) as u32 | 0x8000_0000
+            }
+        };
+        index.extend(offset.to_be_bytes());
+    }
+    index.extend(large_offsets);
+    index.extend(checksum);
+    let index_checksum = sha1::Sha1::digest(&index);
+    index.extend(index_checksum);
+    Ok(index)
+}
+
+/// Returns the packs of the repository at `git_dir`.
+pub fn packs(git_dir: &Path) -> eyre::Result<Vec<Pack>> {
+    measure::phase("packs", || {
+        index_paths(git_dir)?
+            .iter()
+            .map(|index| Pack::open(index))
+            .collect()
+    })
+}
+
+/// Packs opened together, with the paths of their indexes.
+type OpenedPacks = (Vec<PathBuf>, Rc<Vec<Pack>>);
+
+thread_local! {
+    /// The packs opened by [`read_object`], by repository.
+    static OPENED: RefCell<HashMap<PathBuf, OpenedPacks>> = RefCell::new(HashMap::new());
+}
+
+/// Returns the type and content of the object `sha` if it's in one of the
+/// packs of the repository at `git_dir`. The packs are kept open between
+/// calls, as long as no pack is added or removed: packs never change once
+/// written.
+pub fn read_object(git_dir: &Path, sha: &str) -> eyre::Result<Option<(&'static str, Vec<u8>)>> {
+    for pack in opened(git_dir)?.iter() {
+        if let Some(offset) = pack.index.offset(sha) {
+            return pack.read_object(offset).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Returns true if the object `sha` is in one of the packs of the
+/// repository at `git_dir`, without reading it.
+pub fn contains(git_dir: &Path, sha: &str) -> eyre::Result<bool> {
+    Ok(opened(git_dir)?
+        .iter()
+        .any(|pack| pack.index.offset(sha).is_some()))
+}
+
+/// Returns the packs of the repository at `git_dir`, opened once as long
+/// as none is added or removed.
+fn opened(git_dir: &Path) -> eyre::Result<Rc<Vec<Pack>>> {
+    let indexes = index_paths(git_dir)?;
+    let opened = OPENED.with_borrow(|opened| {
+        opened
+            .get(git_dir)
+            .filter(|(paths, _)| *paths == indexes)
+            .map(|(_, packs)| packs.clone())
+    });
+    let packs = match opened {
+        Some(packs) => packs,
+        None => {
+            let packs = Rc::new(packs(git_dir)?);
+            OPENED.with_borrow_mut(|opened| {
+                opened.insert(git_dir.to_path_buf(), (indexes, packs.clone()))
+            });
+            packs
+        }
+    };
+    Ok(packs)
+}
+
+/// Returns the paths of the pack indexes of the repository at `git_dir`,
+/// sorted.
+fn index_paths(git_dir: &Path) -> eyre::Result<Vec<PathBuf>> {
+    let Ok(entries) = fs::read_dir(git_dir.join("objects/pack")) else {
+        return Ok(Vec::new());
+    };
+    let mut indexes = entries
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    indexes.retain(|path| path.extension().is_some_and(|e| e == "idx"));
+    indexes.sort();
+    Ok(indexes)
+}
+
+/// Returns the object type of the pack entry type `kind`.
+fn object_kind(kind: u8) -> eyre::Result<&'static str> {
+    match kind {
+        1 => Ok("commit"),
+        2 => Ok("tree"),
+        3 => Ok("blob"),
+        4 => Ok("tag"),
+        _ => Err(eyre!("invalid pack entry type {kind}")),
+    }
+}
+
+/// Returns the pack entry type of the object type `kind`.
+/// Returns the header of a pack entry of the pack entry type `kind`, which
+/// holds the type and the size of the inflated data in groups of 7 bits,
+/// the first group having 4 bits only.
+fn entry_header(kind: u8, mut size: u64) -> Vec<u8> {
+    let mut byte = kind << 4 | (size & 0xf) as u8;
+    size >>= 4;
+    let mut header = Vec::new();
+    while size > 0 {
+        header.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    header.push(byte);
+    header
+}
+
+fn entry_type(kind: &str) -> eyre::Result<u8> {
+    match kind {
+        "commit" => Ok(1),
+        "tree" => Ok(2),
+        "blob" => Ok(3),
+        "tag" => Ok(4),
+        _ => Err(eyre!("invalid object type {kind}")),
+    }
+}
+
+/// Decodes the distance to the base of an offset delta, returning it with
+/// the number of bytes read. Each continuation adds one to the value, so
+/// that every distance has a single encoding.
+fn base_distance(data: &[u8]) -> eyre::Result<(usize, usize)> {
+    let mut bytes = data.iter();
+    let mut byte = *bytes.next().ok_or(eyre!("truncated delta offset"))?;
+    let mut distance = (byte & 0x7f) as usize;
+    let mut read = 1;
+    while byte & 0x80 != 0 {
+        byte = *bytes.next().ok_or(eyre!("truncated delta offset"))?;
+        distance = ((distance + 1) << 7) | (byte & 0x7f) as usize;
+        read += 1;
+    }
+    Ok((distance, read))
+}
+
+/// Encodes the distance to the base of an offset delta, the way
+/// [`base_distance`] decodes it.
+fn encode_base_distance(distance: u64) -> Vec<u8> {
+    let mut bytes = vec![(distance & 0x7f) as u8];
+    let mut distance = distance >> 7;
+    while distance != 0 {
+        distance -= 1;
+        bytes.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    bytes.reverse();
+    bytes
+}
+
+/// Returns the size of the object a compressed delta produces, stored after
+/// the size of its base at the start of the delta.
+fn delta_result_size(compressed: &[u8]) -> eyre::Result<u64> {
+    // Both sizes take at most 10 bytes each
+    let mut header = Vec::new();
+    flate2::read::ZlibDecoder::new(compressed)
+        .take(20)
+        .read_to_end(&mut header)?;
+
+    let mut position = 0;
+    delta_size(&header, &mut position)?;
+    delta_size(&header, &mut position)
+}
+
+/// Decodes one of the sizes at the start of a delta, little-endian groups
+/// of 7 bits, from `position` which is moved past it.
+fn delta_size(delta: &[u8], position: &mut usize) -> eyre::Result<u64> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *delta
+            .get(*position)
+            .ok_or(eyre!("truncated delta header"))?;
+        *position += 1;
+        size |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+/// Returns the object the inflated `delta` makes from `base`. After the
+/// sizes of the base and the result, a delta is a list of instructions:
+/// copying a range of the base, or inserting the bytes that follow.
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> eyre::Result<Vec<u8>> {
+    let truncated = || eyre!("truncated delta");
+    let mut position = 0;
+    if delta_size(delta, &mut position)? != base.len() as u64 {
+        return Err(eyre!("delta base size mismatch"));
+    }
+    let size = delta_size(delta, &mut position)? as usize;
+
+    // The declared size isn't trusted to allocate the result up front, which
+    // grows as the instructions apply past a size in line with the delta
+    let mut result = Vec::with_capacity(size.min(base.len() + delta.len() * 128));
+    while let Some(&instruction) = delta.get(position) {
+        position += 1;
+        if instruction & 0x80 != 0 {
+            // The bits 0-3 tell which bytes of the offset follow, the bits
+            // 4-6 which bytes of the size, a size of 0 meaning 0x10000
+            let mut bytes = [0usize; 7];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                if instruction & (1 << i) != 0 {
+                    *byte = *delta.get(position).ok_or_else(truncated)? as usize;
+                    position += 1;
+                }
+            }
+            let offset = bytes[..4]
+                .iter()
+                .rev()
+                .fold(0, |offset, byte| offset << 8 | byte);
+            let size = match bytes[4..]
+                .iter()
+                .rev()
+                .fold(0, |size, byte| size << 8 | byte)
+            {
+                0 => 0x10000,
+                size => size,
+            };
+            let copied = base
+                .get(offset..offset + size)
+                .ok_or(eyre!("delta copies past the end of its base"))?;
+            result.extend_from_slice(copied);
+        } else if instruction != 0 {
+            let inserted = delta
+                .get(position..position + instruction as usize)
+                .ok_or_else(truncated)?;
+            result.extend_from_slice(inserted);
+            position += instruction as usize;
+        } else {
+            return Err(eyre!("invalid delta instruction 0"));
+        }
+        if result.len() > size {
+            return Err(eyre!("delta result size mismatch"));
+        }
+    }
+    if result.len() != size {
+        return Err(eyre!("delta result size mismatch"));
+    }
+    Ok(result)
+}
+
+/// The length of the blocks of a delta base indexed to find what the target
+/// of a delta copies from it.
+const DELTA_BLOCK: usize = 16;
+
+/// Returns a delta making `target` from `base`, in the format
+/// [`apply_delta`] reads. Blocks of the base found in the target are copied,
+/// extended to the longest match both ways, and the rest is inserted.
+pub fn create_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    encode_delta_size(&mut delta, base.len());
+    encode_delta_size(&mut delta, target.len());
+
+    let mut blocks = HashMap::new();
+    for start in (0..base.len().saturating_sub(DELTA_BLOCK - 1)).step_by(DELTA_BLOCK) {
+        blocks
+            .entry(&base[start..start + DELTA_BLOCK])
+            .or_insert(start);
+    }
+
+    let mut inserted = Vec::new();
+    let mut position = 0;
+    while position < target.len() {
+        let found = target
+            .get(position..position + DELTA_BLOCK)
+            .and_then(|block| blocks.get(block));
+        let Some(&start) = found else {
+            inserted.push(target[position]);
+            position += 1;
+            continue;
+        };
+
+        let mut length = base[start..]
+            .iter()
+            .zip(&target[position..])
+            .take_while(|(a, b)| a == b)
+            .count();
+        position += length;
+        // Bytes before the block may match too, rather than be inserted
+        let mut start = start;
+        while start > 0 && inserted.last() == Some(&base[start - 1]) {
+            inserted.pop();
+            start -= 1;
+            length += 1;
+        }
+
+        encode_insert(&mut delta, &inserted);
+        inserted.clear();
+        // Copies are split in ranges of 64KiB, the largest size all
+        // versions of git read
+        while length > 0 {
+            let size = length.min(0x10000);
+            encode_copy(&mut delta, start, size);
+            start += size;
+            length -= size;
+        }
+    }
+    encode_insert(&mut delta, &inserted);
+    delta
+}
+
+/// Writes one of the sizes at the start of a delta.
+fn encode_delta_size(delta: &mut Vec<u8>, mut size: usize) {
+    while size >= 0x80 {
+        delta.push(0x80 | (size & 0x7f) as u8);
+        size >>= 7;
+    }
+    delta.push(size as u8);
+}
+
+/// Writes the instructions inserting `bytes`, at most 127 bytes each.
+fn encode_insert(delta: &mut Vec<u8>, bytes: &[u8]) {
+    for chunk in bytes.chunks(0x7f) {
+        delta.push(chunk.len() as u8);
+        delta.extend_from_slice(chunk);
+    }
+}
+
+/// Writes the instruction copying `size` bytes of the base from `offset`,
+/// leaving out the bytes of the offset and size that are zero.
+fn encode_copy(delta: &mut Vec<u8>, offset: usize, size: usize) {
+    let size = match size {
+        0x10000 => 0,
+        size => size,
+    };
+    let mut instruction = 0x80;
+    let mut bytes = Vec::new();
+    for (i, byte) in offset.to_le_bytes()[..4]
+        .iter()
+        .chain(&size.to_le_bytes()[..3])
+        .enumerate()
+    {
+        if *byte != 0 {
+            instruction |= 1 << i;
+            bytes.push(*byte);
+        }
+    }
+    delta.push(instruction);
+    delta.extend(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deltas_round_trip() -> eyre::Result<()> {
+        let base = (0..2000).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+        let mut edited = base.clone();
+        edited.splice(100..120, *b"inserted in the middle");
+        edited.extend_from_slice(&base[..300]);
+        for (base, target) in [
+            (&base[..], &edited[..]),
+            (&edited[..], &base[..]),
+            (&base[..], &base[..]),
+            (&base[..], &b""[..]),
+            (&b""[..], &base[..]),
+            (&base[..10], &b"short"[..]),
+        ] {
+            let delta = create_delta(base, target);
+            assert_eq!(apply_delta(base, &delta)?, target);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn deltas_are_checked() {
+        let base = b"base";
+        let mut delta = Vec::new();
+        encode_delta_size(&mut delta, base.len());
+        // Declares a result far bigger than it inserts
+        encode_delta_size(&mut delta, 1 << 40);
+        encode_insert(&mut delta, b"x");
+        assert!(apply_delta(base, &delta).is_err());
+
+        let mut delta = Vec::new();
+        encode_delta_size(&mut delta, base.len());
+        encode_delta_size(&mut delta, 8);
+        encode_copy(&mut delta, 2, 8);
+        assert!(apply_delta(base, &delta).is_err());
+        assert!(apply_delta(b"other base", &create_delta(base, b"target")).is_err());
+    }
+
+    #[test]
+    fn packs_and_indexes_round_trip() -> eyre::Result<()> {
+        let blob = b"the content of a file\n".repeat(20);
+        let mut edited = blob.clone();
+        edited.extend_from_slice(b"and a line more\n");
+        let tree = b"100644 file\0\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f\x10\x11\x12\x13\x14";
+        let (blob_sha, _) = git::hash_object("blob", &blob);
+        let (edited_sha, _) = git::hash_object("blob", &edited);
+        let (tree_sha, _) = git::hash_object("tree", tree);
+        let (again_sha, _) = git::hash_object("blob", b"again");
+
+        let mut data = Vec::new();
+        let mut writer = PackWriter::new(&mut data, 4)?;
+        writer.add(&blob_sha, "blob", &blob)?;
+        writer.add_delta(&edited_sha, &blob_sha, &create_delta(&blob, &edited), true)?;
+        writer.add(&tree_sha, "tree", tree)?;
+        writer.add_delta(&again_sha, &blob_sha, &create_delta(&blob, b"again"), false)?;
+        assert!(writer.add(&blob_sha, "blob", &blob).is_err());
+        let (checksum, written) = writer.finish()?;
+
+        let mut contents = HashMap::new();
+        let (read_checksum, read, _) = read_pack(
+            Path::new("test.pack"),
+            &data,
+            None,
+            None,
+            |sha, kind, content| {
+                contents.insert(sha.to_string(), (kind, content.to_vec()));
+                Ok(())
+            },
+        )?;
+        assert_eq!(read_checksum, checksum);
+        assert_eq!(contents[&*blob_sha], ("blob", blob.clone()));
+        assert_eq!(contents[&*edited_sha], ("blob", edited));
+        assert_eq!(contents[&*tree_sha], ("tree", tree.to_vec()));
+        assert_eq!(contents[&*again_sha], ("blob", b"again".to_vec()));
+
+        let index = PackIndex::parse(&write_index(&checksum, &written)?)?;
+        assert_eq!(index.entries.len(), 4);
+        for object in &written {
+            assert_eq!(index.offset(&object.sha), Some(object.offset));
+            let read = read.iter().find(|read| read.sha == object.sha).unwrap();
+            assert_eq!((read.offset, read.crc), (object.offset, object.crc));
+        }
+        assert_eq!(index.offset(&"0".repeat(40)), None);
+        Ok(())
+    }
+}
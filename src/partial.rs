@@ -0,0 +1,180 @@
+use crate::config::{self, Config};
+use crate::fetch::{self, Remote};
+use crate::git;
+use crate::pack;
+use eyre::eyre;
+use std::collections::BTreeSet;
+use std::fmt::Formatter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// What a fetch leaves out with `--filter`, making a partial clone whose
+/// missing objects are fetched when needed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// `blob:none`: all the blobs.
+    BlobNone,
+    /// `blob:limit=<n>`: the blobs of at least that many bytes.
+    BlobLimit(u64),
+    /// `tree:<depth>`: the trees and blobs at least that deep below the
+    /// root trees of commits, the root trees being at depth 0.
+    Tree(u64),
+}
+
+impl FromStr for Filter {
+    type Err = eyre::Report;
+
+    fn from_str(spec: &str) -> eyre::Result<Self> {
+        let invalid = || eyre!(tr!("invalid filter-spec '%s'", spec));
+        // Sizes may end with a unit, like `1k`
+        let size = |value: &str| {
+            let (digits, unit) = match value.char_indices().last() {
+                Some((i, unit)) if unit.is_ascii_alphabetic() => (&value[..i], unit),
+                _ => (value, ' '),
+            };
+            let shift = match unit.to_ascii_lowercase() {
+                ' ' => 0,
+                'k' => 10,
+                'm' => 20,
+                'g' => 30,
+                _ => return Err(invalid()),
+            };
+            let size = digits.parse::<u64>().map_err(|_| invalid())?;
+            size.checked_shl(shift).ok_or_else(invalid)
+        };
+        match spec.split_once(':') {
+            _ if spec == "blob:none" => Ok(Self::BlobNone),
+            Some(("blob", limit)) => match limit.strip_prefix("limit=") {
+                Some(limit) => Ok(Self::BlobLimit(size(limit)?)),
+                None => Err(invalid()),
+            },
+            Some(("tree", depth)) => Ok(Self::Tree(depth.parse().map_err(|_| invalid())?)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BlobNone => write!(f, "blob:none"),
+            Self::BlobLimit(limit) => write!(f, "blob:limit={limit}"),
+            Self::Tree(depth) => write!(f, "tree:{depth}"),
+        }
+    }
+}
+
+impl Filter {
+    /// Returns true if the filter keeps a tree `depth` trees below the root
+    /// tree of a commit.
+    pub fn keeps_tree(&self, depth: u64) -> bool {
+        match self {
+            Self::Tree(max) => depth < *max,
+            Self::BlobNone | Self::BlobLimit(_) => true,
+        }
+    }
+
+    /// Returns true if the filter keeps the blob `sha` of the repository at
+    /// `git_dir`, found `depth` trees below the root tree of a commit.
+    pub fn keeps_blob(&self, git_dir: &Path, sha: &str, depth: u64) -> eyre::Result<bool> {
+        match self {
+            Self::BlobNone => Ok(false),
+            Self::BlobLimit(limit) => Ok((git::read_object(git_dir, sha)?.1.len() as u64) < *limit),
+            Self::Tree(max) => Ok(depth < *max),
+        }
+    }
+}
+
+/// Makes the repository at `git_dir` a partial clone of the remote `name`,
+/// from which the objects `filter` left out are fetched when needed.
+pub fn register(git_dir: &Path, name: &str, filter: Filter) -> eyre::Result<()> {
+    config::set_value(git_dir, "core.repositoryformatversion", "1")?;
+    config::set_value(git_dir, &format!("remote.{name}.promisor"), "true")?;
+    config::set_value(
+        git_dir,
+        &format!("remote.{name}.partialclonefilter"),
+        &filter.to_string(),
+    )?;
+    config::set_value(git_dir, "extensions.partialclone", name)
+}
+
+/// Whether objects missing from the repository may be fetched, which they
+/// aren't while fetching.
+static LAZY: AtomicBool = AtomicBool::new(true);
+
+/// The repository whose missing objects are fetched from its promisor
+/// remote when they're read, if any.
+static LAZY_REPOSITORY: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Makes the objects missing from the repository at `git_dir` be fetched
+/// when they're read, if it's a partial clone. Only the repository commands
+/// work on is: servers and the repositories read by local fetches never
+/// fetch objects.
+pub fn enable_lazy_fetch(git_dir: &Path) {
+    *LAZY_REPOSITORY.lock().unwrap_or_else(|e| e.into_inner()) = Some(git_dir.to_path_buf());
+}
+
+/// Runs `f` without fetching the objects missing from the repository, for
+/// fetches which only look for the objects the repository has.
+pub fn without_lazy_fetch<T>(f: impl FnOnce() -> T) -> T {
+    let lazy = LAZY.swap(false, Ordering::SeqCst);
+    let value = f();
+    LAZY.store(lazy, Ordering::SeqCst);
+    value
+}
+
+/// Returns the promisor remote of the current repository, the one named
+/// by `extensions.partialClone`, if it's a partial clone.
+fn promisor(config: &Config) -> eyre::Result<Option<Remote>> {
+    match config.get("extensions.partialclone") {
+        Some(name) => Ok(Some(Remote::load(config, name)?)),
+        None => Ok(None),
+    }
+}
+
+/// Fetches the objects `shas` missing from the repository at `git_dir`
+/// from its promisor remote. Returns false if lazy fetches aren't enabled
+/// for it, or it's not a partial clone.
+pub fn fetch_missing(git_dir: &Path, shas: &[String]) -> eyre::Result<bool> {
+    let enabled = LAZY_REPOSITORY
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_deref()
+        == Some(git_dir);
+    if !LAZY.load(Ordering::SeqCst) || !enabled {
+        return Ok(false);
+    }
+    let config = Config::load(git_dir)?;
+    let Some(remote) = promisor(&config)? else {
+        return Ok(false);
+    };
+    without_lazy_fetch(|| fetch::fetch_objects(git_dir, &config, &remote, shas)).map_err(|e| {
+        eyre!(tr!(
+            "could not fetch %s from promisor remote: %s",
+            &shas[0],
+            e
+        ))
+    })?;
+    Ok(true)
+}
+
+/// Fetches the objects of `shas` missing from the repository at `git_dir`
+/// in a single fetch if it's a partial clone, rather than one by one as
+/// they're read.
+pub fn prefetch(git_dir: &Path, shas: impl IntoIterator<Item = String>) -> eyre::Result<()> {
+    if promisor(&Config::load(git_dir)?)?.is_none() {
+        return Ok(());
+    }
+    let mut missing = BTreeSet::new();
+    for sha in shas {
+        if !git::object_path(git_dir, &sha.parse()?).exists() && !pack::contains(git_dir, &sha)? {
+            missing.insert(sha);
+        }
+    }
+    if !missing.is_empty() {
+        fetch_missing(git_dir, &missing.into_iter().collect::<Vec<_>>())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use eyre::eyre;
+use std::io::{Read, Write};
+
+/// The largest payload of a pkt-line, its 4 bytes of length aside.
+pub const MAX_DATA: usize = 65516;
+/// The largest packet of `side-band-64k` and of protocol version 2, its
+/// length included.
+pub const LARGE_PACKET: usize = 65520;
+/// The largest packet of the older `side-band`.
+pub const SMALL_PACKET: usize = 1000;
+
+/// A pkt-line: data after its length in 4 hexadecimal digits, or one of the
+/// special packets whose length is below 4.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Packet {
+    /// `0000`, ending a list of packets.
+    Flush,
+    /// `0001`, separating the sections of a protocol v2 request or response.
+    Delim,
+    /// `0002`, ending a protocol v2 response over stateless connections.
+    ResponseEnd,
+    Data(Vec<u8>),
+}
+
+/// Reads pkt-lines from a stream.
+pub struct Reader<R> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// Reads the next packet.
+    pub fn read(&mut self) -> eyre::Result<Packet> {
+        let mut length = [0; 4];
+        self.inner
+            .read_exact(&mut length)
+            .map_err(|_| eyre!(tr!("the remote end hung up unexpectedly")))?;
+        let length = std::str::from_utf8(&length)
+            .ok()
+            .and_then(|length| usize::from_str_radix(length, 16).ok())
+            .ok_or(eyre!(tr!(
+                "protocol error: bad line length character: %s",
+                &String::from_utf8_lossy(&length)
+            )))?;
+        match length {
+            0 => Ok(Packet::Flush),
+            1 => Ok(Packet::Delim),
+            2 => Ok(Packet::ResponseEnd),
+            3 => Err(eyre!(tr!("protocol error: bad line length %d", length))),
+            _ => {
+                let mut data = vec![0; length - 4];
+                self.inner.read_exact(&mut data)?;
+                Ok(Packet::Data(data))
+            }
+        }
+    }
+
+    /// Reads a data packet, `None` standing for the flush or delimiter
+    /// packet ending a list.
+    pub fn read_data(&mut self) -> eyre::Result<Option<Vec<u8>>> {
+        match self.read()? {
+            Packet::Data(data) => Ok(Some(data)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads a text packet, without its line feed, like [`Self::read_data`].
+    /// Fails with the message of an `ERR` packet.
+    pub fn read_line(&mut self) -> eyre::Result<Option<String>> {
+        let Some(data) = self.read_data()? else {
+            return Ok(None);
+        };
+        let line = lossy(&data);
+        match line.strip_prefix("ERR ") {
+            Some(message) => Err(eyre!(tr!("remote error: %s", message))),
+            None => Ok(Some(line)),
+        }
+    }
+
+    /// Reads the packets up to a flush packet, demultiplexing the
+    /// side-band: returns the data of band 1, passing the progress messages
+    /// of band 2 to `progress`, and fails with the error of band 3.
+    pub fn read_sideband(
+        &mut self,
+        mut progress: impl FnMut(&[u8]) -> eyre::Result<()>,
+    ) -> eyre::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        while let Some(packet) = self.read_data()? {
+            match packet.split_first() {
+                Some((1, band)) => data.extend(band),
+                Some((2, band)) => progress(band)?,
+                Some((3, band)) => return Err(eyre!(tr!("remote error: %s", &lossy(band)))),
+                _ => return Err(eyre!("invalid side-band packet")),
+            }
+        }
+        Ok(data)
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+/// Writes pkt-lines to a stream.
+pub struct Writer<W> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Writes `data` in a packet, which must fit in [`MAX_DATA`].
+    pub fn write(&mut self, data: &[u8]) -> eyre::Result<()> {
+        if data.len() > MAX_DATA {
+            return Err(eyre!("packet of {} bytes is too long", data.len()));
+        }
+        write!(self.inner, "{:04x}", data.len() + 4)?;
+        self.inner.write_all(data)?;
+        Ok(())
+    }
+
+    /// Writes `line` in a packet, followed by a line feed.
+    pub fn write_line(&mut self, line: &str) -> eyre::Result<()> {
+        self.write(format!("{line}\n").as_bytes())
+    }
+
+    pub fn write_flush(&mut self) -> eyre::Result<()> {
+        self.inner.write_all(b"0000")?;
+        Ok(())
+    }
+
+    pub fn write_delim(&mut self) -> eyre::Result<()> {
+        self.inner.write_all(b"0001")?;
+        Ok(())
+    }
+
+    /// Writes `data` on `band` of the side-band, in packets of at most `max`
+    /// bytes.
+    pub fn write_band(&mut self, band: u8, data: &[u8], max: usize) -> eyre::Result<()> {
+        // Each packet has its length and the band before the data
+        for chunk in data.chunks(max - 5) {
+            let mut packet = vec![band];
+            packet.extend(chunk);
+            self.write(&packet)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// Returns the text of a packet, without its line feed.
+pub fn lossy(packet: &[u8]) -> String {
+    let line = String::from_utf8_lossy(packet);
+    line.strip_suffix('\n').unwrap_or(&line).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_round_trip() -> eyre::Result<()> {
+        let mut writer = Writer::new(Vec::new());
+        writer.write_line("want 0123")?;
+        writer.write(b"\x00binary")?;
+        writer.write_delim()?;
+        writer.write_band(2, b"progress\n", SMALL_PACKET)?;
+        writer.write_band(1, &[7; 2000], SMALL_PACKET)?;
+        writer.write_flush()?;
+        let data = writer.into_inner();
+        assert!(data.starts_with(b"000ewant 0123\n"));
+
+        let mut reader = Reader::new(data.as_slice());
+        assert_eq!(reader.read_line()?.as_deref(), Some("want 0123"));
+        assert_eq!(reader.read()?, Packet::Data(b"\x00binary".to_vec()));
+        assert_eq!(reader.read()?, Packet::Delim);
+        let mut messages = Vec::new();
+        let band = reader.read_sideband(|message| {
+            messages.extend_from_slice(message);
+            Ok(())
+        })?;
+        assert_eq!(messages, b"progress\n");
+        assert_eq!(band, [7; 2000]);
+        assert!(reader.read().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn errors_are_read_as_errors() {
+        let mut reader = Reader::new(&b"0011ERR not here0003"[..]);
+        assert!(reader.read_line().is_err());
+        assert!(reader.read().is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Callbacks receiving the progress of long-running operations, like
+/// clones, fetches, checkouts and the packing of objects, so that programs
+/// embedding the crate can show it without scraping stderr. Every callback
+/// does nothing by default.
+pub trait Progress: Send + Sync {
+    /// Called as the objects to pack are counted, with the number counted
+    /// so far.
+    fn objects_counted(&self, _count: usize) {}
+
+    /// Called as a pack is written, `done` objects out of `total` having
+    /// been.
+    fn objects_written(&self, _done: usize, _total: usize) {}
+
+    /// Called as the response of a remote is received, with the number of
+    /// bytes received so far.
+    fn bytes_received(&self, _bytes: u64) {}
+
+    /// Called as the objects of a received pack are resolved, `done` out of
+    /// `total` having been.
+    fn objects_resolved(&self, _done: usize, _total: usize) {}
+
+    /// Called as a checkout gets to the file at `path`, which it writes
+    /// unless up to date, `done` files out of `total` having been checked
+    /// out.
+    fn checkout(&self, _path: &Path, _done: usize, _total: usize) {}
+
+    /// Called with the progress messages of a remote, like `Counting
+    /// objects: 10% (1/10)\r`.
+    fn remote_message(&self, _message: &[u8]) {}
+}
+
+/// Prints the progress messages of remotes to stderr, each line after
+/// `remote: `, like git. Lines may be split across messages. This is the
+/// sink of the command line.
+#[derive(Default)]
+pub struct Stderr {
+    /// Whether the last message ended in the middle of a line.
+    partial: AtomicBool,
+}
+
+impl Progress for Stderr {
+    fn remote_message(&self, message: &[u8]) {
+        for line in String::from_utf8_lossy(message).split_inclusive(['\n', '\r']) {
+            match self.partial.load(Ordering::Relaxed) {
+                true => eprint!("{line}"),
+                false => eprint!("remote: {line}"),
+            }
+            self.partial
+                .store(!line.ends_with(['\n', '\r']), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Reads through `inner`, reporting the bytes read so far to `progress`.
+pub struct Reader<'a, R> {
+    inner: R,
+    bytes: u64,
+    progress: Option<&'a dyn Progress>,
+}
+
+impl<'a, R: Read> Reader<'a, R> {
+    pub fn new(inner: R, progress: Option<&'a dyn Progress>) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            progress,
+        }
+    }
+}
+
+impl<R: Read> Read for Reader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes += n as u64;
+        if let Some(progress) = self.progress {
+            progress.bytes_received(self.bytes);
+        }
+        Ok(n)
+    }
+}
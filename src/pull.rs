@@ -0,0 +1,82 @@
+use crate::branch;
+use crate::config::Config;
+use crate::fetch::{self, FetchHead, Remote};
+use crate::merge;
+use crate::progress::Progress;
+use crate::rebase;
+use crate::stash;
+use eyre::eyre;
+use std::path::Path;
+
+/// How `pull` integrates what it fetched.
+#[derive(Debug, Default)]
+pub struct PullOptions {
+    /// Rebases the current branch on the fetched commit instead of merging
+    /// it, `pull.rebase` deciding if unset.
+    pub rebase: Option<bool>,
+    /// Refuses to merge unless the current branch can be fast-forwarded.
+    pub ff_only: bool,
+    /// Stashes the local changes before integrating and applies them back
+    /// after, `rebase.autoStash` or `merge.autoStash` deciding if unset.
+    pub autostash: Option<bool>,
+    /// Throws away the local changes in the way.
+    pub force: bool,
+}
+
+/// Fetches the upstream of the current branch and merges it into the
+/// branch, or rebases the branch on it. The fetch is reported to `progress`.
+pub fn pull(
+    git_dir: &Path,
+    config: &Config,
+    options: &PullOptions,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<()> {
+    let (remote, merge_ref) = branch::upstream(git_dir, config)?.ok_or(eyre!(tr!(
+        "There is no tracking information for the current branch."
+    )))?;
+    fetch::fetch(
+        git_dir,
+        config,
+        &Remote::load(config, &remote)?,
+        &[merge_ref],
+        &fetch::FetchOptions::default(),
+        progress,
+    )?;
+
+    let fetched = FetchHead::read(git_dir)?;
+    let head = fetched
+        .iter()
+        .find(|f| f.for_merge)
+        .ok_or(eyre!(tr!("no candidate to merge among the fetched refs")))?;
+
+    let rebase = match options.rebase {
+        Some(rebase) => rebase,
+        None => config.get_bool("pull.rebase")?.unwrap_or(false),
+    };
+    let ff_only = options.ff_only || config.get("pull.ff") == Some("only");
+    let force = options.force;
+    let integrate = || match rebase {
+        true => {
+            let options = rebase::RebaseOptions {
+                force,
+                ..Default::default()
+            };
+            rebase::rebase(git_dir, config, &head.sha, options)
+        }
+        false => {
+            let options = merge::MergeOptions { ff_only, force };
+            merge::merge(git_dir, config, &head.sha, &head.description, options)
+        }
+    };
+
+    let autostash = match options.autostash {
+        Some(autostash) => autostash,
+        None if rebase => config.get_bool("rebase.autoStash")?.unwrap_or(false),
+        None => config.get_bool("merge.autoStash")?.unwrap_or(false),
+    };
+    if autostash {
+        stash::with_autostash(git_dir, config, integrate)
+    } else {
+        integrate()
+    }
+}
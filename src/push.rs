@@ -0,0 +1,388 @@
+use crate::config::Config;
+use crate::fetch::{Refspec, Remote};
+use crate::git::{self, GitFile, GitFileContent, NULL_SHA};
+use crate::pack::{self, PackOptions};
+use crate::pktline;
+use crate::progress::Progress;
+use crate::refs;
+use crate::revision;
+use crate::revwalk::RevWalk;
+use crate::transport::{self, Transport};
+use eyre::eyre;
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::Path;
+
+/// An update of a ref of the remote asked for by a push.
+#[derive(Debug)]
+struct Update {
+    /// The local ref or revision pushed, empty for deletions.
+    src: String,
+    /// The ref of the remote.
+    dst: String,
+    /// What the remote ref points to, if it exists.
+    old: Option<String>,
+    /// What the remote ref is set to, `None` deleting it.
+    new: Option<String>,
+    force: bool,
+    rejected: Option<Rejection>,
+}
+
+/// Why an update was refused.
+#[derive(Debug)]
+enum Rejection {
+    /// The push itself refused it, before sending anything.
+    Local(&'static str),
+    /// The remote refused it, for the given reason.
+    Remote(String),
+}
+
+impl Update {
+    /// Returns the line describing the update once pushed, like
+    /// `   1234567..89abcde  main -> main`.
+    fn status(&self) -> String {
+        let (src, dst) = (short_name(&self.src), short_name(&self.dst));
+        let kind = match self.dst.starts_with("refs/tags/") {
+            true => "tag",
+            false if self.dst.starts_with("refs/heads/") => "branch",
+            false => "ref",
+        };
+        match (&self.old, &self.new, &self.rejected) {
+            (_, _, Some(Rejection::Local(reason))) => {
+                format!(" ! {:<17} {src} -> {dst} ({reason})", "[rejected]")
+            }
+            (_, _, Some(Rejection::Remote(reason))) => {
+                format!(" ! {:<17} {src} -> {dst} ({reason})", "[remote rejected]")
+            }
+            (_, None, None) => format!(" - {:<17} {dst}", "[deleted]"),
+            (None, Some(_), None) => {
+                format!(" * {:<17} {src} -> {dst}", format!("[new {kind}]"))
+            }
+            (Some(old), Some(new), None) if old == new => {
+                format!(" = {:<17} {src} -> {dst}", "[up to date]")
+            }
+            (Some(old), Some(new), None) if self.force => format!(
+                " + {}...{} {src} -> {dst} (forced update)",
+                &old[..7],
+                &new[..7]
+            ),
+            (Some(old), Some(new), None) => {
+                format!("   {}..{}  {src} -> {dst}", &old[..7], &new[..7])
+            }
+        }
+    }
+}
+
+/// Pushes the local refs selected by `refspecs` to `remote`, the current
+/// branch to the branch of the same name by default: the objects the
+/// remote is missing are sent in a pack to its `git-receive-pack` service
+/// along with the ref updates, and the status of each update is printed.
+/// Updates that aren't fast-forwards are rejected unless forced. The
+/// remote-tracking refs of the updated refs follow. The messages of the
+/// remote are reported to `progress`.
+pub fn push(
+    git_dir: &Path,
+    config: &Config,
+    remote: &Remote,
+    refspecs: &[String],
+    force: bool,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<()> {
+    let url = &remote.push_url;
+    let mut transport = transport::open(config, url)?;
+    let advertised = transport.advertisement("git-receive-pack", &[])?;
+
+    let refspecs = match refspecs.is_empty() {
+        true => {
+            let head = refs::symbolic_target(git_dir, "HEAD")?
+                .filter(|head| head.starts_with("refs/heads/"))
+                .ok_or(eyre!(tr!("You are not currently on a branch.")))?;
+            vec![head]
+        }
+        false => refspecs.to_vec(),
+    };
+    let mut walk = RevWalk::new();
+    let mut updates = Vec::new();
+    for refspec in refspecs {
+        let mut update = resolve(git_dir, &Refspec::parse(&refspec), &advertised.refs)?;
+        update.force |= force;
+        update.rejected = check(git_dir, &mut walk, &update, &advertised)?;
+        updates.push(update);
+    }
+
+    let pushed = updates
+        .iter()
+        .filter(|u| u.rejected.is_none() && u.old != u.new)
+        .collect::<Vec<_>>();
+    if pushed.is_empty() && updates.iter().all(|u| u.rejected.is_none()) {
+        eprintln!("{}", tr!("Everything up-to-date"));
+        return Ok(());
+    }
+
+    if !pushed.is_empty() {
+        let report = send(git_dir, transport.as_mut(), &advertised, &pushed, progress)?;
+        for update in &mut updates {
+            if let Some((_, Some(reason))) = report.iter().find(|(name, _)| *name == update.dst) {
+                update.rejected = Some(Rejection::Remote(reason.clone()));
+            }
+        }
+    }
+
+    eprintln!("To {url}");
+    for update in &updates {
+        if update.old != update.new || update.rejected.is_some() {
+            eprintln!("{}", update.status());
+        }
+        if update.rejected.is_some() {
+            continue;
+        }
+        // The remote-tracking refs follow the pushed refs
+        for local in remote.refspecs.iter().filter_map(|r| r.map(&update.dst)) {
+            match &update.new {
+                Some(new) => refs::update_ref(git_dir, &local, new)?,
+                None => refs::delete_ref(git_dir, &local)?,
+            }
+        }
+    }
+    if updates.iter().any(|u| u.rejected.is_some()) {
+        return Err(eyre!(tr!("failed to push some refs to '%s'", url)));
+    }
+    Ok(())
+}
+
+/// Resolves a push refspec `refspec` into the update of a ref of the
+/// remote, whose refs are `remote_refs`. The destination is the source ref
+/// by default, and names without `refs/` are branches or tags like the
+/// source. An empty source deletes the destination.
+fn resolve(
+    git_dir: &Path,
+    refspec: &Refspec,
+    remote_refs: &[(String, String)],
+) -> eyre::Result<Update> {
+    let src = match refspec.src.is_empty() {
+        true => String::new(),
+        false => {
+            revision::symbolic_full_name(git_dir, &refspec.src)?.unwrap_or(refspec.src.clone())
+        }
+    };
+    let dst = match refspec.dst.as_str() {
+        "" if src.starts_with("refs/") => src.clone(),
+        "" => {
+            return Err(eyre!(tr!(
+            "The destination you provided is not a full refname (i.e., starting with \"refs/\")."
+        )))
+        }
+        dst if dst.starts_with("refs/") => dst.to_string(),
+        dst if src.starts_with("refs/tags/") => format!("refs/tags/{dst}"),
+        dst => format!("refs/heads/{dst}"),
+    };
+    let new = match src.is_empty() {
+        true => None,
+        false => Some(
+            revision::resolve(git_dir, &src)
+                .map_err(|_| eyre!(tr!("src refspec %s does not match any", &refspec.src)))?,
+        ),
+    };
+    let old = remote_refs
+        .iter()
+        .find(|(name, _)| *name == dst)
+        .map(|(_, sha)| sha.clone());
+    Ok(Update {
+        src,
+        dst,
+        old,
+        new,
+        force: refspec.force,
+        rejected: None,
+    })
+}
+
+/// Returns why `update` can't be pushed, if it can't: the remote ref has
+/// commits the local repository doesn't have, or that the update would
+/// drop without being forced, or the remote can't delete refs.
+fn check(
+    git_dir: &Path,
+    walk: &mut RevWalk,
+    update: &Update,
+    advertised: &transport::Refs,
+) -> eyre::Result<Option<Rejection>> {
+    let reason = match (&update.old, &update.new) {
+        (None, None) => Some("remote ref does not exist"),
+        (Some(_), None) if !advertised.has("delete-refs") => {
+            Some("remote does not support deleting refs")
+        }
+        (Some(old), Some(new)) if old != new && !update.force => {
+            let local = git::read_object(git_dir, old).is_ok();
+            if !local {
+                Some("fetch first")
+            } else if !walk.is_ancestor(git_dir, old, new)? {
+                Some("non-fast-forward")
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+    Ok(reason.map(Rejection::Local))
+}
+
+/// Sends the ref updates `updates` to the `git-receive-pack` service of
+/// `transport`, which advertised `advertised`, with the objects the remote is
+/// missing. Returns the status the remote reports for each ref, with the
+/// reason of the refused updates.
+fn send(
+    git_dir: &Path,
+    transport: &mut dyn Transport,
+    advertised: &transport::Refs,
+    updates: &[&Update],
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<Vec<(String, Option<String>)>> {
+    let capabilities = ["report-status", "side-band-64k"]
+        .into_iter()
+        .filter(|capability| advertised.has(capability))
+        .chain([transport::AGENT])
+        .collect::<Vec<_>>();
+    let sideband = advertised.has("side-band-64k");
+
+    let mut request = pktline::Writer::new(Vec::new());
+    for (i, update) in updates.iter().enumerate() {
+        let old = update.old.as_deref().unwrap_or(NULL_SHA);
+        let new = update.new.as_deref().unwrap_or(NULL_SHA);
+        match i {
+            0 => request.write_line(&format!(
+                "{old} {new} {}\0{}",
+                update.dst,
+                capabilities.join(" ")
+            ))?,
+            _ => request.write_line(&format!("{old} {new} {}", update.dst))?,
+        }
+    }
+    request.write_flush()?;
+    let mut request = request.into_inner();
+
+    // Deleting refs needs no object
+    let new = updates
+        .iter()
+        .filter_map(|u| u.new.clone())
+        .collect::<Vec<_>>();
+    if !new.is_empty() {
+        let have = advertised
+            .refs
+            .iter()
+            .map(|(_, sha)| sha.clone())
+            .collect::<Vec<_>>();
+        let objects = missing_objects(git_dir, &new, &have)?;
+        let options = PackOptions {
+            delta_base_offset: advertised.has("ofs-delta"),
+            ..PackOptions::default()
+        };
+        pack::pack_objects(git_dir, &objects, &options, progress, &mut request)?;
+    }
+
+    // With a side-band, the report comes on band 1 and the messages of the
+    // remote on band 2
+    let mut response = pktline::Reader::new(transport.request("git-receive-pack", &request)?);
+    let report = match sideband {
+        true => response.read_sideband(|messages| {
+            if let Some(progress) = progress {
+                progress.remote_message(messages);
+            }
+            Ok(())
+        })?,
+        false => {
+            let mut report = Vec::new();
+            response.get_mut().read_to_end(&mut report)?;
+            report
+        }
+    };
+    if !advertised.has("report-status") {
+        return Ok(Vec::new());
+    }
+
+    let mut report = pktline::Reader::new(report.as_slice());
+    let unpack = report.read_line()?.unwrap_or_default();
+    if unpack != "unpack ok" {
+        let error = unpack.strip_prefix("unpack ").unwrap_or(&unpack);
+        eprintln!("{}", tr!("error: remote unpack failed: %s", error));
+    }
+    let mut statuses = Vec::new();
+    while let Some(line) = report.read_line()? {
+        match line.split_once(' ') {
+            Some(("ok", name)) => statuses.push((name.to_string(), None)),
+            Some(("ng", rest)) => {
+                let (name, reason) = rest.split_once(' ').unwrap_or((rest, "failed"));
+                statuses.push((name.to_string(), Some(reason.to_string())));
+            }
+            _ => return Err(eyre!(tr!("invalid status line '%s'", &line))),
+        }
+    }
+    Ok(statuses)
+}
+
+/// Returns the objects reachable from `new` that aren't reachable from the
+/// commits of `have` the local repository has, with the path they were
+/// found at, for [`pack::pack_objects`]. The trees of `have` are taken as
+/// what the remote has, rather than all its history.
+fn missing_objects(
+    git_dir: &Path,
+    new: &[String],
+    have: &[String],
+) -> eyre::Result<Vec<(String, String)>> {
+    let mut walk = RevWalk::new();
+    let mut seen = HashSet::new();
+    let have = have
+        .iter()
+        .filter(|sha| git::read_object(git_dir, sha).is_ok_and(|(kind, _)| kind == "commit"))
+        .cloned()
+        .collect::<Vec<_>>();
+    for sha in &have {
+        let tree = walk.commit(git_dir, sha)?.tree.clone();
+        pack::add_tree(git_dir, &tree, "", &mut seen, &mut Vec::new())?;
+    }
+
+    // Tags are sent along with the objects they tag
+    let mut objects = Vec::new();
+    let mut tips = Vec::new();
+    for sha in new {
+        let mut sha = sha.clone();
+        loop {
+            match GitFile::read(git_dir, &sha)?.file_content {
+                GitFileContent::Tag(tag) => {
+                    if seen.insert(sha.clone()) {
+                        objects.push((sha, String::new()));
+                    }
+                    sha = tag.object;
+                }
+                GitFileContent::Commit(_) => {
+                    tips.push(sha);
+                    break;
+                }
+                GitFileContent::Tree(_) => {
+                    pack::add_tree(git_dir, &sha, "", &mut seen, &mut objects)?;
+                    break;
+                }
+                GitFileContent::Blob(_) => {
+                    if seen.insert(sha.clone()) {
+                        objects.push((sha, String::new()));
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    for commit in walk.list(git_dir, &tips, &have)? {
+        let tree = walk.commit(git_dir, &commit)?.tree.clone();
+        objects.push((commit, String::new()));
+        pack::add_tree(git_dir, &tree, "", &mut seen, &mut objects)?;
+    }
+    Ok(objects)
+}
+
+/// Returns the name of a ref without `refs/heads/` or `refs/tags/`.
+fn short_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
@@ -0,0 +1,128 @@
+use crate::branch;
+use crate::commit::{Commit, Signature};
+use crate::config::Config;
+use crate::merge;
+use crate::refs;
+use crate::revwalk::RevWalk;
+use crate::tree;
+use crate::worktree::{self, Operation};
+use eyre::eyre;
+use std::path::Path;
+
+/// How `rebase` dates the commits it replays.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RebaseOptions {
+    /// Dates the replayed commits with the date of their author, instead of
+    /// now.
+    pub committer_date_is_author_date: bool,
+    /// Dates the authorship of the replayed commits now, instead of keeping
+    /// the original date.
+    pub ignore_date: bool,
+    /// Throws away the local changes in the way of the rebase.
+    pub force: bool,
+}
+
+/// Replays the commits of `HEAD` that aren't in `upstream` on top of
+/// `upstream`. The commits are replayed in memory first, so a conflict
+/// leaves the branch and the working tree untouched, and so do local
+/// changes to the files the rebase changes.
+pub fn rebase(
+    git_dir: &Path,
+    config: &Config,
+    upstream: &str,
+    options: RebaseOptions,
+) -> eyre::Result<()> {
+    let head =
+        refs::read_ref(git_dir, "HEAD")?.ok_or(eyre!(tr!("cannot rebase an unborn branch")))?;
+
+    let mut walk = RevWalk::new();
+    if walk.is_ancestor(git_dir, upstream, &head)? {
+        println!("{}", tr!("Current branch is up to date."));
+        return Ok(());
+    }
+
+    let mut commits = walk.list(
+        git_dir,
+        std::slice::from_ref(&head),
+        &[upstream.to_string()],
+    )?;
+    commits.reverse();
+
+    let mut onto = upstream.to_string();
+    let mut onto_commit = Commit::read(git_dir, &onto)?;
+    for sha in commits {
+        let commit = Commit::read(git_dir, &sha)?;
+
+        // Merge commits are dropped, like git does without --rebase-merges
+        let [parent] = commit.parents.as_slice() else {
+            continue;
+        };
+
+        let base = Commit::read(git_dir, parent)?.tree;
+        let label = format!("{} ({})", &sha[..7], commit.summary());
+        let result = merge::merge_trees(
+            git_dir,
+            Some(&base),
+            &onto_commit.tree,
+            &commit.tree,
+            ("HEAD", &label),
+        )?;
+        if !result.conflicts.is_empty() {
+            let paths = result
+                .conflicts
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>();
+            return Err(eyre!(tr!(
+                "could not apply %s... %s: conflicts in %s",
+                &sha[..7],
+                commit.summary(),
+                paths.join(", ")
+            )));
+        }
+
+        // Changes already present upstream leave nothing to commit
+        let tree = tree::write_tree(git_dir, &result.files)?;
+        if tree == onto_commit.tree {
+            continue;
+        }
+
+        let committer = Signature::now(config, "COMMITTER")?;
+        let mut author = commit.author;
+        if options.ignore_date {
+            (author.time, author.offset) = (committer.time, committer.offset);
+        }
+        let committer = match options.committer_date_is_author_date {
+            true => Signature {
+                time: author.time,
+                offset: author.offset,
+                ..committer
+            },
+            false => committer,
+        };
+
+        let replayed = Commit {
+            tree,
+            parents: vec![onto.clone()],
+            author,
+            committer,
+            encoding: commit.encoding,
+            extra_headers: Vec::new(),
+            message: commit.message,
+        };
+        onto = replayed.write(git_dir)?;
+        onto_commit = replayed;
+    }
+
+    let old = tree::flatten_tree(git_dir, &Commit::read(git_dir, &head)?.tree)?;
+    let new = tree::flatten_tree(git_dir, &onto_commit.tree)?;
+    let current = worktree::prepare_update(git_dir, &old, &new, Operation::Rebase, options.force)?;
+    worktree::checkout_files(git_dir, &current, &new, None)?;
+    branch::update_index(git_dir, &old, &new, options.force)?;
+    refs::record_orig_head(git_dir)?;
+    refs::update_ref(git_dir, "HEAD", &onto)?;
+
+    let branch = refs::symbolic_target(git_dir, "HEAD")?.unwrap_or("HEAD".to_string());
+    println!("{}", tr!("Successfully rebased and updated %s.", branch));
+    Ok(())
+}
@@ -0,0 +1,413 @@
+use crate::commit::Commit;
+use crate::config::{self, Config};
+use crate::fetch;
+use crate::git::{self, NULL_SHA};
+use crate::hooks::{self, RefUpdate};
+use crate::pack;
+use crate::pktline;
+use crate::refs::{self, Transaction};
+use crate::server::{self, Service};
+use eyre::eyre;
+use std::collections::{HashSet, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+/// An update of a ref asked for by a push.
+#[derive(Debug)]
+struct Update {
+    name: String,
+    /// What the client saw the ref point to, `None` if it didn't exist.
+    old: Option<String>,
+    /// What the ref is set to, `None` deleting it.
+    new: Option<String>,
+    /// Why the update was refused, if it was.
+    error: Option<String>,
+}
+
+impl Update {
+    /// Returns the update as hooks see it.
+    fn as_hook(&self) -> RefUpdate<'_> {
+        RefUpdate {
+            name: &self.name,
+            old: self.old.as_deref(),
+            new: self.new.as_deref(),
+        }
+    }
+}
+
+/// What `receive.denyCurrentBranch` does with pushes to the branch checked
+/// out in the working tree of the repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DenyAction {
+    Ignore,
+    Warn,
+    Refuse,
+}
+
+/// Receives a push into the repository at `git_dir`, reading the requests
+/// of the client from `input` and answering on `out`: the refs are
+/// advertised unless `stateless`, as over HTTP where an earlier run did it,
+/// then the ref updates are read along with the pack of the objects they
+/// need, which is stored. The updates are checked, applied, and their
+/// status reported if the client asked for it:
+/// - refs must be under `refs/`, and point to objects the repository has
+/// - `receive.denyNonFastForwards` refuses branch updates losing commits,
+///   `receive.denyDeletes` branch deletions, and `receive.denyCurrentBranch`
+///   and `receive.denyDeleteCurrent` updates and deletions of the branch
+///   checked out in a working tree
+/// - each ref is only updated if it still points to what the client saw
+/// - with the `atomic` capability, either all the refs are updated or none
+///
+/// Packs of fewer objects than `receive.unpackLimit` or
+/// `transfer.unpackLimit`, 100 by default, are unpacked into loose objects.
+pub fn receive_pack(
+    config: &Config,
+    git_dir: &Path,
+    stateless: bool,
+    input: impl Read,
+    mut out: impl Write,
+) -> eyre::Result<()> {
+    if !stateless {
+        server::advertise_refs(config, git_dir, Service::ReceivePack, 0, &mut out)?;
+    }
+
+    // Clients with nothing to push may hang up after the advertisement,
+    // others flush an empty list of updates
+    let mut input = BufReader::new(input);
+    if input.fill_buf()?.is_empty() {
+        return Ok(());
+    }
+
+    // The capabilities follow the first update after a NUL
+    let mut reader = pktline::Reader::new(&mut input);
+    let mut updates = Vec::new();
+    let mut capabilities = HashSet::new();
+    while let Some(line) = reader.read_line()? {
+        let line = match line.split_once('\0') {
+            Some((line, list)) => {
+                capabilities = list.split(' ').map(str::to_string).collect();
+                line.to_string()
+            }
+            None => line,
+        };
+        let mut words = line.splitn(3, ' ');
+        let (Some(old), Some(new), Some(name)) = (words.next(), words.next(), words.next()) else {
+            return Err(eyre!(tr!(
+                "protocol error: expected old/new/ref, got '%s'",
+                &line
+            )));
+        };
+        let sha = |sha: &str| -> eyre::Result<Option<String>> {
+            match sha {
+                NULL_SHA => Ok(None),
+                sha if refs::is_sha(sha) => Ok(Some(sha.to_lowercase())),
+                _ => Err(eyre!(tr!(
+                    "protocol error: expected old/new/ref, got '%s'",
+                    &line
+                ))),
+            }
+        };
+        updates.push(Update {
+            name: name.to_string(),
+            old: sha(old)?,
+            new: sha(new)?,
+            error: None,
+        });
+    }
+    if updates.is_empty() {
+        return Ok(());
+    }
+    // Push options are only read, no hook uses them
+    if capabilities.contains("push-options") {
+        while reader.read_line()?.is_some() {}
+    }
+
+    // Deleting refs needs no object, and no pack is sent for it
+    let unpack = match updates.iter().any(|update| update.new.is_some()) {
+        true => receive_objects(config, git_dir, reader.get_mut()),
+        false => Ok(()),
+    };
+    let sideband = capabilities.contains("side-band-64k");
+    let mut messages = Vec::new();
+    match &unpack {
+        Ok(()) => {
+            for update in &mut updates {
+                update.error = check(config, git_dir, update, &mut messages)?;
+            }
+            receive_hook(git_dir, &mut updates, &mut messages);
+            match capabilities.contains("atomic") {
+                true => apply_atomic(git_dir, &mut updates, &mut messages),
+                false => apply(git_dir, &mut updates, &mut messages),
+            }
+
+            let applied = accepted(&updates);
+            if !applied.is_empty() {
+                if let Err(e) = hooks::run(|hook| hook.post_receive(git_dir, &applied)) {
+                    messages.push(format!("warning: {e}"));
+                }
+            }
+        }
+        Err(_) => {
+            for update in &mut updates {
+                update.error = Some("unpacker error".to_string());
+            }
+        }
+    }
+
+    // Messages go to the client with the side-band, and to the log of the
+    // server otherwise
+    let mut writer = pktline::Writer::new(&mut out);
+    for message in messages {
+        match sideband {
+            true => {
+                writer.write_band(2, format!("{message}\n").as_bytes(), pktline::LARGE_PACKET)?
+            }
+            false => eprintln!("{message}"),
+        }
+    }
+    if capabilities.contains("report-status") || capabilities.contains("report-status-v2") {
+        let mut report = pktline::Writer::new(Vec::new());
+        match &unpack {
+            Ok(()) => report.write_line("unpack ok")?,
+            Err(e) => report.write_line(&format!("unpack {e}"))?,
+        }
+        for update in &updates {
+            match &update.error {
+                None => report.write_line(&format!("ok {}", update.name))?,
+                Some(error) => report.write_line(&format!("ng {} {error}", update.name))?,
+            }
+        }
+        report.write_flush()?;
+        let report = report.into_inner();
+        match sideband {
+            true => writer.write_band(1, &report, pktline::LARGE_PACKET)?,
+            false => writer.get_mut().write_all(&report)?,
+        }
+    }
+    if sideband {
+        writer.write_flush()?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Reads the pack of a push from `input` and stores its objects in the
+/// repository at `git_dir`.
+fn receive_objects(config: &Config, git_dir: &Path, input: &mut impl BufRead) -> eyre::Result<()> {
+    let pack = pack::read_stream(input)?;
+    let limit = match config
+        .get("receive.unpackLimit")
+        .or(config.get("transfer.unpackLimit"))
+    {
+        Some(limit) => limit.parse()?,
+        None => fetch::UNPACK_LIMIT,
+    };
+    let count = u32::from_be_bytes(pack[8..12].try_into()?);
+    match count < limit {
+        true => {
+            pack::unpack_objects(git_dir, &pack, false, None)?;
+        }
+        false => {
+            pack::store(git_dir, &pack, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns why `update` is refused by the rules of the repository at
+/// `git_dir`, adding the error explaining it to `messages`, or `None` if it
+/// may be applied.
+fn check(
+    config: &Config,
+    git_dir: &Path,
+    update: &Update,
+    messages: &mut Vec<String>,
+) -> eyre::Result<Option<String>> {
+    let name = &update.name;
+    if !name.starts_with("refs/") || !refs::is_valid_name(name) {
+        return refuse(
+            messages,
+            tr!("refusing to create funny ref '%s' remotely", name),
+            "funny refname",
+        );
+    }
+
+    // The branch checked out in the working tree would no longer match it
+    let bare = config
+        .get_bool("core.bare")?
+        .unwrap_or(!git_dir.ends_with(".git"));
+    let current = !bare && refs::symbolic_target(git_dir, "HEAD")?.as_ref() == Some(name);
+    let branch = name.starts_with("refs/heads/");
+    match &update.new {
+        Some(new) => {
+            if current {
+                match deny_action(config, "receive.denyCurrentBranch")? {
+                    DenyAction::Refuse => {
+                        return refuse(
+                            messages,
+                            tr!("refusing to update checked out branch: %s", name),
+                            "branch is currently checked out",
+                        )
+                    }
+                    DenyAction::Warn => {
+                        messages.push(format!("warning: {}", tr!("updating the current branch")))
+                    }
+                    DenyAction::Ignore => {}
+                }
+            }
+            if git::read_object(git_dir, new).is_err() {
+                return refuse(
+                    messages,
+                    tr!("unpack should have generated %s, but I can't find it!", new),
+                    "bad pack",
+                );
+            }
+            let deny = config.get_bool("receive.denyNonFastForwards")?;
+            if let (Some(old), true, Some(true)) = (&update.old, branch, deny) {
+                if !is_ancestor(git_dir, old, new)? {
+                    return refuse(
+                        messages,
+                        tr!("denying non-fast-forward %s (you should pull first)", name),
+                        "non-fast-forward",
+                    );
+                }
+            }
+        }
+        None => {
+            if branch && config.get_bool("receive.denyDeletes")? == Some(true) {
+                return refuse(
+                    messages,
+                    tr!("denying ref deletion for %s", name),
+                    "deletion prohibited",
+                );
+            }
+            if current {
+                match deny_action(config, "receive.denyDeleteCurrent")? {
+                    DenyAction::Refuse => {
+                        return refuse(
+                            messages,
+                            tr!("refusing to delete the current branch: %s", name),
+                            "deletion of the current branch prohibited",
+                        )
+                    }
+                    DenyAction::Warn => {
+                        messages.push(format!("warning: {}", tr!("deleting the current branch")))
+                    }
+                    DenyAction::Ignore => {}
+                }
+            }
+            if refs::read_ref(git_dir, name)?.is_none() {
+                messages.push(format!("warning: {}", tr!("deleting a non-existent ref")));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Adds the error `message` to `messages` and returns `reason`, the reason
+/// an update is refused.
+fn refuse(
+    messages: &mut Vec<String>,
+    message: String,
+    reason: &str,
+) -> eyre::Result<Option<String>> {
+    messages.push(format!("error: {message}"));
+    Ok(Some(reason.to_string()))
+}
+
+/// Returns what the config `key` does, `refuse` by default: `ignore`,
+/// `warn`, `refuse` or a boolean, true refusing.
+fn deny_action(config: &Config, key: &str) -> eyre::Result<DenyAction> {
+    match config.get(key) {
+        None | Some("refuse") => Ok(DenyAction::Refuse),
+        Some("warn") => Ok(DenyAction::Warn),
+        Some("ignore") => Ok(DenyAction::Ignore),
+        Some(value) => match config::parse_bool(value)? {
+            true => Ok(DenyAction::Refuse),
+            false => Ok(DenyAction::Ignore),
+        },
+    }
+}
+
+/// Returns true if the commit `old` of the repository at `git_dir` is an
+/// ancestor of the commit `new`, or `new` itself.
+fn is_ancestor(git_dir: &Path, old: &str, new: &str) -> eyre::Result<bool> {
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([new.to_string()]);
+    while let Some(sha) = queue.pop_front() {
+        if sha == old {
+            return Ok(true);
+        }
+        if !seen.insert(sha.clone()) {
+            continue;
+        }
+        // Tags and other objects have no history
+        let Some(content) = git::read_object_as(git_dir, &sha, "commit")? else {
+            continue;
+        };
+        queue.extend(Commit::parse(&content)?.parents);
+    }
+    Ok(false)
+}
+
+/// Returns the updates not refused, as hooks see them.
+fn accepted(updates: &[Update]) -> Vec<RefUpdate<'_>> {
+    updates
+        .iter()
+        .filter(|update| update.error.is_none())
+        .map(Update::as_hook)
+        .collect()
+}
+
+/// Runs the `pre_receive` hooks on the accepted updates, refusing all of
+/// them if one fails.
+fn receive_hook(git_dir: &Path, updates: &mut [Update], messages: &mut Vec<String>) {
+    let accepted = accepted(updates);
+    if accepted.is_empty() {
+        return;
+    }
+    let Err(e) = hooks::run(|hook| hook.pre_receive(git_dir, &accepted)) else {
+        return;
+    };
+    messages.push(format!("error: {e}"));
+    for update in updates.iter_mut().filter(|update| update.error.is_none()) {
+        update.error = Some("pre-receive hook declined".to_string());
+    }
+}
+
+/// Applies the accepted updates one by one, refusing the ones whose ref
+/// can't be locked or has moved since the client saw it.
+fn apply(git_dir: &Path, updates: &mut [Update], messages: &mut Vec<String>) {
+    for update in updates.iter_mut().filter(|update| update.error.is_none()) {
+        let mut transaction = Transaction::new(git_dir);
+        transaction.update(&update.name, update.old.as_deref(), update.new.as_deref());
+        if let Err(e) = transaction.commit() {
+            messages.push(format!("error: {e}"));
+            update.error = Some("failed to update ref".to_string());
+        }
+    }
+}
+
+/// Applies all the updates in a single transaction, or none of them if one
+/// was refused or the transaction fails.
+fn apply_atomic(git_dir: &Path, updates: &mut [Update], messages: &mut Vec<String>) {
+    let reason = match updates.iter().any(|update| update.error.is_some()) {
+        true => "atomic push failure",
+        false => {
+            let mut transaction = Transaction::new(git_dir);
+            for update in updates.iter() {
+                transaction.update(&update.name, update.old.as_deref(), update.new.as_deref());
+            }
+            match transaction.commit() {
+                Ok(()) => return,
+                Err(e) => {
+                    messages.push(format!("error: {e}"));
+                    "atomic transaction failed"
+                }
+            }
+        }
+    };
+    for update in updates.iter_mut().filter(|update| update.error.is_none()) {
+        update.error = Some(reason.to_string());
+    }
+}
@@ -0,0 +1,510 @@
+use crate::commit::{self, Commit, Signature};
+use crate::config::Config;
+use crate::fetch::Refspec;
+use crate::git;
+use crate::refs;
+use crate::revwalk::RevWalk;
+use crate::tag::Tag;
+use crate::wildmatch;
+use eyre::eyre;
+use std::cmp::Ordering;
+use std::io::Write;
+use std::path::Path;
+
+/// The fields a format can show, with an optional `:modifier`.
+const ATOMS: [&str; 16] = [
+    "refname",
+    "objectname",
+    "objecttype",
+    "objectsize",
+    "HEAD",
+    "subject",
+    "body",
+    "contents",
+    "authorname",
+    "authoremail",
+    "authordate",
+    "committername",
+    "committeremail",
+    "committerdate",
+    "creatordate",
+    "upstream",
+];
+
+/// A ref as listed by `for-each-ref` or `branch`.
+#[derive(Debug, Clone)]
+pub struct RefItem {
+    /// The full name of the ref, or a description like `(HEAD detached at
+    /// 1234567)` for a detached `HEAD`.
+    pub name: String,
+    pub sha: String,
+    /// Whether `HEAD` is, or points to, the ref.
+    pub head: bool,
+}
+
+impl RefItem {
+    /// Returns the item of the ref `name` pointing to `sha`.
+    pub fn new(git_dir: &Path, name: String, sha: String) -> eyre::Result<Self> {
+        let head = refs::symbolic_target(git_dir, "HEAD")?.as_deref() == Some(name.as_str());
+        Ok(Self { name, sha, head })
+    }
+}
+
+/// The value of an atom for a ref: its text, and the number it sorts by
+/// for dates and sizes.
+#[derive(Debug, Default)]
+struct Value {
+    text: String,
+    number: Option<i64>,
+}
+
+/// The object a ref points to, with the parts of it the atoms show.
+#[derive(Debug)]
+struct Object {
+    kind: String,
+    size: usize,
+    author: Option<Signature>,
+    committer: Option<Signature>,
+    message: String,
+}
+
+impl Object {
+    fn read(git_dir: &Path, sha: &str) -> eyre::Result<Self> {
+        let (kind, content) = git::read_object(git_dir, sha)?;
+        let mut object = Self {
+            kind,
+            size: content.len(),
+            author: None,
+            committer: None,
+            message: String::new(),
+        };
+        match object.kind.as_str() {
+            "commit" => {
+                let commit = Commit::parse(&content)?;
+                object.author = Some(commit.author);
+                object.committer = Some(commit.committer);
+                object.message = commit.message;
+            }
+            "tag" => {
+                // The tagger stands for the committer
+                let tag = Tag::parse(&content)?;
+                object.committer = tag.tagger;
+                object.message = tag.message;
+            }
+            _ => {}
+        }
+        Ok(object)
+    }
+}
+
+/// A format string like `%(refname:short) %(objectname)`, parsed.
+#[derive(Debug)]
+pub struct Format {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Literal(Vec<u8>),
+    Atom(String),
+}
+
+impl Format {
+    /// Parses a format, where `%(atom)` shows a field of the ref, `%%` is
+    /// a `%` and `%xx` the byte of hexadecimal value `xx`.
+    pub fn parse(format: &str) -> eyre::Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = Vec::new();
+        let mut rest = format;
+        while let Some(start) = rest.find('%') {
+            literal.extend_from_slice(&rest.as_bytes()[..start]);
+            rest = &rest[start + 1..];
+            if let Some(after) = rest.strip_prefix('(') {
+                let end = after
+                    .find(')')
+                    .ok_or(eyre!(tr!("malformed format string %s", format)))?;
+                let atom = &after[..end];
+                check_atom(atom)?;
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+                parts.push(Part::Atom(atom.to_string()));
+                rest = &after[end + 1..];
+            } else if let Some(after) = rest.strip_prefix('%') {
+                literal.push(b'%');
+                rest = after;
+            } else if let Some(byte) = rest.get(..2).and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                literal.push(byte);
+                rest = &rest[2..];
+            } else {
+                literal.push(b'%');
+            }
+        }
+        literal.extend_from_slice(rest.as_bytes());
+        parts.push(Part::Literal(literal));
+        Ok(Self { parts })
+    }
+
+    /// Formats `item`.
+    pub fn format(&self, git_dir: &Path, config: &Config, item: &RefItem) -> eyre::Result<Vec<u8>> {
+        let mut object = None;
+        let mut out = Vec::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => out.extend_from_slice(literal),
+                Part::Atom(atom) => {
+                    let value = value(git_dir, config, item, &mut object, atom)?;
+                    out.extend_from_slice(value.text.as_bytes());
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Which refs `for_each_ref` prints, and how.
+#[derive(Debug, Default)]
+pub struct ForEachRefOptions {
+    /// The format of each ref, `%(objectname) %(objecttype)%09%(refname)`
+    /// by default.
+    pub format: Option<String>,
+    /// The keys the refs are sorted by, see [`sort`].
+    pub sort: Vec<String>,
+    /// Stops after this many refs.
+    pub count: Option<usize>,
+    /// Only prints the refs matching one of these patterns, either a glob
+    /// or a prefix ending at a `/`.
+    pub patterns: Vec<String>,
+}
+
+/// Prints the refs selected by `options` to `out`, one per line.
+pub fn for_each_ref(
+    git_dir: &Path,
+    config: &Config,
+    options: &ForEachRefOptions,
+    mut out: impl Write,
+) -> eyre::Result<()> {
+    let format = Format::parse(
+        options
+            .format
+            .as_deref()
+            .unwrap_or("%(objectname) %(objecttype)%09%(refname)"),
+    )?;
+    let mut items = Vec::new();
+    for (name, sha) in refs::list_refs(git_dir, "refs/")? {
+        let matches = options.patterns.is_empty()
+            || options.patterns.iter().any(|pattern| {
+                let pattern = pattern.trim_end_matches('/');
+                name == pattern
+                    || name.starts_with(&format!("{pattern}/"))
+                    || wildmatch::wildmatch(pattern.as_bytes(), name.as_bytes(), true)
+            });
+        if matches {
+            items.push(RefItem::new(git_dir, name, sha)?);
+        }
+    }
+    sort(git_dir, config, &mut items, &options.sort)?;
+
+    for item in items.iter().take(options.count.unwrap_or(usize::MAX)) {
+        out.write_all(&format.format(git_dir, config, item)?)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Sorts `items` by the atoms of `keys`, reversed with a leading `-`, the
+/// last key deciding first and names breaking ties.
+pub fn sort(
+    git_dir: &Path,
+    config: &Config,
+    items: &mut [RefItem],
+    keys: &[String],
+) -> eyre::Result<()> {
+    let mut sort_keys = Vec::new();
+    for key in keys {
+        let (reverse, atom) = match key.strip_prefix('-') {
+            Some(atom) => (true, atom),
+            None => (false, key.as_str()),
+        };
+        check_atom(atom)?;
+        sort_keys.push((reverse, atom));
+    }
+
+    let mut values = Vec::new();
+    for item in items.iter() {
+        let mut object = None;
+        let mut item_values = Vec::new();
+        for (_, atom) in &sort_keys {
+            item_values.push(value(git_dir, config, item, &mut object, atom)?);
+        }
+        values.push((item.clone(), item_values));
+    }
+    values.sort_by(|(a, a_values), (b, b_values)| {
+        sort_keys
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, (reverse, _))| {
+                let (x, y) = (&a_values[i], &b_values[i]);
+                let ordering = match (x.number, y.number) {
+                    (Some(x), Some(y)) => x.cmp(&y),
+                    _ => x.text.cmp(&y.text),
+                };
+                if *reverse {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    for (item, (sorted, _)) in items.iter_mut().zip(values) {
+        *item = sorted;
+    }
+    Ok(())
+}
+
+/// Fails unless `atom` is a field formats can show.
+fn check_atom(atom: &str) -> eyre::Result<()> {
+    let name = atom.split(':').next().unwrap_or_default();
+    match ATOMS.contains(&name) {
+        true => Ok(()),
+        false => Err(eyre!(tr!("unknown field name: %s", name))),
+    }
+}
+
+/// Returns the value of `atom` for `item`, reading the object it points to
+/// into `object` the first time it's needed.
+fn value(
+    git_dir: &Path,
+    config: &Config,
+    item: &RefItem,
+    object: &mut Option<Object>,
+    atom: &str,
+) -> eyre::Result<Value> {
+    let (name, modifier) = match atom.split_once(':') {
+        Some((name, modifier)) => (name, Some(modifier)),
+        None => (atom, None),
+    };
+    let text = |text: String| Ok(Value { text, number: None });
+
+    match name {
+        "refname" => text(refname(git_dir, &item.name, modifier)?),
+        "objectname" => match modifier {
+            None => text(item.sha.clone()),
+            Some("short") => text(item.sha[..7].to_string()),
+            Some(modifier) => {
+                let length = modifier
+                    .strip_prefix("short=")
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .ok_or(eyre!(tr!(
+                        "unrecognized %%(objectname) argument: %s",
+                        modifier
+                    )))?;
+                text(item.sha[..length.clamp(4, 40)].to_string())
+            }
+        },
+        "HEAD" => text(if item.head { "*" } else { " " }.to_string()),
+        "upstream" => {
+            let Some(branch) = item.name.strip_prefix("refs/heads/") else {
+                return text(String::new());
+            };
+            if let Some(track @ ("track" | "track,nobracket" | "trackshort")) = modifier {
+                let Some((_, counts)) = tracking(git_dir, config, branch, &item.sha)? else {
+                    return text(String::new());
+                };
+                return text(match (track, counts) {
+                    ("trackshort", None) => String::new(),
+                    ("trackshort", Some((0, 0))) => "=".to_string(),
+                    ("trackshort", Some((_, 0))) => ">".to_string(),
+                    ("trackshort", Some((0, _))) => "<".to_string(),
+                    ("trackshort", Some(_)) => "<>".to_string(),
+                    ("track", counts) => match describe_tracking(counts) {
+                        description if description.is_empty() => description,
+                        description => format!("[{description}]"),
+                    },
+                    (_, counts) => describe_tracking(counts),
+                });
+            }
+            match (upstream(config, branch), modifier) {
+                (Some(upstream), Some("short")) => text(refs::shorten(git_dir, &upstream)?),
+                (Some(upstream), None) => text(upstream),
+                (Some(_), Some(modifier)) => Err(eyre!(tr!(
+                    "unrecognized %%(upstream) argument: %s",
+                    modifier
+                ))),
+                (None, _) => text(String::new()),
+            }
+        }
+        _ => {
+            if object.is_none() {
+                *object = Some(Object::read(git_dir, &item.sha)?);
+            }
+            let Some(object) = object.as_ref() else {
+                return text(String::new());
+            };
+            object_value(object, name, modifier)
+        }
+    }
+}
+
+/// Returns the value of an atom showing a part of the object a ref points
+/// to.
+fn object_value(object: &Object, name: &str, modifier: Option<&str>) -> eyre::Result<Value> {
+    let text = |text: String| Ok(Value { text, number: None });
+
+    // Subjects are the first paragraph of the message, on one line
+    let (subject, body) = match object.message.split_once("\n\n") {
+        Some((subject, body)) => (subject, body),
+        None => (object.message.trim_end_matches('\n'), ""),
+    };
+    match name {
+        "objecttype" => text(object.kind.clone()),
+        "objectsize" => Ok(Value {
+            text: object.size.to_string(),
+            number: Some(object.size as i64),
+        }),
+        "subject" => text(subject.lines().collect::<Vec<_>>().join(" ")),
+        "body" => text(body.to_string()),
+        "contents" => match modifier {
+            None => text(object.message.clone()),
+            Some("subject") => text(subject.lines().collect::<Vec<_>>().join(" ")),
+            Some("body") => text(body.to_string()),
+            Some(modifier) => Err(eyre!(tr!(
+                "unrecognized %%(contents) argument: %s",
+                modifier
+            ))),
+        },
+        _ => {
+            // The rest are parts of the author or committer
+            let (role, part) = match name.strip_prefix("author") {
+                Some(part) => (object.author.as_ref(), part),
+                None => match name.strip_prefix("committer") {
+                    Some(part) => (object.committer.as_ref(), part),
+                    // Commits are created by their committer, tags by their
+                    // tagger
+                    None => (object.committer.as_ref(), "date"),
+                },
+            };
+            let Some(signature) = role else {
+                return text(String::new());
+            };
+            match part {
+                "name" => text(signature.name.clone()),
+                "email" => text(format!("<{}>", signature.email)),
+                _ => {
+                    let formatted = match modifier {
+                        None => commit::format_date(signature.time, signature.offset),
+                        Some("unix") => signature.time.to_string(),
+                        Some("raw") => format!(
+                            "{} {}",
+                            signature.time,
+                            commit::format_offset(signature.offset)
+                        ),
+                        Some(modifier) => {
+                            return Err(eyre!(tr!("unknown date format %s", modifier)))
+                        }
+                    };
+                    Ok(Value {
+                        text: formatted,
+                        number: Some(signature.time),
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// Returns the name of a ref as `%(refname)` shows it: in full, `short`,
+/// or without its first (`lstrip=<n>`) or last (`rstrip=<n>`) components.
+fn refname(git_dir: &Path, name: &str, modifier: Option<&str>) -> eyre::Result<String> {
+    let Some(modifier) = modifier else {
+        return Ok(name.to_string());
+    };
+    if modifier == "short" {
+        return refs::shorten(git_dir, name);
+    }
+
+    let invalid = || eyre!(tr!("unrecognized %%(refname) argument: %s", modifier));
+    let (from_start, count) = match modifier.split_once('=') {
+        Some(("lstrip" | "strip", count)) => (true, count),
+        Some(("rstrip", count)) => (false, count),
+        _ => return Err(invalid()),
+    };
+    let count = count.parse::<i64>().map_err(|_| invalid())?;
+    let components = name.split('/').collect::<Vec<_>>();
+    let len = components.len() as i64;
+    // A negative count keeps that many components instead
+    let strip = match count < 0 {
+        true => (len + count).max(0),
+        false => count.min(len),
+    } as usize;
+    let kept = match from_start {
+        true => &components[strip..],
+        false => &components[..components.len() - strip],
+    };
+    Ok(kept.join("/"))
+}
+
+/// Returns the ref `branch` pulls from, from `branch.<name>.remote` and
+/// `branch.<name>.merge`, as the remote-tracking ref it's fetched to.
+pub fn upstream(config: &Config, branch: &str) -> Option<String> {
+    let merge = config.get(&format!("branch.{branch}.merge"))?;
+    let remote = config.get(&format!("branch.{branch}.remote"))?;
+    // Branches tracking a local branch have `.` as their remote
+    if remote == "." {
+        return Some(merge.to_string());
+    }
+    config
+        .get_all(&format!("remote.{remote}.fetch"))
+        .into_iter()
+        .find_map(|spec| Refspec::parse(spec).map(merge))
+}
+
+/// Returns the short name of the `upstream` ref, which `exists` or is gone.
+pub fn upstream_name(git_dir: &Path, upstream: &str, exists: bool) -> eyre::Result<String> {
+    // Refs that are gone can't be ambiguous
+    match exists {
+        true => refs::shorten(git_dir, upstream),
+        false => Ok(["refs/heads/", "refs/remotes/"]
+            .iter()
+            .find_map(|prefix| upstream.strip_prefix(prefix))
+            .unwrap_or(upstream)
+            .to_string()),
+    }
+}
+
+/// How many commits a branch is ahead and behind of its upstream, `None`
+/// if the upstream ref is gone.
+pub type Counts = Option<(usize, usize)>;
+
+/// Returns the upstream of `branch`, at `sha`, and how the branch compares
+/// with it. Returns `None` if the branch has no upstream.
+pub fn tracking(
+    git_dir: &Path,
+    config: &Config,
+    branch: &str,
+    sha: &str,
+) -> eyre::Result<Option<(String, Counts)>> {
+    let Some(upstream) = upstream(config, branch) else {
+        return Ok(None);
+    };
+    let counts = match refs::read_ref(git_dir, &upstream)? {
+        Some(tip) => Some(RevWalk::new().ahead_behind(git_dir, sha, &tip)?),
+        None => None,
+    };
+    Ok(Some((upstream, counts)))
+}
+
+/// Describes how a branch compares with its upstream from the counts of
+/// [`tracking`], like `ahead 1, behind 2`, or `gone`. Branches equal to
+/// their upstream get an empty description.
+pub fn describe_tracking(counts: Counts) -> String {
+    match counts {
+        None => tr!("gone"),
+        Some((0, 0)) => String::new(),
+        Some((ahead, 0)) => tr!("ahead %d", ahead),
+        Some((0, behind)) => tr!("behind %d", behind),
+        Some((ahead, behind)) => tr!("ahead %d, behind %d", ahead, behind),
+    }
+}
@@ -0,0 +1,393 @@
+use crate::commit::{self, Commit, Signature};
+use crate::config::Config;
+use crate::git::{self, GitFile, GitFileContent};
+use crate::refs;
+use crate::revwalk::RevWalk;
+use crate::tree::MODE_GITLINK;
+use eyre::eyre;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// How long entries are kept when nothing else is configured.
+const DEFAULT_EXPIRE: &str = "90 days ago";
+/// How long entries no longer reachable from the ref are kept when nothing
+/// else is configured.
+const DEFAULT_EXPIRE_UNREACHABLE: &str = "30 days ago";
+
+/// An entry of a ref's log, recording an update of the ref.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub old: String,
+    pub new: String,
+    pub who: Signature,
+    pub message: String,
+}
+
+impl Entry {
+    fn parse(line: &str) -> eyre::Result<Self> {
+        let invalid = || eyre!("invalid reflog entry: {line}");
+        let (header, message) = line.split_once('\t').unwrap_or((line, ""));
+        let (old, rest) = header.split_once(' ').ok_or_else(invalid)?;
+        let (new, who) = rest.split_once(' ').ok_or_else(invalid)?;
+        Ok(Self {
+            old: old.to_string(),
+            new: new.to_string(),
+            who: Signature::parse(who)?,
+            message: message.to_string(),
+        })
+    }
+}
+
+/// Reads the log of the ref `name`, oldest entry first.
+pub fn read(git_dir: &Path, name: &str) -> eyre::Result<Vec<Entry>> {
+    let Ok(content) = fs::read_to_string(git_dir.join("logs").join(name)) else {
+        return Ok(Vec::new());
+    };
+    content.lines().map(Entry::parse).collect()
+}
+
+/// Replaces the log of the ref `name` with `entries`.
+fn write(git_dir: &Path, name: &str, entries: &[Entry]) -> eyre::Result<()> {
+    let content = entries
+        .iter()
+        .map(|e| format!("{} {} {}\t{}\n", e.old, e.new, e.who, e.message))
+        .collect::<String>();
+    fs::write(git_dir.join("logs").join(name), content)?;
+    Ok(())
+}
+
+/// Returns the names of the refs that have a log, sorted, with the refs
+/// under `refs/` first like git.
+pub fn list(git_dir: &Path) -> eyre::Result<Vec<String>> {
+    fn walk(dir: &Path, prefix: &str, names: &mut Vec<String>) -> eyre::Result<()> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Ok(());
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                walk(&entry.path(), &format!("{name}/"), names)?;
+            } else {
+                names.push(name);
+            }
+        }
+        Ok(())
+    }
+
+    let mut names = Vec::new();
+    walk(&git_dir.join("logs"), "", &mut names)?;
+    names.sort_by_key(|name| (!name.starts_with("refs/"), name.clone()));
+    Ok(names)
+}
+
+/// When entries of a log expire, as unix timestamps: entries older than
+/// `expire` are pruned, as are entries older than `expire_unreachable`
+/// whose commits aren't reachable from the ref anymore.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub expire: i64,
+    pub expire_unreachable: i64,
+}
+
+impl Policy {
+    /// Returns the policy configured for the ref `name`, the first
+    /// `gc.<pattern>.reflogExpire` whose pattern matches the ref taking
+    /// precedence over `gc.reflogExpire`. Explicit values, from the command
+    /// line, override the configuration.
+    pub fn for_ref(
+        config: &Config,
+        name: &str,
+        expire: Option<&str>,
+        expire_unreachable: Option<&str>,
+        now: i64,
+    ) -> eyre::Result<Self> {
+        let configured = |key: &str, default: &str| {
+            config
+                .subsections("gc", key)
+                .into_iter()
+                .find(|(pattern, _)| glob_match(pattern, name))
+                .map(|(_, value)| value)
+                .or(config.get(&format!("gc.{key}")))
+                .unwrap_or(default)
+                .to_string()
+        };
+        let expire = expire
+            .map(|e| e.to_string())
+            .unwrap_or_else(|| configured("reflogExpire", DEFAULT_EXPIRE));
+        let expire_unreachable = expire_unreachable
+            .map(|e| e.to_string())
+            .unwrap_or_else(|| configured("reflogExpireUnreachable", DEFAULT_EXPIRE_UNREACHABLE));
+        Ok(Self {
+            expire: parse_expiry(&expire, now)?,
+            expire_unreachable: parse_expiry(&expire_unreachable, now)?,
+        })
+    }
+}
+
+/// How `expire` rewrites a log.
+#[derive(Debug, Default)]
+pub struct ExpireOptions {
+    /// Also prunes entries pointing to missing or broken objects.
+    pub stale_fix: bool,
+    /// Sets the old sha of each kept entry to the new sha of the one before.
+    pub rewrite: bool,
+    /// Points the ref at the new sha of the last kept entry.
+    pub update_ref: bool,
+    pub dry_run: bool,
+    pub verbose: bool,
+}
+
+/// Prunes the expired entries of the logs of the refs `names`, with the
+/// policy configured for each unless `expire` or `expire_unreachable` are
+/// given, see [`Policy::for_ref`].
+pub fn expire_refs(
+    git_dir: &Path,
+    config: &Config,
+    names: &[String],
+    expire: Option<&str>,
+    expire_unreachable: Option<&str>,
+    options: &ExpireOptions,
+) -> eyre::Result<()> {
+    let now = commit::now()?;
+    let names = names
+        .iter()
+        .map(|name| match name.as_str() {
+            "HEAD" => Ok(name.clone()),
+            _ => refs::expand(git_dir, name)?.ok_or(eyre!(tr!("%s points nowhere!", name))),
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+    if options.stale_fix && options.verbose {
+        println!("{}", tr!("Marking reachable objects..."));
+    }
+    for name in names {
+        let policy = Policy::for_ref(config, &name, expire, expire_unreachable, now)?;
+        self::expire(git_dir, &name, policy, options)?;
+    }
+    Ok(())
+}
+
+/// Prunes the expired entries of the log of the ref `name`.
+pub fn expire(
+    git_dir: &Path,
+    name: &str,
+    policy: Policy,
+    options: &ExpireOptions,
+) -> eyre::Result<()> {
+    let entries = read(git_dir, name)?;
+    let tip = refs::read_ref(git_dir, name)?;
+
+    // The commits of HEAD's log may have been reached from any branch
+    let tips = match name {
+        "HEAD" => refs::list_refs(git_dir, "refs/")?
+            .into_iter()
+            .map(|(_, sha)| sha)
+            .chain(tip.clone())
+            .collect(),
+        _ => tip.iter().cloned().collect::<Vec<_>>(),
+    };
+    let mut reachable = None;
+    let mut is_unreachable = |sha: &str| -> bool {
+        if sha == git::NULL_SHA {
+            return false;
+        }
+        let reachable = reachable.get_or_insert_with(|| {
+            // Tips that aren't commits don't make anything reachable
+            let tips = tips
+                .iter()
+                .filter(|sha| Commit::read(git_dir, sha).is_ok())
+                .cloned()
+                .collect::<Vec<_>>();
+            let commits = RevWalk::new().list(git_dir, &tips, &[]).unwrap_or_default();
+            commits.into_iter().collect::<HashSet<_>>()
+        });
+        !reachable.contains(sha)
+    };
+
+    let mut complete = HashSet::new();
+    let mut kept: Vec<Entry> = Vec::new();
+    for mut entry in entries {
+        // Rewritten entries are judged by the old sha they'll be written with
+        if options.rewrite {
+            entry.old = kept
+                .last()
+                .map(|e| e.new.clone())
+                .unwrap_or(git::NULL_SHA.to_string());
+        }
+
+        let time = entry.who.time;
+        let prune = time < policy.expire
+            || (options.stale_fix
+                && (is_broken(git_dir, &entry.old, &mut complete)
+                    || is_broken(git_dir, &entry.new, &mut complete)))
+            || (time < policy.expire_unreachable
+                && (tip.is_none()
+                    || policy.expire_unreachable <= policy.expire
+                    || is_unreachable(&entry.old)
+                    || is_unreachable(&entry.new)));
+
+        if options.verbose {
+            let action = match (prune, options.dry_run) {
+                (true, true) => tr!("would prune %s", &entry.message),
+                (true, false) => tr!("prune %s", &entry.message),
+                (false, _) => tr!("keep %s", &entry.message),
+            };
+            println!("{action}");
+        }
+        if !prune {
+            kept.push(entry);
+        }
+    }
+
+    if options.dry_run {
+        return Ok(());
+    }
+    write(git_dir, name, &kept)?;
+    if options.update_ref {
+        if let Some(last) = kept.last().filter(|e| e.new != git::NULL_SHA) {
+            if tip.as_deref() != Some(last.new.as_str()) {
+                refs::update_ref(git_dir, name, &last.new)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if `sha` names a missing object, or a commit some of whose
+/// history or content is missing, the null sha standing for no object.
+/// Objects found complete are added to `complete`, sparing later checks.
+fn is_broken(git_dir: &Path, sha: &str, complete: &mut HashSet<String>) -> bool {
+    if sha == git::NULL_SHA {
+        return false;
+    }
+    let mut seen = HashSet::new();
+    let mut pending = vec![sha.to_string()];
+    while let Some(sha) = pending.pop() {
+        if complete.contains(&sha) || !seen.insert(sha.clone()) {
+            continue;
+        }
+        let Ok(file) = GitFile::read(git_dir, &sha) else {
+            return true;
+        };
+        match file.file_content {
+            GitFileContent::Commit(commit) => {
+                pending.push(commit.tree);
+                pending.extend(commit.parents);
+            }
+            // Submodule commits aren't objects of this repository
+            GitFileContent::Tree(entries) => pending.extend(
+                entries
+                    .iter()
+                    .filter(|e| e.mode != MODE_GITLINK)
+                    .map(|e| hex::encode(&e.sha)),
+            ),
+            GitFileContent::Tag(tag) => pending.push(tag.object),
+            GitFileContent::Blob(_) => {}
+        }
+    }
+    complete.extend(seen);
+    false
+}
+
+/// Parses an expiry date into a unix timestamp: `never` or `false` keeps
+/// everything, `now` or `all` expires everything, and otherwise the value is
+/// a timestamp or a relative date like `90.days.ago` or `2 weeks ago`.
+pub fn parse_expiry(value: &str, now: i64) -> eyre::Result<i64> {
+    let invalid = || eyre!(tr!("'%s' is not a valid expiry date", value));
+    match value.to_lowercase().as_str() {
+        "never" | "false" => return Ok(0),
+        "now" | "all" => return Ok(i64::MAX),
+        _ => {}
+    }
+    if let Ok(time) = value.parse::<i64>() {
+        return Ok(time);
+    }
+
+    let words = value
+        .split(['.', ' '])
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>();
+    let words = match words.as_slice() {
+        [rest @ .., "ago"] => rest,
+        words => words,
+    };
+    let [count, unit] = words else {
+        return Err(invalid());
+    };
+    let count = count.parse::<i64>().map_err(|_| invalid())?;
+    let seconds = match unit.trim_end_matches('s') {
+        "second" => 1,
+        "minute" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        "month" => 30 * 24 * 60 * 60,
+        "year" => 365 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(now - count * seconds)
+}
+
+/// Matches `name` against a pattern where `*` matches any run of characters
+/// and `?` any single character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            name.char_indices()
+                .map(|(i, _)| i)
+                .chain([name.len()])
+                .any(|i| glob_match(rest, &name[i..]))
+        }
+        Some(c) => {
+            let mut chars = name.chars();
+            match chars.next() {
+                Some(n) if c == '?' || c == n => {
+                    glob_match(&pattern[c.len_utf8()..], chars.as_str())
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Deletes the entry at position `index` of the log of the ref `name`, the
+/// latest entry being at position 0. With `rewrite`, the old sha of the
+/// entry following it becomes the new sha of the entry preceding it. With `update_ref`, the ref is pointed at the new sha
+/// of the latest remaining entry, and deleted along with its log when none
+/// remains.
+pub fn delete(
+    git_dir: &Path,
+    name: &str,
+    index: usize,
+    rewrite: bool,
+    update_ref: bool,
+) -> eyre::Result<()> {
+    let mut entries = read(git_dir, name)?;
+    let position = entries.len().checked_sub(index + 1).ok_or(eyre!(tr!(
+        "reflog entry %s@{%d} not found",
+        name,
+        index
+    )))?;
+    entries.remove(position);
+    if rewrite && position < entries.len() {
+        entries[position].old = match position {
+            0 => git::NULL_SHA.to_string(),
+            _ => entries[position - 1].new.clone(),
+        };
+    }
+
+    if update_ref && entries.is_empty() {
+        fs::remove_file(git_dir.join("logs").join(name))?;
+        return refs::delete_ref(git_dir, name);
+    }
+    write(git_dir, name, &entries)?;
+    if let Some(last) = entries.last().filter(|_| update_ref) {
+        if refs::read_ref(git_dir, name)?.as_deref() != Some(last.new.as_str()) {
+            refs::update_ref(git_dir, name, &last.new)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,529 @@
+use crate::commit::Signature;
+use crate::git::{self, Oid};
+use crate::hooks;
+use crate::measure;
+use crate::tempfile::Temporary;
+use eyre::eyre;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Returns true if `s` is a full hexadecimal sha-1.
+pub fn is_sha(s: &str) -> bool {
+    s.len() == 40 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Returns true if `name` is a valid ref name, following the rules of
+/// `git check-ref-format`: no component may start with `.` or end with
+/// `.lock`, and the name can't contain `..`, `@{`, `//`, control characters
+/// nor any of ` ~^:?*[\`, nor start or end with `/` or end with `.`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "@"
+        && !name.starts_with('/')
+        && !name.ends_with(['/', '.'])
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name.contains("//")
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+        && name
+            .split('/')
+            .all(|component| !component.starts_with('.') && !component.ends_with(".lock"))
+}
+
+/// Returns true if `name` can be the name of a ref of the repository: a
+/// valid name under `refs/`, or a pseudo-ref made of uppercase letters and
+/// `_` like `HEAD` or `FETCH_HEAD`. Other files of the git directory, like
+/// `config`, aren't refs.
+pub fn is_ref_name(name: &str) -> bool {
+    let pseudo = name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_');
+    (pseudo || name.starts_with("refs/")) && is_valid_name(name)
+}
+
+/// Returns the target of a symbolic ref of the repository at `git_dir`
+/// (e.g. `refs/heads/main` for `HEAD`), or `None` if the ref is missing or
+/// not symbolic.
+pub fn symbolic_target(git_dir: &Path, name: &str) -> eyre::Result<Option<String>> {
+    let path = git_dir.join(name);
+    if !is_ref_name(name) || !path.is_file() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path)?;
+    Ok(content
+        .trim_end()
+        .strip_prefix("ref: ")
+        .map(|target| target.to_string()))
+}
+
+/// Reads the sha a ref of the repository at `git_dir` points to, following
+/// symbolic refs and falling back to `packed-refs`. Returns `None` if the
+/// ref doesn't exist or `name` isn't the name of a ref, and fails if it
+/// doesn't hold a sha.
+pub fn read_ref(git_dir: &Path, name: &str) -> eyre::Result<Option<String>> {
+    if !is_ref_name(name) {
+        return Ok(None);
+    }
+    let path = git_dir.join(name);
+    if path.is_file() {
+        let content = fs::read_to_string(path)?;
+        if let Some(target) = content.trim_end().strip_prefix("ref: ") {
+            return read_ref(git_dir, target);
+        }
+
+        // Pseudo-refs like `FETCH_HEAD` can hold several lines with
+        // annotations after the sha, the first sha is the value of the ref
+        let Some(sha) = content.split_whitespace().next() else {
+            return Ok(None);
+        };
+        return match sha.parse::<Oid>() {
+            Ok(oid) => Ok(Some(oid.to_string())),
+            Err(_) => Err(eyre!(tr!("bad ref '%s': not a sha", name))),
+        };
+    }
+
+    packed_refs(git_dir)?
+        .into_iter()
+        .find(|(_, r)| r == name)
+        .map(|(sha, _)| Ok(sha.parse::<Oid>()?.to_string()))
+        .transpose()
+}
+
+/// Returns the `(name, sha)` pairs of the refs of the repository at
+/// `git_dir` whose name starts with `prefix` (e.g. `refs/heads/`), sorted by
+/// name.
+pub fn list_refs(git_dir: &Path, prefix: &str) -> eyre::Result<Vec<(String, String)>> {
+    let mut names = packed_refs(git_dir)?
+        .into_iter()
+        .map(|(_, name)| name)
+        .filter(|name| name.starts_with(prefix))
+        .collect::<Vec<_>>();
+
+    // Loose refs live in files under `refs/`
+    let mut dirs = vec![PathBuf::from("refs")];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(git_dir.join(&dir)) else {
+            continue;
+        };
+        for entry in entries {
+            let path = dir.join(entry?.file_name());
+            let name = path.to_string_lossy().replace('\\', "/");
+            if git_dir.join(&path).is_dir() {
+                dirs.push(path);
+            } else if name.starts_with(prefix) && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+
+    names.sort();
+    let mut refs = Vec::new();
+    for name in names {
+        match read_ref(git_dir, &name) {
+            Ok(Some(sha)) => refs.push((name, sha)),
+            Ok(None) => {}
+            // Like git, refs that don't hold a sha are left out
+            Err(_) => eprintln!("{}", tr!("warning: ignoring broken ref %s", name)),
+        }
+    }
+    Ok(refs)
+}
+
+/// Returns the `(sha, name)` pairs listed in the `packed-refs` file of the
+/// repository at `git_dir`.
+fn packed_refs(git_dir: &Path) -> eyre::Result<Vec<(String, String)>> {
+    let content = measure::phase("packed-refs", || {
+        fs::read_to_string(git_dir.join("packed-refs"))
+    });
+    let Ok(content) = content else {
+        return Ok(Vec::new());
+    };
+
+    // Lines are `SHA NAME`, with comments starting with `#` and peeled
+    // tag values starting with `^`
+    Ok(content
+        .lines()
+        .filter(|l| !l.starts_with('#') && !l.starts_with('^'))
+        .filter_map(|l| l.split_once(' '))
+        .map(|(sha, name)| (sha.to_string(), name.to_string()))
+        .collect())
+}
+
+/// Expands a short ref name of the repository at `git_dir` into the full
+/// ref name it refers to, using the same lookup order as git (`<name>`,
+/// `refs/<name>`, `refs/tags/<name>`, `refs/heads/<name>`,
+/// `refs/remotes/<name>`, `refs/remotes/<name>/HEAD`), the ones that
+/// aren't ref names per [`is_ref_name`] never matching.
+pub fn expand(git_dir: &Path, name: &str) -> eyre::Result<Option<String>> {
+    let candidates = [
+        name.to_string(),
+        format!("refs/{name}"),
+        format!("refs/tags/{name}"),
+        format!("refs/heads/{name}"),
+        format!("refs/remotes/{name}"),
+        format!("refs/remotes/{name}/HEAD"),
+    ];
+    for candidate in candidates {
+        if read_ref(git_dir, &candidate)?.is_some() {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the shortest name `expand` turns back into the full ref name
+/// `name`, like `main` for `refs/heads/main`, or `heads/main` when a tag is
+/// also named `main`.
+pub fn shorten(git_dir: &Path, name: &str) -> eyre::Result<String> {
+    let Some(without_refs) = name.strip_prefix("refs/") else {
+        return Ok(name.to_string());
+    };
+    let short = ["heads/", "tags/", "remotes/"]
+        .iter()
+        .find_map(|prefix| without_refs.strip_prefix(prefix));
+    for candidate in short.into_iter().chain([without_refs]) {
+        if expand(git_dir, candidate)?.as_deref() == Some(name) {
+            return Ok(candidate.to_string());
+        }
+    }
+    Ok(name.to_string())
+}
+
+/// Resolves a sha or ref name to the sha it points to.
+pub fn resolve(git_dir: &Path, name: &str) -> eyre::Result<String> {
+    if is_sha(name) {
+        return Ok(name.to_lowercase());
+    }
+    let full = expand(git_dir, name)?.ok_or(eyre!("unknown revision {name}"))?;
+    read_ref(git_dir, &full)?.ok_or(eyre!("unknown revision {name}"))
+}
+
+/// Points the ref at the given sha, creating it if needed. If the ref is
+/// symbolic (like `HEAD` on a branch), the ref it targets is updated instead.
+pub fn update_ref(git_dir: &Path, name: &str, sha: &str) -> eyre::Result<()> {
+    write_ref(git_dir, name, sha, None)
+}
+
+/// Points the ref at the given sha like `update_ref`, provided it still
+/// points to `old`, or doesn't exist if `old` is `None`. The ref is locked
+/// while it's checked and written, so that concurrent updates can't be lost.
+pub fn update_ref_from(
+    git_dir: &Path,
+    name: &str,
+    sha: &str,
+    old: Option<&str>,
+) -> eyre::Result<()> {
+    write_ref(git_dir, name, sha, Some(old))
+}
+
+/// Writes the ref through `<ref>.lock`, failing if its current value isn't
+/// `expected` when given.
+fn write_ref(
+    git_dir: &Path,
+    name: &str,
+    sha: &str,
+    expected: Option<Option<&str>>,
+) -> eyre::Result<()> {
+    if let Some(target) = symbolic_target(git_dir, name)? {
+        return write_ref(git_dir, &target, sha, expected);
+    }
+    let path = git_dir.join(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let lock = format!("{}.lock", path.display());
+    let (_temporary, mut file) = match Temporary::create(&lock) {
+        Ok(created) => created,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(eyre!(tr!(
+                "cannot lock ref '%s': Unable to create '%s': File exists.",
+                name,
+                lock
+            )));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let result = (|| {
+        let old = read_ref(git_dir, name)?;
+        match expected {
+            Some(Some(expected)) if old.as_deref() != Some(expected) => {
+                return Err(match &old {
+                    Some(old) => eyre!(tr!(
+                        "cannot lock ref '%s': is at %s but expected %s",
+                        name,
+                        old,
+                        expected
+                    )),
+                    None => eyre!(tr!(
+                        "cannot lock ref '%s': unable to resolve reference '%s'",
+                        name,
+                        name
+                    )),
+                });
+            }
+            Some(None) if old.is_some() => {
+                return Err(eyre!(tr!(
+                    "cannot lock ref '%s': reference already exists",
+                    name
+                )));
+            }
+            _ => {}
+        }
+        if name.starts_with("refs/") {
+            hooks::run(|hook| hook.ref_update(git_dir, name, old.as_deref(), Some(sha)))?;
+        }
+        file.write_all(format!("{sha}\n").as_bytes())?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => fs::rename(&lock, &path)?,
+        Err(_) => fs::remove_file(&lock)?,
+    }
+    result
+}
+
+/// Makes `name` a symbolic ref pointing to the ref `target`, which doesn't
+/// need to exist (e.g. `HEAD` on an unborn branch).
+pub fn set_symbolic_ref(git_dir: &Path, name: &str, target: &str) -> eyre::Result<()> {
+    fs::write(git_dir.join(name), format!("ref: {target}\n"))?;
+    Ok(())
+}
+
+/// Points `HEAD` directly at the commit `sha`, detaching it from the
+/// branch it was on.
+pub fn detach_head(git_dir: &Path, sha: &str) -> eyre::Result<()> {
+    fs::write(git_dir.join("HEAD"), format!("{sha}\n"))?;
+    Ok(())
+}
+
+/// Removes a ref or pseudo-ref if it exists, loose or packed.
+pub fn delete_ref(git_dir: &Path, name: &str) -> eyre::Result<()> {
+    let path = git_dir.join(name);
+    let packed = packed_refs(git_dir)?
+        .iter()
+        .any(|(_, packed)| packed == name);
+    if name.starts_with("refs/") && (path.is_file() || packed) {
+        let old = read_ref(git_dir, name)?;
+        hooks::run(|hook| hook.ref_update(git_dir, name, old.as_deref(), None))?;
+    }
+    if path.is_file() {
+        fs::remove_file(path)?;
+    }
+    if packed {
+        let content = fs::read_to_string(git_dir.join("packed-refs"))?;
+        fs::write(
+            git_dir.join("packed-refs"),
+            without_packed(&content, &[name]),
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the `packed-refs` file `content` without the refs `names`.
+fn without_packed(content: &str, names: &[&str]) -> String {
+    // The peeled value of a tag follows its line
+    let mut kept = String::new();
+    let mut skipping = false;
+    for line in content.lines() {
+        skipping = match line.strip_prefix('^') {
+            Some(_) => skipping,
+            None => line
+                .split_once(' ')
+                .is_some_and(|(_, r)| names.contains(&r)),
+        };
+        if !skipping {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    kept
+}
+
+/// Creates the lock file of the file at `path`, holding the ref `name`,
+/// failing if it's already locked.
+fn create_lock(path: &Path, name: &str) -> eyre::Result<Temporary> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let lock = PathBuf::from(format!("{}.lock", path.display()));
+    match Temporary::create(&lock) {
+        Ok((temporary, _)) => Ok(temporary),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(eyre!(tr!(
+            "cannot lock ref '%s': Unable to create '%s': File exists.",
+            name,
+            lock.display()
+        ))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Updates of refs of the repository at a git directory applied together:
+/// every ref is locked and checked before any is changed, so that either
+/// all the refs are updated or none is.
+pub struct Transaction {
+    git_dir: PathBuf,
+    /// The refs with the value they must have and the one they're set to,
+    /// `None` standing for a missing ref.
+    updates: Vec<(String, Option<String>, Option<String>)>,
+}
+
+impl Transaction {
+    pub fn new(git_dir: &Path) -> Self {
+        Self {
+            git_dir: git_dir.to_path_buf(),
+            updates: Vec::new(),
+        }
+    }
+
+    /// Adds the update of the ref `name` from `old` to `new`, `None`
+    /// meaning the ref doesn't exist, or is deleted. Symbolic refs update
+    /// the ref they point to.
+    pub fn update(&mut self, name: &str, old: Option<&str>, new: Option<&str>) {
+        self.updates.push((
+            name.to_string(),
+            old.map(str::to_string),
+            new.map(str::to_string),
+        ));
+    }
+
+    /// Applies the updates, failing without changing any ref if one of them
+    /// can't be locked, doesn't have the expected value or is refused by a
+    /// hook.
+    pub fn commit(self) -> eyre::Result<()> {
+        let mut locks = Vec::new();
+        let result = self
+            .lock(&mut locks)
+            .and_then(|()| self.run_hooks())
+            .and_then(|()| self.apply());
+        for lock in &locks {
+            let _ = fs::remove_file(lock.path());
+        }
+        result
+    }
+
+    /// Locks the refs, and `packed-refs` for deletions, adding the lock
+    /// files created to `locks`, and checks their values.
+    fn lock(&self, locks: &mut Vec<Temporary>) -> eyre::Result<()> {
+        if self.updates.iter().any(|(_, _, new)| new.is_none()) {
+            locks.push(create_lock(
+                &self.git_dir.join("packed-refs"),
+                "packed-refs",
+            )?);
+        }
+        for (name, old, _) in &self.updates {
+            let name = self.target(name)?;
+            locks.push(create_lock(&self.git_dir.join(&name), &name)?);
+            let current = read_ref(&self.git_dir, &name)?;
+            if current != *old {
+                return Err(match (current, old) {
+                    (Some(current), Some(old)) => eyre!(tr!(
+                        "cannot lock ref '%s': is at %s but expected %s",
+                        name,
+                        current,
+                        old
+                    )),
+                    (Some(_), None) => {
+                        eyre!(tr!("cannot lock ref '%s': reference already exists", name))
+                    }
+                    (None, _) => eyre!(tr!(
+                        "cannot lock ref '%s': unable to resolve reference '%s'",
+                        name,
+                        name
+                    )),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the `ref_update` hooks on the locked refs.
+    fn run_hooks(&self) -> eyre::Result<()> {
+        for (name, old, new) in &self.updates {
+            if name.starts_with("refs/") {
+                hooks::run(|hook| {
+                    hook.ref_update(&self.git_dir, name, old.as_deref(), new.as_deref())
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the new values of the locked refs, and removes the deleted
+    /// ones.
+    fn apply(&self) -> eyre::Result<()> {
+        let mut deleted = Vec::new();
+        for (name, _, new) in &self.updates {
+            let name = self.target(name)?;
+            let path = self.git_dir.join(&name);
+            match new {
+                Some(new) => {
+                    let lock = format!("{}.lock", path.display());
+                    fs::write(&lock, format!("{new}\n"))?;
+                    fs::rename(&lock, &path)?;
+                }
+                None => {
+                    if path.is_file() {
+                        fs::remove_file(&path)?;
+                    }
+                    deleted.push(name);
+                }
+            }
+        }
+        let path = self.git_dir.join("packed-refs");
+        if let (false, Ok(content)) = (deleted.is_empty(), fs::read_to_string(&path)) {
+            let names = deleted.iter().map(String::as_str).collect::<Vec<_>>();
+            let lock = self.git_dir.join("packed-refs.lock");
+            fs::write(&lock, without_packed(&content, &names))?;
+            fs::rename(&lock, &path)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the ref the symbolic ref `name` ends up pointing to, `name`
+    /// itself if it isn't symbolic.
+    fn target(&self, name: &str) -> eyre::Result<String> {
+        let mut name = name.to_string();
+        while let Some(target) = symbolic_target(&self.git_dir, &name)? {
+            name = target;
+        }
+        Ok(name)
+    }
+}
+
+/// Records the current value of `HEAD` in `ORIG_HEAD` before an operation
+/// moves it (reset, merge, rebase), so it can be undone with
+/// `reset --hard ORIG_HEAD`.
+pub fn record_orig_head(git_dir: &Path) -> eyre::Result<()> {
+    if let Some(head) = read_ref(git_dir, "HEAD")? {
+        update_ref(git_dir, "ORIG_HEAD", &head)?;
+    }
+    Ok(())
+}
+
+/// Appends an entry recording the update of a ref from `old` to `new` to the
+/// ref's log in `.git/logs/`.
+pub fn append_reflog(
+    git_dir: &Path,
+    name: &str,
+    old: Option<&str>,
+    new: &str,
+    who: &Signature,
+    message: &str,
+) -> eyre::Result<()> {
+    let path = git_dir.join("logs").join(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Entries are `OLD NEW IDENTITY\tMESSAGE`, with a null sha for new refs
+    let old = old.unwrap_or(git::NULL_SHA);
+    let entry = format!("{old} {new} {who}\t{message}\n");
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(entry.as_bytes())?;
+    Ok(())
+}
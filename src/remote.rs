@@ -0,0 +1,255 @@
+use crate::config::Config;
+use crate::fetch::{self, Refspec, Remote};
+use crate::git;
+use crate::refs;
+use crate::revision;
+use crate::revwalk::RevWalk;
+use crate::transport::{self, Refs};
+use crate::wildmatch;
+use std::path::Path;
+
+/// What `ls-remote` lists.
+#[derive(Debug, Default)]
+pub struct ListOptions {
+    /// Only lists the branches.
+    pub heads: bool,
+    /// Only lists the tags.
+    pub tags: bool,
+    /// Shows the ref each symbolic ref points to before it.
+    pub symref: bool,
+    /// Only lists the refs whose name ends with one of the patterns, as
+    /// whole components.
+    pub patterns: Vec<String>,
+}
+
+/// Lists the refs of the repository at `url` as `<sha>\t<name>` lines, as
+/// the server advertises them, followed by the objects annotated tags peel
+/// to. Servers speaking version 2 of the protocol are only asked for the
+/// branches or tags when that's all there is to list.
+pub fn list(config: &Config, url: &str, options: &ListOptions) -> eyre::Result<()> {
+    let mut prefixes = Vec::new();
+    if options.heads {
+        prefixes.push("refs/heads/".to_string());
+    }
+    if options.tags {
+        prefixes.push("refs/tags/".to_string());
+    }
+    let advertised = match transport::is_remote(url) {
+        true => transport::open(config, url)?.advertisement("git-upload-pack", &prefixes)?,
+        false => local_refs(url)?,
+    };
+
+    let matches = |name: &str| {
+        (prefixes.is_empty() || prefixes.iter().any(|prefix| name.starts_with(prefix)))
+            && (options.patterns.is_empty()
+                || options.patterns.iter().any(|pattern| {
+                    wildmatch::wildmatch(
+                        format!("*/{pattern}").as_bytes(),
+                        format!("/{name}").as_bytes(),
+                        false,
+                    )
+                }))
+    };
+    for (name, sha) in &advertised.refs {
+        if options.symref {
+            if let Some(target) = advertised.symref(name).filter(|_| matches(name)) {
+                println!("ref: {target}\t{name}");
+            }
+        }
+        if matches(name) {
+            println!("{sha}\t{name}");
+        }
+        let peeled = advertised.peeled.iter().find(|(tag, _)| tag == name);
+        if let Some((_, peeled)) = peeled.filter(|_| matches(&format!("{name}^{{}}"))) {
+            println!("{peeled}\t{name}^{{}}");
+        }
+    }
+    Ok(())
+}
+
+/// Returns the refs the repository at the local path `url` would advertise.
+fn local_refs(url: &str) -> eyre::Result<Refs> {
+    let git_dir = fetch::local_git_dir(url)?;
+    let mut advertised = Refs::default();
+    if let Some(target) = refs::symbolic_target(&git_dir, "HEAD")? {
+        advertised.symrefs.push(("HEAD".to_string(), target));
+    }
+    if let Some(sha) = refs::read_ref(&git_dir, "HEAD")? {
+        advertised.refs.push(("HEAD".to_string(), sha));
+    }
+    for (name, sha) in refs::list_refs(&git_dir, "refs/")? {
+        let peeled = revision::peel(&git_dir, &sha)?;
+        if peeled != sha {
+            advertised.peeled.push((name.clone(), peeled));
+        }
+        advertised.refs.push((name, sha));
+    }
+    Ok(advertised)
+}
+
+/// Deletes the remote-tracking refs of `remote` whose branch was deleted on
+/// the remote, or only lists them with `dry_run`.
+pub fn prune(git_dir: &Path, config: &Config, remote: &Remote, dry_run: bool) -> eyre::Result<()> {
+    let stale = fetch::stale_refs(git_dir, remote, &fetch::ls_remote(config, remote)?)?;
+    if stale.is_empty() {
+        return Ok(());
+    }
+
+    println!("{}", tr!("Pruning %s", &remote.name));
+    println!("URL: {}", remote.url);
+    for name in stale {
+        let display = name.strip_prefix("refs/remotes/").unwrap_or(&name);
+        if dry_run {
+            println!(" * [{}] {display}", tr!("would prune"));
+        } else {
+            refs::delete_ref(git_dir, &name)?;
+            println!(" * [{}] {display}", tr!("pruned"));
+        }
+    }
+    Ok(())
+}
+
+/// Describes `remote` from what it has now: its urls, the branch of its
+/// `HEAD`, whether its branches are tracked, new or stale, and the local
+/// branches pulling from it or pushing to it.
+pub fn show(git_dir: &Path, config: &Config, remote: &Remote) -> eyre::Result<()> {
+    let advertisement = fetch::ls_remote(config, remote)?;
+    println!("* remote {}", remote.name);
+    println!("  Fetch URL: {}", remote.url);
+    println!("  Push  URL: {}", remote.push_url);
+
+    let heads = advertisement
+        .head
+        .iter()
+        .map(|name| name.strip_prefix("refs/heads/").unwrap_or(name))
+        .collect::<Vec<_>>();
+    match heads[..] {
+        [] => println!("  HEAD branch: (unknown)"),
+        [head] => println!("  HEAD branch: {head}"),
+        _ => {
+            println!("  HEAD branch (remote HEAD is ambiguous, may be one of the following):");
+            heads.iter().for_each(|head| println!("    {head}"));
+        }
+    }
+
+    // Remote branches are tracked once fetched, stale ones being listed by
+    // their local name
+    let mut branches = Vec::new();
+    for (name, _) in &advertisement.refs {
+        let Some(branch) = name.strip_prefix("refs/heads/") else {
+            continue;
+        };
+        let Some(local) = remote.refspecs.iter().find_map(|r| r.map(name)) else {
+            continue;
+        };
+        let status = match refs::read_ref(git_dir, &local)? {
+            Some(_) => tr!("tracked"),
+            None => tr!("new (next fetch will store in remotes/%s)", &remote.name),
+        };
+        branches.push((branch.to_string(), status));
+    }
+    for name in fetch::stale_refs(git_dir, remote, &advertisement)? {
+        branches.push((name, tr!("stale (use 'git remote prune' to remove)")));
+    }
+    branches.sort();
+    print_section(&tr!("Remote branch:"), &tr!("Remote branches:"), &branches);
+
+    let local_branches = refs::list_refs(git_dir, "refs/heads/")?;
+    let mut pulls = Vec::new();
+    for (name, _) in &local_branches {
+        let branch = name.strip_prefix("refs/heads/").unwrap_or(name);
+        if config.get(&format!("branch.{branch}.remote")) != Some(&remote.name) {
+            continue;
+        }
+        let Some(merge) = config.get(&format!("branch.{branch}.merge")) else {
+            continue;
+        };
+        let merge = merge.strip_prefix("refs/heads/").unwrap_or(merge);
+        let rebase = config.get_bool(&format!("branch.{branch}.rebase"))? == Some(true);
+        pulls.push((branch.to_string(), merge.to_string(), rebase));
+    }
+    // Merges are aligned with the longer rebases
+    let any_rebase = pulls.iter().any(|(_, _, rebase)| *rebase);
+    let pulls = pulls
+        .into_iter()
+        .map(|(branch, merge, rebase)| {
+            let description = match (rebase, any_rebase) {
+                (true, _) => tr!("rebases onto remote %s", &merge),
+                (false, true) => format!(" {}", tr!("merges with remote %s", &merge)),
+                (false, false) => tr!("merges with remote %s", &merge),
+            };
+            (branch, description)
+        })
+        .collect::<Vec<_>>();
+    print_section(
+        &tr!("Local branch configured for 'git pull':"),
+        &tr!("Local branches configured for 'git pull':"),
+        &pulls,
+    );
+
+    // Without push refspecs, branches push to the remote branches of the
+    // same name
+    let configured = config.get_all(&format!("remote.{}.push", remote.name));
+    let matching = configured.is_empty();
+    let refspecs = match matching {
+        true => vec![Refspec::parse("refs/heads/*:refs/heads/*")],
+        false => configured.into_iter().map(Refspec::parse).collect(),
+    };
+    let mut walk = RevWalk::new();
+    let mut pushes = Vec::new();
+    for (name, sha) in &local_branches {
+        let Some(target) = refspecs.iter().find_map(|r| r.map(name)) else {
+            continue;
+        };
+        let remote_sha = advertisement
+            .refs
+            .iter()
+            .find(|(r, _)| *r == target)
+            .map(|(_, sha)| sha);
+        let status = match remote_sha {
+            None if matching => continue,
+            None => tr!("create"),
+            Some(remote_sha) if remote_sha == sha => tr!("up to date"),
+            Some(remote_sha)
+                if git::object_path(git_dir, &remote_sha.parse()?).exists()
+                    && walk.is_ancestor(git_dir, remote_sha, sha)? =>
+            {
+                tr!("fast-forwardable")
+            }
+            Some(_) => tr!("local out of date"),
+        };
+        let branch = name.strip_prefix("refs/heads/").unwrap_or(name);
+        let target = target.strip_prefix("refs/heads/").unwrap_or(&target);
+        pushes.push((branch.to_string(), target.to_string(), status));
+    }
+    let width = pushes.iter().map(|(_, t, _)| t.len()).max().unwrap_or(0);
+    let pushes = pushes
+        .into_iter()
+        .map(|(branch, target, status)| {
+            let description = tr!("pushes to %s", &format!("{target:width$}"));
+            (branch, format!("{description} ({status})"))
+        })
+        .collect::<Vec<_>>();
+    print_section(
+        &tr!("Local ref configured for 'git push':"),
+        &tr!("Local refs configured for 'git push':"),
+        &pushes,
+    );
+    Ok(())
+}
+
+/// Prints a section of `remote show`, its names aligned, under the title
+/// `one` or `many` depending on the number of lines. Empty sections are
+/// left out.
+fn print_section(one: &str, many: &str, lines: &[(String, String)]) {
+    let title = match lines.len() {
+        0 => return,
+        1 => one,
+        _ => many,
+    };
+    println!("  {title}");
+    let width = lines.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, description) in lines {
+        println!("    {name:width$} {description}");
+    }
+}
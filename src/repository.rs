@@ -0,0 +1,284 @@
+use crate::config::{self, Config};
+use crate::git::{self, Oid};
+use crate::init::{self, InitOptions};
+use crate::odb::Odb;
+use crate::refs;
+use eyre::eyre;
+use std::path::{Path, PathBuf};
+
+/// A repository found on disk: its git directory, and the working tree it
+/// belongs to unless it's bare.
+#[derive(Debug, Clone)]
+pub struct Repository {
+    pub git_dir: PathBuf,
+    pub work_tree: Option<PathBuf>,
+}
+
+impl Repository {
+    /// Opens the repository at `path`, either the top of its working tree
+    /// or its git directory, without looking in the parent directories.
+    pub fn open(path: &Path) -> eyre::Result<Self> {
+        let dot_git = path.join(".git");
+        let git_dir = match dot_git.is_file() {
+            true => read_gitfile(&dot_git)?,
+            false => Some(dot_git),
+        };
+        match git_dir {
+            Some(git_dir) if is_git_dir(&git_dir) => Ok(Self {
+                git_dir: git_dir.canonicalize()?,
+                work_tree: Some(path.canonicalize()?),
+            }),
+            _ if is_git_dir(path) => Ok(Self {
+                git_dir: path.canonicalize()?,
+                work_tree: None,
+            }),
+            _ => Err(eyre!(tr!("not a git repository: '%s'", path.display()))),
+        }
+    }
+
+    /// Creates an empty repository in the directory `path`, creating it if
+    /// needed, or opens it if it already exists.
+    pub fn init(path: &Path, options: &InitOptions) -> eyre::Result<Self> {
+        std::fs::create_dir_all(path)?;
+        let config = Config::load(&path.join(".git"))?;
+        init::init(path, &config, options)?;
+        Self::open(path)
+    }
+
+    /// Finds the repository containing `start`, looking for a `.git`
+    /// directory in it and its parents, or for one of them being a git
+    /// directory itself. The search stops before the directories of
+    /// `GIT_CEILING_DIRECTORIES`, and at the boundary of the filesystem of
+    /// `start` unless `GIT_DISCOVERY_ACROSS_FILESYSTEM` is set.
+    pub fn discover(start: &Path) -> eyre::Result<Self> {
+        let start = start.canonicalize()?;
+        let ceiling = ceiling_directory(&start);
+        let across_filesystems = match std::env::var("GIT_DISCOVERY_ACROSS_FILESYSTEM") {
+            Ok(value) => config::parse_bool(&value)?,
+            Err(_) => false,
+        };
+        let start_device = device(&start);
+        let mut previous = start.as_path();
+        for dir in start.ancestors() {
+            if ceiling
+                .as_ref()
+                .is_some_and(|ceiling| ceiling.starts_with(dir))
+            {
+                break;
+            }
+            if !across_filesystems && device(dir) != start_device {
+                return Err(eyre!(tr!(
+                    "not a git repository (or any parent up to mount point %s)\nStopping at filesystem boundary (GIT_DISCOVERY_ACROSS_FILESYSTEM not set).",
+                    previous.display()
+                )));
+            }
+            previous = dir;
+
+            let dot_git = dir.join(".git");
+            // A `.git` file points to a git directory elsewhere
+            if dot_git.is_file() {
+                if let Some(git_dir) = read_gitfile(&dot_git)?.filter(|d| is_git_dir(d)) {
+                    return Ok(Self {
+                        git_dir: git_dir.canonicalize()?,
+                        work_tree: Some(dir.to_path_buf()),
+                    });
+                }
+            }
+            if is_git_dir(&dot_git) {
+                return Ok(Self {
+                    git_dir: dot_git,
+                    work_tree: Some(dir.to_path_buf()),
+                });
+            }
+            if is_git_dir(dir) {
+                // The `.git` directory of a working tree isn't bare
+                let work_tree = match dir.file_name() {
+                    Some(name) if name == ".git" => dir.parent().map(Path::to_path_buf),
+                    _ => None,
+                };
+                return Ok(Self {
+                    git_dir: dir.to_path_buf(),
+                    work_tree,
+                });
+            }
+        }
+        Err(eyre!(tr!(
+            "not a git repository (or any of the parent directories): %s",
+            ".git"
+        )))
+    }
+
+    /// Returns true if the repository has no working tree.
+    pub fn is_bare(&self) -> bool {
+        self.work_tree.is_none()
+    }
+
+    /// Returns true if `path` is in the working tree, outside the git
+    /// directory.
+    pub fn is_inside_work_tree(&self, path: &Path) -> bool {
+        let Ok(path) = path.canonicalize() else {
+            return false;
+        };
+        self.work_tree.as_ref().is_some_and(|work_tree| {
+            path.starts_with(work_tree) && !path.starts_with(&self.git_dir)
+        })
+    }
+
+    /// Returns the git directory as git shows it from `cwd`: relative when
+    /// `cwd` is the git directory itself or the top of the working tree
+    /// holding it, absolute otherwise.
+    pub fn git_dir_from(&self, cwd: &Path) -> PathBuf {
+        let cwd = cwd.canonicalize().unwrap_or(cwd.to_path_buf());
+        if cwd == self.git_dir {
+            PathBuf::from(".")
+        } else if self.work_tree.as_ref() == Some(&cwd) && self.git_dir == cwd.join(".git") {
+            PathBuf::from(".git")
+        } else {
+            self.git_dir.clone()
+        }
+    }
+
+    /// Returns the path of `cwd` relative to the top of the working tree,
+    /// empty at the top or outside of the working tree. Commands run from
+    /// the top, this prefix locating the paths given to them.
+    pub fn prefix(&self, cwd: &Path) -> PathBuf {
+        let cwd = cwd.canonicalize().unwrap_or(cwd.to_path_buf());
+        self.work_tree
+            .as_ref()
+            .and_then(|work_tree| cwd.strip_prefix(work_tree).ok())
+            .filter(|_| self.is_inside_work_tree(&cwd))
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+
+    /// Returns the type and content of the object `sha`.
+    pub fn read_object(&self, sha: &str) -> eyre::Result<(String, Vec<u8>)> {
+        git::read_object(&self.git_dir, sha)
+    }
+
+    /// Stores an object of the type `kind` with the content `payload`, and
+    /// returns its sha.
+    pub fn write_object(&self, kind: &str, payload: &[u8]) -> eyre::Result<Oid> {
+        Odb::new(&self.git_dir).write(kind, payload)
+    }
+
+    /// Returns the commit `HEAD` points to, `None` on an unborn branch.
+    pub fn head(&self) -> eyre::Result<Option<String>> {
+        refs::read_ref(&self.git_dir, "HEAD")
+    }
+
+    /// Returns the sha the ref `name` points to, expanding short names
+    /// like `main` or `v1.0` the way git does, or `None` if there's no
+    /// such ref.
+    pub fn resolve_ref(&self, name: &str) -> eyre::Result<Option<String>> {
+        match refs::expand(&self.git_dir, name)? {
+            Some(full) => refs::read_ref(&self.git_dir, &full),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Resolves `path`, relative to the directory `prefix` of the working tree,
+/// into a path from the top of the working tree. Paths naming a directory,
+/// ending with `/`, `.` or `..`, keep a trailing `/`, and the top itself is
+/// the empty path.
+pub fn resolve_path(prefix: &Path, path: &str) -> eyre::Result<String> {
+    let mut components = prefix
+        .iter()
+        .map(|c| c.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                if components.pop().is_none() {
+                    return Err(eyre!(tr!("'%s' is outside repository", path)));
+                }
+            }
+            component => components.push(component.to_string()),
+        }
+    }
+
+    let mut resolved = components.join("/");
+    let directory = path.is_empty()
+        || path.ends_with('/')
+        || matches!(path.rsplit('/').next(), Some("." | ".."));
+    if directory && !resolved.is_empty() {
+        resolved.push('/');
+    }
+    Ok(resolved)
+}
+
+/// Resolves the paths of a pathspec given from the directory `prefix` of
+/// the working tree, like [`resolve_path`].
+pub fn resolve_pathspec(prefix: &Path, pathspec: &[PathBuf]) -> eyre::Result<Vec<PathBuf>> {
+    pathspec
+        .iter()
+        .map(|path| resolve_path(prefix, &path.to_string_lossy()).map(PathBuf::from))
+        .collect()
+}
+
+/// Returns `path`, from the top of the working tree, as seen from the
+/// directory `prefix`: `../` leads out of it, and `./` names it.
+pub fn relative_path(path: &str, prefix: &Path) -> String {
+    let prefix = prefix
+        .iter()
+        .map(|c| c.to_string_lossy())
+        .collect::<Vec<_>>();
+    let components = path.split('/').collect::<Vec<_>>();
+    let common = prefix
+        .iter()
+        .zip(&components)
+        .take_while(|(a, b)| a == *b)
+        .count();
+    let rest = components[common..].join("/");
+    match (prefix.len() - common, rest.is_empty()) {
+        (0, true) => "./".to_string(),
+        (up, _) => format!("{}{rest}", "../".repeat(up)),
+    }
+}
+
+/// Returns the git directory the `.git` file at `path` points to with a
+/// `gitdir: <path>` line, relative to the directory of the file, if it
+/// exists.
+pub fn read_gitfile(path: &Path) -> eyre::Result<Option<PathBuf>> {
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return Ok(None);
+    };
+    let target = content
+        .strip_prefix("gitdir: ")
+        .map(str::trim_end)
+        .ok_or(eyre!(tr!("invalid gitfile format: %s", path.display())))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    Ok(Some(dir.join(target)))
+}
+
+/// Returns the deepest directory of `GIT_CEILING_DIRECTORIES` above `start`,
+/// which the search for a repository doesn't get to. The list is separated
+/// by colons, and relative directories are ignored.
+fn ceiling_directory(start: &Path) -> Option<PathBuf> {
+    let directories = std::env::var_os("GIT_CEILING_DIRECTORIES")?;
+    std::env::split_paths(&directories)
+        .filter(|dir| dir.is_absolute())
+        .map(|dir| dir.canonicalize().unwrap_or(dir))
+        .filter(|dir| start.starts_with(dir) && start != dir)
+        .max_by_key(|dir| dir.components().count())
+}
+
+/// Returns the device of the filesystem holding `path`.
+#[cfg(unix)]
+fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    path.metadata().ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Returns true if `path` looks like a git directory: it has a `HEAD`, an
+/// object database and refs.
+fn is_git_dir(path: &Path) -> bool {
+    path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir()
+}
@@ -0,0 +1,102 @@
+use crate::commit::{Commit, Signature};
+use crate::config::Config;
+use crate::index::{Entry, Index};
+use crate::refs;
+use crate::revision;
+use crate::tree::{self, Files};
+use crate::worktree;
+use std::fs;
+use std::path::Path;
+
+/// What `reset` resets besides the branch.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Only the branch is moved.
+    Soft,
+    /// The index is reset to the commit too.
+    #[default]
+    Mixed,
+    /// The index and the working tree are reset to the commit.
+    Hard,
+}
+
+/// Moves `HEAD`, or the branch it's on, to the commit `revision` names,
+/// recording where it was in `ORIG_HEAD` and in the reflogs, and abandoning
+/// any merge in progress. Depending on `mode`, the index and the working
+/// tree are reset to the commit too.
+pub fn reset(git_dir: &Path, revision: &str, mode: Mode) -> eyre::Result<()> {
+    let target = revision::resolve(git_dir, revision)?;
+    let head = refs::read_ref(git_dir, "HEAD")?;
+
+    if mode != Mode::Soft {
+        let files = tree::flatten_tree(git_dir, &Commit::read(git_dir, &target)?.tree)?;
+        if mode == Mode::Hard {
+            // Every file is written again, local changes or not, and the
+            // tracked files the commit doesn't have are removed
+            let mut removed = Index::read(git_dir)?.files();
+            if let Some(head) = &head {
+                removed.extend(tree::flatten_tree(
+                    git_dir,
+                    &Commit::read(git_dir, head)?.tree,
+                )?);
+            }
+            removed.retain(|path, _| !files.contains_key(path));
+            worktree::checkout_files(git_dir, &removed, &files, None)?;
+        }
+        reset_index(git_dir, &files, mode == Mode::Hard)?;
+    }
+
+    refs::record_orig_head(git_dir)?;
+    refs::update_ref(git_dir, "HEAD", &target)?;
+    refs::delete_ref(git_dir, "MERGE_HEAD")?;
+
+    let who = Signature::now(&Config::load(git_dir)?, "COMMITTER")?;
+    let message = format!("reset: moving to {revision}");
+    let branch = refs::symbolic_target(git_dir, "HEAD")?;
+    for name in branch.iter().map(String::as_str).chain(["HEAD"]) {
+        refs::append_reflog(git_dir, name, head.as_deref(), &target, &who, &message)?;
+    }
+
+    if mode == Mode::Hard {
+        println!("{}", tr!("HEAD is now at %s", &target[..7]));
+    }
+    Ok(())
+}
+
+/// Stages exactly the `files` of the commit, resolving any conflict. The
+/// entries already staged as in the commit keep their stat information;
+/// after a hard reset, the others get the stat information of the files
+/// just written.
+fn reset_index(git_dir: &Path, files: &Files, written: bool) -> eyre::Result<()> {
+    let mut index = Index::read(git_dir)?;
+    let stale = index
+        .entries
+        .iter()
+        .filter(|e| e.stage() != 0 || !files.contains_key(Path::new(&e.path)))
+        .map(|e| e.path.clone())
+        .collect::<Vec<_>>();
+    for path in stale {
+        index.remove(&path);
+    }
+
+    for (path, (mode, sha)) in files {
+        let name = path.to_string_lossy().replace('\\', "/");
+        let staged = index
+            .get(&name)
+            .is_some_and(|e| e.mode == *mode && e.sha == *sha);
+        if staged && !written {
+            continue;
+        }
+        let entry = match fs::symlink_metadata(path) {
+            Ok(metadata) if written => Entry::new(name, sha.clone(), *mode, &metadata),
+            _ => Entry {
+                mode: *mode,
+                sha: sha.clone(),
+                path: name,
+                ..Entry::default()
+            },
+        };
+        index.add(entry);
+    }
+    index.write(git_dir)
+}
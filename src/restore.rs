@@ -0,0 +1,255 @@
+use crate::commit::Commit;
+use crate::diff;
+use crate::git;
+use crate::index::{Entry, Index};
+use crate::interactive::{self, HunkSelector, Split};
+use crate::odb::Odb;
+use crate::refs;
+use crate::revision;
+use crate::tree::{self, Files};
+use crate::worktree;
+use eyre::eyre;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What `restore` restores, and from where.
+#[derive(Debug, Default)]
+pub struct RestoreOptions {
+    /// The commit to restore the files from. Without it, the working tree is
+    /// restored from the index, and the index from `HEAD`.
+    pub source: Option<String>,
+    /// Restores the index.
+    pub staged: bool,
+    /// Restores the working tree, the default without `staged`.
+    pub worktree: bool,
+    /// Asks which hunks of the changes to restore.
+    pub patch: bool,
+}
+
+/// Restores the files matched by `pathspec` as `options` say.
+///
+/// Restoring from the index or `HEAD` discards local changes, so the hunks
+/// are shown as changes from there to the working tree or the index.
+/// Restoring from another commit applies its content, shown as changes
+/// from the working tree or the index to it.
+pub fn restore(git_dir: &Path, pathspec: &[PathBuf], options: &RestoreOptions) -> eyre::Result<()> {
+    if pathspec.is_empty() && !options.patch {
+        return Err(eyre!(tr!("you must specify path(s) to restore")));
+    }
+    let head = refs::read_ref(git_dir, "HEAD")?;
+    let source = options
+        .source
+        .as_deref()
+        .map(|name| revision::resolve(git_dir, name))
+        .transpose()?;
+    let discard = source.is_none() || source == head;
+    let from = match (&source, &head) {
+        (Some(sha), _) => tree::flatten_tree(git_dir, &tree::peel_to_tree(git_dir, sha)?)?,
+        (None, Some(head)) => tree::flatten_tree(git_dir, &Commit::read(git_dir, head)?.tree)?,
+        (None, None) => Files::new(),
+    };
+
+    let mut index = Index::read(git_dir)?;
+    match (options.staged, options.worktree) {
+        // The index gets the files the working tree was restored to
+        (true, true) => {
+            let result =
+                restore_worktree(git_dir, &index, &from, pathspec, options.patch, discard)?;
+            stage(&mut index, &result, pathspec);
+        }
+        (true, false) => {
+            restore_index(git_dir, &mut index, &from, pathspec, options.patch, discard)?
+        }
+        (false, _) => {
+            let from = match source {
+                Some(_) => from,
+                None => index.files(),
+            };
+            restore_worktree(git_dir, &index, &from, pathspec, options.patch, discard)?;
+            return Ok(());
+        }
+    }
+    index.write(git_dir)
+}
+
+/// Recreates the conflicts of the files matched by `pathspec`, like
+/// `checkout -m` does: the conflicts that were resolved are taken back from
+/// the resolve-undo extension, and the conflicted files are written with
+/// conflict markers between our and their version, discarding their
+/// resolution.
+pub fn checkout_merge(git_dir: &Path, pathspec: &[PathBuf]) -> eyre::Result<()> {
+    let mut index = Index::read(git_dir)?;
+    let known = index
+        .entries
+        .iter()
+        .map(|e| PathBuf::from(&e.path))
+        .collect::<Vec<_>>();
+    tree::check_pathspec(pathspec, known.iter())?;
+    let resolved = index
+        .resolve_undo
+        .keys()
+        .filter(|path| tree::pathspec_matches(pathspec, Path::new(path)))
+        .cloned()
+        .collect::<Vec<_>>();
+    for path in resolved {
+        index.unresolve(&path);
+    }
+
+    let conflicted = index
+        .entries
+        .iter()
+        .filter(|e| e.stage() > 0 && tree::pathspec_matches(pathspec, Path::new(&e.path)))
+        .map(|e| e.path.clone())
+        .collect::<BTreeSet<_>>();
+    let mut merged = Files::new();
+    for path in conflicted {
+        let (Some(ours), Some(theirs)) = (index.get_stage(&path, 2), index.get_stage(&path, 3))
+        else {
+            return Err(eyre!(tr!(
+                "path '%s' does not have necessary versions",
+                path
+            )));
+        };
+        let base = match index.get_stage(&path, 1) {
+            Some(entry) => git::read_blob(git_dir, &entry.sha)?,
+            None => Vec::new(),
+        };
+        let merge = diff::merge3(
+            &base,
+            &git::read_blob(git_dir, &ours.sha)?,
+            &git::read_blob(git_dir, &theirs.sha)?,
+            ("ours", "theirs"),
+        );
+        let sha = Odb::new(git_dir).write("blob", &merge.content)?;
+        merged.insert(PathBuf::from(path), (ours.mode, sha.into()));
+    }
+    worktree::checkout_files(git_dir, &Files::new(), &merged, None)?;
+    index.write(git_dir)
+}
+
+/// Stages the files of `result` matched by `pathspec`, unstaging the ones
+/// missing from it.
+fn stage(index: &mut Index, result: &Files, pathspec: &[PathBuf]) {
+    for path in result.keys().chain(index.files().keys()) {
+        if !tree::pathspec_matches(pathspec, path) {
+            continue;
+        }
+        let name = path.to_string_lossy().replace('\\', "/");
+        let Some((mode, sha)) = result.get(path).cloned() else {
+            index.remove(&name);
+            continue;
+        };
+        let entry = match fs::symlink_metadata(path) {
+            Ok(metadata) => Entry::new(name, sha, mode, &metadata),
+            Err(_) => Entry {
+                mode,
+                sha,
+                path: name,
+                ..Entry::default()
+            },
+        };
+        index.add(entry);
+    }
+}
+
+/// Restores the entries of `index` matched by `pathspec` to the `from`
+/// files, asking which hunks to restore with `patch`.
+fn restore_index(
+    git_dir: &Path,
+    index: &mut Index,
+    from: &Files,
+    pathspec: &[PathBuf],
+    patch: bool,
+    discard: bool,
+) -> eyre::Result<()> {
+    let staged = index.files();
+    tree::check_pathspec(pathspec, from.keys().chain(staged.keys()))?;
+
+    let (old, new, mode) = match discard {
+        true => (from, &staged, &interactive::UNSTAGE),
+        false => (&staged, from, &interactive::APPLY_TO_INDEX),
+    };
+    let mut selector = HunkSelector::new(mode);
+    for change in tree::diff_files(old, new) {
+        if !tree::pathspec_matches(pathspec, &change.path) {
+            continue;
+        }
+        let split = match patch {
+            true => selector.split(git_dir, &change)?,
+            false => Split {
+                selected: change.new.clone(),
+                rest: change.old.clone(),
+            },
+        };
+        let file = match discard {
+            true => split.rest,
+            false => split.selected,
+        };
+        // The restored entries have no stat information, so that they're
+        // compared with the working tree by content
+        let path = change.path.to_string_lossy().replace('\\', "/");
+        match file {
+            Some((mode, sha)) => index.add(Entry {
+                mode,
+                sha,
+                path,
+                ..Entry::default()
+            }),
+            None => index.remove(&path),
+        }
+    }
+    Ok(())
+}
+
+/// Restores the files of the working tree matched by `pathspec` to the
+/// `restored` ones, asking which hunks to restore with `patch`, and returns
+/// the files of the working tree afterwards.
+fn restore_worktree(
+    git_dir: &Path,
+    index: &Index,
+    restored: &Files,
+    pathspec: &[PathBuf],
+    patch: bool,
+    discard: bool,
+) -> eyre::Result<Files> {
+    let mut known = index.files();
+    known.extend(restored.clone());
+    let current = worktree::snapshot(git_dir, &known)?;
+    tree::check_pathspec(pathspec, restored.keys().chain(current.keys()))?;
+    worktree::write_blobs(
+        git_dir,
+        current
+            .iter()
+            .filter(|(path, entry)| known.get(*path) != Some(entry)),
+    )?;
+
+    let (old, new, mode) = match discard {
+        true => (restored, &current, &interactive::DISCARD),
+        false => (&current, restored, &interactive::APPLY),
+    };
+    let mut selector = HunkSelector::new(mode);
+    let mut result = current.clone();
+    for change in tree::diff_files(old, new) {
+        if !tree::pathspec_matches(pathspec, &change.path) {
+            continue;
+        }
+        let split = match patch {
+            true => selector.split(git_dir, &change)?,
+            false => Split {
+                selected: change.new.clone(),
+                rest: change.old.clone(),
+            },
+        };
+        let file = match discard {
+            true => split.rest,
+            false => split.selected,
+        };
+        match file {
+            Some(file) => result.insert(change.path, file),
+            None => result.remove(&change.path),
+        };
+    }
+    worktree::checkout_files(git_dir, &current, &result, None)?;
+    Ok(result)
+}
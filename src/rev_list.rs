@@ -0,0 +1,67 @@
+use crate::revision;
+use crate::revwalk::{RevWalk, Side};
+use eyre::eyre;
+use std::path::Path;
+
+/// What `rev_list` prints.
+#[derive(Debug, Default)]
+pub struct RevListOptions {
+    /// Marks the commits of a symmetric range with the side they're
+    /// reachable from, `<` or `>`, or counts each side separately.
+    pub left_right: bool,
+    /// Prints the number of commits instead of the commits.
+    pub count: bool,
+}
+
+/// Prints the commits reachable from `revisions`, newest first. `^A` and
+/// the left side of `A..B` exclude the commits reachable from `A`, and a
+/// symmetric range `A...B` lists the commits reachable from either side
+/// but not both.
+pub fn rev_list(
+    git_dir: &Path,
+    revisions: &[String],
+    options: &RevListOptions,
+) -> eyre::Result<()> {
+    let mut walk = RevWalk::new();
+
+    if let Some((left, right)) = revisions.iter().find_map(|r| r.split_once("...")) {
+        if revisions.len() > 1 {
+            return Err(eyre!(tr!(
+                "a symmetric range can't be combined with other revisions"
+            )));
+        }
+        let left = revision::resolve(git_dir, if left.is_empty() { "HEAD" } else { left })?;
+        let right = revision::resolve(git_dir, if right.is_empty() { "HEAD" } else { right })?;
+
+        if options.count {
+            if options.left_right {
+                let (ahead, behind) = walk.ahead_behind(git_dir, &left, &right)?;
+                println!("{ahead}\t{behind}");
+            } else {
+                println!(
+                    "{}",
+                    walk.symmetric_difference(git_dir, &left, &right)?.len()
+                );
+            }
+            return Ok(());
+        }
+
+        for (side, sha) in walk.symmetric_difference(git_dir, &left, &right)? {
+            match (options.left_right, side) {
+                (true, Side::Left) => println!("<{sha}"),
+                (true, Side::Right) => println!(">{sha}"),
+                (false, _) => println!("{sha}"),
+            }
+        }
+        return Ok(());
+    }
+
+    let (include, exclude) = revision::resolve_ranges(git_dir, revisions)?;
+    let commits = walk.list(git_dir, &include, &exclude)?;
+    if options.count {
+        println!("{}", commits.len());
+    } else {
+        commits.iter().for_each(|sha| println!("{sha}"));
+    }
+    Ok(())
+}
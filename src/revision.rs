@@ -0,0 +1,234 @@
+use crate::commit::Commit;
+use crate::git;
+use crate::index::Index;
+use crate::refs;
+use crate::repository::Repository;
+use crate::revwalk::RevWalk;
+use crate::tag::Tag;
+use crate::tree;
+use eyre::eyre;
+use std::path::Path;
+
+/// Resolves a revision to a sha. Accepts shas, ref names, pseudo-refs like
+/// `ORIG_HEAD` or `FETCH_HEAD`, `@` as a shorthand for `HEAD`, and any chain of
+/// `~<n>` (n-th first-parent ancestor) and `^<n>` (n-th parent) suffixes.
+/// `<rev>:<path>` names the blob or tree at the path, from the top of the
+/// tree of the revision, and `:<path>` or `:<n>:<path>` the blob staged at
+/// the path, at stage 0 or `n`.
+pub fn resolve(git_dir: &Path, spec: &str) -> eyre::Result<String> {
+    if let Some(staged) = spec.strip_prefix(':') {
+        let (stage, path) = match staged.split_once(':') {
+            Some((n @ ("0" | "1" | "2" | "3"), path)) => (n.parse()?, path),
+            _ => (0, staged),
+        };
+        return resolve_staged(git_dir, path, stage);
+    }
+    if let Some((revision, path)) = spec.split_once(':').filter(|(r, _)| !r.is_empty()) {
+        let tree = tree::peel_to_tree(git_dir, &resolve(git_dir, revision)?)?;
+        return match tree::entry_at(git_dir, &tree, Path::new(path))? {
+            Some((_, sha)) => Ok(sha),
+            None => Err(eyre!(tr!(
+                "path '%s' does not exist in '%s'",
+                path,
+                revision
+            ))),
+        };
+    }
+
+    let end = spec.find(['~', '^']).unwrap_or(spec.len());
+    let (base, mut suffix) = spec.split_at(end);
+    let base = if base.is_empty() || base == "@" {
+        "HEAD"
+    } else {
+        base
+    };
+    let mut sha = refs::resolve(git_dir, base)?;
+
+    while let Some(op) = suffix.chars().next() {
+        suffix = &suffix[1..];
+        let digits = suffix.bytes().take_while(u8::is_ascii_digit).count();
+        let n = match digits {
+            0 => 1,
+            _ => suffix[..digits].parse::<usize>()?,
+        };
+        suffix = &suffix[digits..];
+
+        match op {
+            '~' => {
+                for _ in 0..n {
+                    sha = Commit::read(git_dir, &sha)?
+                        .parents
+                        .into_iter()
+                        .next()
+                        .ok_or(eyre!("revision {spec} goes past a root commit"))?;
+                }
+            }
+            // `^0` is the commit itself
+            '^' if n == 0 => {}
+            '^' => {
+                sha = Commit::read(git_dir, &sha)?
+                    .parents
+                    .into_iter()
+                    .nth(n - 1)
+                    .ok_or(eyre!("revision {spec} has no parent {n}"))?;
+            }
+            _ => return Err(eyre!("invalid revision {spec}")),
+        }
+    }
+
+    Ok(sha)
+}
+
+/// Returns the sha of the blob staged at `path` at `stage`.
+fn resolve_staged(git_dir: &Path, path: &str, stage: u16) -> eyre::Result<String> {
+    let index = Index::read(git_dir)?;
+    if let Some(entry) = index.get_stage(path, stage) {
+        return Ok(entry.sha.clone());
+    }
+    let staged = index.entries.iter().any(|e| e.path == path);
+    Err(eyre!(
+        match (staged, Path::new(path).symlink_metadata().is_ok()) {
+            (true, _) => tr!(
+                "path '%s' is in the index, but not at stage %d",
+                path,
+                stage
+            ),
+            (false, true) => tr!("path '%s' exists on disk, but not in the index", path),
+            (false, false) => tr!(
+                "path '%s' does not exist (neither on disk nor in the index)",
+                path
+            ),
+        }
+    ))
+}
+
+/// Peels the tag objects of the repository at `git_dir`, returning the sha
+/// of the object the chain of tags starting at `sha` ends at. Other objects
+/// are returned as they are.
+pub fn peel(git_dir: &Path, sha: &str) -> eyre::Result<String> {
+    let mut sha = sha.to_string();
+    loop {
+        let (kind, content) = git::read_object(git_dir, &sha)?;
+        if kind != "tag" {
+            return Ok(sha);
+        }
+        sha = Tag::parse(&content)
+            .map_err(|_| eyre!("invalid tag object {sha}"))?
+            .object;
+    }
+}
+
+/// Returns the full name of the ref a revision names, like
+/// `refs/heads/main` for `main` or for `HEAD` on that branch. Revisions
+/// that aren't refs, like shas or `HEAD~2`, have none.
+pub fn symbolic_full_name(git_dir: &Path, spec: &str) -> eyre::Result<Option<String>> {
+    if spec.contains(['~', '^']) || refs::is_sha(spec) {
+        return Ok(None);
+    }
+    let name = match spec {
+        "" | "@" => "HEAD",
+        spec => spec,
+    };
+    if name == "HEAD" {
+        return Ok(Some(
+            refs::symbolic_target(git_dir, "HEAD")?.unwrap_or(name.to_string()),
+        ));
+    }
+    match refs::expand(git_dir, name)? {
+        Some(full) => Ok(Some(full)),
+        None => Err(eyre!("unknown revision {spec}")),
+    }
+}
+
+/// Splits revisions into the commits to walk from and the commits whose
+/// history is excluded: `^A` and the left side of `A..B` exclude `A`, and
+/// an empty side of `A..B` is `HEAD`.
+pub fn resolve_ranges(
+    git_dir: &Path,
+    revisions: &[String],
+) -> eyre::Result<(Vec<String>, Vec<String>)> {
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+    for revision in revisions {
+        if let Some((from, to)) = revision.split_once("..") {
+            exclude.push(resolve(
+                git_dir,
+                if from.is_empty() { "HEAD" } else { from },
+            )?);
+            include.push(resolve(git_dir, if to.is_empty() { "HEAD" } else { to })?);
+        } else if let Some(revision) = revision.strip_prefix('^') {
+            exclude.push(resolve(git_dir, revision)?);
+        } else {
+            include.push(resolve(git_dir, revision)?);
+        }
+    }
+    Ok((include, exclude))
+}
+
+/// Resolves the objects named by `names`, in order. With ranges like `A..B`
+/// or exclusions like `^A`, returns the commits they select instead, newest
+/// first, walked with `walk`. Like git, the other objects are then left out.
+pub fn resolve_objects(
+    git_dir: &Path,
+    walk: &mut RevWalk,
+    names: &[String],
+) -> eyre::Result<Vec<String>> {
+    if names.iter().any(|n| n.contains("..") || n.starts_with('^')) {
+        let (include, exclude) = resolve_ranges(git_dir, names)?;
+        let mut commits = Vec::new();
+        for sha in include {
+            if git::read_object(git_dir, &sha)?.0 == "commit" {
+                commits.push(sha);
+            }
+        }
+        return walk.list(git_dir, &commits, &exclude);
+    }
+    names.iter().map(|name| resolve(git_dir, name)).collect()
+}
+
+/// Prints what each of `args` asks for, like `git rev-parse` run from `cwd`
+/// in `repository`: the sha of revisions, or with options the full or short
+/// name of the ref revisions name, or where the repository and the current
+/// directory are.
+pub fn rev_parse(repository: &Repository, cwd: &Path, args: &[String]) -> eyre::Result<()> {
+    let git_dir = &repository.git_dir;
+    let prefix = repository.prefix(cwd);
+    let mut names = None;
+    for arg in args {
+        match arg.as_str() {
+            "--git-dir" => println!("{}", repository.git_dir_from(cwd).display()),
+            "--show-toplevel" => match &repository.work_tree {
+                Some(work_tree) if repository.is_inside_work_tree(cwd) => {
+                    println!("{}", work_tree.display())
+                }
+                _ => return Err(eyre!(tr!("this operation must be run in a work tree"))),
+            },
+            "--is-inside-work-tree" => println!("{}", repository.is_inside_work_tree(cwd)),
+            "--is-bare-repository" => println!("{}", repository.is_bare()),
+            "--show-prefix" => match prefix.as_os_str().is_empty() {
+                true => println!(),
+                false => println!("{}/", prefix.display()),
+            },
+            // Outside of the working tree there's no way up to its top
+            "--show-cdup" => {
+                if repository.is_inside_work_tree(cwd) {
+                    println!("{}", "../".repeat(prefix.iter().count()))
+                }
+            }
+            "--abbrev-ref" | "--symbolic-full-name" => names = Some(arg.as_str()),
+            revision => match names {
+                Some(mode) => {
+                    let Some(name) = symbolic_full_name(git_dir, revision)? else {
+                        continue;
+                    };
+                    match mode {
+                        "--abbrev-ref" => println!("{}", refs::shorten(git_dir, &name)?),
+                        _ => println!("{name}"),
+                    }
+                }
+                None => println!("{}", resolve(git_dir, revision)?),
+            },
+        }
+    }
+    Ok(())
+}
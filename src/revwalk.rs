@@ -0,0 +1,372 @@
+use crate::commit::Commit;
+use crate::commit_graph::CommitGraph;
+use crate::shallow;
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+use std::path::Path;
+
+/// Flag painted on commits reachable from the left side of a walk.
+const LEFT: u8 = 1;
+/// Flag painted on commits reachable from the right side of a walk.
+const RIGHT: u8 = 2;
+/// Flag painted on ancestors of common ancestors found during a walk.
+const STALE: u8 = 4;
+
+/// The side of a symmetric difference a commit is reachable from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// Walks commit history, caching the commits it has parsed so several walks
+/// over the same history only read each object once.
+#[derive(Default)]
+pub struct RevWalk {
+    commits: HashMap<String, Commit>,
+    /// The commits of a shallow repository whose parents are missing, read
+    /// with the first commit.
+    shallow: Option<BTreeSet<String>>,
+}
+
+impl RevWalk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the parsed commit with the given sha. The commits at the
+    /// boundary of a shallow repository have no parents.
+    pub fn commit(&mut self, git_dir: &Path, sha: &str) -> eyre::Result<&Commit> {
+        if !self.commits.contains_key(sha) {
+            let mut commit = Commit::read(git_dir, sha)?;
+            let shallow = self
+                .shallow
+                .get_or_insert_with(|| shallow::read(git_dir).unwrap_or_default());
+            if shallow.contains(sha) {
+                commit.parents.clear();
+            }
+            self.commits.insert(sha.to_string(), commit);
+        }
+        Ok(&self.commits[sha])
+    }
+
+    /// Returns the commits reachable from `include` but not from `exclude`,
+    /// newest first.
+    pub fn list(
+        &mut self,
+        git_dir: &Path,
+        include: &[String],
+        exclude: &[String],
+    ) -> eyre::Result<Vec<String>> {
+        let tips = include
+            .iter()
+            .map(|sha| (sha.clone(), LEFT))
+            .chain(exclude.iter().map(|sha| (sha.clone(), RIGHT)))
+            .collect::<Vec<_>>();
+
+        // Anything reachable from an excluded commit is uninteresting
+        let flags = self.paint(git_dir, &tips, |flags| flags & RIGHT != 0)?;
+        self.sorted(
+            git_dir,
+            flags
+                .into_iter()
+                .filter(|(_, f)| *f == LEFT)
+                .map(|(sha, _)| sha),
+        )
+    }
+
+    /// Returns the commits reachable from exactly one of `left` and `right`,
+    /// newest first, tagged with the side they are reachable from.
+    pub fn symmetric_difference(
+        &mut self,
+        git_dir: &Path,
+        left: &str,
+        right: &str,
+    ) -> eyre::Result<Vec<(Side, String)>> {
+        let tips = [(left.to_string(), LEFT), (right.to_string(), RIGHT)];
+
+        // Commits reachable from both sides can't contribute to the result
+        let flags = self.paint(git_dir, &tips, |flags| flags == LEFT | RIGHT)?;
+        let sides = flags
+            .into_iter()
+            .filter_map(|(sha, f)| match f {
+                LEFT => Some((sha, Side::Left)),
+                RIGHT => Some((sha, Side::Right)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+
+        let sorted = self.sorted(git_dir, sides.keys().cloned())?;
+        Ok(sorted.into_iter().map(|sha| (sides[&sha], sha)).collect())
+    }
+
+    /// Returns how many commits `local` has that `upstream` doesn't (ahead)
+    /// and how many `upstream` has that `local` doesn't (behind).
+    pub fn ahead_behind(
+        &mut self,
+        git_dir: &Path,
+        local: &str,
+        upstream: &str,
+    ) -> eyre::Result<(usize, usize)> {
+        let difference = self.symmetric_difference(git_dir, local, upstream)?;
+        let ahead = difference.iter().filter(|(s, _)| *s == Side::Left).count();
+        Ok((ahead, difference.len() - ahead))
+    }
+
+    /// Returns the best common ancestors of `a` and `b`, i.e. the commits
+    /// reachable from both that aren't ancestors of another such commit.
+    pub fn merge_bases(&mut self, git_dir: &Path, a: &str, b: &str) -> eyre::Result<Vec<String>> {
+        if a == b {
+            return Ok(vec![a.to_string()]);
+        }
+
+        let mut flags: HashMap<String, u8> = HashMap::from([(a.to_string(), LEFT)]);
+        *flags.entry(b.to_string()).or_default() |= RIGHT;
+        let mut queue = Queue::default();
+        let mut results = Vec::new();
+        for sha in [a, b] {
+            let time = self.commit(git_dir, sha)?.committer.time;
+            queue.push(time, sha.to_string(), false);
+        }
+
+        while let Some(sha) = queue.pop(|sha| flags[sha] & STALE != 0) {
+            // Commits reachable from both sides are common ancestors, their
+            // own ancestors can't be the best ones
+            let mut current = flags[&sha];
+            if current & (LEFT | RIGHT) == LEFT | RIGHT && current & STALE == 0 {
+                if !results.contains(&sha) {
+                    results.push(sha.clone());
+                }
+                current |= STALE;
+            }
+
+            let parents = self.commit(git_dir, &sha)?.parents.clone();
+            for parent in parents {
+                let entry = flags.entry(parent.clone()).or_default();
+                if *entry | current != *entry {
+                    if *entry & STALE == 0 && current & STALE != 0 {
+                        queue.stale(&parent);
+                    }
+                    *entry |= current;
+                    let time = self.commit(git_dir, &parent)?.committer.time;
+                    queue.push(time, parent, *entry & STALE != 0);
+                }
+            }
+        }
+
+        // Drop the results found to be ancestors of another result
+        let mut bases = results
+            .into_iter()
+            .filter(|sha| flags[sha] & STALE == 0)
+            .collect::<Vec<_>>();
+        if bases.len() > 1 {
+            let candidates = bases.clone();
+            for candidate in &candidates {
+                let others = candidates
+                    .iter()
+                    .filter(|c| *c != candidate)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if self.list(git_dir, &others, &[])?.contains(candidate) {
+                    bases.retain(|b| b != candidate);
+                }
+            }
+        }
+        Ok(bases)
+    }
+
+    /// Returns true if `ancestor` is reachable from `descendant`.
+    pub fn is_ancestor(
+        &mut self,
+        git_dir: &Path,
+        ancestor: &str,
+        descendant: &str,
+    ) -> eyre::Result<bool> {
+        Ok(self
+            .merge_bases(git_dir, ancestor, descendant)?
+            .iter()
+            .any(|b| b == ancestor))
+    }
+
+    /// Returns, for each of `tips`, whether `target` is reachable from it.
+    /// Answers are shared between tips, and with a commit-graph the walk
+    /// skips the commits whose generation isn't above the one of `target`,
+    /// which can't reach it.
+    pub fn contains(
+        &mut self,
+        git_dir: &Path,
+        tips: &[String],
+        target: &str,
+    ) -> eyre::Result<Vec<bool>> {
+        let graph = CommitGraph::load(git_dir)?;
+        let generation = |sha: &str| -> eyre::Result<Option<u32>> {
+            let Some(graph) = &graph else {
+                return Ok(None);
+            };
+            // Graphs written without generations have zeros in their place
+            Ok(match graph.position(sha)? {
+                Some(position) => Some(graph.commit(position)?.generation).filter(|g| *g > 0),
+                None => None,
+            })
+        };
+        let cutoff = generation(target)?;
+
+        let mut known = HashMap::from([(target.to_string(), true)]);
+        let mut result = Vec::new();
+        for tip in tips {
+            let mut stack = vec![tip.clone()];
+            while let Some(sha) = stack.last().cloned() {
+                if known.contains_key(&sha) {
+                    stack.pop();
+                    continue;
+                }
+                if let (Some(cutoff), Some(generation)) = (cutoff, generation(&sha)?) {
+                    if generation <= cutoff {
+                        known.insert(sha, false);
+                        stack.pop();
+                        continue;
+                    }
+                }
+
+                // A commit is decided once one of its parents reaches the
+                // target, or once none of them does
+                let parents = self.commit(git_dir, &sha)?.parents.clone();
+                if parents.iter().any(|p| known.get(p) == Some(&true)) {
+                    known.insert(sha, true);
+                    stack.pop();
+                } else if let Some(parent) = parents.into_iter().find(|p| !known.contains_key(p)) {
+                    stack.push(parent);
+                } else {
+                    known.insert(sha, false);
+                    stack.pop();
+                }
+            }
+            result.push(known[tip]);
+        }
+        Ok(result)
+    }
+
+    /// Returns the commits of `commits` that aren't reachable from another
+    /// one, in their order and without duplicates.
+    pub fn independent(&mut self, git_dir: &Path, commits: &[String]) -> eyre::Result<Vec<String>> {
+        let mut result = Vec::new();
+        for (i, commit) in commits.iter().enumerate() {
+            if commits[..i].contains(commit) {
+                continue;
+            }
+            let others = commits
+                .iter()
+                .filter(|other| *other != commit)
+                .cloned()
+                .collect::<Vec<_>>();
+            if !self.contains(git_dir, &others, commit)?.contains(&true) {
+                result.push(commit.clone());
+            }
+        }
+        Ok(result)
+    }
+
+    /// Propagates the flags of `tips` to their ancestors, visiting commits
+    /// newest first. The walk stops once every queued commit is `stale`, i.e.
+    /// once nothing left to visit can change the outcome.
+    fn paint(
+        &mut self,
+        git_dir: &Path,
+        tips: &[(String, u8)],
+        stale: impl Fn(u8) -> bool,
+    ) -> eyre::Result<HashMap<String, u8>> {
+        let mut flags: HashMap<String, u8> = HashMap::new();
+        let mut processed: HashMap<String, u8> = HashMap::new();
+        let mut queue = Queue::default();
+
+        for (sha, flag) in tips {
+            *flags.entry(sha.clone()).or_default() |= flag;
+        }
+        for (sha, _) in tips {
+            let time = self.commit(git_dir, sha)?.committer.time;
+            queue.push(time, sha.clone(), stale(flags[sha]));
+        }
+
+        while let Some(sha) = queue.pop(|sha| stale(flags[sha])) {
+            // A commit is queued again whenever it gains new flags, skip the
+            // stale copies
+            let current = flags[&sha];
+            if processed.get(&sha) == Some(&current) {
+                continue;
+            }
+            processed.insert(sha.clone(), current);
+
+            let parents = self.commit(git_dir, &sha)?.parents.clone();
+            for parent in parents {
+                let entry = flags.entry(parent.clone()).or_default();
+                if *entry | current != *entry {
+                    if !stale(*entry) && stale(*entry | current) {
+                        queue.stale(&parent);
+                    }
+                    *entry |= current;
+                    let time = self.commit(git_dir, &parent)?.committer.time;
+                    queue.push(time, parent, stale(*entry));
+                }
+            }
+        }
+
+        Ok(flags)
+    }
+
+    /// Sorts the commits by committer date, newest first.
+    fn sorted(
+        &mut self,
+        git_dir: &Path,
+        shas: impl Iterator<Item = String>,
+    ) -> eyre::Result<Vec<String>> {
+        let mut dated = Vec::new();
+        for sha in shas {
+            let time = self.commit(git_dir, &sha)?.committer.time;
+            dated.push((time, sha));
+        }
+        dated.sort_by(|a, b| b.cmp(a));
+        Ok(dated.into_iter().map(|(_, sha)| sha).collect())
+    }
+}
+
+/// The commits left to visit by a walk painting flags, newest first. The
+/// walk stops once every queued commit is stale, so the queue counts its
+/// entries whose commit isn't, commits only ever becoming stale as they
+/// gain flags.
+#[derive(Default)]
+struct Queue {
+    heap: BinaryHeap<(i64, String)>,
+    /// The number of entries of each commit in the heap.
+    entries: HashMap<String, usize>,
+    /// The number of entries whose commit isn't stale.
+    live: usize,
+}
+
+impl Queue {
+    fn push(&mut self, time: i64, sha: String, stale: bool) {
+        *self.entries.entry(sha.clone()).or_default() += 1;
+        if !stale {
+            self.live += 1;
+        }
+        self.heap.push((time, sha));
+    }
+
+    /// Pops the newest commit, unless every queued commit is `stale`.
+    fn pop(&mut self, stale: impl Fn(&str) -> bool) -> Option<String> {
+        if self.live == 0 {
+            return None;
+        }
+        let (_, sha) = self.heap.pop()?;
+        if let Some(count) = self.entries.get_mut(&sha) {
+            *count -= 1;
+        }
+        if !stale(&sha) {
+            self.live -= 1;
+        }
+        Some(sha)
+    }
+
+    /// Records that the commit `sha` became stale.
+    fn stale(&mut self, sha: &str) {
+        self.live -= self.entries.get(sha).copied().unwrap_or(0);
+    }
+}
@@ -0,0 +1,257 @@
+#[cfg(feature = "search-index")]
+pub use self::index::SearchIndex;
+use eyre::eyre;
+use regex::{Regex, RegexBuilder};
+
+/// Selects commits by their message, like `log --grep` does.
+#[derive(Debug, Default)]
+pub struct MessageFilter {
+    patterns: Vec<Regex>,
+    /// The patterns as given, to look them up in a [`SearchIndex`].
+    #[cfg_attr(not(feature = "search-index"), allow(dead_code))]
+    sources: Vec<String>,
+    /// Requires every pattern to match, rather than any of them.
+    all_match: bool,
+    /// Selects the commits the patterns don't match instead.
+    invert: bool,
+}
+
+impl MessageFilter {
+    /// Returns the filter of the regular expressions `patterns`, case
+    /// insensitive with `ignore_case`.
+    pub fn new(
+        patterns: &[String],
+        ignore_case: bool,
+        all_match: bool,
+        invert: bool,
+    ) -> eyre::Result<Self> {
+        let regexes = patterns
+            .iter()
+            .map(|pattern| {
+                RegexBuilder::new(pattern)
+                    .case_insensitive(ignore_case)
+                    .multi_line(true)
+                    .build()
+                    .map_err(|_| eyre!(tr!("invalid regex: %s", pattern)))
+            })
+            .collect::<eyre::Result<_>>()?;
+        Ok(Self {
+            patterns: regexes,
+            sources: patterns.to_vec(),
+            all_match,
+            invert,
+        })
+    }
+
+    /// Returns true if the filter selects every commit.
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Returns true if the filter selects the commit of the given message.
+    pub fn matches(&self, message: &str) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let mut patterns = self.patterns.iter();
+        let matched = match self.all_match {
+            true => patterns.all(|pattern| pattern.is_match(message)),
+            false => patterns.any(|pattern| pattern.is_match(message)),
+        };
+        matched != self.invert
+    }
+}
+
+#[cfg(feature = "search-index")]
+mod index {
+    use super::MessageFilter;
+    use crate::git;
+    use crate::refs;
+    use crate::revision;
+    use crate::revwalk::RevWalk;
+    use crate::tempfile::Temporary;
+    use eyre::eyre;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// A trigram index of the messages of commits at `.git/search-index`,
+    /// listing the commits whose lowercased message holds each sequence of
+    /// three bytes. Patterns without regex syntax only match the commits
+    /// holding all their trigrams, so the others can be skipped.
+    ///
+    /// The file starts with `SIDX` and the version 1, then lists the
+    /// commits indexed, after their number, and the trigrams, after their
+    /// number, each followed by the number and the positions of the commits
+    /// holding it. Numbers are 32 bits, big-endian.
+    #[derive(Debug, Default)]
+    pub struct SearchIndex {
+        path: PathBuf,
+        commits: Vec<String>,
+        positions: HashMap<String, u32>,
+        trigrams: BTreeMap<[u8; 3], Vec<u32>>,
+    }
+
+    impl SearchIndex {
+        /// Reads the index of the repository at `git_dir`, if there's one.
+        pub fn load(git_dir: &Path) -> eyre::Result<Option<Self>> {
+            let path = git_dir.join("search-index");
+            let Ok(data) = fs::read(&path) else {
+                return Ok(None);
+            };
+            let invalid = || eyre!("invalid search index {}", path.display());
+            if !data.starts_with(b"SIDX\0\0\0\x01") {
+                return Err(invalid());
+            }
+            let bytes = |offset: &mut usize, len: usize| -> eyre::Result<&[u8]> {
+                let bytes = data.get(*offset..*offset + len).ok_or_else(invalid)?;
+                *offset += len;
+                Ok(bytes)
+            };
+            let word = |offset: &mut usize| -> eyre::Result<u32> {
+                Ok(u32::from_be_bytes(bytes(offset, 4)?.try_into()?))
+            };
+
+            let mut index = Self {
+                path: path.clone(),
+                ..Self::default()
+            };
+            let mut offset = 8;
+            for _ in 0..word(&mut offset)? {
+                index.push_commit(hex::encode(bytes(&mut offset, 20)?));
+            }
+            for _ in 0..word(&mut offset)? {
+                let trigram = bytes(&mut offset, 3)?.try_into()?;
+                let postings = (0..word(&mut offset)?)
+                    .map(|_| word(&mut offset))
+                    .collect::<eyre::Result<_>>()?;
+                index.trigrams.insert(trigram, postings);
+            }
+            Ok(Some(index))
+        }
+
+        /// Indexes the messages of the commits reachable from the refs and
+        /// `HEAD` of the repository at `git_dir` that aren't indexed yet,
+        /// creating the index if needed. Returns the number of commits
+        /// added.
+        pub fn update(git_dir: &Path) -> eyre::Result<usize> {
+            let mut index = match Self::load(git_dir)? {
+                Some(index) => index,
+                None => Self {
+                    path: git_dir.join("search-index"),
+                    ..Self::default()
+                },
+            };
+            let mut tips = Vec::new();
+            for (_, sha) in refs::list_refs(git_dir, "refs/")?
+                .into_iter()
+                .chain(refs::read_ref(git_dir, "HEAD")?.map(|sha| (String::new(), sha)))
+            {
+                let sha = revision::peel(git_dir, &sha)?;
+                if git::read_object(git_dir, &sha)?.0 == "commit" {
+                    tips.push(sha);
+                }
+            }
+
+            // Commits are indexed oldest first, so that positions follow
+            // history
+            let mut walk = RevWalk::new();
+            let mut added = 0;
+            for sha in walk.list(git_dir, &tips, &[])?.into_iter().rev() {
+                if index.positions.contains_key(&sha) {
+                    continue;
+                }
+                let message = walk.commit(git_dir, &sha)?.message.to_ascii_lowercase();
+                let position = index.push_commit(sha);
+                let mut seen = HashSet::new();
+                for trigram in message.as_bytes().windows(3) {
+                    if seen.insert(trigram) {
+                        let trigram = [trigram[0], trigram[1], trigram[2]];
+                        index.trigrams.entry(trigram).or_default().push(position);
+                    }
+                }
+                added += 1;
+            }
+            index.write()?;
+            Ok(added)
+        }
+
+        /// Returns a function telling whether a commit may be selected by
+        /// `filter`, false only for indexed commits its patterns can't
+        /// match. Without a pattern the index can rule out commits with,
+        /// there's no such function.
+        pub fn candidates(&self, filter: &MessageFilter) -> Option<impl Fn(&str) -> bool + '_> {
+            if filter.invert || filter.sources.is_empty() {
+                return None;
+            }
+            let mut sets = filter.sources.iter().map(|pattern| self.lookup(pattern));
+            let positions = match filter.all_match {
+                // Patterns that can't be looked up match any commit
+                true => sets.flatten().reduce(|a, b| &a & &b)?,
+                false => sets.try_fold(HashSet::new(), |a, b| Some(&a | &b?))?,
+            };
+            Some(move |sha: &str| {
+                self.positions
+                    .get(sha)
+                    .map_or(true, |position| positions.contains(position))
+            })
+        }
+
+        /// Returns the positions of the commits whose message holds every
+        /// trigram of `pattern`, if it's a literal of at least 3 ASCII
+        /// characters.
+        fn lookup(&self, pattern: &str) -> Option<HashSet<u32>> {
+            let literal = pattern.is_ascii()
+                && pattern.len() >= 3
+                && !pattern.contains(|c| "\\.^$|?*+()[]{}".contains(c));
+            if !literal {
+                return None;
+            }
+            let pattern = pattern.to_ascii_lowercase();
+            let mut positions: Option<HashSet<u32>> = None;
+            for trigram in pattern.as_bytes().windows(3) {
+                let postings = self
+                    .trigrams
+                    .get(trigram)
+                    .map(|postings| postings.iter().copied().collect::<HashSet<_>>())
+                    .unwrap_or_default();
+                positions = Some(match positions {
+                    Some(positions) => &positions & &postings,
+                    None => postings,
+                });
+            }
+            positions
+        }
+
+        /// Appends the commit `sha`, returning its position.
+        fn push_commit(&mut self, sha: String) -> u32 {
+            let position = self.commits.len() as u32;
+            self.positions.insert(sha.clone(), position);
+            self.commits.push(sha);
+            position
+        }
+
+        /// Writes the index through a lock file.
+        fn write(&self) -> eyre::Result<()> {
+            let mut out = b"SIDX".to_vec();
+            out.extend(1u32.to_be_bytes());
+            out.extend((self.commits.len() as u32).to_be_bytes());
+            for sha in &self.commits {
+                out.extend(hex::decode(sha)?);
+            }
+            out.extend((self.trigrams.len() as u32).to_be_bytes());
+            for (trigram, postings) in &self.trigrams {
+                out.extend(trigram);
+                out.extend((postings.len() as u32).to_be_bytes());
+                for position in postings {
+                    out.extend(position.to_be_bytes());
+                }
+            }
+            let lock = self.path.with_extension("lock");
+            let _temporary = Temporary::new(&lock);
+            fs::write(&lock, out)?;
+            fs::rename(lock, &self.path)?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use codecrafters_git::config::Config;
+use codecrafters_git::fetch;
+use codecrafters_git::pktline;
+use codecrafters_git::receive_pack;
+use codecrafters_git::server::{self, Service};
+use codecrafters_git::tr;
+use codecrafters_git::upload_pack;
+use eyre::eyre;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+
+/// A request read from a client.
+#[derive(Debug)]
+struct Request {
+    method: String,
+    /// The path of the request, with its query.
+    target: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Returns the value of the header `name`, whatever its case.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A response to a request: its status, the type of its content and the
+/// content.
+type Response = (u16, String, Vec<u8>);
+
+/// Serves the repositories at `directories` over the smart HTTP protocol
+/// on `address`, each under the name of its directory, like
+/// `http://<address>/project.git`, until killed. Fetches are served unless
+/// a repository sets `http.uploadPack` to false, and pushes are received
+/// with `read_write`, unless a repository sets `http.receivePack`, which
+/// decides either way.
+pub fn serve(address: &str, directories: &[PathBuf], read_write: bool) -> eyre::Result<()> {
+    let mut repositories: Vec<(String, PathBuf)> = Vec::new();
+    for dir in directories {
+        let git_dir = fetch::local_git_dir(&dir.to_string_lossy())?.canonicalize()?;
+        let name = dir
+            .canonicalize()?
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if repositories.iter().any(|(served, _)| *served == name) {
+            return Err(eyre!(tr!("two repositories are named '%s'", &name)));
+        }
+        repositories.push((name, git_dir));
+    }
+
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    eprintln!(
+        "{}",
+        tr!(
+            "Serving %d repositories on http://%s/",
+            repositories.len(),
+            address
+        )
+    );
+    for (name, _) in &repositories {
+        eprintln!("  http://{address}/{name}");
+    }
+    let repositories = &repositories;
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("error: {e}");
+                    continue;
+                }
+            };
+            scope.spawn(move || {
+                if let Err(e) = handle(stream, repositories, read_write) {
+                    eprintln!("error: {e}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Answers the requests of a connection, which is kept open between them
+/// unless the client closes it.
+fn handle(
+    stream: TcpStream,
+    repositories: &[(String, PathBuf)],
+    read_write: bool,
+) -> eyre::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                write_response(
+                    &mut writer,
+                    (400, "text/plain".into(), format!("{e}\n").into()),
+                )?;
+                return Ok(());
+            }
+        };
+        // Clients sending large bodies wait to be told to go on
+        if request.header("Expect") == Some("100-continue") {
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+        let body = read_body(&mut reader, &request)?;
+        let response = respond(&request, &body, repositories, read_write)
+            .unwrap_or_else(|e| (500, "text/plain".into(), format!("{e}\n").into()));
+        eprintln!("{} {} {}", request.method, request.target, response.0);
+        write_response(&mut writer, response)?;
+        if request.header("Connection") == Some("close") {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the request line and the headers of the next request, or `None`
+/// if the client closed the connection.
+fn read_request(reader: &mut impl BufRead) -> eyre::Result<Option<Request>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut words = line.split_whitespace();
+    let (Some(method), Some(target)) = (words.next(), words.next()) else {
+        return Err(eyre!("invalid request line '{}'", line.trim_end()));
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers: Vec::new(),
+    };
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(Some(request));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(eyre!("invalid header '{line}'"))?;
+        request
+            .headers
+            .push((name.to_string(), value.trim().to_string()));
+    }
+}
+
+/// Reads the body of `request`, of a given length or in chunks, and
+/// decompresses it if gzipped.
+fn read_body(reader: &mut impl BufRead, request: &Request) -> eyre::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    if request
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        // Chunks are preceded by their length in hexadecimal, the last one
+        // being empty, and followed by a line feed
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size = line.trim_end().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| eyre!("invalid chunk size '{}'", line.trim_end()))?;
+            if size == 0 {
+                // Trailers may follow the last chunk, up to an empty line
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+                        break;
+                    }
+                }
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            reader.read_line(&mut line)?;
+        }
+    } else if let Some(length) = request.header("Content-Length") {
+        body.resize(length.parse()?, 0);
+        reader.read_exact(&mut body)?;
+    }
+
+    match request.header("Content-Encoding") {
+        Some("gzip" | "x-gzip") => {
+            let mut inflated = Vec::new();
+            flate2::read::GzDecoder::new(body.as_slice()).read_to_end(&mut inflated)?;
+            Ok(inflated)
+        }
+        _ => Ok(body),
+    }
+}
+
+/// Answers `request` for one of `repositories`: the refs advertised by
+/// `info/refs` and the results of the `git-upload-pack` and
+/// `git-receive-pack` services.
+fn respond(
+    request: &Request,
+    body: &[u8],
+    repositories: &[(String, PathBuf)],
+    read_write: bool,
+) -> eyre::Result<Response> {
+    let not_found = || Ok((404, "text/plain".into(), b"Not Found\n".to_vec()));
+    let (path, query) = request
+        .target
+        .split_once('?')
+        .unwrap_or((&request.target, ""));
+    let path = path.trim_start_matches('/');
+    let Some((git_dir, endpoint)) = repositories.iter().find_map(|(name, git_dir)| {
+        let endpoint = path.strip_prefix(name.as_str())?.strip_prefix('/')?;
+        Some((git_dir, endpoint))
+    }) else {
+        return not_found();
+    };
+
+    let (service, advertise) = match (request.method.as_str(), endpoint) {
+        ("GET", "info/refs") => {
+            let service = query
+                .split('&')
+                .find_map(|parameter| parameter.strip_prefix("service="));
+            match service {
+                Some("git-upload-pack") => (Service::UploadPack, true),
+                Some("git-receive-pack") => (Service::ReceivePack, true),
+                // Only the smart protocol is served
+                _ => return not_found(),
+            }
+        }
+        ("POST", "git-upload-pack") => (Service::UploadPack, false),
+        ("POST", "git-receive-pack") => (Service::ReceivePack, false),
+        (_, "info/refs" | "git-upload-pack" | "git-receive-pack") => {
+            return Ok((405, "text/plain".into(), b"Method Not Allowed\n".to_vec()))
+        }
+        _ => return not_found(),
+    };
+    let name = match service {
+        Service::UploadPack => "git-upload-pack",
+        Service::ReceivePack => "git-receive-pack",
+    };
+
+    let config = Config::load(git_dir)?;
+    if !enabled(&config, service, read_write)? {
+        return Ok((403, "text/plain".into(), b"Forbidden\n".to_vec()));
+    }
+    let version = server::parse_version(request.header("Git-Protocol").unwrap_or_default());
+    let mut out = Vec::new();
+    match (service, advertise) {
+        (_, true) => {
+            // The service announces itself, except in version 2
+            if service == Service::ReceivePack || version < 2 {
+                let mut writer = pktline::Writer::new(&mut out);
+                writer.write_line(&format!("# service={name}"))?;
+                writer.write_flush()?;
+            }
+            server::advertise_refs(&config, git_dir, service, version, &mut out)?;
+            return Ok((200, format!("application/x-{name}-advertisement"), out));
+        }
+        (Service::UploadPack, false) => {
+            upload_pack::upload_pack(&config, git_dir, version, true, body, &mut out)?
+        }
+        (Service::ReceivePack, false) => {
+            receive_pack::receive_pack(&config, git_dir, true, body, &mut out)?
+        }
+    }
+    Ok((200, format!("application/x-{name}-result"), out))
+}
+
+/// Returns true if the repository whose config is `config` serves
+/// `service`, pushes being received with `read_write` unless the config
+/// decides.
+fn enabled(config: &Config, service: Service, read_write: bool) -> eyre::Result<bool> {
+    Ok(match service {
+        Service::UploadPack => config.get_bool("http.uploadpack")?.unwrap_or(true),
+        Service::ReceivePack => config.get_bool("http.receivepack")?.unwrap_or(read_write),
+    })
+}
+
+/// Writes `response` with the headers telling its length and type, and
+/// that it mustn't be cached.
+fn write_response(
+    writer: &mut impl Write,
+    (status, content_type, body): Response,
+) -> eyre::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {status} {reason}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-cache\r\n\
+         \r\n",
+        body.len()
+    )?;
+    writer.write_all(&body)?;
+    writer.flush()?;
+    Ok(())
+}
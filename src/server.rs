@@ -0,0 +1,248 @@
+use crate::config::Config;
+use crate::pktline;
+use crate::refs;
+use crate::revision;
+use eyre::eyre;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// The sha standing for no object, advertised with the capabilities of
+/// repositories without refs.
+const NULL_SHA: &str = "0000000000000000000000000000000000000000";
+
+/// The programs serving the two sides of the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    /// Sends objects to fetches and clones.
+    UploadPack,
+    /// Receives objects from pushes.
+    ReceivePack,
+}
+
+/// Returns the capabilities `service` advertises for the repository at
+/// `git_dir`, following its configuration:
+/// - `uploadpack.allowFilter` advertises `filter`, for partial clones
+/// - `uploadpack.allowTipSHA1InWant`, `uploadpack.allowReachableSHA1InWant`
+///   and `uploadpack.allowAnySHA1InWant` allow wanting objects by sha
+/// - `uploadpack.allowShallow` and `uploadpack.allowSideband`, on unless
+///   disabled, advertise shallow fetches and multiplexed progress
+/// - `receive.advertiseAtomic`, `receive.advertisePushOptions` and
+///   `receive.advertiseSideband` do the same for pushes
+/// - `extensions.objectFormat` is the hash of the objects
+pub fn capabilities(
+    config: &Config,
+    git_dir: &Path,
+    service: Service,
+) -> eyre::Result<Vec<String>> {
+    let enabled = |key: &str, default: bool| -> eyre::Result<bool> {
+        Ok(config.get_bool(key)?.unwrap_or(default))
+    };
+    let mut capabilities = Vec::new();
+    let mut add = |capability: &str| capabilities.push(capability.to_string());
+
+    match service {
+        Service::UploadPack => {
+            add("multi_ack");
+            add("thin-pack");
+            if enabled("uploadpack.allowSideband", true)? {
+                add("side-band");
+                add("side-band-64k");
+            }
+            add("ofs-delta");
+            if enabled("uploadpack.allowShallow", true)? {
+                add("shallow");
+                add("deepen-since");
+                add("deepen-not");
+                add("deepen-relative");
+            }
+            add("no-progress");
+            add("include-tag");
+            add("multi_ack_detailed");
+            let any = enabled("uploadpack.allowAnySHA1InWant", false)?;
+            if any || enabled("uploadpack.allowTipSHA1InWant", false)? {
+                add("allow-tip-sha1-in-want");
+            }
+            if any || enabled("uploadpack.allowReachableSHA1InWant", false)? {
+                add("allow-reachable-sha1-in-want");
+            }
+            add("no-done");
+            // Clients learn the default branch from where `HEAD` points
+            let head = fs::read_to_string(git_dir.join("HEAD")).unwrap_or_default();
+            if let Some(target) = head.trim_end().strip_prefix("ref: ") {
+                if refs::read_ref(git_dir, target)?.is_some() {
+                    add(&format!("symref=HEAD:{target}"));
+                }
+            }
+            if enabled("uploadpack.allowFilter", false)? {
+                add("filter");
+            }
+        }
+        Service::ReceivePack => {
+            add("report-status");
+            add("report-status-v2");
+            add("delete-refs");
+            if enabled("receive.advertiseSideband", true)? {
+                add("side-band-64k");
+            }
+            add("quiet");
+            if enabled("receive.advertiseAtomic", true)? {
+                add("atomic");
+            }
+            add("ofs-delta");
+            if enabled("receive.advertisePushOptions", false)? {
+                add("push-options");
+            }
+        }
+    }
+
+    add(&object_format(config)?);
+    add(concat!(
+        "agent=",
+        env!("CARGO_PKG_NAME"),
+        "/",
+        env!("CARGO_PKG_VERSION")
+    ));
+    Ok(capabilities)
+}
+
+/// Returns the capabilities of protocol version 2, where fetches start from
+/// the capabilities alone and ask for the refs with the `ls-refs` command:
+/// - `lsrefs.unborn` decides whether `ls-refs` advertises an unborn `HEAD`
+/// - `uploadpack.allowRefInWant` lets `fetch` want refs by name, which
+///   the server resolves itself, so they can't move between `ls-refs` and
+///   `fetch`
+/// - `uploadpack.allowFilter` and `uploadpack.allowShallow` as in version 1
+fn capabilities_v2(config: &Config) -> eyre::Result<Vec<String>> {
+    let enabled = |key: &str, default: bool| -> eyre::Result<bool> {
+        Ok(config.get_bool(key)?.unwrap_or(default))
+    };
+    let mut capabilities = vec![concat!(
+        "agent=",
+        env!("CARGO_PKG_NAME"),
+        "/",
+        env!("CARGO_PKG_VERSION")
+    )
+    .to_string()];
+
+    match config.get("lsrefs.unborn").unwrap_or("advertise") {
+        "advertise" => capabilities.push("ls-refs=unborn".to_string()),
+        "allow" | "ignore" => capabilities.push("ls-refs".to_string()),
+        value => {
+            return Err(eyre!(tr!(
+                "invalid value for '%s': '%s'",
+                "lsrefs.unborn",
+                value
+            )))
+        }
+    }
+
+    let mut fetch = Vec::new();
+    if enabled("uploadpack.allowShallow", true)? {
+        fetch.push("shallow");
+    }
+    fetch.push("wait-for-done");
+    if enabled("uploadpack.allowFilter", false)? {
+        fetch.push("filter");
+    }
+    if enabled("uploadpack.allowRefInWant", false)? {
+        fetch.push("ref-in-want");
+    }
+    capabilities.push(format!("fetch={}", fetch.join(" ")));
+    capabilities.push("server-option".to_string());
+    capabilities.push(object_format(config)?);
+    capabilities.push("object-info".to_string());
+    Ok(capabilities)
+}
+
+/// Returns the `object-format` capability of the repository, from
+/// `extensions.objectFormat`.
+fn object_format(config: &Config) -> eyre::Result<String> {
+    // Only repositories of sha-1 objects can be read, let alone served
+    match config.get("extensions.objectFormat") {
+        None => Ok("object-format=sha1".to_string()),
+        Some(format) if format.eq_ignore_ascii_case("sha1") => Ok("object-format=sha1".to_string()),
+        Some(format) => Err(eyre!(tr!("unsupported object format '%s'", format))),
+    }
+}
+
+/// Returns the version of the protocol the client asked for in the
+/// `GIT_PROTOCOL` environment variable, like `version=2`, 0 by default.
+pub fn requested_version() -> u32 {
+    parse_version(&std::env::var("GIT_PROTOCOL").unwrap_or_default())
+}
+
+/// Returns the version of the protocol asked for by `parameters`, the
+/// colon-separated parameters of `GIT_PROTOCOL` or of the `Git-Protocol`
+/// HTTP header, 0 if none is.
+pub fn parse_version(parameters: &str) -> u32 {
+    parameters
+        .split(':')
+        .filter_map(|parameter| parameter.strip_prefix("version="))
+        .filter_map(|version| version.parse().ok())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Writes the ref advertisement `service` starts with, in pkt-lines: a line
+/// per ref, the first one followed by the capabilities after a NUL, and a
+/// flush packet. Fetches also see `HEAD` and the objects tags peel to.
+///
+/// Fetches asking for protocol version 2 get the capabilities of that
+/// version instead, one per line after `version 2`. Pushes don't have a
+/// version 2.
+pub fn advertise_refs(
+    config: &Config,
+    git_dir: &Path,
+    service: Service,
+    version: u32,
+    out: &mut impl Write,
+) -> eyre::Result<()> {
+    let mut writer = pktline::Writer::new(&mut *out);
+    if service == Service::UploadPack && version >= 2 {
+        writer.write_line("version 2")?;
+        for capability in capabilities_v2(config)? {
+            writer.write_line(&capability)?;
+        }
+        writer.write_flush()?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    // Version 1 is version 0 announcing itself, pushes fall back to it
+    // from version 2
+    if version == 1 {
+        writer.write_line("version 1")?;
+    }
+    let mut lines = Vec::new();
+    if service == Service::UploadPack {
+        if let Some(sha) = refs::read_ref(git_dir, "HEAD")? {
+            lines.push((sha, "HEAD".to_string()));
+        }
+    }
+    for (name, sha) in refs::list_refs(git_dir, "refs/")? {
+        let peeled = match service {
+            Service::UploadPack => Some(revision::peel(git_dir, &sha)?),
+            Service::ReceivePack => None,
+        };
+        lines.push((sha.clone(), name.clone()));
+        if let Some(peeled) = peeled.filter(|peeled| *peeled != sha) {
+            lines.push((peeled, format!("{name}^{{}}")));
+        }
+    }
+
+    // Pushes into an empty repository still need the capabilities
+    if lines.is_empty() && service == Service::ReceivePack {
+        lines.push((NULL_SHA.to_string(), "capabilities^{}".to_string()));
+    }
+    let capabilities = capabilities(config, git_dir, service)?.join(" ");
+    for (i, (sha, name)) in lines.iter().enumerate() {
+        match i {
+            0 => writer.write_line(&format!("{sha} {name}\0{capabilities}"))?,
+            _ => writer.write_line(&format!("{sha} {name}"))?,
+        }
+    }
+    writer.write_flush()?;
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use crate::measure;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+/// Returns the commits listed in the `shallow` file of the repository at
+/// `git_dir`: the commits whose parents weren't fetched, and which history
+/// walks treat as root commits.
+pub fn read(git_dir: &Path) -> eyre::Result<BTreeSet<String>> {
+    match measure::phase("shallow", || fs::read_to_string(git_dir.join("shallow"))) {
+        Ok(content) => Ok(content.lines().map(str::to_string).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Adds `commits` to the `shallow` file of the repository at `git_dir`.
+pub fn extend(git_dir: &Path, commits: impl IntoIterator<Item = String>) -> eyre::Result<()> {
+    update(git_dir, commits, &[])
+}
+
+/// Adds `shallow` to the `shallow` file of the repository at `git_dir` and
+/// removes `unshallow`, the commits whose parents were fetched. The file is
+/// removed once empty.
+pub fn update(
+    git_dir: &Path,
+    shallow: impl IntoIterator<Item = String>,
+    unshallow: &[String],
+) -> eyre::Result<()> {
+    let old = read(git_dir)?;
+    let mut new = old.clone();
+    new.extend(shallow);
+    new.retain(|sha| !unshallow.contains(sha));
+    if new == old {
+        return Ok(());
+    }
+    match new.is_empty() {
+        true => fs::remove_file(git_dir.join("shallow"))?,
+        false => {
+            let content = new.iter().map(|sha| format!("{sha}\n")).collect::<String>();
+            fs::write(git_dir.join("shallow"), content)?;
+        }
+    }
+    Ok(())
+}
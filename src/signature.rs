@@ -0,0 +1,95 @@
+use crate::commit;
+use crate::config::Config;
+use eyre::eyre;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Headers of a commit holding its signature, one per hash algorithm.
+const SIGNATURE_HEADERS: [&[u8]; 2] = [b"gpgsig ", b"gpgsig-sha256 "];
+
+/// Lines starting the signature appended to the message of a tag.
+const SIGNATURE_STARTS: [&[u8]; 3] = [
+    b"-----BEGIN PGP SIGNATURE-----",
+    b"-----BEGIN SSH SIGNATURE-----",
+    b"-----BEGIN SIGNED MESSAGE-----",
+];
+
+/// Returns the part of a commit or tag object covered by its signature,
+/// exactly as the signer saw it: the object without its signature.
+///
+/// A commit carries its signature in a `gpgsig` header, continued on the
+/// following lines starting with a space. A tag carries it at the end of
+/// its message.
+pub fn payload(kind: &str, content: &[u8]) -> eyre::Result<Vec<u8>> {
+    match kind {
+        "commit" => {
+            let headers_end = content
+                .windows(2)
+                .position(|w| w == b"\n\n")
+                .map_or(content.len(), |i| i + 1);
+
+            let mut payload = Vec::with_capacity(content.len());
+            let mut in_signature = false;
+            for line in content[..headers_end].split_inclusive(|b| *b == b'\n') {
+                in_signature = match line.first() {
+                    Some(b' ') => in_signature,
+                    _ => SIGNATURE_HEADERS.iter().any(|h| line.starts_with(h)),
+                };
+                if !in_signature {
+                    payload.extend_from_slice(line);
+                }
+            }
+            payload.extend_from_slice(&content[headers_end..]);
+            Ok(payload)
+        }
+        "tag" => {
+            let mut offset = 0;
+            let mut start = content.len();
+            for line in content.split_inclusive(|b| *b == b'\n') {
+                if SIGNATURE_STARTS.iter().any(|s| line.starts_with(s)) {
+                    start = offset;
+                }
+                offset += line.len();
+            }
+            Ok(content[..start].to_vec())
+        }
+        _ => Err(eyre!("a {kind} object can't be signed")),
+    }
+}
+
+/// Signs `payload` with `gpg.program`, `gpg` by default, and returns the
+/// armored detached signature. The key is `key` if not empty, else
+/// `user.signingKey`, else the committer identity.
+pub fn sign(config: &Config, payload: &[u8], key: &str) -> eyre::Result<String> {
+    let key = match (key, config.get("user.signingKey")) {
+        ("", Some(configured)) => configured.to_string(),
+        ("", None) => {
+            let committer = commit::Signature::now(config, "COMMITTER")?;
+            format!("{} <{}>", committer.name, committer.email)
+        }
+        (key, _) => key.to_string(),
+    };
+    let program = config.get("gpg.program").unwrap_or("gpg");
+    let mut child = Command::new(program)
+        .args(["--status-fd=2", "-bsau", &key])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| eyre!(tr!("cannot run %s", program)).wrap_err(e))?;
+    if let Some(mut input) = child.stdin.take() {
+        input.write_all(payload)?;
+    }
+    let output = child.wait_with_output()?;
+
+    // Like git, the signature only counts if gpg says it made one
+    let status = String::from_utf8_lossy(&output.stderr);
+    let created = status
+        .lines()
+        .any(|line| line.starts_with("[GNUPG:] SIG_CREATED "));
+    if !output.status.success() || !created {
+        eprint!("{status}");
+        return Err(eyre!(tr!("gpg failed to sign the data")));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
@@ -0,0 +1,168 @@
+use crate::config::Config;
+use crate::pktline;
+use crate::transport::{self, Refs, Transport};
+use eyre::eyre;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// Returns true if `url` names a repository reached over SSH: an `ssh://`
+/// url, or the scp-like `[user@]host:path` whose host has no slash.
+pub fn is_ssh(url: &str) -> bool {
+    if let Some((scheme, _)) = url.split_once("://") {
+        return matches!(scheme, "ssh" | "git+ssh" | "ssh+git");
+    }
+    let start = bracket_end(url);
+    url[start..]
+        .find(':')
+        .is_some_and(|colon| !url[..start + colon].contains('/'))
+}
+
+/// A repository reached over SSH, at an `ssh://[user@]host[:port]/path` url
+/// or an scp-like `[user@]host:path` one. The service runs on the host
+/// through the `ssh` command, `GIT_SSH_COMMAND`, `core.sshCommand` or
+/// `GIT_SSH` if set, and the requests and their responses follow one
+/// another on its standard input and output.
+pub struct Connection<'a> {
+    config: &'a Config,
+    /// The host, with the user if the url has one.
+    host: String,
+    port: Option<String>,
+    /// The path of the repository, relative to the home directory of the
+    /// user unless absolute.
+    path: String,
+    /// The version of the protocol asked for with `protocol.version`.
+    version: u32,
+    child: Option<(Child, ChildStdin, ChildStdout)>,
+}
+
+impl<'a> Connection<'a> {
+    pub fn new(config: &'a Config, url: &str) -> eyre::Result<Self> {
+        let (host, port, path) = match url.split_once("://") {
+            Some((_, rest)) => {
+                let slash = rest.find('/').ok_or(eyre!(tr!(
+                    "no path specified; see 'git help pull' for valid url syntax"
+                )))?;
+                let (authority, path) = rest.split_at(slash);
+                // Paths under the home directory of a user are `/~user/...`
+                let path = match path.starts_with("/~") {
+                    true => &path[1..],
+                    false => path,
+                };
+                let start = bracket_end(authority);
+                match authority[start..].rfind(':') {
+                    Some(colon) => {
+                        let (host, port) = authority.split_at(start + colon);
+                        (host, Some(port[1..].to_string()), path)
+                    }
+                    None => (authority, None, path),
+                }
+            }
+            None => {
+                let start = bracket_end(url);
+                let colon = start + url[start..].find(':').unwrap_or_default();
+                (&url[..colon], None, &url[colon + 1..])
+            }
+        };
+        if path.is_empty() {
+            return Err(eyre!(tr!(
+                "no path specified; see 'git help pull' for valid url syntax"
+            )));
+        }
+        Ok(Self {
+            config,
+            host: host.replace(['[', ']'], ""),
+            port,
+            path: path.to_string(),
+            version: transport::version(config)?,
+            child: None,
+        })
+    }
+
+    /// Returns the command running `ssh`, whose arguments are added after
+    /// the ones of the configured command.
+    fn ssh_command(&self) -> Command {
+        let command = std::env::var("GIT_SSH_COMMAND")
+            .ok()
+            .or(self.config.get("core.sshCommand").map(str::to_string))
+            .filter(|command| !command.is_empty());
+        match command {
+            Some(command) => {
+                let mut ssh = Command::new("sh");
+                ssh.arg("-c").arg(format!("{command} \"$@\"")).arg(&command);
+                ssh
+            }
+            None => Command::new(std::env::var_os("GIT_SSH").unwrap_or("ssh".into())),
+        }
+    }
+}
+
+impl Transport for Connection<'_> {
+    fn connect(&mut self, service: &str) -> eyre::Result<Refs> {
+        let mut ssh = self.ssh_command();
+        if let Some(port) = &self.port {
+            ssh.arg("-p").arg(port);
+        }
+        // OpenSSH only passes the version to the remote service if asked to
+        if self.version == 2 && service == "git-upload-pack" {
+            let program = Path::new(ssh.get_program()).file_name();
+            if program.is_some_and(|program| program == "ssh") {
+                ssh.arg("-o").arg("SendEnv=GIT_PROTOCOL");
+            }
+            ssh.env("GIT_PROTOCOL", "version=2");
+        }
+        let mut child = ssh
+            .arg(&self.host)
+            .arg(format!("{service} {}", quote(&self.path)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| eyre!(tr!("cannot run ssh: %s", &e.to_string())))?;
+        let stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+
+        let mut reader = pktline::Reader::new(&mut stdout);
+        let line = reader.read_line()?;
+        let refs = transport::read_refs(&mut reader, line)?;
+        self.child = Some((child, stdin, stdout));
+        Ok(refs)
+    }
+
+    fn request(&mut self, service: &str, request: &[u8]) -> eyre::Result<Box<dyn Read + '_>> {
+        let (_, stdin, stdout) = self
+            .child
+            .as_mut()
+            .ok_or(eyre!("{service} was not started"))?;
+        stdin.write_all(request)?;
+        Ok(Box::new(stdout))
+    }
+
+    fn is_stateless(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for Connection<'_> {
+    /// Closes the input of the service, which then exits, and waits for
+    /// `ssh` to return.
+    fn drop(&mut self) {
+        if let Some((mut child, stdin, _)) = self.child.take() {
+            drop(stdin);
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Returns where the brackets around the IPv6 address of the host of `url`
+/// end, 0 without any, the colons inside not being the one after the host.
+fn bracket_end(url: &str) -> usize {
+    match (url.find('['), url.find(']')) {
+        (Some(open), Some(close)) if open < close && !url[..open].contains([':', '/']) => close,
+        _ => 0,
+    }
+}
+
+/// Quotes `path` for the shell the remote service runs in.
+fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', "'\\''"))
+}
@@ -0,0 +1,301 @@
+use crate::commit::{Commit, Signature};
+use crate::config::Config;
+use crate::interactive::{self, HunkSelector, Split};
+use crate::merge;
+use crate::reflog;
+use crate::refs;
+use crate::tree::{self, Files};
+use crate::worktree::{self, Operation};
+use eyre::eyre;
+use std::path::{Path, PathBuf};
+
+/// Returns the tracked files of `HEAD` and their current state in the
+/// working tree.
+fn worktree_state(git_dir: &Path) -> eyre::Result<(Commit, Files, Files)> {
+    let head =
+        refs::read_ref(git_dir, "HEAD")?.ok_or(eyre!(tr!("no commit to stash changes against")))?;
+    let commit = Commit::read(git_dir, &head)?;
+    let tracked = tree::flatten_tree(git_dir, &commit.tree)?;
+    let current = worktree::snapshot(git_dir, &tracked)?;
+    Ok((commit, tracked, current))
+}
+
+/// Returns true if tracked files of the working tree differ from `HEAD`.
+pub fn is_dirty(git_dir: &Path) -> eyre::Result<bool> {
+    let (_, tracked, current) = worktree_state(git_dir)?;
+    Ok(tracked != current)
+}
+
+/// Which changes of the working tree are stashed.
+#[derive(Debug, Default)]
+pub struct Selection {
+    /// Only stash the changes of files under these paths, if any.
+    pub pathspec: Vec<PathBuf>,
+    /// Ask which hunks of the changes to stash.
+    pub patch: bool,
+}
+
+/// Saves the changes made to tracked files in a stash commit and resets them
+/// to their content in `HEAD`, or the `selection` of them. Returns the stash
+/// commit, or `None` if there was nothing to stash. Without a `message`, the
+/// stash is described by the commit of `HEAD`.
+///
+/// Like git, the stash commit has `HEAD` as first parent and a commit of the
+/// staged content as second parent.
+pub fn create(
+    git_dir: &Path,
+    config: &Config,
+    message: Option<&str>,
+    selection: &Selection,
+) -> eyre::Result<Option<String>> {
+    let (commit, tracked, current) = worktree_state(git_dir)?;
+    tree::check_pathspec(&selection.pathspec, tracked.keys().chain(current.keys()))?;
+    worktree::write_blobs(
+        git_dir,
+        current
+            .iter()
+            .filter(|(path, entry)| tracked.get(*path) != Some(entry)),
+    )?;
+
+    // The stash holds the selected changes, the working tree keeps the rest
+    let mut stashed = tracked.clone();
+    let mut kept = tracked.clone();
+    let mut selector = HunkSelector::new(&interactive::STASH);
+    for change in tree::diff_files(&tracked, &current) {
+        let matches = tree::pathspec_matches(&selection.pathspec, &change.path);
+        let split = match (matches, selection.patch) {
+            (false, _) => Split {
+                selected: change.old.clone(),
+                rest: change.new.clone(),
+            },
+            (true, false) => Split {
+                selected: change.new.clone(),
+                rest: change.old.clone(),
+            },
+            (true, true) => selector.split(git_dir, &change)?,
+        };
+        for (files, file) in [(&mut stashed, split.selected), (&mut kept, split.rest)] {
+            match file {
+                Some(file) => files.insert(change.path.clone(), file),
+                None => files.remove(&change.path),
+            };
+        }
+    }
+    if stashed == tracked {
+        return Ok(None);
+    }
+
+    let head = refs::read_ref(git_dir, "HEAD")?.unwrap_or_default();
+    let branch = refs::symbolic_target(git_dir, "HEAD")?
+        .map(|b| b.trim_start_matches("refs/heads/").to_string())
+        .unwrap_or("(no branch)".to_string());
+    let signature = Signature::now(config, "COMMITTER")?;
+    let encoding = Commit::encoding_for(config)?;
+    let description = format!("{} {}", &head[..7], commit.summary());
+
+    // Without an index, the staged content is the content of HEAD
+    let index = Commit {
+        tree: commit.tree.clone(),
+        parents: vec![head.clone()],
+        author: signature.clone(),
+        committer: signature.clone(),
+        encoding: encoding.clone(),
+        extra_headers: Vec::new(),
+        message: format!("index on {branch}: {description}\n"),
+    }
+    .write(git_dir)?;
+
+    let message = match message {
+        Some(message) => format!("On {branch}: {message}\n"),
+        None => format!("WIP on {branch}: {description}\n"),
+    };
+    let stash = Commit {
+        tree: tree::write_tree(git_dir, &stashed)?,
+        parents: vec![head, index],
+        author: signature.clone(),
+        committer: signature,
+        encoding,
+        extra_headers: Vec::new(),
+        message,
+    }
+    .write(git_dir)?;
+
+    worktree::checkout_files(git_dir, &current, &kept, None)?;
+    Ok(Some(stash))
+}
+
+/// Applies the changes of a stash commit to the working tree, merging them
+/// with the changes made to `HEAD` since the stash was created. Returns the
+/// paths that couldn't be merged, written with conflict markers.
+pub fn apply(git_dir: &Path, stash: &str) -> eyre::Result<Vec<PathBuf>> {
+    let stash = Commit::read(git_dir, stash)?;
+    let base = Commit::read(
+        git_dir,
+        stash.parents.first().ok_or(eyre!("invalid stash commit"))?,
+    )?;
+    let (head, _, current) = worktree_state(git_dir)?;
+
+    let result = merge::merge_trees(
+        git_dir,
+        Some(&base.tree),
+        &head.tree,
+        &stash.tree,
+        ("Updated upstream", "Stashed changes"),
+    )?;
+    worktree::checkout_files(git_dir, &current, &result.files, None)?;
+    Ok(result.conflicts)
+}
+
+/// Records a stash commit in `refs/stash` and its log, so it shows up in the
+/// stash list.
+pub fn store(git_dir: &Path, config: &Config, stash: &str, message: &str) -> eyre::Result<()> {
+    let old = refs::read_ref(git_dir, "refs/stash")?;
+    refs::update_ref(git_dir, "refs/stash", stash)?;
+    let who = Signature::now(config, "COMMITTER")?;
+    refs::append_reflog(git_dir, "refs/stash", old.as_deref(), stash, &who, message)
+}
+
+/// Runs `operation` with the changes to tracked files stashed away, and
+/// reapplies them once it completes. If they don't apply cleanly, they are
+/// kept in the stash list.
+pub fn with_autostash(
+    git_dir: &Path,
+    config: &Config,
+    operation: impl FnOnce() -> eyre::Result<()>,
+) -> eyre::Result<()> {
+    let Some(stash) = create(git_dir, config, Some("autostash"), &Selection::default())? else {
+        return operation();
+    };
+    println!("{}", tr!("Created autostash: %s", &stash[..7]));
+
+    let result = operation();
+
+    // A merge left in progress must be concluded before the changes can be
+    // reapplied on top of it
+    if refs::read_ref(git_dir, "MERGE_HEAD")?.is_some() {
+        store(git_dir, config, &stash, "autostash")?;
+        println!("{}", tr!("Your changes are safe in the stash."));
+        return result;
+    }
+
+    let conflicts = apply(git_dir, &stash)?;
+    if conflicts.is_empty() {
+        println!("{}", tr!("Applied autostash."));
+    } else {
+        store(git_dir, config, &stash, "autostash")?;
+        for path in &conflicts {
+            println!(
+                "{}",
+                tr!(
+                    "CONFLICT (%s): Merge conflict in %s",
+                    "content",
+                    path.display()
+                )
+            );
+        }
+        println!("{}", tr!("Applying autostash resulted in conflicts."));
+        println!("{}", tr!("Your changes are safe in the stash."));
+    }
+    result
+}
+
+/// Stashes the `selection` of the changes made to tracked files and records
+/// the stash in the stash list.
+pub fn push(
+    git_dir: &Path,
+    config: &Config,
+    message: Option<&str>,
+    selection: &Selection,
+) -> eyre::Result<()> {
+    if !is_dirty(git_dir)? {
+        println!("{}", tr!("No local changes to save"));
+        return Ok(());
+    }
+    let Some(stash) = create(git_dir, config, message, selection)? else {
+        return Err(eyre!(tr!("No changes selected")));
+    };
+    let summary = Commit::read(git_dir, &stash)?.summary().to_string();
+    store(git_dir, config, &stash, &summary)?;
+    println!(
+        "{}",
+        tr!("Saved working directory and index state %s", summary)
+    );
+    Ok(())
+}
+
+/// Returns the position in the stash list and the sha of the stash `name`,
+/// `stash@{n}` or just `n`, the latest stash by default.
+pub fn resolve(git_dir: &Path, name: Option<&str>) -> eyre::Result<(usize, String)> {
+    let entries = reflog::read(git_dir, "refs/stash")?;
+    if entries.is_empty() {
+        return Err(eyre!(tr!("No stash entries found.")));
+    }
+    let invalid = || eyre!(tr!("%s is not a valid reference", name.unwrap_or_default()));
+    let index = match name {
+        None => 0,
+        Some(name) => name
+            .strip_prefix("stash@{")
+            .and_then(|n| n.strip_suffix('}'))
+            .unwrap_or(name)
+            .parse::<usize>()
+            .map_err(|_| invalid())?,
+    };
+    let entry = entries.iter().rev().nth(index).ok_or_else(invalid)?;
+    Ok((index, entry.new.clone()))
+}
+
+/// Removes the stash at position `index` from the stash list.
+pub fn drop(git_dir: &Path, index: usize) -> eyre::Result<()> {
+    reflog::delete(git_dir, "refs/stash", index, true, true)
+}
+
+/// Creates the branch `name` at the commit the stash `stash` was created on,
+/// checks it out and applies the stash there, dropping it if it applies
+/// cleanly.
+pub fn branch(git_dir: &Path, name: &str, stash: Option<&str>) -> eyre::Result<()> {
+    let (index, sha) = resolve(git_dir, stash)?;
+    let branch = format!("refs/heads/{name}");
+    if refs::read_ref(git_dir, &branch)?.is_some() {
+        return Err(eyre!(tr!("a branch named '%s' already exists", name)));
+    }
+
+    let base = Commit::read(git_dir, &sha)?
+        .parents
+        .first()
+        .cloned()
+        .ok_or(eyre!("invalid stash commit"))?;
+    let (_, tracked, _) = worktree_state(git_dir)?;
+    let base_files = tree::flatten_tree(git_dir, &Commit::read(git_dir, &base)?.tree)?;
+    worktree::check_update(git_dir, &tracked, &base_files, Operation::Checkout)?;
+    worktree::checkout_files(git_dir, &tracked, &base_files, None)?;
+    refs::update_ref(git_dir, &branch, &base)?;
+    refs::set_symbolic_ref(git_dir, "HEAD", &branch)?;
+    println!("{}", tr!("Switched to a new branch '%s'", name));
+
+    let conflicts = apply(git_dir, &sha)?;
+    if !conflicts.is_empty() {
+        for path in &conflicts {
+            println!(
+                "{}",
+                tr!(
+                    "CONFLICT (%s): Merge conflict in %s",
+                    "content",
+                    path.display()
+                )
+            );
+        }
+        println!(
+            "{}",
+            tr!("The stash entry is kept in case you need it again.")
+        );
+        return Ok(());
+    }
+
+    drop(git_dir, index)?;
+    let name = match stash {
+        Some(name) if name.starts_with("stash@{") => name.to_string(),
+        _ => format!("refs/stash@{{{index}}}"),
+    };
+    println!("{}", tr!("Dropped %s (%s)", name, sha));
+    Ok(())
+}
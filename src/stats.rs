@@ -0,0 +1,219 @@
+use crate::git;
+use crate::mailmap::Mailmap;
+use crate::odb;
+use crate::pack::{self, ObjectInfo};
+use crate::refs;
+use crate::revwalk::RevWalk;
+use crate::tree::{TreeIter, MODE_TREE};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// How many of the largest blobs are listed.
+const LARGEST_BLOBS: usize = 10;
+/// How many of the most costly paths and directories are listed.
+const LARGEST_PATHS: usize = 20;
+
+/// Prints statistics about the size of the repository at `git_dir`: its
+/// history, its objects and its refs. With `blame_size`, prints which paths
+/// cost the most bytes across history instead.
+pub fn stats(git_dir: &Path, mailmap: &Mailmap, blame_size: bool) -> eyre::Result<()> {
+    let packs = pack::packs(git_dir)?;
+    let mut infos = Vec::new();
+    for sha in odb::objects(git_dir, &packs, true)? {
+        let info = odb::object_info(git_dir, &packs, &sha)?;
+        infos.push((sha, info));
+    }
+
+    // History reachable from the refs, annotated tags aside
+    let refs = refs::list_refs(git_dir, "refs/")?;
+    let kinds = infos
+        .iter()
+        .map(|(sha, info)| (sha.as_str(), info.kind))
+        .collect::<BTreeMap<_, _>>();
+    let tips = refs
+        .iter()
+        .map(|(_, sha)| sha.clone())
+        .chain(refs::read_ref(git_dir, "HEAD")?)
+        .filter(|sha| kinds.get(sha.as_str()) == Some(&"commit"))
+        .collect::<Vec<_>>();
+    let mut walk = RevWalk::new();
+    let commits = walk.list(git_dir, &tips, &[])?;
+    if blame_size {
+        let infos = infos.into_iter().collect::<HashMap<_, _>>();
+        return print_blame_size(git_dir, &mut walk, &commits, &infos);
+    }
+
+    let mut contributors = HashSet::new();
+    for sha in &commits {
+        let author = &walk.commit(git_dir, sha)?.author;
+        contributors.insert(mailmap.map(&author.name, &author.email));
+    }
+
+    println!("Commits:       {}", commits.len());
+    println!("Contributors:  {}", contributors.len());
+
+    println!();
+    println!("Objects:");
+    for kind in ["commit", "tree", "blob", "tag"] {
+        let of_kind = infos.iter().filter(|(_, info)| info.kind == kind);
+        let (count, size) = of_kind.fold((0, 0), |(count, size), (_, info)| {
+            (count + 1, size + info.size)
+        });
+        println!(
+            "  {:<8} {count:>8} {:>12}",
+            format!("{kind}s"),
+            format_size(size)
+        );
+    }
+
+    let mut blobs = infos
+        .iter()
+        .filter(|(_, info)| info.kind == "blob")
+        .collect::<Vec<_>>();
+    blobs.sort_by(|(a, a_info), (b, b_info)| b_info.size.cmp(&a_info.size).then(a.cmp(b)));
+    if !blobs.is_empty() {
+        println!();
+        println!("Largest blobs:");
+        for (sha, info) in blobs.into_iter().take(LARGEST_BLOBS) {
+            println!("  {sha} {:>12}", format_size(info.size));
+        }
+    }
+
+    let deepest = infos
+        .iter()
+        .max_by_key(|(sha, info)| (info.delta_depth, std::cmp::Reverse(sha)));
+    if let Some((sha, ObjectInfo { delta_depth, .. })) = deepest.filter(|(_, i)| i.delta_depth > 0)
+    {
+        println!();
+        println!("Deepest delta chain: {delta_depth} ({sha})");
+    }
+
+    println!();
+    println!("Refs:");
+    let mut counts = BTreeMap::new();
+    for (name, _) in &refs {
+        let kind = match name.split('/').nth(1) {
+            Some("heads") => "branches",
+            Some("tags") => "tags",
+            Some("remotes") => "remote-tracking",
+            _ => "other",
+        };
+        *counts.entry(kind).or_insert(0) += 1;
+    }
+    for kind in ["branches", "tags", "remote-tracking", "other"] {
+        println!("  {kind:<16} {:>8}", counts.get(kind).unwrap_or(&0));
+    }
+    Ok(())
+}
+
+/// Prints the paths and directories whose blobs take the most space across
+/// the history of `commits`. A blob counts for every path it appears at,
+/// but once per directory.
+fn print_blame_size(
+    git_dir: &Path,
+    walk: &mut RevWalk,
+    commits: &[String],
+    infos: &HashMap<String, ObjectInfo>,
+) -> eyre::Result<()> {
+    let mut seen = HashSet::new();
+    let mut paths = BTreeMap::new();
+    for sha in commits {
+        let tree = walk.commit(git_dir, sha)?.tree.clone();
+        collect_blobs(git_dir, &tree, PathBuf::new(), &mut seen, &mut paths)?;
+    }
+
+    // Directories hold the blobs of all their descendants
+    let mut dirs = BTreeMap::<PathBuf, HashSet<String>>::new();
+    for (path, blobs) in &paths {
+        for dir in path.ancestors().skip(1) {
+            dirs.entry(dir.to_path_buf())
+                .or_default()
+                .extend(blobs.iter().cloned());
+        }
+    }
+
+    println!("Size by path:");
+    print_sizes(&paths, infos);
+    println!();
+    println!("Size by directory:");
+    print_sizes(&dirs, infos);
+    Ok(())
+}
+
+/// Records the blobs of the tree `sha` under the paths they appear at. Trees
+/// already seen at the same path are skipped, as history mostly shares them.
+fn collect_blobs(
+    git_dir: &Path,
+    sha: &str,
+    prefix: PathBuf,
+    seen: &mut HashSet<(String, PathBuf)>,
+    paths: &mut BTreeMap<PathBuf, HashSet<String>>,
+) -> eyre::Result<()> {
+    if !seen.insert((sha.to_string(), prefix.clone())) {
+        return Ok(());
+    }
+    let (kind, content) = git::read_object(git_dir, sha)?;
+    if kind != "tree" {
+        return Ok(());
+    }
+    for entry in TreeIter::new(&content) {
+        let entry = entry?;
+        let path = prefix.join(entry.name);
+        let sha = entry.sha();
+        match entry.mode {
+            MODE_TREE => collect_blobs(git_dir, &sha, path, seen, paths)?,
+            _ => {
+                paths.entry(path).or_default().insert(sha);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints the largest entries of `blobs`, by the size their blobs take on
+/// disk.
+fn print_sizes(blobs: &BTreeMap<PathBuf, HashSet<String>>, infos: &HashMap<String, ObjectInfo>) {
+    let mut sizes = blobs
+        .iter()
+        .map(|(path, blobs)| {
+            // Submodule commits aren't objects of this repository
+            let infos = blobs.iter().filter_map(|sha| infos.get(sha));
+            let (disk, size) = infos.fold((0, 0), |(disk, size), info| {
+                (disk + info.disk_size, size + info.size)
+            });
+            (path, disk, size, blobs.len())
+        })
+        .collect::<Vec<_>>();
+    sizes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    println!(
+        "  {:>12} {:>12} {:>8}  path",
+        "on disk", "unpacked", "versions"
+    );
+    for (path, disk, size, versions) in sizes.into_iter().take(LARGEST_PATHS) {
+        let path = match path.as_os_str().is_empty() {
+            true => "<toplevel>".to_string(),
+            false => path.display().to_string(),
+        };
+        println!(
+            "  {:>12} {:>12} {versions:>8}  {path}",
+            format_size(disk),
+            format_size(size)
+        );
+    }
+}
+
+/// Formats a size in bytes with a binary unit, like `1.5 MiB`.
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
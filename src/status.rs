@@ -0,0 +1,493 @@
+use crate::commit::Commit;
+use crate::config::Config;
+use crate::exclude::Excludes;
+use crate::git;
+use crate::index::{self, Index};
+use crate::ref_filter;
+use crate::refs;
+use crate::repository;
+use crate::revwalk::RevWalk;
+use crate::tree::{self, Change, Files, MODE_GITLINK};
+use crate::worktree;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How the working tree and the index differ from `HEAD`.
+#[derive(Debug)]
+pub struct Status {
+    /// The commit `HEAD` points to, unless the branch is unborn.
+    pub head: Option<String>,
+    /// The changes staged in the index, from `HEAD`.
+    pub staged: Vec<Change>,
+    /// The files of `staged` added as the exact copy of a file deleted from
+    /// `HEAD`, which was renamed, with the path of that file.
+    pub renamed_from: BTreeMap<PathBuf, PathBuf>,
+    /// The paths with conflicts, with which of the base, ours and theirs
+    /// stages they have.
+    pub unmerged: BTreeMap<PathBuf, [bool; 3]>,
+    /// The changes of the tracked files of the working tree, from the index.
+    pub unstaged: Vec<Change>,
+    /// The files of the working tree that aren't in the index, directories
+    /// without tracked files being listed as a whole with a trailing `/`.
+    pub untracked: Vec<String>,
+}
+
+impl Status {
+    /// Compares `HEAD`, the index and the working tree.
+    pub fn read(git_dir: &Path, config: &Config) -> eyre::Result<Self> {
+        let head = refs::read_ref(git_dir, "HEAD")?;
+        let committed = match &head {
+            Some(head) => tree::flatten_tree(git_dir, &Commit::read(git_dir, head)?.tree)?,
+            None => Files::new(),
+        };
+        let index = Index::read(git_dir)?;
+        let staged_files = index.files();
+        let mut unmerged = BTreeMap::new();
+        for entry in index.entries.iter().filter(|e| e.stage() > 0) {
+            let stages = unmerged
+                .entry(PathBuf::from(&entry.path))
+                .or_insert([false; 3]);
+            stages[entry.stage() as usize - 1] = true;
+        }
+
+        // Files whose stat information didn't change since they were staged
+        // aren't read again. Files colliding with others by case are read
+        // where the checkout renamed them.
+        let renames = worktree::collision_renames(git_dir, config, &staged_files)?;
+        let mut worktree = Files::new();
+        for entry in index.entries.iter().filter(|e| e.stage() == 0) {
+            let path = PathBuf::from(&entry.path);
+            if let Some(renamed) = renames.get(&path) {
+                let name = renamed.to_string_lossy().replace('\\', "/");
+                let Ok(metadata) = fs::symlink_metadata(renamed) else {
+                    continue;
+                };
+                let (mode, content) =
+                    index::worktree_file(git_dir, config, &index, &name, &metadata)?;
+                worktree.insert(
+                    path,
+                    (mode, git::hash_object("blob", &content).0.to_string()),
+                );
+                continue;
+            }
+            // Submodules are compared by their own status, and files assumed
+            // unchanged or skipped aren't compared
+            if entry.mode == MODE_GITLINK || entry.ignores_worktree() {
+                worktree.insert(path, (entry.mode, entry.sha.clone()));
+                continue;
+            }
+            let Ok(metadata) = fs::symlink_metadata(&entry.path) else {
+                continue;
+            };
+            if metadata.is_dir() {
+                continue;
+            }
+            if index.is_up_to_date(entry, &metadata) {
+                worktree.insert(path, (entry.mode, entry.sha.clone()));
+                continue;
+            }
+            let (mode, content) =
+                index::worktree_file(git_dir, config, &index, &entry.path, &metadata)?;
+            worktree.insert(
+                path,
+                (mode, git::hash_object("blob", &content).0.to_string()),
+            );
+        }
+
+        // Untracked files are shown by the outermost directory without
+        // tracked files they are in
+        let tracked_directories = index
+            .entries
+            .iter()
+            .flat_map(|e| Path::new(&e.path).ancestors().skip(1))
+            .map(Path::to_path_buf)
+            .collect::<HashSet<_>>();
+        let mut files = Vec::new();
+        let mut excludes = Excludes::load(git_dir, config)?;
+        index::walk(Path::new("."), "", &mut excludes, &mut files)?;
+        let mut untracked = Vec::new();
+        let renamed = renames.values().collect::<HashSet<_>>();
+        for file in files {
+            if staged_files.contains_key(Path::new(&file))
+                || unmerged.contains_key(Path::new(&file))
+                || renamed.contains(&PathBuf::from(&file))
+            {
+                continue;
+            }
+            let outermost = Path::new(&file)
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| {
+                    !dir.as_os_str().is_empty() && !tracked_directories.contains(*dir)
+                })
+                .last();
+            untracked.push(match outermost {
+                Some(dir) => format!("{}/", dir.to_string_lossy()),
+                None => file,
+            });
+        }
+        untracked.sort();
+        untracked.dedup();
+
+        // Conflicted paths only show as unmerged
+        let mut staged = tree::diff_files(&committed, &staged_files);
+        staged.retain(|change| !unmerged.contains_key(&change.path));
+        let renamed_from = find_renames(&mut staged);
+        Ok(Self {
+            head,
+            staged,
+            renamed_from,
+            unmerged,
+            unstaged: tree::diff_files(&staged_files, &worktree),
+            untracked,
+        })
+    }
+}
+
+/// Pairs the added files of `changes` with the deleted files of the same
+/// content, and removes these deletions, returning the paths the added files
+/// were renamed from. Only exact renames are found.
+fn find_renames(changes: &mut Vec<Change>) -> BTreeMap<PathBuf, PathBuf> {
+    let mut deleted = BTreeMap::new();
+    for change in changes.iter() {
+        if let (Some((_, sha)), None) = (&change.old, &change.new) {
+            deleted.entry(sha.clone()).or_insert(change.path.clone());
+        }
+    }
+    let mut renamed_from = BTreeMap::new();
+    for change in changes.iter() {
+        if let (None, Some((_, sha))) = (&change.old, &change.new) {
+            if let Some(from) = deleted.remove(sha) {
+                renamed_from.insert(change.path.clone(), from);
+            }
+        }
+    }
+    let sources = renamed_from.values().collect::<HashSet<_>>();
+    changes.retain(|change| change.new.is_some() || !sources.contains(&change.path));
+    renamed_from
+}
+
+/// Prints `status` the way `git status` does by default, with paths
+/// relative to the directory `prefix` of the working tree.
+pub fn print_long(
+    git_dir: &Path,
+    config: &Config,
+    status: &Status,
+    prefix: &Path,
+) -> eyre::Result<()> {
+    let branch = refs::symbolic_target(git_dir, "HEAD")?;
+    match (&branch, &status.head) {
+        (Some(branch), _) => {
+            let name = branch.strip_prefix("refs/heads/").unwrap_or(branch);
+            println!("{}", tr!("On branch %s", name));
+            print_tracking(git_dir, config, name, status.head.as_deref())?;
+        }
+        (None, Some(head)) => println!("{}", tr!("HEAD detached at %s", &head[..7])),
+        (None, None) => {}
+    }
+    if status.head.is_none() {
+        println!();
+        println!("{}", tr!("No commits yet"));
+        println!();
+    }
+
+    let relative = |path: &Path| repository::relative_path(&path.to_string_lossy(), prefix);
+    if !status.staged.is_empty() {
+        println!("{}", tr!("Changes to be committed:"));
+        match status.head {
+            Some(_) => println!(
+                "{}",
+                tr!("  (use \"git restore --staged <file>...\" to unstage)")
+            ),
+            None => println!(
+                "{}",
+                tr!("  (use \"git rm --cached <file>...\" to unstage)")
+            ),
+        }
+        for change in &status.staged {
+            match status.renamed_from.get(&change.path) {
+                Some(from) => println!(
+                    "\t{:<12}{} -> {}",
+                    tr!("renamed:"),
+                    relative(from),
+                    relative(&change.path)
+                ),
+                None => println!("\t{:<12}{}", label(change), relative(&change.path)),
+            }
+        }
+        println!();
+    }
+    if !status.unmerged.is_empty() {
+        println!("{}", tr!("Unmerged paths:"));
+        println!(
+            "{}",
+            tr!("  (use \"git add <file>...\" to mark resolution)")
+        );
+        for (path, stages) in &status.unmerged {
+            println!("\t{:<17}{}", conflict_label(stages), relative(path));
+        }
+        println!();
+    }
+    if !status.unstaged.is_empty() {
+        println!("{}", tr!("Changes not staged for commit:"));
+        match status.unstaged.iter().any(|c| c.new.is_none()) {
+            true => println!(
+                "{}",
+                tr!("  (use \"git add/rm <file>...\" to update what will be committed)")
+            ),
+            false => println!(
+                "{}",
+                tr!("  (use \"git add <file>...\" to update what will be committed)")
+            ),
+        }
+        println!(
+            "{}",
+            tr!("  (use \"git restore <file>...\" to discard changes in working directory)")
+        );
+        for change in &status.unstaged {
+            println!("\t{:<12}{}", label(change), relative(&change.path));
+        }
+        println!();
+    }
+    if !status.untracked.is_empty() {
+        println!("{}", tr!("Untracked files:"));
+        println!(
+            "{}",
+            tr!("  (use \"git add <file>...\" to include in what will be committed)")
+        );
+        for path in &status.untracked {
+            let mut shown = repository::relative_path(path, prefix);
+            if path.ends_with('/') && !shown.ends_with('/') {
+                shown.push('/');
+            }
+            println!("\t{shown}");
+        }
+        println!();
+    }
+
+    if !status.staged.is_empty() || !status.unmerged.is_empty() {
+        return Ok(());
+    }
+    let summary = match (status.unstaged.is_empty(), status.untracked.is_empty()) {
+        (false, _) => tr!("no changes added to commit (use \"git add\" and/or \"git commit -a\")"),
+        (true, false) => {
+            tr!("nothing added to commit but untracked files present (use \"git add\" to track)")
+        }
+        (true, true) if status.head.is_none() => {
+            tr!("nothing to commit (create/copy files and use \"git add\" to track)")
+        }
+        (true, true) => tr!("nothing to commit, working tree clean"),
+    };
+    println!("{summary}");
+    Ok(())
+}
+
+/// Prints `status` in the short format of `git status -s`: a line per path
+/// with its two-letter code, the staged change then the unstaged one, with
+/// paths relative to the directory `prefix`. With `branch`, a `##` header
+/// line names the branch and how it compares with its upstream.
+pub fn print_short(
+    git_dir: &Path,
+    config: &Config,
+    status: &Status,
+    prefix: &Path,
+    branch: bool,
+) -> eyre::Result<()> {
+    if branch {
+        println!(
+            "## {}",
+            branch_header(git_dir, config, status.head.as_deref())?
+        );
+    }
+
+    let relative = |path: &Path| repository::relative_path(&path.to_string_lossy(), prefix);
+    let mut lines = BTreeMap::new();
+    for (path, stages) in &status.unmerged {
+        lines.insert(
+            path,
+            format!("{} {}", conflict_code(stages), relative(path)),
+        );
+    }
+    for change in &status.staged {
+        let line = match status.renamed_from.get(&change.path) {
+            Some(from) => format!("R  {} -> {}", relative(from), relative(&change.path)),
+            None => format!("{}  {}", change.status(), relative(&change.path)),
+        };
+        lines.insert(&change.path, line);
+    }
+    for change in &status.unstaged {
+        let code = change.status();
+        let line = match lines.remove(&change.path) {
+            // The second letter of the staged change's line is a space
+            Some(staged) => format!("{}{code}{}", &staged[..1], &staged[2..]),
+            None => format!(" {code} {}", relative(&change.path)),
+        };
+        lines.insert(&change.path, line);
+    }
+    for line in lines.values() {
+        println!("{line}");
+    }
+    for path in &status.untracked {
+        let mut shown = repository::relative_path(path, prefix);
+        if path.ends_with('/') && !shown.ends_with('/') {
+            shown.push('/');
+        }
+        println!("?? {shown}");
+    }
+    Ok(())
+}
+
+/// Returns the `##` header of the short format, without the `## `: the
+/// branch, `HEAD (no branch)` if detached, then its upstream and how far
+/// ahead and behind it the branch is.
+fn branch_header(git_dir: &Path, config: &Config, head: Option<&str>) -> eyre::Result<String> {
+    let Some(branch) = refs::symbolic_target(git_dir, "HEAD")? else {
+        return Ok(tr!("HEAD (no branch)"));
+    };
+    let name = branch.strip_prefix("refs/heads/").unwrap_or(&branch);
+    let mut header = match head {
+        Some(_) => name.to_string(),
+        None => tr!("No commits yet on %s", name),
+    };
+    let Some(upstream) = ref_filter::upstream(config, name) else {
+        return Ok(header);
+    };
+    let tip = refs::read_ref(git_dir, &upstream)?;
+    header.push_str(&format!(
+        "...{}",
+        ref_filter::upstream_name(git_dir, &upstream, tip.is_some())?
+    ));
+    let (Some(head), Some(tip)) = (head, tip) else {
+        header.push_str(&format!(" [{}]", tr!("gone")));
+        return Ok(header);
+    };
+    let counts = match RevWalk::new().ahead_behind(git_dir, head, &tip)? {
+        (0, 0) => return Ok(header),
+        (ahead, 0) => tr!("ahead %d", ahead),
+        (0, behind) => tr!("behind %d", behind),
+        (ahead, behind) => tr!("ahead %d, behind %d", ahead, behind),
+    };
+    header.push_str(&format!(" [{counts}]"));
+    Ok(header)
+}
+
+/// Prints how the branch `name`, at `head`, compares with its upstream.
+fn print_tracking(
+    git_dir: &Path,
+    config: &Config,
+    name: &str,
+    head: Option<&str>,
+) -> eyre::Result<()> {
+    let Some(upstream) = ref_filter::upstream(config, name) else {
+        return Ok(());
+    };
+    let tip = refs::read_ref(git_dir, &upstream)?;
+    let short = ref_filter::upstream_name(git_dir, &upstream, tip.is_some())?;
+    let (Some(head), Some(tip)) = (head, tip) else {
+        println!(
+            "{}",
+            tr!(
+                "Your branch is based on '%s', but the upstream is gone.",
+                &short
+            )
+        );
+        println!(
+            "{}",
+            tr!("  (use \"git branch --unset-upstream\" to fixup)")
+        );
+        println!();
+        return Ok(());
+    };
+
+    let commits = |count: usize| match count {
+        1 => tr!("%d commit", 1),
+        count => tr!("%d commits", count),
+    };
+    match RevWalk::new().ahead_behind(git_dir, head, &tip)? {
+        (0, 0) => println!("{}", tr!("Your branch is up to date with '%s'.", &short)),
+        (ahead, 0) => {
+            println!(
+                "{}",
+                tr!(
+                    "Your branch is ahead of '%s' by %s.",
+                    &short,
+                    commits(ahead)
+                )
+            );
+            println!(
+                "{}",
+                tr!("  (use \"git push\" to publish your local commits)")
+            );
+        }
+        (0, behind) => {
+            println!(
+                "{}",
+                tr!(
+                    "Your branch is behind '%s' by %s, and can be fast-forwarded.",
+                    &short,
+                    commits(behind)
+                )
+            );
+            println!(
+                "{}",
+                tr!("  (use \"git pull\" to update your local branch)")
+            );
+        }
+        (ahead, behind) => {
+            println!("{}", tr!("Your branch and '%s' have diverged,", &short));
+            println!(
+                "{}",
+                tr!(
+                    "and have %d and %d different commits each, respectively.",
+                    ahead,
+                    behind
+                )
+            );
+            println!(
+                "{}",
+                tr!("  (use \"git pull\" to merge the remote branch into yours)")
+            );
+        }
+    }
+    println!();
+    Ok(())
+}
+
+/// Returns the label of a change in the long format.
+fn label(change: &Change) -> String {
+    match change.status() {
+        'A' => tr!("new file:"),
+        'D' => tr!("deleted:"),
+        'T' => tr!("typechange:"),
+        _ => tr!("modified:"),
+    }
+}
+
+/// Returns the two-letter code of a conflict in the short format, from which
+/// of the base, ours and theirs stages the path has: `D` where a side
+/// deleted the file, `A` where it added it, `U` where it's unmerged.
+fn conflict_code(stages: &[bool; 3]) -> &'static str {
+    match stages {
+        [true, false, false] => "DD",
+        [false, true, false] => "AU",
+        [true, true, false] => "UD",
+        [false, false, true] => "UA",
+        [true, false, true] => "DU",
+        [false, true, true] => "AA",
+        _ => "UU",
+    }
+}
+
+/// Returns the label of a conflict in the long format.
+fn conflict_label(stages: &[bool; 3]) -> String {
+    match conflict_code(stages) {
+        "DD" => tr!("both deleted:"),
+        "AU" => tr!("added by us:"),
+        "UD" => tr!("deleted by them:"),
+        "UA" => tr!("added by them:"),
+        "DU" => tr!("deleted by us:"),
+        "AA" => tr!("both added:"),
+        _ => tr!("both modified:"),
+    }
+}
@@ -0,0 +1,277 @@
+use crate::commit::{self, Signature};
+use crate::config::Config;
+use crate::git;
+use crate::odb::Odb;
+use crate::refs;
+use crate::revision;
+use crate::revwalk::RevWalk;
+use crate::wildmatch::wildmatch;
+use eyre::eyre;
+use std::cmp::Ordering;
+use std::path::Path;
+
+/// A parsed tag object, naming an object with a message.
+#[derive(Debug, Clone)]
+pub struct Tag {
+    /// The sha of the tagged object.
+    pub object: String,
+    /// The type of the tagged object.
+    pub kind: String,
+    /// The name of the tag.
+    pub name: String,
+    /// Who created the tag and when. Very old tags have none.
+    pub tagger: Option<Signature>,
+    /// The message, followed by the signature of signed tags.
+    pub message: String,
+}
+
+impl Tag {
+    /// Parses the content of a tag object (without the object header).
+    pub fn parse(content: &[u8]) -> eyre::Result<Self> {
+        let content = String::from_utf8_lossy(content);
+        let (headers, message) = content.split_once("\n\n").unwrap_or((&content, ""));
+
+        let (mut object, mut kind, mut name, mut tagger) = (None, None, None, None);
+        for line in headers.lines() {
+            let Some((key, value)) = line.split_once(' ') else {
+                continue;
+            };
+            match key {
+                "object" => object = Some(value.to_string()),
+                "type" => kind = Some(value.to_string()),
+                "tag" => name = Some(value.to_string()),
+                "tagger" => tagger = Some(Signature::parse(value)?),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            object: object.ok_or(eyre!("missing object in tag"))?,
+            kind: kind.ok_or(eyre!("missing type in tag"))?,
+            name: name.ok_or(eyre!("missing tag name in tag"))?,
+            tagger,
+            message: message.to_string(),
+        })
+    }
+
+    /// Returns the content of the tag object (without the object header).
+    pub fn serialize(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    /// Writes the tag to the object database and returns its sha.
+    pub fn write(&self, git_dir: &Path) -> eyre::Result<String> {
+        Odb::new(git_dir)
+            .write("tag", &self.serialize())
+            .map(String::from)
+    }
+}
+
+impl std::fmt::Display for Tag {
+    /// Formats the content of the tag object as git prints it.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "object {}", self.object)?;
+        writeln!(f, "type {}", self.kind)?;
+        writeln!(f, "tag {}", self.name)?;
+        if let Some(tagger) = &self.tagger {
+            writeln!(f, "tagger {tagger}")?;
+        }
+        writeln!(f)?;
+        f.write_str(&self.message)
+    }
+}
+
+/// Points `refs/tags/<name>` at the object `target`, a lightweight tag.
+/// Existing tags are only replaced with `force`.
+pub fn create(git_dir: &Path, name: &str, target: &str, force: bool) -> eyre::Result<()> {
+    let reference = tag_ref(git_dir, name, force)?;
+    refs::update_ref(git_dir, &reference, target)
+}
+
+/// Creates the tag object `name` pointing at the object `target` with
+/// `message`, tagged by the current user, and points `refs/tags/<name>` at
+/// it. Existing tags are only replaced with `force`.
+pub fn create_annotated(
+    git_dir: &Path,
+    config: &Config,
+    name: &str,
+    target: &str,
+    message: &str,
+    force: bool,
+) -> eyre::Result<String> {
+    let reference = tag_ref(git_dir, name, force)?;
+    let message = commit::cleanup_message(message);
+    if message.is_empty() {
+        return Err(eyre!(tr!("no tag message?")));
+    }
+
+    let (kind, _) = git::read_object(git_dir, target)?;
+    let tag = Tag {
+        object: target.to_string(),
+        kind,
+        name: name.to_string(),
+        tagger: Some(Signature::now(config, "COMMITTER")?),
+        message,
+    };
+    let sha = tag.write(git_dir)?;
+    refs::update_ref(git_dir, &reference, &sha)?;
+    Ok(sha)
+}
+
+/// Returns the ref of the new tag `name`, failing if the name is invalid, or
+/// if the tag exists unless it's replaced with `force`.
+fn tag_ref(git_dir: &Path, name: &str, force: bool) -> eyre::Result<String> {
+    let reference = format!("refs/tags/{name}");
+    if !refs::is_valid_name(&reference) {
+        return Err(eyre!(tr!("'%s' is not a valid tag name.", name)));
+    }
+    if !force && refs::read_ref(git_dir, &reference)?.is_some() {
+        return Err(eyre!(tr!("tag '%s' already exists", name)));
+    }
+    Ok(reference)
+}
+
+/// Which tags `tag -l` lists, and in which order.
+#[derive(Debug, Default)]
+pub struct ListOptions {
+    /// Globs the names must match one of, every tag matching without any.
+    pub patterns: Vec<String>,
+    /// Sort keys, like `refname` or `-v:refname`, the last one deciding
+    /// first, those of `tag.sort` if empty.
+    pub sort: Vec<String>,
+    /// Only lists the tags of commits from which this commit is reachable.
+    pub contains: Option<String>,
+    /// Only lists the tags of this object.
+    pub points_at: Option<String>,
+}
+
+/// Returns the names of the tags selected by `options`, in order.
+pub fn list(git_dir: &Path, config: &Config, options: &ListOptions) -> eyre::Result<Vec<String>> {
+    let mut tags = refs::list_refs(git_dir, "refs/tags/")?
+        .into_iter()
+        .map(|(name, sha)| (name["refs/tags/".len()..].to_string(), sha))
+        .filter(|(name, _)| {
+            options.patterns.is_empty()
+                || options
+                    .patterns
+                    .iter()
+                    .any(|pattern| wildmatch(pattern.as_bytes(), name.as_bytes(), false))
+        })
+        .collect::<Vec<_>>();
+
+    // Annotated tags point at the object of the tag object
+    if let Some(object) = &options.points_at {
+        let object = revision::resolve(git_dir, object)?;
+        let mut kept = Vec::new();
+        for (name, sha) in tags {
+            if sha == object || tag_target(git_dir, &sha)?.as_deref() == Some(object.as_str()) {
+                kept.push((name, sha));
+            }
+        }
+        tags = kept;
+    }
+
+    if let Some(commit) = &options.contains {
+        let commit = revision::peel(git_dir, &revision::resolve(git_dir, commit)?)?;
+        let mut peeled = Vec::new();
+        for (name, sha) in tags {
+            let target = revision::peel(git_dir, &sha)?;
+            if git::read_object(git_dir, &target)?.0 == "commit" {
+                peeled.push((name, sha, target));
+            }
+        }
+        let tips = peeled.iter().map(|(_, _, t)| t.clone()).collect::<Vec<_>>();
+        let contained = RevWalk::new().contains(git_dir, &tips, &commit)?;
+        tags = peeled
+            .into_iter()
+            .zip(contained)
+            .filter(|(_, contained)| *contained)
+            .map(|((name, sha, _), _)| (name, sha))
+            .collect();
+    }
+
+    let mut names = tags.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+    let sort = match options.sort.is_empty() {
+        true => config
+            .get_all("tag.sort")
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+        false => options.sort.clone(),
+    };
+    let mut keys = Vec::new();
+    for key in &sort {
+        let (reverse, key) = match key.strip_prefix('-') {
+            Some(key) => (true, key),
+            None => (false, key.as_str()),
+        };
+        let compare: fn(&str, &str) -> Ordering = match key {
+            "refname" => |a, b| a.cmp(b),
+            "v:refname" | "version:refname" => version_cmp,
+            _ => return Err(eyre!(tr!("unsupported sort specification '%s'", key))),
+        };
+        keys.push((reverse, compare));
+    }
+    // Names break ties, and the last key is the one deciding first
+    names.sort_by(|a, b| {
+        keys.iter()
+            .rev()
+            .map(|(reverse, compare)| match reverse {
+                true => compare(b, a),
+                false => compare(a, b),
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(a.cmp(b))
+    });
+    Ok(names)
+}
+
+/// Returns the object a tag object points at, or `None` if `sha` isn't a
+/// tag object.
+fn tag_target(git_dir: &Path, sha: &str) -> eyre::Result<Option<String>> {
+    let (kind, content) = git::read_object(git_dir, sha)?;
+    if kind != "tag" {
+        return Ok(None);
+    }
+    Ok(Some(Tag::parse(&content)?.object))
+}
+
+/// Compares names as versions: runs of digits compare as numbers, so that
+/// `v1.10` comes after `v1.9` and `v1.02` before `v1.2`.
+fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+                let (x_len, y_len) = (digits(a), digits(b));
+                let x_num = trim_zeros(&a[..x_len]);
+                let y_num = trim_zeros(&b[..y_len]);
+                // Leading zeros make a number smaller, like a fraction
+                let ordering = x_num
+                    .len()
+                    .cmp(&y_num.len())
+                    .then(x_num.cmp(y_num))
+                    .then(y_len.cmp(&x_len));
+                if ordering.is_ne() {
+                    return ordering;
+                }
+                a = &a[x_len..];
+                b = &b[y_len..];
+            }
+            (Some(x), Some(y)) if x != y => return x.cmp(y),
+            _ => {
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|c| **c == b'0').count();
+    &digits[zeros..]
+}
@@ -0,0 +1,75 @@
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+/// The files being written before being renamed in place, like lock files
+/// and temporary objects, which are removed when the process is killed.
+fn registry() -> &'static Mutex<Vec<PathBuf>> {
+    static FILES: OnceLock<Mutex<Vec<PathBuf>>> = OnceLock::new();
+    FILES.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// A file in progress, removed if the process is interrupted or terminated
+/// before the guard is dropped, once the file was renamed or removed.
+pub struct Temporary {
+    path: PathBuf,
+}
+
+impl Temporary {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        registry()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(path.clone());
+        Self { path }
+    }
+
+    /// Creates the file at `path`, failing if it exists, like a lock file.
+    /// It's registered as it's created, so that a signal can't come in
+    /// between and leave it behind.
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<(Self, fs::File)> {
+        let path = path.as_ref().to_path_buf();
+        let mut files = registry().lock().unwrap_or_else(|e| e.into_inner());
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        files.push(path.clone());
+        Ok((Self { path }, file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Temporary {
+    fn drop(&mut self) {
+        let mut files = registry().lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = files.iter().rposition(|path| *path == self.path) {
+            files.swap_remove(i);
+        }
+    }
+}
+
+/// Handles SIGINT and SIGTERM by removing the files in progress, so that
+/// an interrupted command doesn't leave `.git/index.lock` behind, then
+/// dying of the signal as if it wasn't handled.
+pub fn install() -> eyre::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    std::thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            // The lock is kept so that no file is registered afterwards
+            let files = registry().lock().unwrap_or_else(|e| e.into_inner());
+            for path in files.iter() {
+                let _ = fs::remove_file(path);
+            }
+            let _ = signal_hook::low_level::emulate_default_handler(signal);
+            std::process::exit(128 + signal);
+        }
+    });
+    Ok(())
+}
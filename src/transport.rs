@@ -0,0 +1,214 @@
+use crate::config::Config;
+use crate::daemon;
+use crate::http;
+use crate::pktline;
+use crate::ssh;
+use eyre::eyre;
+use std::io::Read;
+use std::path::Path;
+
+/// The `agent` capability clients send.
+pub const AGENT: &str = concat!(
+    "agent=",
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION")
+);
+
+/// Returns true if `url` names a repository reached through a transport,
+/// rather than one on the local filesystem.
+pub fn is_remote(url: &str) -> bool {
+    http::is_http(url) || daemon::is_git(url) || ssh::is_ssh(url)
+}
+
+/// Opens the transport to the repository at `url`.
+pub fn open<'a>(config: &'a Config, url: &str) -> eyre::Result<Box<dyn Transport + 'a>> {
+    if http::is_http(url) {
+        Ok(Box::new(http::Client::new(config, url)?))
+    } else if daemon::is_git(url) {
+        Ok(Box::new(daemon::Connection::new(config, url)?))
+    } else if ssh::is_ssh(url) {
+        Ok(Box::new(ssh::Connection::new(config, url)?))
+    } else {
+        Err(eyre!(tr!("unsupported protocol in url %s", url)))
+    }
+}
+
+/// Returns the version of the protocol asked for with `protocol.version`,
+/// 2 by default, which only fetches have.
+pub fn version(config: &Config) -> eyre::Result<u32> {
+    match config.get("protocol.version") {
+        Some(version) => Ok(version.parse()?),
+        None => Ok(2),
+    }
+}
+
+/// The refs and capabilities a server advertises at the start of the
+/// protocol.
+#[derive(Debug, Default)]
+pub struct Refs {
+    /// The version of the protocol the server speaks, 0 or 2.
+    pub version: u32,
+    /// The `(name, sha)` pairs of the refs, `HEAD` included, in the order
+    /// of the advertisement. Peeled tags are left out.
+    pub refs: Vec<(String, String)>,
+    /// The `(name, sha)` pairs of the annotated tags among the refs, with
+    /// the sha of the object they peel to.
+    pub peeled: Vec<(String, String)>,
+    /// The `(name, target)` pairs of the symbolic refs among them.
+    pub symrefs: Vec<(String, String)>,
+    pub capabilities: Vec<String>,
+    /// Whether the server only serves the files of the repository, without
+    /// the smart protocol.
+    pub dumb: bool,
+}
+
+impl Refs {
+    /// Returns true if the server advertised `capability`.
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Returns true if the server advertised `feature` for the version 2
+    /// `command`, like `shallow` with `fetch=shallow`.
+    pub fn has_feature(&self, command: &str, feature: &str) -> bool {
+        self.capabilities.iter().any(|c| {
+            c.strip_prefix(command)
+                .and_then(|c| c.strip_prefix('='))
+                .is_some_and(|features| features.split(' ').any(|f| f == feature))
+        })
+    }
+
+    /// Returns the ref the symbolic ref `name` points to.
+    pub fn symref(&self, name: &str) -> Option<&str> {
+        self.symrefs
+            .iter()
+            .find(|(symref, _)| symref == name)
+            .map(|(_, target)| target.as_str())
+    }
+}
+
+/// The way to the services of a remote repository, like `git-upload-pack`,
+/// which exchange pkt-lines with the client.
+pub trait Transport {
+    /// Starts `service` and returns what it advertises: its refs and
+    /// capabilities, or only its capabilities in version 2 of the protocol.
+    fn connect(&mut self, service: &str) -> eyre::Result<Refs>;
+
+    /// Sends `request` to the started `service` and returns its response.
+    fn request(&mut self, service: &str, request: &[u8]) -> eyre::Result<Box<dyn Read + '_>>;
+
+    /// Returns true if each request is answered on its own, the server
+    /// forgetting the previous ones, rather than over a single connection.
+    fn is_stateless(&self) -> bool;
+
+    /// Downloads the objects reachable from `wants` the local repository is
+    /// missing from a server without the smart protocol, which only HTTP
+    /// servers can be.
+    fn download(&mut self, _git_dir: &Path, _wants: &[String]) -> eyre::Result<()> {
+        Err(eyre!("the transport has no dumb protocol"))
+    }
+
+    /// Returns the refs and capabilities `service` advertises. Servers
+    /// speaking version 2 of the protocol only list the refs starting with
+    /// one of `prefixes`, or all of them without any.
+    fn advertisement(&mut self, service: &str, prefixes: &[String]) -> eyre::Result<Refs> {
+        let mut refs = self.connect(service)?;
+        if refs.version == 2 {
+            ls_refs(self, &mut refs, prefixes)?;
+        }
+        Ok(refs)
+    }
+}
+
+/// Reads an advertisement from `reader`, whose first line was `line`: the
+/// capabilities of version 2, or the refs of version 0 and 1.
+pub fn read_refs(
+    reader: &mut pktline::Reader<impl Read>,
+    line: Option<String>,
+) -> eyre::Result<Refs> {
+    let mut refs = Refs::default();
+    if line.as_deref() == Some("version 2") {
+        refs.version = 2;
+        while let Some(capability) = reader.read_line()? {
+            refs.capabilities.push(capability);
+        }
+        return Ok(refs);
+    }
+
+    // Version 1 announces itself before the refs of version 0
+    let mut next = match line.as_deref() {
+        Some("version 1") => reader.read_line()?,
+        _ => line,
+    };
+    while let Some(line) = next.take() {
+        next = reader.read_line()?;
+        // The capabilities follow the first ref after a NUL
+        let line = match line.split_once('\0') {
+            Some((line, capabilities)) => {
+                refs.capabilities = capabilities.split(' ').map(str::to_string).collect();
+                refs.symrefs = refs
+                    .capabilities
+                    .iter()
+                    .filter_map(|c| c.strip_prefix("symref=")?.split_once(':'))
+                    .map(|(name, target)| (name.to_string(), target.to_string()))
+                    .collect();
+                line.to_string()
+            }
+            None => line,
+        };
+        let (sha, name) = line
+            .split_once(' ')
+            .ok_or(eyre!(tr!("invalid ref advertisement line '%s'", &line)))?;
+        // Peeled tags aren't refs, nor the placeholder repositories
+        // without refs advertise their capabilities with
+        if let Some(tag) = name.strip_suffix("^{}") {
+            if tag != "capabilities" {
+                refs.peeled.push((tag.to_string(), sha.to_string()));
+            }
+            continue;
+        }
+        refs.refs.push((name.to_string(), sha.to_string()));
+    }
+    Ok(refs)
+}
+
+/// Lists the refs of a server speaking version 2 into `refs` with the
+/// `ls-refs` command, the ones starting with one of `prefixes` if any.
+fn ls_refs<T: Transport + ?Sized>(
+    transport: &mut T,
+    refs: &mut Refs,
+    prefixes: &[String],
+) -> eyre::Result<()> {
+    let mut request = pktline::Writer::new(Vec::new());
+    request.write_line("command=ls-refs")?;
+    request.write_line(AGENT)?;
+    request.write_delim()?;
+    request.write_line("symrefs")?;
+    request.write_line("peel")?;
+    for prefix in prefixes {
+        request.write_line(&format!("ref-prefix {prefix}"))?;
+    }
+    request.write_flush()?;
+
+    let response = transport.request("git-upload-pack", &request.into_inner())?;
+    let mut reader = pktline::Reader::new(response);
+    // Lines are `<sha> <name>` followed by attributes like
+    // `symref-target:<ref>` and `peeled:<sha>`
+    while let Some(line) = reader.read_line()? {
+        let mut words = line.split(' ');
+        let (Some(sha), Some(name)) = (words.next(), words.next()) else {
+            return Err(eyre!(tr!("invalid ls-refs response: %s", &line)));
+        };
+        for attribute in words {
+            if let Some(target) = attribute.strip_prefix("symref-target:") {
+                refs.symrefs.push((name.to_string(), target.to_string()));
+            }
+            if let Some(peeled) = attribute.strip_prefix("peeled:") {
+                refs.peeled.push((name.to_string(), peeled.to_string()));
+            }
+        }
+        refs.refs.push((name.to_string(), sha.to_string()));
+    }
+    Ok(())
+}
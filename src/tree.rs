@@ -0,0 +1,461 @@
+use crate::commit::Commit;
+use crate::git::{self, GitFile, GitFileContent, TreeContent};
+use crate::odb::Odb;
+use crate::revision;
+use eyre::eyre;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+/// Mode of a directory entry in a tree.
+pub const MODE_TREE: u32 = 40000;
+/// Mode of a regular file in a tree.
+pub const MODE_FILE: u32 = 100644;
+/// Mode of an executable file in a tree.
+pub const MODE_EXECUTABLE: u32 = 100755;
+/// Mode of a symbolic link in a tree.
+pub const MODE_SYMLINK: u32 = 120000;
+/// Mode of a submodule commit in a tree.
+pub const MODE_GITLINK: u32 = 160000;
+
+/// The files of a tree, keyed by their path relative to the root of the
+/// tree, with their mode and blob sha.
+pub type Files = BTreeMap<PathBuf, (u32, String)>;
+
+/// Returns the type of the objects of tree entries with the given mode.
+pub fn kind_of(mode: u32) -> &'static str {
+    match mode {
+        MODE_TREE => "tree",
+        MODE_GITLINK => "commit",
+        _ => "blob",
+    }
+}
+
+/// An entry of a tree, borrowed from the content of the tree object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeEntry<'a> {
+    pub mode: u32,
+    pub name: &'a str,
+    /// The raw 20 bytes of the sha of the entry.
+    pub oid: &'a [u8],
+}
+
+impl TreeEntry<'_> {
+    /// Returns the sha of the entry, hex encoded.
+    pub fn sha(&self) -> String {
+        hex::encode(self.oid)
+    }
+}
+
+/// Iterates over the entries of the content of a tree object, header
+/// excluded, without copying their names or shas.
+#[derive(Debug, Clone)]
+pub struct TreeIter<'a> {
+    content: &'a [u8],
+}
+
+impl<'a> TreeIter<'a> {
+    pub fn new(content: &'a [u8]) -> Self {
+        Self { content }
+    }
+
+    fn parse_entry(&mut self) -> eyre::Result<TreeEntry<'a>> {
+        // Tree files are split into MODE NAME\0SHA-1
+        let malformed = || eyre!("malformed tree entry");
+        let space = self.content.iter().position(|&b| b == b' ');
+        let space = space.ok_or_else(malformed)?;
+        let zero_byte = self.content[space..].iter().position(|&b| b == 0);
+        let zero_byte = space + zero_byte.ok_or_else(malformed)?;
+        let end = zero_byte + 1 + 20;
+        if end > self.content.len() {
+            return Err(malformed());
+        }
+
+        let mode = &self.content[..space];
+        if mode.is_empty() || !mode.iter().all(u8::is_ascii_digit) {
+            return Err(malformed());
+        }
+        let mode = mode.iter().fold(0u32, |mode, &digit| {
+            mode.wrapping_mul(10).wrapping_add((digit - b'0') as u32)
+        });
+        let entry = TreeEntry {
+            mode,
+            name: std::str::from_utf8(&self.content[space + 1..zero_byte])?,
+            oid: &self.content[zero_byte + 1..end],
+        };
+        self.content = &self.content[end..];
+        Ok(entry)
+    }
+}
+
+impl<'a> Iterator for TreeIter<'a> {
+    type Item = eyre::Result<TreeEntry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.content.is_empty() {
+            return None;
+        }
+        let entry = self.parse_entry();
+        // Nothing past a malformed entry can be parsed
+        if entry.is_err() {
+            self.content = &[];
+        }
+        Some(entry)
+    }
+}
+
+/// Returns the content of the tree object `sha`, to iterate over with
+/// [`TreeIter`].
+pub fn read_raw_tree(git_dir: &Path, sha: &str) -> eyre::Result<Vec<u8>> {
+    match git::read_object(git_dir, sha)? {
+        (kind, content) if kind == "tree" => Ok(content),
+        _ => Err(eyre!("object {sha} is not a tree")),
+    }
+}
+
+/// Returns true if `path` is one of the paths of `pathspec` or under one of
+/// them. An empty pathspec matches every path.
+pub fn pathspec_matches(pathspec: &[PathBuf], path: &Path) -> bool {
+    pathspec.is_empty()
+        || pathspec
+            .iter()
+            .any(|spec| spec == Path::new(".") || path.starts_with(spec))
+}
+
+/// Fails if a path of `pathspec` matches none of the `known` paths.
+pub fn check_pathspec<'a>(
+    pathspec: &[PathBuf],
+    known: impl Iterator<Item = &'a PathBuf> + Clone,
+) -> eyre::Result<()> {
+    for spec in pathspec {
+        let spec = std::slice::from_ref(spec);
+        if !known.clone().any(|path| pathspec_matches(spec, path)) {
+            return Err(eyre!(tr!(
+                "pathspec '%s' did not match any file(s) known to git",
+                spec[0].display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the sha of the tree of a tree-ish object: the object itself for
+/// a tree, the tree of a commit.
+pub fn peel_to_tree(git_dir: &Path, sha: &str) -> eyre::Result<String> {
+    match GitFile::read(git_dir, sha)?.file_content {
+        GitFileContent::Tree(_) => Ok(sha.to_string()),
+        GitFileContent::Commit(commit) => Ok(commit.tree),
+        _ => Err(eyre!("object {sha} is not a tree-ish")),
+    }
+}
+
+/// Returns the files of the tree with the given sha, recursing into
+/// sub-trees.
+pub fn flatten_tree(git_dir: &Path, sha: &str) -> eyre::Result<Files> {
+    let mut files = Files::new();
+    flatten_into(git_dir, sha, PathBuf::new(), &mut files)?;
+    Ok(files)
+}
+
+fn flatten_into(git_dir: &Path, sha: &str, prefix: PathBuf, files: &mut Files) -> eyre::Result<()> {
+    for entry in TreeIter::new(&read_raw_tree(git_dir, sha)?) {
+        let entry = entry?;
+        let path = prefix.join(entry.name);
+        let sha = entry.sha();
+        if entry.mode == MODE_TREE {
+            flatten_into(git_dir, &sha, path, files)?;
+        } else {
+            files.insert(path, (entry.mode, sha));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the entries of the tree with the given sha, without recursing
+/// into sub-trees.
+pub fn read_tree(git_dir: &Path, sha: &str) -> eyre::Result<Files> {
+    TreeIter::new(&read_raw_tree(git_dir, sha)?)
+        .map(|entry| {
+            let entry = entry?;
+            Ok((PathBuf::from(entry.name), (entry.mode, entry.sha())))
+        })
+        .collect()
+}
+
+/// Returns the mode and sha of the entry at `path` in the tree with the
+/// given sha, reading only the sub-trees leading to it.
+pub fn entry_at(git_dir: &Path, sha: &str, path: &Path) -> eyre::Result<Option<(u32, String)>> {
+    let mut entry = (MODE_TREE, sha.to_string());
+    for component in path.components() {
+        if entry.0 != MODE_TREE {
+            return Ok(None);
+        }
+        let name = component.as_os_str().to_str();
+        let content = read_raw_tree(git_dir, &entry.1)?;
+        let mut entries = TreeIter::new(&content);
+        match entries.find(|e| e.as_ref().map_or(true, |e| Some(e.name) == name)) {
+            Some(found) => {
+                let found = found?;
+                entry = (found.mode, found.sha());
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(entry))
+}
+
+/// What `ls_tree` lists.
+#[derive(Debug, Default)]
+pub struct LsTreeOptions {
+    /// Lists the content of sub-trees instead of the sub-trees.
+    pub recursive: bool,
+    /// Lists only trees.
+    pub only_trees: bool,
+    /// Also lists the trees recursed into.
+    pub show_trees: bool,
+    pub name_only: bool,
+    /// Shows paths relative to this directory of the tree.
+    pub relative_to: Option<PathBuf>,
+}
+
+/// Lists the entries of the tree `sha` matching `paths`, paths from the root
+/// of the tree where an empty path or one ending with `/` matches what's
+/// inside. Sub-trees are only read when a path leads into them.
+pub fn ls_tree(
+    git_dir: &Path,
+    sha: &str,
+    paths: &[String],
+    options: &LsTreeOptions,
+) -> eyre::Result<()> {
+    let matches = |path: &str| -> (bool, bool) {
+        let dir = format!("{path}/");
+        let leads_into = paths.iter().any(|spec| spec.starts_with(&dir));
+        let inside = paths.is_empty()
+            || paths.iter().any(|spec| {
+                spec.is_empty()
+                    || path == spec
+                    || match spec.strip_suffix('/') {
+                        Some(_) => path.starts_with(spec),
+                        None => path.starts_with(&format!("{spec}/")),
+                    }
+            });
+        (inside, leads_into)
+    };
+
+    let mut lines = Vec::new();
+    ls_tree_into(git_dir, sha, "", &matches, options, &mut lines)?;
+    for (path, mode, sha) in lines {
+        let path = match &options.relative_to {
+            Some(prefix) => crate::repository::relative_path(&path, prefix),
+            None => path,
+        };
+        if options.name_only {
+            println!("{path}");
+        } else {
+            let kind = kind_of(mode);
+            println!("{mode:06} {kind} {sha}\t{path}");
+        }
+    }
+    Ok(())
+}
+
+/// Adds the entries of the tree `sha`, at `dir`, to `lines` in tree order,
+/// `matches` telling whether a path is inside the listed paths and whether
+/// it leads into them.
+fn ls_tree_into(
+    git_dir: &Path,
+    sha: &str,
+    dir: &str,
+    matches: &dyn Fn(&str) -> (bool, bool),
+    options: &LsTreeOptions,
+    lines: &mut Vec<(String, u32, String)>,
+) -> eyre::Result<()> {
+    for entry in TreeIter::new(&read_raw_tree(git_dir, sha)?) {
+        let entry = entry?;
+        let path = format!("{dir}{}", entry.name);
+        let sha = entry.sha();
+        let (inside, leads_into) = matches(&path);
+        if entry.mode != MODE_TREE {
+            if inside && !options.only_trees {
+                lines.push((path, entry.mode, sha));
+            }
+            continue;
+        }
+
+        // Sub-trees are listed in place of their entry
+        let recurse = leads_into || (inside && options.recursive);
+        if (inside && (!recurse || options.only_trees)) || (recurse && options.show_trees) {
+            lines.push((path.clone(), entry.mode, sha.clone()));
+        }
+        if recurse {
+            ls_tree_into(git_dir, &sha, &format!("{path}/"), matches, options, lines)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the tree objects holding the given files and returns the sha of
+/// the root tree.
+pub fn write_tree(git_dir: &Path, files: &Files) -> eyre::Result<String> {
+    write_subtree(git_dir, files, Path::new(""))
+}
+
+fn write_subtree(git_dir: &Path, files: &Files, dir: &Path) -> eyre::Result<String> {
+    let mut entries = Vec::new();
+    let mut subdirs = Vec::new();
+
+    for (path, (mode, sha)) in files.range(dir.to_path_buf()..) {
+        let Ok(relative) = path.strip_prefix(dir) else {
+            break;
+        };
+        let mut components = relative.components();
+        let name = components
+            .next()
+            .ok_or(eyre!("invalid path {}", path.display()))?
+            .as_os_str()
+            .to_string_lossy()
+            .to_string();
+
+        if components.next().is_some() {
+            // Files in sub-directories are written in the sub-tree, once
+            if subdirs.last() != Some(&name) {
+                subdirs.push(name);
+            }
+        } else {
+            entries.push(TreeContent {
+                mode: *mode,
+                name,
+                sha: hex::decode(sha)?,
+            });
+        }
+    }
+
+    for name in subdirs {
+        let sha = write_subtree(git_dir, files, &dir.join(&name))?;
+        entries.push(TreeContent {
+            mode: MODE_TREE,
+            name,
+            sha: hex::decode(sha)?,
+        });
+    }
+
+    Odb::new(git_dir)
+        .write("tree", &TreeContent::serialize(&entries))
+        .map(String::from)
+}
+
+/// Writes a tree of the entries listed by the lines of `input`, in the
+/// format `ls-tree` prints them, and returns its sha.
+pub fn mktree(git_dir: &Path, input: impl BufRead) -> eyre::Result<String> {
+    let mut entries = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let (info, name) = line
+            .split_once('\t')
+            .ok_or(eyre!("input format error: {line}"))?;
+        let [mode, _, sha] = info.split(' ').collect::<Vec<_>>()[..] else {
+            return Err(eyre!("input format error: {line}"));
+        };
+        let sha = sha.parse::<git::Oid>()?;
+        entries.push(TreeContent {
+            mode: mode.parse()?,
+            name: name.to_string(),
+            sha: hex::decode(&*sha)?,
+        });
+    }
+
+    // Without entries, this is the well-known empty tree
+    Odb::new(git_dir)
+        .write("tree", &TreeContent::serialize(&entries))
+        .map(String::from)
+}
+
+/// A file that differs between two sets of files.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub path: PathBuf,
+    pub old: Option<(u32, String)>,
+    pub new: Option<(u32, String)>,
+}
+
+impl Change {
+    /// Returns the letter git uses for the kind of change: `A`dded,
+    /// `D`eleted, `M`odified or `T`ype changed.
+    pub fn status(&self) -> char {
+        match (&self.old, &self.new) {
+            (None, _) => 'A',
+            (_, None) => 'D',
+            (Some((old, _)), Some((new, _))) if file_type(*old) != file_type(*new) => 'T',
+            _ => 'M',
+        }
+    }
+}
+
+/// Returns the type of file (regular, symlink or submodule) of a mode, the
+/// executable bit aside.
+fn file_type(mode: u32) -> u32 {
+    match mode {
+        MODE_EXECUTABLE => MODE_FILE,
+        mode => mode,
+    }
+}
+
+/// Returns the changes turning the `old` files into the `new` ones, sorted
+/// by path.
+pub fn diff_files(old: &Files, new: &Files) -> Vec<Change> {
+    let paths = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+    paths
+        .into_iter()
+        .filter(|path| old.get(*path) != new.get(*path))
+        .map(|path| Change {
+            path: path.clone(),
+            old: old.get(path).cloned(),
+            new: new.get(path).cloned(),
+        })
+        .collect()
+}
+
+/// Prints the changes between the trees of the revisions `trees`, or
+/// between the commit of `trees` and its first parent, in the raw format of
+/// `git diff-tree`. Only the entries of the root trees are compared unless
+/// `recursive`, and a root commit is compared to the empty tree with `root`.
+pub fn diff_tree(
+    git_dir: &Path,
+    trees: &[String],
+    recursive: bool,
+    root: bool,
+) -> eyre::Result<()> {
+    let (old, new) = match trees {
+        [old, new] => (
+            peel_to_tree(git_dir, &revision::resolve(git_dir, old)?)?,
+            peel_to_tree(git_dir, &revision::resolve(git_dir, new)?)?,
+        ),
+        [commit] => {
+            let sha = revision::resolve(git_dir, commit)?;
+            let commit = Commit::read(git_dir, &sha)?;
+            let old = match commit.parents.first() {
+                Some(parent) => Commit::read(git_dir, parent)?.tree,
+                None if root => git::EMPTY_TREE.to_string(),
+                None => return Ok(()),
+            };
+            println!("{sha}");
+            (old, commit.tree)
+        }
+        _ => return Err(eyre!(tr!("diff-tree needs one commit or two trees"))),
+    };
+
+    let read = match recursive {
+        true => flatten_tree,
+        false => read_tree,
+    };
+    for change in diff_files(&read(git_dir, &old)?, &read(git_dir, &new)?) {
+        let (old_mode, old_sha) = change.old.clone().unwrap_or((0, git::NULL_SHA.to_string()));
+        let (new_mode, new_sha) = change.new.clone().unwrap_or((0, git::NULL_SHA.to_string()));
+        println!(
+            ":{old_mode:06} {new_mode:06} {old_sha} {new_sha} {}\t{}",
+            change.status(),
+            change.path.display()
+        );
+    }
+    Ok(())
+}
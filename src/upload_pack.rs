@@ -0,0 +1,1049 @@
+use crate::commit::Commit;
+use crate::config::Config;
+use crate::git;
+use crate::pack::{self, PackOptions};
+use crate::partial::Filter;
+use crate::pktline::{self, Packet};
+use crate::refs;
+use crate::revision;
+use crate::server::{self, Service};
+use crate::shallow;
+use crate::tag::Tag;
+use eyre::eyre;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// How acknowledgements are sent during a negotiation of protocol version 0,
+/// following the `multi_ack` capabilities the client chose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MultiAck {
+    /// Only the first common commit is acknowledged.
+    None,
+    /// `multi_ack`: each common commit is acknowledged with `continue`.
+    Continue,
+    /// `multi_ack_detailed`: common commits are acknowledged with
+    /// `common`, and `ready` tells the client it can stop.
+    Detailed,
+}
+
+/// How a client cuts the history it fetches, with the `deepen` lines.
+#[derive(Debug, Default)]
+struct Deepen {
+    /// The number of commits kept from the wanted ones, 0 for all.
+    depth: u32,
+    /// Counts the depth from the shallow commits of the client instead.
+    relative: bool,
+    /// Leaves out the commits older than this timestamp.
+    since: Option<i64>,
+    /// Leaves out the commits reachable from these refs.
+    not: Vec<String>,
+}
+
+/// What a client asks for in a fetch.
+#[derive(Debug, Default)]
+struct Request {
+    wants: Vec<String>,
+    /// The refs wanted by name with `want-ref` in version 2, with the shas
+    /// they resolved to.
+    wanted_refs: Vec<(String, String)>,
+    haves: Vec<String>,
+    /// The commits of the client whose parents it doesn't have.
+    shallow: Vec<String>,
+    deepen: Deepen,
+    /// What the client leaves out of the objects it fetches, in a partial
+    /// clone.
+    filter: Option<Filter>,
+    /// The capabilities the client chose in version 0, or the flags of the
+    /// request like `ofs-delta` in version 2.
+    options: HashSet<String>,
+    /// Whether the client is done negotiating, in version 2.
+    done: bool,
+}
+
+impl Request {
+    fn has(&self, option: &str) -> bool {
+        self.options.contains(option)
+    }
+
+    /// Returns true if the client asked to cut the history it fetches.
+    fn is_deepening(&self) -> bool {
+        self.deepen.depth > 0 || self.deepen.since.is_some() || !self.deepen.not.is_empty()
+    }
+
+    /// Reads a line both versions of the protocol share, other than the
+    /// wants of version 0 with their capabilities. Returns false if `line`
+    /// isn't one of them.
+    fn parse(&mut self, line: &str) -> eyre::Result<bool> {
+        let Some((key, value)) = line.split_once(' ') else {
+            return Ok(false);
+        };
+        let sha = || match refs::is_sha(value) {
+            true => Ok(value.to_string()),
+            false => Err(eyre!(tr!(
+                "git upload-pack: protocol error, expected to get object ID, not '%s'",
+                line
+            ))),
+        };
+        match key {
+            "want" => self.wants.push(sha()?),
+            "have" => self.haves.push(sha()?),
+            "shallow" => self.shallow.push(sha()?),
+            "deepen" => {
+                self.deepen.depth = value
+                    .parse()
+                    .map_err(|_| eyre!(tr!("invalid deepen: %s", value)))?
+            }
+            "deepen-since" => {
+                self.deepen.since = Some(
+                    value
+                        .parse()
+                        .map_err(|_| eyre!(tr!("invalid deepen-since: %s", line)))?,
+                )
+            }
+            "deepen-not" => self.deepen.not.push(value.to_string()),
+            "filter" => self.filter = Some(value.parse()?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// Serves a fetch from the repository at `git_dir`, reading the requests of
+/// the client from `input` and answering on `out`, in the `version` of the
+/// protocol it asked for: advertises the refs, reads the objects the client
+/// wants, negotiates the commits it already has, and sends it a pack of the
+/// missing objects.
+///
+/// With `stateless`, the refs were advertised by an earlier run and a single
+/// request is answered, the client sending everything it said before along
+/// with each round, as over HTTP.
+pub fn upload_pack(
+    config: &Config,
+    git_dir: &Path,
+    version: u32,
+    stateless: bool,
+    input: impl Read,
+    mut out: impl Write,
+) -> eyre::Result<()> {
+    if !stateless {
+        server::advertise_refs(config, git_dir, Service::UploadPack, version, &mut out)?;
+    }
+    let mut reader = pktline::Reader::new(input);
+    if version < 2 {
+        return UploadPack::new(config, git_dir)?.serve_v0(&mut reader, &mut out, stateless);
+    }
+    // Each command of version 2 stands alone
+    while UploadPack::new(config, git_dir)?.serve_command(&mut reader, &mut out)? && !stateless {}
+    Ok(())
+}
+
+/// A fetch served from the repository at `git_dir`.
+struct UploadPack<'a> {
+    config: &'a Config,
+    git_dir: &'a Path,
+    commits: HashMap<String, Commit>,
+    /// The commits whose parents the walks leave out: the shallow commits
+    /// of the repository, then the ones of the client and the ones the
+    /// fetch cuts its history at.
+    shallow: HashSet<String>,
+    /// The commits the client has, and the parents of its commits.
+    they_have: HashSet<String>,
+    /// The commits the client said it has that the repository has too, in
+    /// the order of the `have` lines.
+    common: Vec<String>,
+    /// The commit date of the oldest of `common`, older commits not being
+    /// walked when looking for them.
+    oldest_have: Option<i64>,
+    /// The wanted objects known to reach one of `common`.
+    reaching: HashSet<String>,
+}
+
+impl<'a> UploadPack<'a> {
+    fn new(config: &'a Config, git_dir: &'a Path) -> eyre::Result<Self> {
+        Ok(Self {
+            config,
+            git_dir,
+            commits: HashMap::new(),
+            shallow: shallow::read(git_dir)?.into_iter().collect(),
+            they_have: HashSet::new(),
+            common: Vec::new(),
+            oldest_have: None,
+            reaching: HashSet::new(),
+        })
+    }
+
+    /// Returns the parsed commit `sha`.
+    fn commit(&mut self, sha: &str) -> eyre::Result<&Commit> {
+        if !self.commits.contains_key(sha) {
+            let content = git::read_object_as(self.git_dir, sha, "commit")?
+                .ok_or(eyre!("object {sha} is not a commit"))?;
+            self.commits
+                .insert(sha.to_string(), Commit::parse(&content)?);
+        }
+        Ok(&self.commits[sha])
+    }
+
+    /// Returns the parents of the commit `sha` the walks go through, none
+    /// for the shallow ones.
+    fn parents(&mut self, sha: &str) -> eyre::Result<Vec<String>> {
+        match self.shallow.contains(sha) {
+            true => Ok(Vec::new()),
+            false => Ok(self.commit(sha)?.parents.clone()),
+        }
+    }
+
+    /// Returns the type of the object `sha`, if the repository has it.
+    fn kind(&self, sha: &str) -> Option<String> {
+        git::read_object(self.git_dir, sha)
+            .ok()
+            .map(|(kind, _)| kind)
+    }
+
+    /// Serves a fetch in version 0 or 1 of the protocol. Returns early if
+    /// the client wants nothing, having only asked for the refs, or at the
+    /// end of a stateless round of the negotiation.
+    fn serve_v0(
+        &mut self,
+        reader: &mut pktline::Reader<impl Read>,
+        out: &mut impl Write,
+        stateless: bool,
+    ) -> eyre::Result<()> {
+        let mut request = Request::default();
+        // The client may hang up instead of sending a flush packet
+        while let Ok(Packet::Data(data)) = reader.read() {
+            let line = pktline::lossy(&data);
+            // The capabilities the client chose follow its first want
+            if let Some(rest) = line.strip_prefix("want ") {
+                if let Some((sha, capabilities)) = rest.split_once(' ') {
+                    if request.wants.is_empty() {
+                        request.options = capabilities.split(' ').map(str::to_string).collect();
+                    }
+                    request.parse(&format!("want {sha}"))?;
+                    continue;
+                }
+            }
+            if !request.parse(&line)? || !request.haves.is_empty() {
+                return Err(eyre!(tr!(
+                    "git upload-pack: protocol error, expected to get object ID, not '%s'",
+                    &line
+                )));
+            }
+        }
+        if request.wants.is_empty() {
+            return Ok(());
+        }
+        request.deepen.relative = request.has("deepen-relative");
+        if request.filter.is_some() && !request.has("filter") {
+            return Err(eyre!(tr!(
+                "git upload-pack: filtering capability not negotiated"
+            )));
+        }
+        self.check_wants(&request, stateless, out)?;
+
+        let mut writer = pktline::Writer::new(&mut *out);
+        let mut unshallow = Vec::new();
+        match request.is_deepening() {
+            true => {
+                let (shallow, unshallowed) = self.deepen(&request)?;
+                for sha in &shallow {
+                    writer.write_line(&format!("shallow {sha}"))?;
+                }
+                for sha in &unshallowed {
+                    writer.write_line(&format!("unshallow {sha}"))?;
+                }
+                writer.write_flush()?;
+                out.flush()?;
+                unshallow = unshallowed;
+            }
+            false => self.shallow.extend(request.shallow.iter().cloned()),
+        }
+
+        if !self.negotiate_v0(reader, out, &request, stateless)? {
+            return Ok(());
+        }
+        let sideband = match (request.has("side-band-64k"), request.has("side-band")) {
+            (true, _) => Some(pktline::LARGE_PACKET),
+            (_, true) => Some(pktline::SMALL_PACKET),
+            _ => None,
+        };
+        self.send_pack(&request, &unshallow, sideband, out)
+    }
+
+    /// Reads the `have` lines of the client and acknowledges the commits the
+    /// repository has too, round after round, up to the `done` line or the
+    /// `ready` acknowledgement of `no-done`. Returns false when a stateless
+    /// round ends before, the client sending the next one in a new request.
+    fn negotiate_v0(
+        &mut self,
+        reader: &mut pktline::Reader<impl Read>,
+        out: &mut impl Write,
+        request: &Request,
+        stateless: bool,
+    ) -> eyre::Result<bool> {
+        let multi_ack = match (request.has("multi_ack_detailed"), request.has("multi_ack")) {
+            (true, _) => MultiAck::Detailed,
+            (_, true) => MultiAck::Continue,
+            _ => MultiAck::None,
+        };
+        let (mut got_common, mut got_other, mut sent_ready) = (false, false, false);
+        let mut last = String::new();
+        loop {
+            let mut writer = pktline::Writer::new(&mut *out);
+            let line = match reader.read()? {
+                Packet::Data(data) => pktline::lossy(&data),
+                Packet::Flush => {
+                    if multi_ack == MultiAck::Detailed
+                        && got_common
+                        && !got_other
+                        && self.ok_to_give_up(&request.wants)?
+                    {
+                        sent_ready = true;
+                        writer.write_line(&format!("ACK {last} ready"))?;
+                    }
+                    if self.common.is_empty() || multi_ack != MultiAck::None {
+                        writer.write_line("NAK")?;
+                    }
+                    if request.has("no-done") && sent_ready {
+                        writer.write_line(&format!("ACK {last}"))?;
+                        out.flush()?;
+                        return Ok(true);
+                    }
+                    out.flush()?;
+                    if stateless {
+                        return Ok(false);
+                    }
+                    (got_common, got_other) = (false, false);
+                    continue;
+                }
+                packet => return Err(eyre!("unexpected packet {packet:?}")),
+            };
+
+            if let Some(sha) = line.strip_prefix("have ") {
+                match self.got_have(sha)? {
+                    true => {
+                        got_common = true;
+                        last = sha.to_string();
+                        match multi_ack {
+                            MultiAck::Detailed => {
+                                writer.write_line(&format!("ACK {sha} common"))?
+                            }
+                            MultiAck::Continue => {
+                                writer.write_line(&format!("ACK {sha} continue"))?
+                            }
+                            MultiAck::None if self.common.len() == 1 => {
+                                writer.write_line(&format!("ACK {sha}"))?
+                            }
+                            MultiAck::None => {}
+                        }
+                    }
+                    // Commits only the client has tell that it's ahead,
+                    // which may be enough
+                    false => {
+                        got_other = true;
+                        if multi_ack != MultiAck::None && self.ok_to_give_up(&request.wants)? {
+                            match multi_ack {
+                                MultiAck::Detailed => {
+                                    sent_ready = true;
+                                    writer.write_line(&format!("ACK {sha} ready"))?;
+                                }
+                                _ => writer.write_line(&format!("ACK {sha} continue"))?,
+                            }
+                        }
+                    }
+                }
+            } else if line == "done" {
+                match (self.common.is_empty(), multi_ack) {
+                    (true, _) => writer.write_line("NAK")?,
+                    (false, MultiAck::None) => {}
+                    (false, _) => writer.write_line(&format!("ACK {last}"))?,
+                }
+                out.flush()?;
+                return Ok(true);
+            } else {
+                return Err(eyre!(tr!(
+                    "git upload-pack: expected SHA1 list, got '%s'",
+                    &line
+                )));
+            }
+        }
+    }
+
+    /// Serves a command of version 2 of the protocol: `ls-refs`, `fetch` or
+    /// `object-info`. Returns false once the client ends the session, with
+    /// a flush packet or by hanging up.
+    fn serve_command(
+        &mut self,
+        reader: &mut pktline::Reader<impl Read>,
+        out: &mut impl Write,
+    ) -> eyre::Result<bool> {
+        let Ok(Packet::Data(data)) = reader.read() else {
+            return Ok(false);
+        };
+        let line = pktline::lossy(&data);
+        let command = line
+            .strip_prefix("command=")
+            .ok_or(eyre!(tr!("unexpected line: '%s'", &line)))?
+            .to_string();
+
+        // The capabilities of the client come before the arguments of the
+        // command, after a delimiter
+        let mut arguments = Vec::new();
+        let mut in_arguments = false;
+        loop {
+            match reader.read()? {
+                Packet::Data(data) if in_arguments => arguments.push(pktline::lossy(&data)),
+                Packet::Data(_) => {}
+                Packet::Delim if !in_arguments => in_arguments = true,
+                Packet::Flush => break,
+                packet => return Err(eyre!("unexpected packet {packet:?}")),
+            }
+        }
+
+        match command.as_str() {
+            "ls-refs" => self.ls_refs(&arguments, out)?,
+            "fetch" => self.fetch(&arguments, out)?,
+            "object-info" => self.object_info(&arguments, out)?,
+            _ => return Err(eyre!(tr!("invalid command '%s'", &command))),
+        }
+        out.flush()?;
+        Ok(true)
+    }
+
+    /// Lists the refs of the repository, `HEAD` first, the ones starting
+    /// with one of the `ref-prefix` arguments if any. `symrefs` adds the
+    /// targets of symbolic refs, `peel` the objects tags peel to, and
+    /// `unborn` a `HEAD` pointing to a branch without commits.
+    fn ls_refs(&mut self, arguments: &[String], out: &mut impl Write) -> eyre::Result<()> {
+        let (mut symrefs, mut peel, mut unborn) = (false, false, false);
+        let mut prefixes = Vec::new();
+        for argument in arguments {
+            match argument.as_str() {
+                "symrefs" => symrefs = true,
+                "peel" => peel = true,
+                "unborn" => {
+                    unborn = matches!(
+                        self.config.get("lsrefs.unborn").unwrap_or("advertise"),
+                        "advertise" | "allow"
+                    )
+                }
+                argument => match argument.strip_prefix("ref-prefix ") {
+                    Some(prefix) => prefixes.push(prefix.to_string()),
+                    None => return Err(eyre!(tr!("unexpected line: '%s'", argument))),
+                },
+            }
+        }
+
+        let mut writer = pktline::Writer::new(&mut *out);
+        let mut refs = Vec::new();
+        if prefixes.is_empty() || prefixes.iter().any(|prefix| "HEAD".starts_with(prefix)) {
+            match refs::read_ref(self.git_dir, "HEAD")? {
+                Some(sha) => refs.push(("HEAD".to_string(), sha)),
+                None if unborn => {
+                    if let Some(target) = refs::symbolic_target(self.git_dir, "HEAD")? {
+                        match symrefs {
+                            true => {
+                                writer.write_line(&format!("unborn HEAD symref-target:{target}"))?
+                            }
+                            false => writer.write_line("unborn HEAD")?,
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+        refs.extend(
+            refs::list_refs(self.git_dir, "refs/")?
+                .into_iter()
+                .filter(|(name, _)| {
+                    prefixes.is_empty() || prefixes.iter().any(|prefix| name.starts_with(prefix))
+                }),
+        );
+        for (name, sha) in refs {
+            let mut line = format!("{sha} {name}");
+            if symrefs {
+                if let Some(target) = refs::symbolic_target(self.git_dir, &name)? {
+                    line.push_str(&format!(" symref-target:{target}"));
+                }
+            }
+            if peel {
+                let peeled = revision::peel(self.git_dir, &sha)?;
+                if peeled != sha {
+                    line.push_str(&format!(" peeled:{peeled}"));
+                }
+            }
+            writer.write_line(&line)?;
+        }
+        writer.write_flush()
+    }
+
+    /// Serves a round of the negotiation of a fetch in version 2, then the
+    /// pack once the client is done or the repository is ready to send it:
+    /// the response has sections for the acknowledgements, the shallow
+    /// commits, the refs wanted by name and the pack.
+    fn fetch(&mut self, arguments: &[String], out: &mut impl Write) -> eyre::Result<()> {
+        let mut request = Request::default();
+        for argument in arguments {
+            match argument.as_str() {
+                "done" => request.done = true,
+                "deepen-relative" => request.deepen.relative = true,
+                "thin-pack" | "no-progress" | "include-tag" | "ofs-delta" | "sideband-all"
+                | "wait-for-done" => {
+                    request.options.insert(argument.clone());
+                }
+                argument => {
+                    if let Some(name) = argument.strip_prefix("want-ref ") {
+                        if !self
+                            .config
+                            .get_bool("uploadpack.allowRefInWant")?
+                            .unwrap_or(false)
+                        {
+                            return Err(eyre!(tr!("unexpected line: '%s'", argument)));
+                        }
+                        let sha = refs::read_ref(self.git_dir, name)?
+                            .filter(|_| name == "HEAD" || name.starts_with("refs/"))
+                            .ok_or(eyre!(tr!("unknown ref %s", name)))?;
+                        request.wanted_refs.push((name.to_string(), sha.clone()));
+                        request.wants.push(sha);
+                    } else if !request.parse(argument)? {
+                        return Err(eyre!(tr!("unexpected line: '%s'", argument)));
+                    }
+                }
+            }
+        }
+        let allow_filter = self.config.get_bool("uploadpack.allowFilter")?;
+        if request.filter.is_some() && allow_filter != Some(true) {
+            return Err(eyre!(tr!(
+                "git upload-pack: filtering capability not negotiated"
+            )));
+        }
+        self.check_wants(&request, true, out)?;
+
+        for have in &request.haves {
+            self.got_have(have)?;
+        }
+        let mut writer = pktline::Writer::new(&mut *out);
+        if !request.done {
+            writer.write_line("acknowledgments")?;
+            if self.common.is_empty() {
+                writer.write_line("NAK")?;
+            }
+            for sha in &self.common {
+                writer.write_line(&format!("ACK {sha}"))?;
+            }
+            // The client sends another round unless the repository is ready
+            if request.has("wait-for-done") || !self.ok_to_give_up(&request.wants)? {
+                return writer.write_flush();
+            }
+            writer.write_line("ready")?;
+            writer.write_delim()?;
+        }
+
+        let mut unshallow = Vec::new();
+        let repository_shallow = !self.shallow.is_empty();
+        if request.is_deepening() || !request.shallow.is_empty() || repository_shallow {
+            writer.write_line("shallow-info")?;
+            match request.is_deepening() {
+                true => {
+                    let (shallow, unshallowed) = self.deepen(&request)?;
+                    for sha in &shallow {
+                        writer.write_line(&format!("shallow {sha}"))?;
+                    }
+                    for sha in &unshallowed {
+                        writer.write_line(&format!("unshallow {sha}"))?;
+                    }
+                    unshallow = unshallowed;
+                }
+                false => {
+                    self.shallow.extend(request.shallow.iter().cloned());
+                    // A shallow repository only has the history down to
+                    // its own shallow commits to send
+                    if repository_shallow {
+                        let (_, boundary) = self.deepen_by_depth(&request, u32::MAX)?;
+                        for sha in boundary.iter().filter(|sha| !request.shallow.contains(sha)) {
+                            writer.write_line(&format!("shallow {sha}"))?;
+                        }
+                    }
+                }
+            }
+            writer.write_delim()?;
+        }
+
+        if !request.wanted_refs.is_empty() {
+            writer.write_line("wanted-refs")?;
+            for (name, sha) in &request.wanted_refs {
+                writer.write_line(&format!("{sha} {name}"))?;
+            }
+            writer.write_delim()?;
+        }
+
+        writer.write_line("packfile")?;
+        self.send_pack(&request, &unshallow, Some(pktline::LARGE_PACKET), out)
+    }
+
+    /// Sends the size of the objects of the `oid` arguments, if asked for
+    /// with the `size` argument. Missing objects have an empty size.
+    fn object_info(&mut self, arguments: &[String], out: &mut impl Write) -> eyre::Result<()> {
+        let mut size = false;
+        let mut oids = Vec::new();
+        for argument in arguments {
+            match argument.strip_prefix("oid ") {
+                Some(oid) => oids.push(oid),
+                None if argument == "size" => size = true,
+                None => return Err(eyre!(tr!("unexpected line: '%s'", argument))),
+            }
+        }
+
+        let mut writer = pktline::Writer::new(&mut *out);
+        // Unlike the other responses, the lines have no line feed
+        if size {
+            writer.write(b"size")?;
+        }
+        for oid in oids {
+            let line = match (size, git::read_object(self.git_dir, oid)) {
+                (false, _) => oid.to_string(),
+                (true, Ok((_, content))) => format!("{oid} {}", content.len()),
+                (true, Err(_)) => format!("{oid} "),
+            };
+            writer.write(line.as_bytes())?;
+        }
+        writer.write_flush()
+    }
+
+    /// Checks that the client only wants objects it may fetch, telling it
+    /// with an `ERR` packet otherwise: the objects the refs point or peel
+    /// to, the commits reachable from them as well with `reachable` or
+    /// `uploadpack.allowReachableSHA1InWant`, and any object with
+    /// `uploadpack.allowAnySHA1InWant`. Partial clones fetch the trees and
+    /// blobs they miss by sha, which `reachable` allows too.
+    fn check_wants(
+        &mut self,
+        request: &Request,
+        reachable: bool,
+        out: &mut impl Write,
+    ) -> eyre::Result<()> {
+        let enabled =
+            |key: &str| -> eyre::Result<bool> { Ok(self.config.get_bool(key)?.unwrap_or(false)) };
+        let any = enabled("uploadpack.allowAnySHA1InWant")?;
+        let reachable = reachable || any || enabled("uploadpack.allowReachableSHA1InWant")?;
+
+        let mut tips = HashSet::new();
+        let head = refs::read_ref(self.git_dir, "HEAD")?;
+        for (_, sha) in head
+            .map(|sha| ("HEAD".to_string(), sha))
+            .into_iter()
+            .chain(refs::list_refs(self.git_dir, "refs/")?)
+        {
+            tips.insert(revision::peel(self.git_dir, &sha)?);
+            tips.insert(sha);
+        }
+        let mut reachable_commits: Option<HashSet<String>> = None;
+        for want in &request.wants {
+            if tips.contains(want) || (any && self.kind(want).is_some()) {
+                continue;
+            }
+            let kind = self.kind(want);
+            if reachable && kind.is_some() && kind.as_deref() != Some("commit") {
+                continue;
+            }
+            if reachable {
+                if reachable_commits.is_none() {
+                    let mut pending = tips
+                        .iter()
+                        .filter(|sha| self.kind(sha).as_deref() == Some("commit"))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    let mut seen = HashSet::new();
+                    while let Some(sha) = pending.pop() {
+                        if seen.insert(sha.clone()) {
+                            pending.extend(self.parents(&sha)?);
+                        }
+                    }
+                    reachable_commits = Some(seen);
+                }
+                if reachable_commits.as_ref().is_some_and(|c| c.contains(want)) {
+                    continue;
+                }
+            }
+            pktline::Writer::new(&mut *out)
+                .write_line(&format!("ERR upload-pack: not our ref {want}"))?;
+            out.flush()?;
+            return Err(eyre!(tr!("git upload-pack: not our ref %s", want)));
+        }
+        Ok(())
+    }
+
+    /// Records that the client has the object `sha`, returning false if the
+    /// repository doesn't have it. The client also has the parents of its
+    /// commits, which aren't common commits of their own.
+    fn got_have(&mut self, sha: &str) -> eyre::Result<bool> {
+        let Some(kind) = self.kind(sha) else {
+            return Ok(false);
+        };
+        if kind == "commit" {
+            if self.they_have.contains(sha) {
+                return Ok(true);
+            }
+            let commit = self.commit(sha)?;
+            let (time, parents) = (commit.committer.time, commit.parents.clone());
+            if !self.oldest_have.is_some_and(|oldest| oldest <= time) {
+                self.oldest_have = Some(time);
+            }
+            self.they_have.extend(parents);
+        }
+        if self.they_have.insert(sha.to_string()) {
+            self.common.push(sha.to_string());
+        }
+        Ok(true)
+    }
+
+    /// Returns true if each of the wanted commits reaches a commit the
+    /// client has, so the negotiation can stop. Commits older than the
+    /// oldest common commit aren't walked.
+    fn ok_to_give_up(&mut self, wants: &[String]) -> eyre::Result<bool> {
+        if self.common.is_empty() {
+            return Ok(false);
+        }
+        for want in wants {
+            if self.reaching.contains(want) {
+                continue;
+            }
+            // Only the history of commits tells what they reach
+            let peeled = revision::peel(self.git_dir, want)?;
+            let mut reaches = self.kind(&peeled).as_deref() != Some("commit");
+            let mut pending = vec![peeled];
+            let mut seen = HashSet::new();
+            while let Some(sha) = pending.pop() {
+                if reaches || !seen.insert(sha.clone()) {
+                    continue;
+                }
+                if self.they_have.contains(&sha) {
+                    reaches = true;
+                    continue;
+                }
+                let time = self.commit(&sha)?.committer.time;
+                if !self.oldest_have.is_some_and(|oldest| time < oldest) {
+                    pending.extend(self.parents(&sha)?);
+                }
+            }
+            if !reaches {
+                return Ok(false);
+            }
+            self.reaching.insert(want.clone());
+        }
+        Ok(true)
+    }
+
+    /// Cuts the history the client fetches as its `deepen` lines ask: to a
+    /// number of commits from the wanted ones, or from its shallow commits
+    /// with `deepen-relative`, or leaving out the commits older than a date
+    /// or reachable from some refs. Returns the commits that become shallow
+    /// for the client, and the shallow commits of the client that no longer
+    /// are. The walks that follow stop at the shallow commits of both.
+    fn deepen(&mut self, request: &Request) -> eyre::Result<(Vec<String>, Vec<String>)> {
+        let deepen = &request.deepen;
+        let (kept, boundary) = match deepen.depth {
+            0 => self.deepen_by_rev_list(request)?,
+            _ if deepen.since.is_some() || !deepen.not.is_empty() => {
+                return Err(eyre!(tr!(
+                    "--depth and --shallow-since (or --shallow-exclude) cannot be used together"
+                )))
+            }
+            depth => self.deepen_by_depth(request, depth)?,
+        };
+
+        let shallow = boundary
+            .into_iter()
+            .filter(|sha| !request.shallow.contains(sha))
+            .collect::<Vec<_>>();
+        let unshallow = request
+            .shallow
+            .iter()
+            .filter(|sha| kept.contains(*sha))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.shallow.extend(request.shallow.iter().cloned());
+        self.shallow.extend(shallow.iter().cloned());
+        Ok((shallow, unshallow))
+    }
+
+    /// Walks `depth` commits down from the wanted commits, or from the
+    /// shallow commits of the client with `deepen-relative`. Returns the
+    /// commits whose parents are kept and, in the order they were found,
+    /// the commits at the boundary.
+    fn deepen_by_depth(
+        &mut self,
+        request: &Request,
+        depth: u32,
+    ) -> eyre::Result<(HashSet<String>, Vec<String>)> {
+        let (starts, depth) = match request.deepen.relative {
+            true => (&request.shallow, depth.saturating_add(1)),
+            false => (&request.wants, depth),
+        };
+        let mut pending = VecDeque::new();
+        let mut seen = HashSet::new();
+        for sha in starts {
+            let sha = revision::peel(self.git_dir, sha)?;
+            if self.kind(&sha).as_deref() == Some("commit") && seen.insert(sha.clone()) {
+                pending.push_back((sha, 1));
+            }
+        }
+
+        // The walk is breadth first, finding each commit at its smallest
+        // depth
+        let mut kept = HashSet::new();
+        let mut boundary = Vec::new();
+        while let Some((sha, current)) = pending.pop_front() {
+            if current >= depth || self.shallow.contains(&sha) {
+                boundary.push(sha);
+                continue;
+            }
+            for parent in self.parents(&sha)? {
+                if seen.insert(parent.clone()) {
+                    pending.push_back((parent, current + 1));
+                }
+            }
+            kept.insert(sha);
+        }
+        Ok((kept, boundary))
+    }
+
+    /// Keeps the commits reachable from the wanted ones, leaving out the
+    /// ones older than `deepen-since` and the ones reachable from the refs
+    /// of `deepen-not`. Returns the kept commits whose parents are all kept
+    /// too, and the other kept commits, at the boundary.
+    fn deepen_by_rev_list(
+        &mut self,
+        request: &Request,
+    ) -> eyre::Result<(HashSet<String>, Vec<String>)> {
+        let mut excluded = HashSet::new();
+        let mut pending = Vec::new();
+        for name in &request.deepen.not {
+            let mut found = None;
+            for candidate in [
+                name.to_string(),
+                format!("refs/{name}"),
+                format!("refs/tags/{name}"),
+                format!("refs/heads/{name}"),
+            ] {
+                found = refs::read_ref(self.git_dir, &candidate)?;
+                if found.is_some() {
+                    break;
+                }
+            }
+            let sha = found.ok_or(eyre!(tr!("deepen-not is not a ref: %s", name)))?;
+            pending.push(revision::peel(self.git_dir, &sha)?);
+        }
+        while let Some(sha) = pending.pop() {
+            if excluded.insert(sha.clone()) {
+                pending.extend(self.parents(&sha)?);
+            }
+        }
+
+        // Old commits are left out, but not the history behind them
+        let mut included = Vec::new();
+        let mut seen = HashSet::new();
+        for want in &request.wants {
+            let sha = revision::peel(self.git_dir, want)?;
+            if self.kind(&sha).as_deref() == Some("commit") {
+                pending.push(sha);
+            }
+        }
+        while let Some(sha) = pending.pop() {
+            if excluded.contains(&sha) || !seen.insert(sha.clone()) {
+                continue;
+            }
+            let time = self.commit(&sha)?.committer.time;
+            if !request.deepen.since.is_some_and(|since| time < since) {
+                included.push(sha.clone());
+            }
+            pending.extend(self.parents(&sha)?);
+        }
+        if included.is_empty() {
+            return Err(eyre!(tr!("no commits selected for shallow requests")));
+        }
+
+        let included_set = included.iter().cloned().collect::<HashSet<_>>();
+        let mut kept = HashSet::new();
+        let mut boundary = Vec::new();
+        for sha in included {
+            if self
+                .parents(&sha)?
+                .iter()
+                .all(|parent| included_set.contains(parent))
+            {
+                kept.insert(sha);
+            } else {
+                boundary.push(sha);
+            }
+        }
+        Ok((kept, boundary))
+    }
+
+    /// Sends the pack of the objects reachable from the wanted objects and
+    /// from the parents of the `unshallow` commits, but not from the common
+    /// commits nor from the `unshallow` commits themselves. With `sideband`,
+    /// the pack is sent on band 1 of the side-band in packets of at most
+    /// that many bytes, and the progress on band 2 unless the client asked
+    /// for none.
+    fn send_pack(
+        &mut self,
+        request: &Request,
+        unshallow: &[String],
+        sideband: Option<usize>,
+        out: &mut impl Write,
+    ) -> eyre::Result<()> {
+        let mut wants = request.wants.clone();
+        for sha in unshallow {
+            wants.extend(self.commit(sha)?.parents.clone());
+        }
+        let mut haves = self.common.clone();
+        haves.extend(unshallow.iter().cloned());
+        let objects = self.objects(&wants, &haves, request.has("include-tag"), request.filter)?;
+
+        let options = PackOptions {
+            delta_base_offset: request.has("ofs-delta"),
+            ..PackOptions::default()
+        };
+        let mut data = Vec::new();
+        pack::pack_objects(self.git_dir, &objects, &options, None, &mut data)?;
+
+        let Some(max) = sideband else {
+            out.write_all(&data)?;
+            out.flush()?;
+            return Ok(());
+        };
+        let mut writer = pktline::Writer::new(&mut *out);
+        if !request.has("no-progress") {
+            let progress = format!("Enumerating objects: {}, done.\n", objects.len());
+            writer.write_band(2, progress.as_bytes(), max)?;
+        }
+        writer.write_band(1, &data, max)?;
+        writer.write_flush()?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Returns the objects reachable from `wants` but not from the commits
+    /// of `haves`, with the path they were found at, for
+    /// [`pack::pack_objects`]. The trees of `haves`, and of the commits
+    /// they reach whose children are sent, are taken as what the client
+    /// has. With `include_tag`, the annotated tags of the objects sent come
+    /// along. The trees and blobs `filter` doesn't keep are left out, unless
+    /// wanted themselves.
+    fn objects(
+        &mut self,
+        wants: &[String],
+        haves: &[String],
+        include_tag: bool,
+        filter: Option<Filter>,
+    ) -> eyre::Result<Vec<(String, String)>> {
+        let mut uninteresting = HashSet::new();
+        let mut pending = Vec::new();
+        for have in haves {
+            let sha = revision::peel(self.git_dir, have)?;
+            if self.kind(&sha).as_deref() == Some("commit") {
+                pending.push(sha);
+            }
+        }
+        let edges = pending.clone();
+        while let Some(sha) = pending.pop() {
+            if uninteresting.insert(sha.clone()) {
+                pending.extend(self.parents(&sha)?);
+            }
+        }
+
+        // Tags are sent along with the objects they tag
+        let mut objects = Vec::new();
+        let mut seen = HashSet::new();
+        let mut trees = Vec::new();
+        for want in wants {
+            let mut sha = want.clone();
+            loop {
+                let (kind, content) = git::read_object(self.git_dir, &sha)?;
+                match kind.as_str() {
+                    "tag" => {
+                        if seen.insert(sha.clone()) {
+                            objects.push((sha, String::new()));
+                        }
+                        sha = Tag::parse(&content)?.object;
+                    }
+                    "commit" => {
+                        pending.push(sha);
+                        break;
+                    }
+                    "tree" => {
+                        trees.push(sha);
+                        break;
+                    }
+                    _ => {
+                        if seen.insert(sha.clone()) {
+                            objects.push((sha, String::new()));
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut commits = Vec::new();
+        let mut visited = HashSet::new();
+        let mut boundary = edges;
+        while let Some(sha) = pending.pop() {
+            if !visited.insert(sha.clone()) {
+                continue;
+            }
+            if uninteresting.contains(&sha) {
+                boundary.push(sha);
+                continue;
+            }
+            pending.extend(self.parents(&sha)?);
+            commits.push(sha);
+        }
+        for sha in boundary {
+            let tree = self.commit(&sha)?.tree.clone();
+            pack::add_tree(self.git_dir, &tree, "", &mut seen, &mut Vec::new())?;
+        }
+        for sha in commits {
+            let tree = self.commit(&sha)?.tree.clone();
+            seen.insert(sha.clone());
+            objects.push((sha, String::new()));
+            pack::add_filtered_tree(self.git_dir, &tree, "", filter, 0, &mut seen, &mut objects)?;
+        }
+        for tree in trees {
+            // Wanted trees are sent whatever the filter, unlike their entries
+            let filter = filter.map(|filter| match filter {
+                Filter::Tree(0) => Filter::Tree(1),
+                filter => filter,
+            });
+            pack::add_filtered_tree(self.git_dir, &tree, "", filter, 0, &mut seen, &mut objects)?;
+        }
+
+        if include_tag {
+            let sent = objects
+                .iter()
+                .map(|(sha, _)| sha.clone())
+                .collect::<HashSet<_>>();
+            for (_, sha) in refs::list_refs(self.git_dir, "refs/tags/")? {
+                let mut chain = Vec::new();
+                let mut object = sha;
+                loop {
+                    let (kind, content) = git::read_object(self.git_dir, &object)?;
+                    if kind != "tag" {
+                        break;
+                    }
+                    let tagged = Tag::parse(&content)?.object;
+                    chain.push(std::mem::replace(&mut object, tagged));
+                }
+                if !chain.is_empty() && sent.contains(&object) {
+                    for tag in chain {
+                        if seen.insert(tag.clone()) {
+                            objects.push((tag, String::new()));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(objects)
+    }
+}
@@ -0,0 +1,69 @@
+/// Matches `text` against a glob pattern. With `pathname`, `*`, `?` and
+/// brackets don't match `/` but `**` matches across directories, otherwise
+/// `*` matches anything.
+pub fn wildmatch(pattern: &[u8], text: &[u8], pathname: bool) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            // Zero or more leading directories
+            wildmatch(rest, text, pathname)
+                || text
+                    .iter()
+                    .enumerate()
+                    .any(|(i, c)| *c == b'/' && wildmatch(rest, &text[i + 1..], pathname))
+        }
+        [b'*', b'*'] => true,
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || !pathname || text[i - 1] != b'/')
+            .any(|i| wildmatch(rest, &text[i..], pathname)),
+        [b'?', rest @ ..] => {
+            matches!(text.first(), Some(c) if !pathname || *c != b'/')
+                && wildmatch(rest, &text[1..], pathname)
+        }
+        [b'[', rest @ ..] => {
+            let Some(end) = rest.iter().skip(1).position(|c| *c == b']').map(|i| i + 1) else {
+                return text.first() == Some(&b'[') && wildmatch(rest, &text[1..], pathname);
+            };
+            let Some(c) = text.first().filter(|c| !pathname || **c != b'/') else {
+                return false;
+            };
+            let (negated, class) = match rest[..end].split_first() {
+                Some((b'!' | b'^', class)) => (true, class),
+                _ => (false, &rest[..end]),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    found |= (class[i]..=class[i + 2]).contains(c);
+                    i += 3;
+                } else {
+                    found |= class[i] == *c;
+                    i += 1;
+                }
+            }
+            found != negated && wildmatch(&rest[end + 1..], &text[1..], pathname)
+        }
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => {
+            text.first() == Some(c) && wildmatch(rest, &text[1..], pathname)
+        }
+    }
+}
+
+/// Matches `path` against the pattern of a `.gitattributes` or `.gitignore`
+/// line: patterns without a `/` match the name of the file, the others the
+/// path from the directory of the file they're in.
+pub fn matches_path(pattern: &str, path: &str) -> bool {
+    match pattern.contains('/') {
+        true => wildmatch(
+            pattern.trim_start_matches('/').as_bytes(),
+            path.as_bytes(),
+            true,
+        ),
+        false => wildmatch(
+            pattern.as_bytes(),
+            path.rsplit('/').next().unwrap_or_default().as_bytes(),
+            true,
+        ),
+    }
+}
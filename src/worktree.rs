@@ -0,0 +1,438 @@
+use crate::config::Config;
+use crate::git::{self, GitFile, GitFileContent};
+use crate::index::Index;
+use crate::odb::Odb;
+use crate::partial;
+use crate::progress::Progress;
+use crate::tree::{self, Files, MODE_EXECUTABLE, MODE_FILE, MODE_GITLINK, MODE_SYMLINK};
+use eyre::eyre;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Updates the working tree from the content of tree `from` (if any) to the
+/// content of tree `to`: files of `to` are written and files only present in
+/// `from` are removed, reporting each file to `progress`.
+pub fn checkout(
+    git_dir: &Path,
+    from: Option<&str>,
+    to: &str,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<()> {
+    let old = match from {
+        Some(sha) => tree::flatten_tree(git_dir, sha)?,
+        None => Files::new(),
+    };
+    checkout_files(git_dir, &old, &tree::flatten_tree(git_dir, to)?, progress)
+}
+
+/// Updates the working tree from the `old` files to the `new` ones,
+/// reporting each file to `progress`.
+pub fn checkout_files(
+    git_dir: &Path,
+    old: &Files,
+    new: &Files,
+    progress: Option<&dyn Progress>,
+) -> eyre::Result<()> {
+    let renames = case_collisions(git_dir, old, new)?;
+    let old_renames = match collision_groups(old).is_empty() {
+        true => HashMap::new(),
+        false => collision_renames(git_dir, &Config::load(git_dir)?, old)?,
+    };
+    // Files are removed from where they were written, unless they stay there
+    for path in old.keys() {
+        let target = old_renames.get(path).unwrap_or(path);
+        if new.contains_key(path) && renames.get(path).unwrap_or(path) == target {
+            continue;
+        }
+        if target.symlink_metadata().is_ok() {
+            fs::remove_file(target)?;
+        }
+        remove_empty_parents(target);
+    }
+
+    // Partial clones fetch the blobs they miss at once
+    let written = new.iter().filter(|(path, (mode, sha))| {
+        *mode != MODE_GITLINK && (old.get(*path) != Some(&(*mode, sha.clone())) || !path.exists())
+    });
+    partial::prefetch(git_dir, written.map(|(_, (_, sha))| sha.clone()))?;
+
+    for (i, (path, (mode, sha))) in new.iter().enumerate() {
+        if let Some(progress) = progress {
+            progress.checkout(path, i, new.len());
+        }
+        // Files that collide are written under their new name
+        let target = renames.get(path).unwrap_or(path);
+        // Skip files that are already up to date
+        if old.get(path) == Some(&(*mode, sha.clone())) && target.exists() {
+            continue;
+        }
+        write_file(git_dir, target, *mode, sha)?;
+    }
+    Ok(())
+}
+
+/// Returns where to write the files of `new` whose paths only differ by
+/// case from others, when the filesystem can't tell them apart. Following
+/// `checkout.caseCollisions`, the checkout is refused (`error`, the
+/// default), one file of each group overwrites the others with a warning
+/// (`warn`, like git), or the files after the first of each group are
+/// written with a `~<n>` suffix (`rename`), with a warning for the ones
+/// changed since the `old` files.
+fn case_collisions(
+    git_dir: &Path,
+    old: &Files,
+    new: &Files,
+) -> eyre::Result<HashMap<PathBuf, PathBuf>> {
+    let groups = collision_groups(new);
+    if groups.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let config = Config::load(git_dir)?;
+    if !ignores_case(git_dir, &config)? {
+        return Ok(HashMap::new());
+    }
+
+    let list = groups
+        .iter()
+        .flatten()
+        .map(|path| format!("  '{}'\n", path.display()))
+        .collect::<String>();
+    match config.get("checkout.caseCollisions").unwrap_or("error") {
+        "error" => Err(eyre!(tr!(
+            "the following paths collide on this case-insensitive filesystem:\n%s",
+            list.trim_end()
+        ))),
+        "warn" => {
+            eprint!(
+                "{}{list}",
+                tr!("warning: the following paths have collided (e.g. case-sensitive paths\non a case-insensitive filesystem) and only one from the same\ncolliding group is in the working tree:\n")
+            );
+            Ok(HashMap::new())
+        }
+        "rename" => {
+            let renames = rename_collisions(new, &groups);
+            for group in &groups {
+                for path in &group[1..] {
+                    let renamed = &renames[*path];
+                    if old.get(*path) == new.get(*path) && renamed.exists() {
+                        continue;
+                    }
+                    eprintln!(
+                        "{}",
+                        tr!(
+                            "warning: '%s' collides with '%s', checked out as '%s'",
+                            path.display(),
+                            group[0].display(),
+                            renamed.display()
+                        )
+                    );
+                }
+            }
+            Ok(renames)
+        }
+        other => Err(eyre!(tr!(
+            "invalid value for checkout.caseCollisions: %s",
+            other
+        ))),
+    }
+}
+
+/// Returns where the files of `files` whose paths only differ by case from
+/// others are in the working tree, when `checkout.caseCollisions` renames
+/// them on a filesystem that can't tell them apart.
+pub fn collision_renames(
+    git_dir: &Path,
+    config: &Config,
+    files: &Files,
+) -> eyre::Result<HashMap<PathBuf, PathBuf>> {
+    let groups = collision_groups(files);
+    let renamed = config.get("checkout.caseCollisions") == Some("rename");
+    match !groups.is_empty() && renamed && ignores_case(git_dir, config)? {
+        true => Ok(rename_collisions(files, &groups)),
+        false => Ok(HashMap::new()),
+    }
+}
+
+/// Returns the groups of paths of `files` that only differ by case.
+fn collision_groups(files: &Files) -> Vec<Vec<&PathBuf>> {
+    let mut groups = BTreeMap::<String, Vec<&PathBuf>>::new();
+    for path in files.keys() {
+        groups.entry(fold_case(path)).or_default().push(path);
+    }
+    groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+/// Returns the names of the paths after the first of each of `groups`,
+/// with a `~<n>` suffix making them unique among `files`.
+fn rename_collisions(files: &Files, groups: &[Vec<&PathBuf>]) -> HashMap<PathBuf, PathBuf> {
+    let mut taken = files
+        .keys()
+        .map(|path| fold_case(path))
+        .collect::<HashSet<_>>();
+    let mut renames = HashMap::new();
+    for group in groups {
+        for path in &group[1..] {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let renamed = (1..)
+                .map(|n| path.with_file_name(format!("{name}~{n}")))
+                .find(|renamed| !taken.contains(&fold_case(renamed)))
+                .unwrap_or_default();
+            taken.insert(fold_case(&renamed));
+            renames.insert(path.to_path_buf(), renamed);
+        }
+    }
+    renames
+}
+
+/// Returns true if the filesystem doesn't tell apart paths differing by
+/// case, following `core.ignoreCase` or else asking the filesystem.
+fn ignores_case(git_dir: &Path, config: &Config) -> eyre::Result<bool> {
+    match config.get_bool("core.ignoreCase")? {
+        Some(ignore_case) => Ok(ignore_case),
+        None => Ok(git_dir.join("hEAD").exists()),
+    }
+}
+
+fn fold_case(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// An operation updating the working tree, named in the errors of
+/// [`check_update`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Checkout,
+    Merge,
+    Rebase,
+}
+
+/// Fails if updating the working tree from the `old` files to the `new` ones
+/// would lose local changes, listing the files in the way: tracked files
+/// modified since `old` that the update changes, and untracked files it
+/// would write over.
+pub fn check_update(
+    git_dir: &Path,
+    old: &Files,
+    new: &Files,
+    operation: Operation,
+) -> eyre::Result<()> {
+    let current = snapshot(git_dir, old)?;
+    let modified = old
+        .iter()
+        .filter(|(path, entry)| {
+            current.get(*path).is_some_and(|e| e != *entry) && new.get(*path) != Some(entry)
+        })
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>();
+    let untracked = new
+        .keys()
+        .filter(|path| !old.contains_key(*path) && path.symlink_metadata().is_ok())
+        .collect::<Vec<_>>();
+    if modified.is_empty() && untracked.is_empty() {
+        return Ok(());
+    }
+
+    let list = |paths: &[&PathBuf]| {
+        paths
+            .iter()
+            .map(|path| format!("\t{}\n", path.display()))
+            .collect::<String>()
+    };
+    let mut errors = Vec::new();
+    if !modified.is_empty() {
+        errors.push(match operation {
+            Operation::Checkout => tr!(
+                "Your local changes to the following files would be overwritten by checkout:\n%sPlease commit your changes or stash them before you switch branches.",
+                list(&modified)
+            ),
+            Operation::Merge => tr!(
+                "Your local changes to the following files would be overwritten by merge:\n%sPlease commit your changes or stash them before you merge.",
+                list(&modified)
+            ),
+            Operation::Rebase => tr!(
+                "Your local changes to the following files would be overwritten by rebase:\n%sPlease commit your changes or stash them before you rebase.",
+                list(&modified)
+            ),
+        });
+    }
+    if !untracked.is_empty() {
+        errors.push(match operation {
+            Operation::Checkout => tr!(
+                "The following untracked working tree files would be overwritten by checkout:\n%sPlease move or remove them before you switch branches.",
+                list(&untracked)
+            ),
+            Operation::Merge => tr!(
+                "The following untracked working tree files would be overwritten by merge:\n%sPlease move or remove them before you merge.",
+                list(&untracked)
+            ),
+            Operation::Rebase => tr!(
+                "The following untracked working tree files would be overwritten by rebase:\n%sPlease move or remove them before you rebase.",
+                list(&untracked)
+            ),
+        });
+    }
+    errors.push(tr!("Aborting"));
+    Err(eyre!(errors.join("\n")))
+}
+
+/// Returns the files to update the working tree from to get from the `old`
+/// files to the `new` ones: `old`, once [`check_update`] found no local
+/// change in the way, or with `force`, the files as they are, so that their
+/// local changes are thrown away, their blobs being written first.
+pub fn prepare_update(
+    git_dir: &Path,
+    old: &Files,
+    new: &Files,
+    operation: Operation,
+    force: bool,
+) -> eyre::Result<Files> {
+    match force {
+        true => {
+            // The local changes thrown away are kept as blobs
+            let current = snapshot(git_dir, old)?;
+            write_blobs(
+                git_dir,
+                current.iter().filter(|(path, entry)| {
+                    old.get(*path) != Some(entry) && new.get(*path) != Some(entry)
+                }),
+            )?;
+            Ok(current)
+        }
+        false => {
+            check_update(git_dir, old, new, operation)?;
+            Ok(old.clone())
+        }
+    }
+}
+
+/// Returns the current state in the working tree of the `tracked` files,
+/// without writing the blobs of their content: files whose stat information
+/// the index knows to be up to date keep their staged blob, the others are
+/// hashed. Files missing from the working tree are left out.
+pub fn snapshot(git_dir: &Path, tracked: &Files) -> eyre::Result<Files> {
+    let index = Index::read(git_dir)?;
+    let mut files = Files::new();
+    for (path, (mode, sha)) in tracked {
+        // Submodules are tracked by commit, not by content
+        if *mode == MODE_GITLINK {
+            files.insert(path.clone(), (*mode, sha.clone()));
+            continue;
+        }
+        let Ok(metadata) = path.symlink_metadata() else {
+            continue;
+        };
+        let staged = index.get(&path.to_string_lossy().replace('\\', "/"));
+        if let Some(entry) = staged.filter(|e| index.is_up_to_date(e, &metadata)) {
+            files.insert(path.clone(), (entry.mode, entry.sha.clone()));
+            continue;
+        }
+        let Some((mode, content)) = read_file(path, &metadata)? else {
+            continue;
+        };
+        files.insert(
+            path.clone(),
+            (mode, git::hash_object("blob", &content).0.to_string()),
+        );
+    }
+    Ok(files)
+}
+
+/// Writes the blobs of the `files` of the working tree that [`snapshot`]
+/// only hashed, so that their content can be read back.
+pub fn write_blobs<'a>(
+    git_dir: &Path,
+    files: impl IntoIterator<Item = (&'a PathBuf, &'a (u32, String))>,
+) -> eyre::Result<()> {
+    let odb = Odb::new(git_dir);
+    for (path, (mode, _)) in files {
+        if *mode == MODE_GITLINK {
+            continue;
+        }
+        let metadata = path.symlink_metadata()?;
+        if let Some((_, content)) = read_file(path, &metadata)? {
+            odb.write("blob", &content)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the mode and the content of the file or symlink at `path`
+/// described by `metadata`, or `None` for anything else.
+fn read_file(path: &Path, metadata: &fs::Metadata) -> eyre::Result<Option<(u32, Vec<u8>)>> {
+    if metadata.is_symlink() {
+        let target = fs::read_link(path)?;
+        Ok(Some((
+            MODE_SYMLINK,
+            target.to_string_lossy().as_bytes().to_vec(),
+        )))
+    } else if metadata.is_file() {
+        Ok(Some((file_mode(metadata), fs::read(path)?)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the tree mode of a regular file.
+pub fn file_mode(metadata: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 != 0 {
+            return MODE_EXECUTABLE;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+    MODE_FILE
+}
+
+/// Writes the blob with the given sha to `path` with the permissions
+/// described by `mode`.
+fn write_file(git_dir: &Path, path: &Path, mode: u32, sha: &str) -> eyre::Result<()> {
+    // Submodules are checked out separately
+    if mode == MODE_GITLINK {
+        return Ok(());
+    }
+
+    let GitFileContent::Blob(content) = GitFile::read(git_dir, sha)?.file_content else {
+        return Err(eyre!("object {sha} is not a blob"));
+    };
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    if path.symlink_metadata().is_ok() {
+        fs::remove_file(path)?;
+    }
+
+    #[cfg(unix)]
+    if mode == MODE_SYMLINK {
+        let target = std::str::from_utf8(&content)?;
+        std::os::unix::fs::symlink(target, path)?;
+        return Ok(());
+    }
+
+    fs::write(path, content)?;
+
+    #[cfg(unix)]
+    if mode == MODE_EXECUTABLE {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Removes the directories containing `path` as long as they are empty.
+fn remove_empty_parents(path: &Path) {
+    let mut dir = path.parent();
+    while let Some(d) = dir.filter(|d| !d.as_os_str().is_empty()) {
+        if fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}